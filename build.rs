@@ -1,9 +1,13 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     configure_me_codegen::build_script_auto().unwrap_or_else(|error| error.report_and_exit());
 
-    // Compile the protos for our grpc server.
+    // Compile the protos for our grpc server. The generated types are serializable so that the
+    // cli can output them as JSON, and deserializable so that the REST server can accept them as
+    // JSON. Fields that are left out of a JSON request take their default value, as in protobuf.
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .message_attribute(".", "#[serde(default)]")
         .compile(&["proto/lndkrpc.proto"], &["proto"])?;
 
     Ok(())
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lightning::offers::invoice::Bolt12Invoice;
+use lndk::server::generate_bolt12_invoice_contents;
+
+// Works on raw invoice bytes so that the fuzzer spends its time on the invoice itself and its
+// conversion to our proto types, rather than on string encodings.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(invoice) = Bolt12Invoice::try_from(data.to_vec()) {
+        generate_bolt12_invoice_contents(&invoice);
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lightning::offers::invoice::Bolt12Invoice;
+use lndk::server::generate_bolt12_invoice_contents;
+use lndk::Bolt12InvoiceString;
+
+// Invoice strings are supplied by API callers in either hex or bech32 encoding.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(invoice_str) = std::str::from_utf8(data) {
+        let invoice_string = Bolt12InvoiceString(invoice_str.to_string());
+        if let Ok(invoice) = Bolt12Invoice::try_from(invoice_string) {
+            generate_bolt12_invoice_contents(&invoice);
+        }
+    }
+});
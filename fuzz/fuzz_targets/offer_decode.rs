@@ -0,0 +1,16 @@
+#![no_main]
+
+use bitcoin::network::constants::Network;
+use libfuzzer_sys::fuzz_target;
+use lndk::lndk_offers::decode;
+use lndk::server::generate_offer_contents;
+
+// Offer strings come straight from API callers, so decoding them (and describing what we decoded)
+// must never panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(offer_str) = std::str::from_utf8(data) {
+        if let Ok(offer) = decode(offer_str.to_string()) {
+            generate_offer_contents(&offer, Network::Regtest);
+        }
+    }
+});
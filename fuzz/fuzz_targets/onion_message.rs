@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lndk::onion_messenger::parse_onion_message;
+
+// The first 33 bytes are used as the peer's public key and the rest as the custom message's data,
+// mirroring the fields of the custom messages LND forwards to us.
+fuzz_target!(|data: &[u8]| {
+    let (peer, message) = data.split_at(data.len().min(33));
+    let _ = parse_onion_message(peer, message);
+});
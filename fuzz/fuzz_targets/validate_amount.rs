@@ -0,0 +1,38 @@
+#![no_main]
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use lightning::offers::offer::Amount;
+use lndk::lndk_offers::validate_amount;
+
+// Input layout:
+// * byte 0: the offer's amount kind (none, bitcoin or currency).
+// * byte 1: whether the payer set an amount.
+// * bytes 2..10: the offer amount, big endian.
+// * bytes 10..18: the payer's amount, big endian.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 18 {
+        return;
+    }
+    let offer_amount = u64::from_be_bytes(data[2..10].try_into().unwrap());
+    let pay_amount = u64::from_be_bytes(data[10..18].try_into().unwrap());
+
+    let offer_amount = match data[0] % 3 {
+        0 => None,
+        1 => Some(Amount::Bitcoin {
+            amount_msats: offer_amount,
+        }),
+        _ => Some(Amount::Currency {
+            iso4217_code: *b"USD",
+            amount: offer_amount,
+        }),
+    };
+    let pay_amount = (data[1] % 2 == 1).then_some(pay_amount);
+
+    if let Ok(amount) = block_on(validate_amount(offer_amount.as_ref(), pay_amount)) {
+        // We should never agree to pay less than a bitcoin denominated offer asks for.
+        if let Some(Amount::Bitcoin { amount_msats }) = offer_amount {
+            assert!(amount >= amount_msats);
+        }
+    }
+});
@@ -0,0 +1,519 @@
+use bitcoin::secp256k1::PublicKey;
+use lightning::ln::channelmanager::PaymentId;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// SpendingLimits holds the caps that we apply to outgoing payments. Any limit that isn't set is
+/// not enforced.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpendingLimits {
+    /// The largest amount we'll send in a single payment.
+    pub max_payment_msats: Option<u64>,
+    /// The most we'll spend in any rolling one hour window.
+    pub max_hourly_msats: Option<u64>,
+    /// The most we'll spend in any rolling 24 hour window.
+    pub max_daily_msats: Option<u64>,
+    /// The most we'll spend with a single offer issuer in any rolling 24 hour window, keyed by the
+    /// offer's signing pubkey.
+    pub issuer_daily_msats: HashMap<PublicKey, u64>,
+}
+
+impl SpendingLimits {
+    /// The user passes in per-issuer limits as a comma-delimited string, where each entry takes
+    /// the form '<pubkey>:<msats>'. Here we turn that string into a map.
+    pub fn parse_issuer_limits(limits_str: &str) -> Result<HashMap<PublicKey, u64>, BudgetError> {
+        let mut limits = HashMap::new();
+        for entry in limits_str.split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+
+            let (pubkey, msats) = entry
+                .split_once(':')
+                .ok_or_else(|| BudgetError::InvalidIssuerLimit(entry.to_string()))?;
+            let pubkey = PublicKey::from_str(pubkey)
+                .map_err(|_| BudgetError::InvalidIssuerLimit(entry.to_string()))?;
+            let msats = msats
+                .parse::<u64>()
+                .map_err(|_| BudgetError::InvalidIssuerLimit(entry.to_string()))?;
+
+            limits.insert(pubkey, msats);
+        }
+
+        Ok(limits)
+    }
+}
+
+/// SpendingLimit identifies the limit that a payment would have exceeded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SpendingLimit {
+    /// The maximum amount allowed for a single payment.
+    Payment(u64),
+    /// The maximum amount allowed in a rolling hour.
+    Hourly(u64),
+    /// The maximum amount allowed in a rolling day.
+    Daily(u64),
+    /// The maximum amount allowed in a rolling day for the given offer issuer.
+    Issuer(PublicKey, u64),
+}
+
+impl Display for SpendingLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpendingLimit::Payment(max) => write!(f, "maximum payment amount of {max} msats"),
+            SpendingLimit::Hourly(max) => write!(f, "hourly spending limit of {max} msats"),
+            SpendingLimit::Daily(max) => write!(f, "daily spending limit of {max} msats"),
+            SpendingLimit::Issuer(issuer, max) => write!(
+                f,
+                "daily spending limit of {max} msats for offer issuer {issuer}"
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// BudgetError is an error that occurs while checking or recording spending.
+pub enum BudgetError {
+    /// The payment would take us over one of our spending limits.
+    LimitExceeded(SpendingLimit),
+    /// Failed to read or write our spending record.
+    Io(std::io::Error),
+    /// Failed to (de)serialize our spending record.
+    Serialization(serde_json::Error),
+    /// User provided an issuer limit we couldn't parse.
+    InvalidIssuerLimit(String),
+}
+
+impl Display for BudgetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetError::LimitExceeded(limit) => write!(f, "Payment would exceed the {limit}"),
+            BudgetError::Io(e) => write!(f, "IO error: {e:?}"),
+            BudgetError::Serialization(e) => write!(f, "Error serializing spending record: {e}"),
+            BudgetError::InvalidIssuerLimit(s) => write!(
+                f,
+                "Invalid issuer limit '{s}', expected the form '<pubkey>:<msats>'"
+            ),
+        }
+    }
+}
+
+impl Error for BudgetError {}
+
+/// SpendRecord is a single payment that counts towards our spending limits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SpendRecord {
+    payment_id: String,
+    issuer: String,
+    amount_msats: u64,
+    timestamp: u64,
+}
+
+/// BudgetStatus is a summary of how much we've spent and how much more we're allowed to spend.
+/// Remaining amounts are only set if the corresponding limit is configured.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BudgetStatus {
+    pub max_payment_msats: Option<u64>,
+    pub hourly_spent_msats: u64,
+    pub hourly_remaining_msats: Option<u64>,
+    pub daily_spent_msats: u64,
+    pub daily_remaining_msats: Option<u64>,
+    pub issuer_spent_msats: Option<u64>,
+    pub issuer_remaining_msats: Option<u64>,
+}
+
+/// Budget tracks outgoing payments against our spending limits. A payment is recorded as soon as
+/// it's approved, before any HTLC is dispatched, so in-flight payments count towards our limits
+/// too. If a path is provided, the spending record is persisted to disk so that the limits still
+/// hold across restarts.
+pub struct Budget {
+    limits: Mutex<SpendingLimits>,
+    records: Mutex<Vec<SpendRecord>>,
+    path: Option<PathBuf>,
+}
+
+impl Budget {
+    pub fn new(limits: SpendingLimits, path: Option<PathBuf>) -> Result<Self, BudgetError> {
+        let records = match path {
+            Some(ref path) if path.exists() => {
+                let contents = fs::read_to_string(path).map_err(BudgetError::Io)?;
+                serde_json::from_str(&contents).map_err(BudgetError::Serialization)?
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(Budget {
+            limits: Mutex::new(limits),
+            records: Mutex::new(records),
+            path,
+        })
+    }
+
+    pub fn limits(&self) -> SpendingLimits {
+        self.limits.lock().unwrap().clone()
+    }
+
+    /// set_limits replaces our spending limits. Payments we've already made keep counting towards
+    /// the new limits.
+    pub fn set_limits(&self, limits: SpendingLimits) {
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    /// reserve checks that a payment fits within our spending limits and, if it does, records it.
+    pub(crate) fn reserve(
+        &self,
+        payment_id: PaymentId,
+        issuer: PublicKey,
+        amount_msats: u64,
+    ) -> Result<(), BudgetError> {
+        self.reserve_at(payment_id, issuer, amount_msats, unix_time())
+    }
+
+    /// release removes a payment from our spending record. It should only be used if a payment
+    /// failed before any funds could leave our node.
+    pub(crate) fn release(&self, payment_id: PaymentId) {
+        let payment_id = hex::encode(payment_id.0);
+        let mut records = self.records.lock().unwrap();
+        records.retain(|record| record.payment_id != payment_id);
+
+        if let Err(e) = self.persist(&records) {
+            error!("Could not persist spending record: {e}");
+        }
+    }
+
+    /// status reports our current spending, optionally including the spending for a single offer
+    /// issuer.
+    pub fn status(&self, issuer: Option<PublicKey>) -> BudgetStatus {
+        self.status_at(issuer, unix_time())
+    }
+
+    fn reserve_at(
+        &self,
+        payment_id: PaymentId,
+        issuer: PublicKey,
+        amount_msats: u64,
+        now: Duration,
+    ) -> Result<(), BudgetError> {
+        let limits = self.limits();
+        if let Some(max) = limits.max_payment_msats {
+            if amount_msats > max {
+                return Err(BudgetError::LimitExceeded(SpendingLimit::Payment(max)));
+            }
+        }
+
+        let mut records = self.records.lock().unwrap();
+
+        // Records older than a day no longer count towards any of our limits.
+        records.retain(|record| now.as_secs().saturating_sub(record.timestamp) < DAY.as_secs());
+
+        if let Some(max) = limits.max_hourly_msats {
+            if spent_since(&records, now, HOUR, None).saturating_add(amount_msats) > max {
+                return Err(BudgetError::LimitExceeded(SpendingLimit::Hourly(max)));
+            }
+        }
+
+        if let Some(max) = limits.max_daily_msats {
+            if spent_since(&records, now, DAY, None).saturating_add(amount_msats) > max {
+                return Err(BudgetError::LimitExceeded(SpendingLimit::Daily(max)));
+            }
+        }
+
+        let issuer_str = issuer.to_string();
+        if let Some(max) = limits.issuer_daily_msats.get(&issuer) {
+            if spent_since(&records, now, DAY, Some(&issuer_str)).saturating_add(amount_msats)
+                > *max
+            {
+                return Err(BudgetError::LimitExceeded(SpendingLimit::Issuer(
+                    issuer, *max,
+                )));
+            }
+        }
+
+        records.push(SpendRecord {
+            payment_id: hex::encode(payment_id.0),
+            issuer: issuer_str,
+            amount_msats,
+            timestamp: now.as_secs(),
+        });
+
+        // If we can't persist the payment, we don't allow it to go through. Otherwise we could
+        // lose track of it across a restart.
+        if let Err(e) = self.persist(&records) {
+            records.pop();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn status_at(&self, issuer: Option<PublicKey>, now: Duration) -> BudgetStatus {
+        let limits = self.limits();
+        let records = self.records.lock().unwrap();
+        let hourly_spent_msats = spent_since(&records, now, HOUR, None);
+        let daily_spent_msats = spent_since(&records, now, DAY, None);
+
+        let (issuer_spent_msats, issuer_remaining_msats) = match issuer {
+            Some(issuer) => {
+                let spent = spent_since(&records, now, DAY, Some(&issuer.to_string()));
+                let remaining = limits
+                    .issuer_daily_msats
+                    .get(&issuer)
+                    .map(|max| max.saturating_sub(spent));
+                (Some(spent), remaining)
+            }
+            None => (None, None),
+        };
+
+        BudgetStatus {
+            max_payment_msats: limits.max_payment_msats,
+            hourly_spent_msats,
+            hourly_remaining_msats: limits
+                .max_hourly_msats
+                .map(|max| max.saturating_sub(hourly_spent_msats)),
+            daily_spent_msats,
+            daily_remaining_msats: limits
+                .max_daily_msats
+                .map(|max| max.saturating_sub(daily_spent_msats)),
+            issuer_spent_msats,
+            issuer_remaining_msats,
+        }
+    }
+
+    fn persist(&self, records: &[SpendRecord]) -> Result<(), BudgetError> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        // Write to a temporary file first, so that we never leave a half-written record behind.
+        let contents = serde_json::to_string(records).map_err(BudgetError::Serialization)?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).map_err(BudgetError::Io)?;
+        fs::rename(&tmp_path, path).map_err(BudgetError::Io)
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget {
+            limits: Mutex::new(SpendingLimits::default()),
+            records: Mutex::new(Vec::new()),
+            path: None,
+        }
+    }
+}
+
+// Sums up the payments made within the window before now, optionally only for a single issuer.
+fn spent_since(
+    records: &[SpendRecord],
+    now: Duration,
+    window: Duration,
+    issuer: Option<&str>,
+) -> u64 {
+    records
+        .iter()
+        .filter(|record| now.as_secs().saturating_sub(record.timestamp) < window.as_secs())
+        .filter(|record| issuer.map_or(true, |issuer| record.issuer == issuer))
+        .fold(0, |total, record| total.saturating_add(record.amount_msats))
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixtures::pubkey;
+
+    fn payment_id(byte: u8) -> PaymentId {
+        PaymentId([byte; 32])
+    }
+
+    #[test]
+    fn test_parse_issuer_limits() {
+        let limits_str = format!("{}:1000, {}:2000", pubkey(0), pubkey(1));
+        let limits = SpendingLimits::parse_issuer_limits(&limits_str).unwrap();
+        assert_eq!(limits.len(), 2);
+        assert_eq!(limits.get(&pubkey(0)), Some(&1000));
+        assert_eq!(limits.get(&pubkey(1)), Some(&2000));
+
+        assert!(SpendingLimits::parse_issuer_limits("").unwrap().is_empty());
+        assert!(SpendingLimits::parse_issuer_limits("notapubkey:1000").is_err());
+        assert!(SpendingLimits::parse_issuer_limits(&format!("{}", pubkey(0))).is_err());
+        assert!(SpendingLimits::parse_issuer_limits(&format!("{}:lots", pubkey(0))).is_err());
+    }
+
+    #[test]
+    fn test_payment_limit() {
+        let limits = SpendingLimits {
+            max_payment_msats: Some(1000),
+            ..Default::default()
+        };
+        let budget = Budget::new(limits, None).unwrap();
+        let now = Duration::from_secs(1_000_000);
+
+        assert!(budget
+            .reserve_at(payment_id(0), pubkey(0), 1000, now)
+            .is_ok());
+        assert!(matches!(
+            budget.reserve_at(payment_id(1), pubkey(0), 1001, now),
+            Err(BudgetError::LimitExceeded(SpendingLimit::Payment(1000)))
+        ));
+    }
+
+    #[test]
+    fn test_rolling_limits() {
+        let limits = SpendingLimits {
+            max_hourly_msats: Some(1000),
+            max_daily_msats: Some(1500),
+            ..Default::default()
+        };
+        let budget = Budget::new(limits, None).unwrap();
+        let start = Duration::from_secs(1_000_000);
+
+        assert!(budget
+            .reserve_at(payment_id(0), pubkey(0), 800, start)
+            .is_ok());
+        assert!(matches!(
+            budget.reserve_at(payment_id(1), pubkey(0), 300, start),
+            Err(BudgetError::LimitExceeded(SpendingLimit::Hourly(1000)))
+        ));
+
+        // Once the hour has passed, we're within our hourly limit again but should hit the daily
+        // limit instead.
+        let later = start + HOUR;
+        assert!(budget
+            .reserve_at(payment_id(1), pubkey(0), 700, later)
+            .is_ok());
+        assert!(matches!(
+            budget.reserve_at(payment_id(2), pubkey(0), 100, later),
+            Err(BudgetError::LimitExceeded(SpendingLimit::Daily(1500)))
+        ));
+
+        // After a day has passed, we have our full budget back.
+        let status = budget.status_at(None, start + DAY + HOUR);
+        assert_eq!(status.daily_spent_msats, 0);
+        assert_eq!(status.daily_remaining_msats, Some(1500));
+        assert_eq!(status.hourly_remaining_msats, Some(1000));
+    }
+
+    #[test]
+    fn test_issuer_limit() {
+        let mut issuer_daily_msats = HashMap::new();
+        issuer_daily_msats.insert(pubkey(0), 1000);
+        let limits = SpendingLimits {
+            issuer_daily_msats,
+            ..Default::default()
+        };
+        let budget = Budget::new(limits, None).unwrap();
+        let now = Duration::from_secs(1_000_000);
+
+        assert!(budget
+            .reserve_at(payment_id(0), pubkey(0), 1000, now)
+            .is_ok());
+        assert!(matches!(
+            budget.reserve_at(payment_id(1), pubkey(0), 1, now),
+            Err(BudgetError::LimitExceeded(SpendingLimit::Issuer(_, 1000)))
+        ));
+
+        // Other issuers aren't affected.
+        assert!(budget
+            .reserve_at(payment_id(2), pubkey(1), 5000, now)
+            .is_ok());
+
+        let status = budget.status_at(Some(pubkey(0)), now);
+        assert_eq!(status.issuer_spent_msats, Some(1000));
+        assert_eq!(status.issuer_remaining_msats, Some(0));
+        assert_eq!(status.daily_spent_msats, 6000);
+        assert_eq!(status.daily_remaining_msats, None);
+    }
+
+    #[test]
+    fn test_release() {
+        let limits = SpendingLimits {
+            max_daily_msats: Some(1000),
+            ..Default::default()
+        };
+        let budget = Budget::new(limits, None).unwrap();
+        let now = Duration::from_secs(1_000_000);
+
+        assert!(budget
+            .reserve_at(payment_id(0), pubkey(0), 1000, now)
+            .is_ok());
+        assert!(budget
+            .reserve_at(payment_id(1), pubkey(0), 1000, now)
+            .is_err());
+
+        // Once a failed payment is released, it no longer counts against our budget.
+        budget.release(payment_id(0));
+        assert!(budget
+            .reserve_at(payment_id(1), pubkey(0), 1000, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let limits = SpendingLimits {
+            max_daily_msats: Some(1000),
+            ..Default::default()
+        };
+        let budget = Budget::new(limits, None).unwrap();
+        let now = Duration::from_secs(1_000_000);
+
+        assert!(budget
+            .reserve_at(payment_id(0), pubkey(0), 800, now)
+            .is_ok());
+
+        // Raising the limit lets more payments through, but what we've already spent still counts.
+        budget.set_limits(SpendingLimits {
+            max_daily_msats: Some(2000),
+            ..Default::default()
+        });
+        assert!(budget
+            .reserve_at(payment_id(1), pubkey(0), 1000, now)
+            .is_ok());
+        assert!(matches!(
+            budget.reserve_at(payment_id(2), pubkey(0), 300, now),
+            Err(BudgetError::LimitExceeded(SpendingLimit::Daily(2000)))
+        ));
+        assert_eq!(budget.status_at(None, now).daily_remaining_msats, Some(200));
+    }
+
+    #[test]
+    fn test_budget_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("budget.json");
+        let limits = SpendingLimits {
+            max_daily_msats: Some(1000),
+            ..Default::default()
+        };
+
+        let budget = Budget::new(limits.clone(), Some(path.clone())).unwrap();
+        let now = unix_time();
+        assert!(budget
+            .reserve_at(payment_id(0), pubkey(0), 600, now)
+            .is_ok());
+        drop(budget);
+
+        // Spending should still be accounted for after we reload the budget from disk.
+        let budget = Budget::new(limits, Some(path)).unwrap();
+        assert_eq!(budget.status_at(None, now).daily_spent_msats, 600);
+        assert!(budget
+            .reserve_at(payment_id(1), pubkey(0), 600, now)
+            .is_err());
+    }
+}
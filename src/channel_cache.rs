@@ -0,0 +1,372 @@
+use crate::clock::{Clock, TokioClock};
+use crate::lnd::{ChannelInfoProvider, EventStream, EventSubscriber};
+use async_trait::async_trait;
+use bitcoin::secp256k1::{self, PublicKey};
+use futures::StreamExt;
+use lightning::blinded_path::Direction;
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::time::{Duration, Instant};
+use tonic_lnd::lnrpc::channel_event_update::Channel;
+use tonic_lnd::lnrpc::{ChanInfoRequest, ChannelEdge, ChannelEventUpdate, GraphTopologyUpdate};
+use tonic_lnd::tonic::Status;
+use tonic_lnd::Client;
+use triggered::Listener;
+
+/// DEFAULT_CHANNEL_CACHE_SIZE is the default number of channels we hold in the cache.
+pub const DEFAULT_CHANNEL_CACHE_SIZE: usize = 1_000;
+
+/// DEFAULT_CHANNEL_CACHE_TTL is the default amount of time we trust a cached channel for before
+/// asking LND about it again.
+pub const DEFAULT_CHANNEL_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// ChannelNodes holds the nodes on either side of a channel, as hex encoded pubkeys in the form
+/// LND returns them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelNodes {
+    pub node1_pub: String,
+    pub node2_pub: String,
+}
+
+impl ChannelNodes {
+    /// Returns the node that the direction of a blinded path's introduction node points to.
+    pub fn node_id(&self, direction: Direction) -> Result<PublicKey, secp256k1::Error> {
+        let pubkey = match direction {
+            Direction::NodeOne => &self.node1_pub,
+            Direction::NodeTwo => &self.node2_pub,
+        };
+        PublicKey::from_str(pubkey)
+    }
+
+    /// Returns the node on the other side of the channel from us, which is where a message that
+    /// names this channel as its next hop goes.
+    pub fn counterparty(&self, our_node_id: &PublicKey) -> Result<PublicKey, secp256k1::Error> {
+        let node1 = PublicKey::from_str(&self.node1_pub)?;
+        if node1 == *our_node_id {
+            PublicKey::from_str(&self.node2_pub)
+        } else {
+            Ok(node1)
+        }
+    }
+}
+
+struct CachedChannel {
+    nodes: ChannelNodes,
+    inserted: Instant,
+}
+
+/// ChannelCache remembers which nodes are on either side of a short channel id, so that we don't
+/// need to ask LND every time we resolve a compact (scid-based) blinded path. Entries expire after
+/// a ttl, and the oldest entries are evicted once the cache is full. Channels that close are
+/// dropped from the cache by invalidate_closed_channels.
+pub struct ChannelCache<C: Clock = TokioClock> {
+    channels: Mutex<HashMap<u64, CachedChannel>>,
+    capacity: usize,
+    ttl: Duration,
+    clock: C,
+}
+
+impl ChannelCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        ChannelCache::with_clock(capacity, ttl, TokioClock::new())
+    }
+}
+
+impl<C: Clock> ChannelCache<C> {
+    pub(crate) fn with_clock(capacity: usize, ttl: Duration, clock: C) -> Self {
+        ChannelCache {
+            channels: Mutex::new(HashMap::new()),
+            capacity,
+            ttl,
+            clock,
+        }
+    }
+
+    /// Returns the nodes of the channel if we have it cached and the entry hasn't expired yet.
+    pub fn get(&self, scid: u64) -> Option<ChannelNodes> {
+        let now = self.clock.now();
+        let mut channels = self.channels.lock().unwrap();
+        match channels.get(&scid) {
+            Some(channel) if now.duration_since(channel.inserted) < self.ttl => {
+                Some(channel.nodes.clone())
+            }
+            Some(_) => {
+                channels.remove(&scid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, scid: u64, nodes: ChannelNodes) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let now = self.clock.now();
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(&scid) && channels.len() >= self.capacity {
+            // Make room by dropping expired entries first, and if that isn't enough, the oldest.
+            channels.retain(|_, channel| now.duration_since(channel.inserted) < self.ttl);
+            if channels.len() >= self.capacity {
+                let oldest = channels
+                    .iter()
+                    .min_by_key(|(_, channel)| channel.inserted)
+                    .map(|(scid, _)| *scid);
+                if let Some(oldest) = oldest {
+                    channels.remove(&oldest);
+                }
+            }
+        }
+
+        channels.insert(
+            scid,
+            CachedChannel {
+                nodes,
+                inserted: now,
+            },
+        );
+    }
+
+    /// Drops the channel from the cache, e.g. because it has closed.
+    pub fn invalidate(&self, scid: u64) {
+        self.channels.lock().unwrap().remove(&scid);
+    }
+
+    /// Returns the nodes of the channel, only calling LND's GetChanInfo if we don't have a fresh
+    /// entry cached.
+    pub async fn lookup(
+        &self,
+        mut client: impl ChannelInfoProvider + Send,
+        scid: u64,
+    ) -> Result<ChannelNodes, Status> {
+        if let Some(nodes) = self.get(scid) {
+            return Ok(nodes);
+        }
+
+        let channel_info = client.get_chan_info(scid).await?;
+        let nodes = ChannelNodes {
+            node1_pub: channel_info.node1_pub,
+            node2_pub: channel_info.node2_pub,
+        };
+        self.insert(scid, nodes.clone());
+
+        Ok(nodes)
+    }
+}
+
+#[async_trait]
+impl ChannelInfoProvider for Client {
+    async fn get_chan_info(&mut self, short_channel_id: u64) -> Result<ChannelEdge, Status> {
+        let resp = self
+            .lightning_read_only()
+            .get_chan_info(ChanInfoRequest {
+                chan_id: short_channel_id,
+            })
+            .await?;
+        Ok(resp.into_inner())
+    }
+}
+
+impl Default for ChannelCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHANNEL_CACHE_SIZE, DEFAULT_CHANNEL_CACHE_TTL)
+    }
+}
+
+/// invalidate_closed_channels drops channels from the cache as LND tells us they close, until we
+/// receive the shutdown signal. Closes of our own channels come from LND's channel events, and
+/// closes of other channels in the graph from its graph topology updates. If we can't subscribe to
+/// either, cached channels still expire after the cache's ttl.
+pub async fn invalidate_closed_channels(
+    mut client: impl EventSubscriber + Send,
+    cache: Arc<ChannelCache>,
+    listener: Listener,
+) {
+    let mut channel_events = client
+        .subscribe_channel_events()
+        .await
+        .map_err(|e| {
+            warn!("Could not subscribe to channel events, cached channels will expire: {e}.")
+        })
+        .ok();
+    let mut graph_updates = client
+        .subscribe_channel_graph()
+        .await
+        .map_err(|e| {
+            warn!("Could not subscribe to graph updates, cached channels will expire: {e}.")
+        })
+        .ok();
+
+    while channel_events.is_some() || graph_updates.is_some() {
+        select! {
+            _ = listener.clone() => {
+                debug!("Channel cache invalidation received shutdown signal.");
+                return;
+            }
+            event = next_message(&mut channel_events), if channel_events.is_some() => {
+                match event {
+                    Ok(Some(ChannelEventUpdate {
+                        channel: Some(Channel::ClosedChannel(summary)),
+                        ..
+                    })) => cache.invalidate(summary.chan_id),
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        warn!("Channel event subscription ended.");
+                        channel_events = None;
+                    }
+                    Err(e) => {
+                        error!("Error receiving channel events: {e}.");
+                        channel_events = None;
+                    }
+                }
+            }
+            update = next_message(&mut graph_updates), if graph_updates.is_some() => {
+                match update {
+                    Ok(Some(GraphTopologyUpdate { closed_chans, .. })) => {
+                        for closed in closed_chans {
+                            cache.invalidate(closed.chan_id);
+                        }
+                    }
+                    Ok(None) => {
+                        warn!("Graph topology subscription ended.");
+                        graph_updates = None;
+                    }
+                    Err(e) => {
+                        error!("Error receiving graph updates: {e}.");
+                        graph_updates = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// next_message reads the next message from a subscription that we know is still open.
+async fn next_message<T>(subscription: &mut Option<EventStream<T>>) -> Result<Option<T>, Status> {
+    match subscription {
+        Some(stream) => stream.next().await.transpose(),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixtures::pubkey;
+    use mockall::mock;
+
+    mock! {
+        FixedClock{}
+
+        impl Clock for FixedClock{
+            fn now(&self) -> Instant;
+        }
+    }
+
+    fn nodes(byte: u8) -> ChannelNodes {
+        ChannelNodes {
+            node1_pub: pubkey(byte).to_string(),
+            node2_pub: pubkey(byte + 1).to_string(),
+        }
+    }
+
+    // Returns a cache whose clock can be moved forward through the returned handle.
+    fn test_cache(
+        capacity: usize,
+        ttl: Duration,
+    ) -> (ChannelCache<MockFixedClock>, Arc<Mutex<Instant>>) {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let clock_now = Arc::clone(&now);
+        let mut clock = MockFixedClock::new();
+        clock
+            .expect_now()
+            .returning(move || *clock_now.lock().unwrap());
+
+        (ChannelCache::with_clock(capacity, ttl, clock), now)
+    }
+
+    #[test]
+    fn test_channel_nodes() {
+        // LND gives us the channel's nodes as hex strings.
+        let nodes = nodes(1);
+        assert_eq!(nodes.node_id(Direction::NodeOne).unwrap(), pubkey(1));
+        assert_eq!(nodes.node_id(Direction::NodeTwo).unwrap(), pubkey(2));
+
+        assert_eq!(nodes.counterparty(&pubkey(1)).unwrap(), pubkey(2));
+        assert_eq!(nodes.counterparty(&pubkey(2)).unwrap(), pubkey(1));
+
+        let invalid = ChannelNodes {
+            node1_pub: "not a pubkey".to_string(),
+            node2_pub: pubkey(2).to_string(),
+        };
+        assert!(invalid.node_id(Direction::NodeOne).is_err());
+        assert!(invalid.counterparty(&pubkey(2)).is_err());
+    }
+
+    #[test]
+    fn test_cache_expiry() {
+        let ttl = Duration::from_secs(60);
+        let (cache, now) = test_cache(10, ttl);
+
+        assert_eq!(cache.get(1), None);
+        cache.insert(1, nodes(1));
+        assert_eq!(cache.get(1), Some(nodes(1)));
+
+        *now.lock().unwrap() += ttl - Duration::from_secs(1);
+        assert_eq!(cache.get(1), Some(nodes(1)));
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(cache.get(1), None);
+
+        // Inserting the channel again refreshes it.
+        cache.insert(1, nodes(1));
+        assert_eq!(cache.get(1), Some(nodes(1)));
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        let ttl = Duration::from_secs(60);
+        let (cache, now) = test_cache(2, ttl);
+
+        cache.insert(1, nodes(1));
+        *now.lock().unwrap() += Duration::from_secs(1);
+        cache.insert(2, nodes(2));
+        *now.lock().unwrap() += Duration::from_secs(1);
+
+        // The oldest channel makes way for a new one, but updating a channel we already have
+        // doesn't evict anything.
+        cache.insert(3, nodes(3));
+        assert_eq!(cache.get(1), None);
+        cache.insert(3, nodes(4));
+        assert_eq!(cache.get(2), Some(nodes(2)));
+        assert_eq!(cache.get(3), Some(nodes(4)));
+
+        // Expired channels make way before live ones do.
+        *now.lock().unwrap() += ttl - Duration::from_secs(1);
+        cache.insert(4, nodes(4));
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(3), Some(nodes(4)));
+        assert_eq!(cache.get(4), Some(nodes(4)));
+
+        // A cache without capacity doesn't hold on to anything.
+        let (cache, _) = test_cache(0, ttl);
+        cache.insert(1, nodes(1));
+        assert_eq!(cache.get(1), None);
+    }
+
+    #[test]
+    fn test_cache_invalidate() {
+        let (cache, _) = test_cache(10, Duration::from_secs(60));
+        cache.insert(1, nodes(1));
+        cache.insert(2, nodes(2));
+
+        cache.invalidate(1);
+        cache.invalidate(5);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(2), Some(nodes(2)));
+    }
+}
@@ -1,19 +1,28 @@
 use clap::{Parser, Subcommand};
 use lightning::offers::invoice::Bolt12Invoice;
+use lndk::lnd::string_to_network;
 use lndk::lndk_offers::decode;
 use lndk::lndkrpc::offers_client::OffersClient;
-use lndk::lndkrpc::{GetInvoiceRequest, PayInvoiceRequest, PayOfferRequest};
+use lndk::lndkrpc::{
+    CancelPaymentRequest, GetBudgetRequest, GetInvoiceRequest, GetMetricsRequest,
+    GetPaymentRequest, GetProofOfPaymentRequest, PayInvoiceRequest, PayOfferRequest,
+    ProofOfPayment, ReloadConfigRequest,
+};
+use lndk::server::{generate_bolt12_invoice_contents, generate_offer_contents, NODE_METADATA_KEY};
 use lndk::{
-    Bolt12InvoiceString, DEFAULT_DATA_DIR, DEFAULT_RESPONSE_INVOICE_TIMEOUT, DEFAULT_SERVER_HOST,
-    DEFAULT_SERVER_PORT, TLS_CERT_FILENAME,
+    default_data_dir, network_data_dir, Bolt12InvoiceString, DEFAULT_RESPONSE_INVOICE_TIMEOUT,
+    DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, TLS_CERT_FILENAME,
 };
+use serde::Serialize;
+use serde_json::json;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
 use std::path::PathBuf;
 use std::process::exit;
-use tonic::transport::{Certificate, Channel, ClientTlsConfig};
-use tonic::Request;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::{Code, Request, Status};
 
 fn get_macaroon_path_default(network: &str) -> PathBuf {
     home::home_dir()
@@ -45,25 +54,56 @@ struct Cli {
     macaroon_hex: Option<String>,
 
     /// This option is for passing a pem-encoded TLS certificate string to establish a connection
-    /// with the LNDK server. If this isn't set, the cli will look for the TLS file in the default
-    /// location (~.lndk).
+    /// with the LNDK server. If this isn't set, the cli will look for the TLS file in the
+    /// network's data directory (~/.lndk/data/<network> by default).
     /// Only one of cert_pem or cert_path can be set at once.
     #[arg(long, global = true, required = false)]
     cert_pem: Option<String>,
 
     /// This option is for passing a file path to a pem-encoded TLS certificate string to establish
     /// a connection with the LNDK server. If this isn't set, the cli will look for the TLS file in
-    /// the default location (~.lndk).
+    /// the network's data directory (~/.lndk/data/<network> by default).
     /// Only one of cert_pem or cert_path can be set at once.
     #[arg(long, global = true, required = false)]
     cert_path: Option<PathBuf>,
 
+    /// The directory LNDK stores its data in, if it was configured with a custom data_dir. The
+    /// cli looks for LNDK's TLS certificate in the network's subdirectory. Defaults to ~/.lndk.
+    #[arg(long, global = true, required = false)]
+    data_dir: Option<PathBuf>,
+
+    /// The path to a pem-encoded client certificate, for when the LNDK server requires clients to
+    /// authenticate with TLS (tls_client_ca_path). Must be set together with client_key_path.
+    #[arg(long, global = true, required = false)]
+    client_cert_path: Option<PathBuf>,
+
+    /// The path to the private key for the certificate set in client_cert_path.
+    #[arg(long, global = true, required = false)]
+    client_key_path: Option<PathBuf>,
+
+    /// The name the LNDK server's TLS certificate is issued for. This only needs to be changed if
+    /// LNDK is using a certificate that wasn't generated by LNDK.
+    #[arg(long, global = true, required = false, default_value = "localhost")]
+    tls_domain: String,
+
     #[arg(long, global = true, required = false, default_value = format!("https://{DEFAULT_SERVER_HOST}"))]
     grpc_host: String,
 
     #[arg(long, global = true, required = false, default_value = DEFAULT_SERVER_PORT.to_string())]
     grpc_port: u16,
 
+    /// The pubkey or alias of the LND node to use, if LNDK is serving more than one. Can be left
+    /// out if LNDK only has one node.
+    #[arg(long, global = true, required = false)]
+    node: Option<String>,
+
+    /// Print results and errors as JSON. Errors are printed as an object with an 'error' field.
+    /// The exit code tells apart different kinds of errors: 1 for general errors, 2 for invalid
+    /// input, 3 for connection errors, 4 if something wasn't found, 5 if a spending limit was
+    /// hit, and 6 if a payment failed.
+    #[arg(long, global = true, required = false)]
+    json: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -75,9 +115,10 @@ enum Commands {
         /// The offer string to decode.
         offer_string: String,
     },
-    /// Decodes a bech32-encoded invoice string into a BOLT 12 invoice.
+    /// Decodes a hex or bech32-encoded invoice string into a BOLT 12 invoice.
     DecodeInvoice {
-        /// The invoice string to decode.
+        /// The invoice string to decode, either hex-encoded or bech32-encoded with an 'lni'
+        /// prefix.
         invoice_string: String,
     },
     /// PayOffer pays a BOLT 12 offer, provided as a 'lno'-prefaced offer string.
@@ -98,6 +139,16 @@ enum Commands {
         /// arrive. If this isn't set, we'll use the default value.
         #[arg(long, global = false, required = false, default_value = DEFAULT_RESPONSE_INVOICE_TIMEOUT.to_string())]
         response_invoice_timeout: Option<u32>,
+
+        /// A key identifying this payment. Retrying with the same key won't pay twice, but
+        /// returns the result of the original attempt.
+        #[arg(long, required = false)]
+        idempotency_key: Option<String>,
+
+        /// Return as soon as the invoice request has been sent, rather than waiting for the
+        /// payment to complete. The payment can then be checked on with get-payment.
+        #[arg(long, required = false)]
+        no_wait: bool,
     },
     /// GetInvoice fetch a BOLT 12 invoice, which will be returned as a hex-encoded string. It
     /// fetches the invoice from a BOLT 12 offer, provided as a 'lno'-prefaced offer string.
@@ -119,53 +170,221 @@ enum Commands {
         #[arg(long, global = false, required = false, default_value = DEFAULT_RESPONSE_INVOICE_TIMEOUT.to_string())]
         response_invoice_timeout: Option<u32>,
     },
-    /// PayInvoice pays a hex-encoded BOLT12 invoice.
+    /// PayInvoice pays a BOLT12 invoice, provided as a hex string or a 'lni'-prefaced bech32
+    /// string.
     PayInvoice {
-        /// The hex-encoded invoice string.
+        /// The invoice string, either hex-encoded or bech32-encoded with an 'lni' prefix.
         invoice_string: String,
         /// Amount the user would like to pay. If this isn't set, we'll assume the user is paying
         /// whatever the invoice amount is set to.
         #[arg(required = false)]
         amount: Option<u64>,
+
+        /// A key identifying this payment. Retrying with the same key won't pay twice, but
+        /// returns the result of the original attempt.
+        #[arg(long, required = false)]
+        idempotency_key: Option<String>,
+    },
+    /// GetBudget shows how much LNDK has spent and how much it may still spend under its
+    /// configured spending limits.
+    GetBudget {
+        /// The hex-encoded signing pubkey of an offer issuer, to also show the budget remaining
+        /// for that issuer.
+        #[arg(long, required = false)]
+        issuer: Option<String>,
+    },
+    /// GetMetrics shows how many payments each of LNDK's nodes has attempted, and how they turned
+    /// out.
+    GetMetrics,
+    /// GetPayment shows the progress of an offer payment, given the hex-encoded payment id
+    /// returned by pay-offer.
+    GetPayment {
+        /// The hex-encoded payment id.
+        payment_id: String,
+    },
+    /// GetProofOfPayment fetches the proof of payment for a completed payment, given the
+    /// hex-encoded payment id returned by pay-offer.
+    GetProofOfPayment {
+        /// The hex-encoded payment id.
+        payment_id: String,
+    },
+    /// VerifyProofOfPayment checks that a proof of payment is valid: that the invoice is signed
+    /// by the offer's signing key and that the preimage matches the invoice's payment hash.
+    VerifyProofOfPayment {
+        /// The invoice that was paid, either hex-encoded or bech32-encoded with an 'lni' prefix.
+        invoice: String,
+
+        /// The hex-encoded payment preimage.
+        preimage: String,
+
+        /// The bech32-encoded offer that was paid, if the payment was made to an offer.
+        #[arg(long, required = false)]
+        offer: Option<String>,
+
+        /// The hex-encoded invoice request that was sent for the offer.
+        #[arg(long, required = false)]
+        invoice_request: Option<String>,
+    },
+    /// CancelPayment cancels an offer payment that hasn't been dispatched yet, given the
+    /// hex-encoded payment id returned by pay-offer.
+    CancelPayment {
+        /// The hex-encoded payment id.
+        payment_id: String,
     },
+    /// ReloadConfig makes LNDK re-read its config file and apply the settings it can change
+    /// without a restart, listing any changed settings that need a restart.
+    ReloadConfig,
+}
+
+// ErrorClass groups the errors the cli can run into, so that scripts can tell them apart by the
+// exit code.
+#[derive(Clone, Copy, Debug)]
+enum ErrorClass {
+    /// Any error that doesn't fall into one of the classes below.
+    Other = 1,
+    /// The user provided invalid input, such as an offer we can't decode.
+    InvalidInput = 2,
+    /// We couldn't connect to or authenticate with the LNDK server.
+    Connection = 3,
+    /// The requested payment or proof doesn't exist.
+    NotFound = 4,
+    /// The payment would exceed one of LNDK's spending limits.
+    LimitExceeded = 5,
+    /// The payment failed.
+    PaymentFailed = 6,
+}
+
+impl ErrorClass {
+    // Works out the class of error from the status returned by the LNDK server. If the command
+    // makes a payment, internal errors are most likely a failed payment.
+    fn from_status(status: &Status, is_payment: bool) -> Self {
+        match status.code() {
+            Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+                ErrorClass::InvalidInput
+            }
+            Code::Unavailable | Code::Unauthenticated | Code::PermissionDenied => {
+                ErrorClass::Connection
+            }
+            Code::NotFound => ErrorClass::NotFound,
+            Code::ResourceExhausted => ErrorClass::LimitExceeded,
+            Code::Aborted | Code::DeadlineExceeded | Code::Internal if is_payment => {
+                ErrorClass::PaymentFailed
+            }
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+// Output prints the result of a command, either as human-readable text or as JSON if the user
+// passed in --json.
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn success<T: Serialize>(&self, text: impl Display, value: &T) {
+        if self.json {
+            match serde_json::to_string(value) {
+                Ok(json) => println!("{json}"),
+                Err(e) => self.error(ErrorClass::Other, format!("Error serializing output: {e}")),
+            }
+        } else {
+            println!("{text}");
+        }
+    }
+
+    fn error(&self, class: ErrorClass, message: impl Display) -> ! {
+        if self.json {
+            println!("{}", json!({ "error": { "message": message.to_string() } }));
+        } else {
+            println!("{message}");
+        }
+        exit(class as i32)
+    }
+
+    fn status_error(&self, context: &str, status: Status, is_payment: bool) -> ! {
+        let class = ErrorClass::from_status(&status, is_payment);
+        if self.json {
+            println!(
+                "{}",
+                json!({
+                    "error": {
+                        "message": format!("{context}: {}", status.message()),
+                        "grpc_code": status.code() as i32,
+                        "grpc_status": format!("{:?}", status.code()),
+                    }
+                })
+            );
+        } else {
+            println!("{context}: {status:?}");
+        }
+        exit(class as i32)
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
+    let output = Output { json: args.json };
+
+    // If no certificate was passed in, we look for the one LNDK generated in the network's data
+    // directory.
+    let cert_path = match (&args.cert_pem, args.cert_path) {
+        (None, None) => string_to_network(&args.network).ok().map(|network| {
+            let data_dir = args.data_dir.clone().unwrap_or_else(default_data_dir);
+            network_data_dir(&data_dir, network).join(TLS_CERT_FILENAME)
+        }),
+        (_, cert_path) => cert_path,
+    };
+    let tls_args = TlsArgs {
+        cert_pem: args.cert_pem,
+        cert_path,
+        client_cert_path: args.client_cert_path,
+        client_key_path: args.client_key_path,
+        domain: args.tls_domain,
+    };
+
     match args.command {
         Commands::DecodeOffer { offer_string } => {
-            println!("Decoding offer: {offer_string}.");
+            if !output.json {
+                println!("Decoding offer: {offer_string}.");
+            }
+            let network = string_to_network(&args.network)
+                .unwrap_or_else(|e| output.error(ErrorClass::InvalidInput, format!("ERROR: {e}.")));
             match decode(offer_string) {
-                Ok(offer) => {
-                    println!("Decoded offer: {:?}.", offer)
-                }
-                Err(e) => {
-                    println!(
+                Ok(offer) => output.success(
+                    format!("Decoded offer: {:?}.", offer),
+                    &generate_offer_contents(&offer, network),
+                ),
+                Err(e) => output.error(
+                    ErrorClass::InvalidInput,
+                    format!(
                         "ERROR please provide offer starting with lno. Provided offer is \
                         invalid, failed to decode with error: {:?}.",
                         e
-                    );
-                    exit(1)
-                }
+                    ),
+                ),
             }
         }
         Commands::DecodeInvoice { invoice_string } => {
-            println!("Decoding invoice: {invoice_string}.");
+            if !output.json {
+                println!("Decoding invoice: {invoice_string}.");
+            }
 
             let invoice_string: Bolt12InvoiceString = invoice_string.clone().into();
             match Bolt12Invoice::try_from(invoice_string) {
-                Ok(invoice) => {
-                    println!("Decoded invoice: {:?}.", invoice);
-                }
-                Err(e) => {
-                    println!(
-                        "ERROR please provide hex-encoded invoice string. Provided invoice is \
-                        invalid, failed to decode with error: {:?}.",
+                Ok(invoice) => output.success(
+                    format!("Decoded invoice: {:?}.", invoice),
+                    &generate_bolt12_invoice_contents(&invoice),
+                ),
+                Err(e) => output.error(
+                    ErrorClass::InvalidInput,
+                    format!(
+                        "ERROR please provide a hex-encoded or 'lni'-prefaced invoice string. \
+                        Provided invoice is invalid, failed to decode with error: {}.",
                         e
-                    );
-                    exit(1);
-                }
+                    ),
+                ),
             }
         }
         Commands::PayOffer {
@@ -173,57 +392,49 @@ async fn main() {
             amount,
             payer_note,
             response_invoice_timeout,
+            idempotency_key,
+            no_wait,
         } => {
-            let tls = read_cert_from_args(args.cert_pem, args.cert_path);
-            let grpc_host = args.grpc_host;
-            let grpc_port = args.grpc_port;
-            let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
-                .unwrap_or_else(|e| {
-                    println!("ERROR creating endpoint: {e:?}");
-                    exit(1)
-                })
-                .tls_config(tls)
-                .unwrap_or_else(|e| {
-                    println!("ERROR tls config: {e:?}");
-                    exit(1)
-                })
-                .connect()
-                .await
-                .unwrap_or_else(|e| {
-                    println!("ERROR connecting: {e:?}");
-                    exit(1)
-                });
-
-            let mut client = OffersClient::new(channel);
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
 
             let offer = match decode(offer_string.to_owned()) {
                 Ok(offer) => offer,
-                Err(e) => {
-                    println!(
+                Err(e) => output.error(
+                    ErrorClass::InvalidInput,
+                    format!(
                         "ERROR: please provide offer starting with lno. Provided offer is \
                         invalid, failed to decode with error: {:?}.",
                         e
-                    );
-                    exit(1)
-                }
+                    ),
+                ),
             };
 
-            let macaroon =
-                read_macaroon_from_args(args.macaroon_path, args.macaroon_hex, &args.network);
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
             let mut request = Request::new(PayOfferRequest {
                 offer: offer.to_string(),
                 amount,
                 payer_note,
                 response_invoice_timeout,
+                idempotency_key,
+                no_wait,
             });
-            add_metadata(&mut request, macaroon).unwrap_or_else(|_| exit(1));
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
 
             match client.pay_offer(request).await {
-                Ok(_) => println!("Successfully paid for offer!"),
-                Err(err) => {
-                    println!("Error paying for offer: {err:?}");
-                    exit(1)
-                }
+                Ok(response) if no_wait => output.success(
+                    format!(
+                        "Invoice request sent, payment id: {}.",
+                        response.get_ref().payment_id
+                    ),
+                    response.get_ref(),
+                ),
+                Ok(response) => output.success("Successfully paid for offer!", response.get_ref()),
+                Err(err) => output.status_error("Error paying for offer", err, true),
             };
         }
         Commands::GetInvoice {
@@ -232,108 +443,258 @@ async fn main() {
             payer_note,
             response_invoice_timeout,
         } => {
-            let tls = read_cert_from_args(args.cert_pem, args.cert_path);
-            let grpc_host = args.grpc_host;
-            let grpc_port = args.grpc_port;
-            let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
-                .unwrap_or_else(|e| {
-                    println!("ERROR creating endpoint: {e:?}");
-                    exit(1)
-                })
-                .tls_config(tls)
-                .unwrap_or_else(|e| {
-                    println!("ERROR tls config: {e:?}");
-                    exit(1)
-                })
-                .connect()
-                .await
-                .unwrap_or_else(|e| {
-                    println!("ERROR connecting: {e:?}");
-                    exit(1)
-                });
-
-            let mut client = OffersClient::new(channel);
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
             let offer = match decode(offer_string.to_owned()) {
                 Ok(offer) => offer,
-                Err(e) => {
-                    println!(
+                Err(e) => output.error(
+                    ErrorClass::InvalidInput,
+                    format!(
                         "ERROR: please provide offer starting with lno. Provided offer is \
                         invalid, failed to decode with error: {:?}.",
                         e
-                    );
-                    exit(1)
-                }
+                    ),
+                ),
             };
 
-            let macaroon =
-                read_macaroon_from_args(args.macaroon_path, args.macaroon_hex, &args.network);
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
             let mut request = Request::new(GetInvoiceRequest {
                 offer: offer.to_string(),
                 amount,
                 payer_note,
                 response_invoice_timeout,
             });
-            add_metadata(&mut request, macaroon).unwrap_or_else(|_| exit(1));
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
             match client.get_invoice(request).await {
-                Ok(response) => {
-                    println!("Invoice: {:?}.", response.get_ref())
-                }
-                Err(err) => {
-                    println!("Error getting invoice for offer: {err:?}");
-                    exit(1)
-                }
+                Ok(response) => output.success(
+                    format!("Invoice: {:?}.", response.get_ref()),
+                    response.get_ref(),
+                ),
+                Err(err) => output.status_error("Error getting invoice for offer", err, false),
             }
         }
         Commands::PayInvoice {
             ref invoice_string,
             amount,
+            idempotency_key,
         } => {
-            let tls = read_cert_from_args(args.cert_pem, args.cert_path);
-            let grpc_host = args.grpc_host.clone();
-            let grpc_port = args.grpc_port;
-            let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
-                .unwrap_or_else(|e| {
-                    println!("ERROR creating endpoint: {e:?}");
-                    exit(1)
-                })
-                .tls_config(tls)
-                .unwrap_or_else(|e| {
-                    println!("ERROR tls config: {e:?}");
-                    exit(1)
-                })
-                .connect()
-                .await
-                .unwrap_or_else(|e| {
-                    println!("ERROR connecting: {e:?}");
-                    exit(1)
-                });
-
-            let mut client = OffersClient::new(channel);
-            let macaroon =
-                read_macaroon_from_args(args.macaroon_path, args.macaroon_hex, &args.network);
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
             let mut request = Request::new(PayInvoiceRequest {
                 invoice: invoice_string.to_owned(),
                 amount,
+                idempotency_key,
             });
-            add_metadata(&mut request, macaroon).unwrap_or_else(|_| exit(1));
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
             match client.pay_invoice(request).await {
-                Ok(_) => println!("Successfully paid for offer!"),
-                Err(err) => {
-                    println!("Error paying invoice: {err:?}");
-                    exit(1)
+                Ok(response) => output.success("Successfully paid for offer!", response.get_ref()),
+                Err(err) => output.status_error("Error paying invoice", err, true),
+            }
+        }
+        Commands::GetBudget { issuer } => {
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
+            let mut request = Request::new(GetBudgetRequest { issuer });
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
+            match client.get_budget(request).await {
+                Ok(response) => output.success(
+                    format!("Budget: {:?}.", response.get_ref()),
+                    response.get_ref(),
+                ),
+                Err(err) => output.status_error("Error getting budget", err, false),
+            }
+        }
+        Commands::GetMetrics => {
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
+            let mut request = Request::new(GetMetricsRequest {});
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
+            match client.get_metrics(request).await {
+                Ok(response) => output.success(
+                    format!("Metrics: {:?}.", response.get_ref()),
+                    response.get_ref(),
+                ),
+                Err(err) => output.status_error("Error getting metrics", err, false),
+            }
+        }
+        Commands::GetPayment { payment_id } => {
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
+            let mut request = Request::new(GetPaymentRequest { payment_id });
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
+            match client.get_payment(request).await {
+                Ok(response) => output.success(
+                    format!("Payment: {:?}.", response.get_ref()),
+                    response.get_ref(),
+                ),
+                Err(err) => output.status_error("Error getting payment", err, false),
+            }
+        }
+        Commands::GetProofOfPayment { payment_id } => {
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
+            let mut request = Request::new(GetProofOfPaymentRequest { payment_id });
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
+            match client.get_proof_of_payment(request).await {
+                Ok(response) => output.success(
+                    format!("Proof of payment: {:?}.", response.get_ref()),
+                    response.get_ref(),
+                ),
+                Err(err) => output.status_error("Error getting proof of payment", err, false),
+            }
+        }
+        Commands::VerifyProofOfPayment {
+            invoice,
+            preimage,
+            offer,
+            invoice_request,
+        } => {
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
+            let request = Request::new(ProofOfPayment {
+                offer,
+                invoice_request_hex_str: invoice_request,
+                invoice_hex_str: invoice,
+                payment_preimage: preimage,
+                paid_at: 0,
+            });
+            match client.verify_proof_of_payment(request).await {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    // An invalid proof is still reported as the response object in JSON mode,
+                    // but we exit with an error so that scripts can check the result.
+                    match response.error {
+                        Some(ref e) => {
+                            output.success(format!("Proof of payment is invalid: {e}."), &response);
+                            exit(ErrorClass::InvalidInput as i32)
+                        }
+                        None => output.success("Proof of payment is valid.", &response),
+                    }
                 }
+                Err(err) => output.status_error("Error verifying proof of payment", err, false),
+            }
+        }
+        Commands::CancelPayment { payment_id } => {
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
+            let mut request = Request::new(CancelPaymentRequest { payment_id });
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
+            match client.cancel_payment(request).await {
+                Ok(response) => output.success("Payment cancelled.", response.get_ref()),
+                Err(err) => output.status_error("Error cancelling payment", err, false),
+            }
+        }
+        Commands::ReloadConfig => {
+            let mut client = new_client(&output, tls_args, args.grpc_host, args.grpc_port).await;
+            let macaroon = read_macaroon_from_args(
+                &output,
+                args.macaroon_path,
+                args.macaroon_hex,
+                &args.network,
+            );
+            let mut request = Request::new(ReloadConfigRequest {});
+            add_metadata(&output, &mut request, macaroon, args.node.as_deref());
+            match client.reload_config(request).await {
+                Ok(response) => {
+                    let reply = response.get_ref();
+                    output.success(
+                        format!(
+                            "Config reloaded. Applied: {:?}. Requires restart: {:?}.",
+                            reply.applied, reply.requires_restart
+                        ),
+                        reply,
+                    )
+                }
+                Err(err) => output.status_error("Error reloading config", err, false),
             }
         }
     }
 }
 
-fn add_metadata<R>(request: &mut Request<R>, macaroon: String) -> Result<(), ()> {
-    let macaroon = macaroon.parse().map_err(|e| {
-        println!("Error parsing provided macaroon string into tonic metadata {e:?}")
-    })?;
+// Connects to the LNDK server with the TLS certificate provided by the user.
+async fn new_client(
+    output: &Output,
+    tls_args: TlsArgs,
+    grpc_host: String,
+    grpc_port: u16,
+) -> OffersClient<Channel> {
+    let tls = read_cert_from_args(output, tls_args);
+    let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
+        .unwrap_or_else(|e| {
+            output.error(
+                ErrorClass::InvalidInput,
+                format!("ERROR creating endpoint: {e:?}"),
+            )
+        })
+        .tls_config(tls)
+        .unwrap_or_else(|e| {
+            output.error(ErrorClass::Connection, format!("ERROR tls config: {e:?}"))
+        })
+        .connect()
+        .await
+        .unwrap_or_else(|e| {
+            output.error(ErrorClass::Connection, format!("ERROR connecting: {e:?}"))
+        });
+
+    OffersClient::new(channel)
+}
+
+fn add_metadata<R>(
+    output: &Output,
+    request: &mut Request<R>,
+    macaroon: String,
+    node: Option<&str>,
+) {
+    let macaroon = macaroon.parse().unwrap_or_else(|e| {
+        output.error(
+            ErrorClass::InvalidInput,
+            format!("Error parsing provided macaroon string into tonic metadata {e:?}"),
+        )
+    });
     request.metadata_mut().insert("macaroon", macaroon);
 
-    Ok(())
+    if let Some(node) = node {
+        let node = node.parse().unwrap_or_else(|e| {
+            output.error(
+                ErrorClass::InvalidInput,
+                format!("Error parsing provided node into tonic metadata {e:?}"),
+            )
+        });
+        request.metadata_mut().insert(NODE_METADATA_KEY, node);
+    }
 }
 
 fn read_macaroon_from_file(path: PathBuf) -> Result<String, std::io::Error> {
@@ -345,60 +706,103 @@ fn read_macaroon_from_file(path: PathBuf) -> Result<String, std::io::Error> {
     Ok(hex::encode(buffer))
 }
 
-fn read_cert_from_args(cert_pem: Option<String>, cert_path: Option<PathBuf>) -> ClientTlsConfig {
+// The options for establishing a secure connection to the LNDK server.
+struct TlsArgs {
+    cert_pem: Option<String>,
+    cert_path: Option<PathBuf>,
+    client_cert_path: Option<PathBuf>,
+    client_key_path: Option<PathBuf>,
+    domain: String,
+}
+
+fn read_cert_from_args(output: &Output, tls_args: TlsArgs) -> ClientTlsConfig {
+    let TlsArgs {
+        cert_pem,
+        cert_path,
+        client_cert_path,
+        client_key_path,
+        domain,
+    } = tls_args;
+
     // Make sure both cert options are not set.
     if cert_path.is_some() && cert_pem.is_some() {
-        println!("ERROR: Only one of `cert_path` or `cert_pem` should be set.");
-        exit(1)
+        output.error(
+            ErrorClass::InvalidInput,
+            "ERROR: Only one of `cert_path` or `cert_pem` should be set.",
+        )
     }
 
     let pem = match (&cert_pem, &cert_path) {
         (Some(pem), _) => pem.clone(),
         (None, Some(cert_path)) => std::fs::read_to_string(cert_path).unwrap_or_else(|e| {
-            println!("ERROR reading cert: {e:?}");
-            exit(1)
+            output.error(ErrorClass::Connection, format!("ERROR reading cert: {e:?}"))
         }),
-        (None, None) => {
-            // If no cert pem string is provided, we'll look for the tls certificate in the
-            // default location.
-            let data_dir = home::home_dir().unwrap().join(DEFAULT_DATA_DIR);
-            std::fs::read_to_string(data_dir.join(TLS_CERT_FILENAME)).unwrap_or_else(|e| {
-                println!("ERROR reading cert: {e:?}");
-                exit(1)
-            })
-        }
+        (None, None) => output.error(
+            ErrorClass::InvalidInput,
+            "ERROR: Couldn't find LNDK's TLS certificate, please set `cert_path` or `cert_pem`.",
+        ),
     };
     let cert = Certificate::from_pem(pem);
-    ClientTlsConfig::new()
+    let config = ClientTlsConfig::new()
         .ca_certificate(cert)
-        .domain_name("localhost")
+        .domain_name(domain);
+
+    // If the server requires clients to authenticate, we present our own certificate.
+    match (client_cert_path, client_key_path) {
+        (Some(client_cert_path), Some(client_key_path)) => {
+            let read = |path: PathBuf| {
+                std::fs::read_to_string(path).unwrap_or_else(|e| {
+                    output.error(
+                        ErrorClass::InvalidInput,
+                        format!("ERROR reading client credentials: {e:?}"),
+                    )
+                })
+            };
+            config.identity(Identity::from_pem(
+                read(client_cert_path),
+                read(client_key_path),
+            ))
+        }
+        (None, None) => config,
+        _ => output.error(
+            ErrorClass::InvalidInput,
+            "ERROR: `client_cert_path` and `client_key_path` must be set together.",
+        ),
+    }
 }
 
 fn read_macaroon_from_args(
+    output: &Output,
     macaroon_path: Option<PathBuf>,
     macaroon_hex: Option<String>,
     network: &str,
 ) -> String {
     // Make sure both macaroon options are not set.
     if macaroon_path.is_some() && macaroon_hex.is_some() {
-        println!("ERROR: Only one of `macaroon_path` or `macaroon_hex` should be set.");
-        exit(1)
+        output.error(
+            ErrorClass::InvalidInput,
+            "ERROR: Only one of `macaroon_path` or `macaroon_hex` should be set.",
+        )
     }
 
     // Let's grab the macaroon string now. If neither macaroon_path nor macaroon_hex are
     // set, use the default macaroon path.
     match macaroon_path {
         Some(path) => read_macaroon_from_file(path.clone()).unwrap_or_else(|e| {
-            println!("ERROR reading macaroon from file {e:?}");
-            exit(1)
+            output.error(
+                ErrorClass::Connection,
+                format!("ERROR reading macaroon from file {e:?}"),
+            )
         }),
         None => match &macaroon_hex {
             Some(macaroon) => macaroon.clone(),
             None => {
                 let path = get_macaroon_path_default(network);
                 read_macaroon_from_file(path).unwrap_or_else(|e| {
-                    println!("ERROR reading macaroon from file {e:?}");
-                    exit(1)
+                    output.error(
+                        ErrorClass::Connection,
+                        format!("ERROR reading macaroon from file {e:?}"),
+                    )
                 })
             }
         },
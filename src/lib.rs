@@ -1,59 +1,103 @@
+pub mod budget;
+pub mod channel_cache;
 mod clock;
 #[allow(dead_code)]
 pub mod lnd;
 pub mod lndk_offers;
+pub mod logging;
 pub mod onion_messenger;
+pub mod payer_key;
+pub mod proof;
 mod rate_limit;
+pub mod reload;
+pub mod rest;
 pub mod server;
+pub mod sim;
+pub mod tls;
 
 pub mod lndkrpc {
     tonic::include_proto!("lndkrpc");
 }
 
+use crate::budget::{Budget, BudgetError, SpendingLimits};
+use crate::channel_cache::{invalidate_closed_channels, ChannelCache};
 use crate::lnd::{
     features_support_onion_messages, get_lnd_client, get_network, has_build_tags, has_version,
-    LndCfg, LndNodeSigner, MIN_LND_MAJOR_VER, MIN_LND_MINOR_VER, MIN_LND_PATCH_VER,
-    MIN_LND_PRE_RELEASE_VER,
+    LndCfg, LndNodeSigner, NodeBackend, NodeInfoProvider, PeerConnector, MIN_LND_MAJOR_VER,
+    MIN_LND_MINOR_VER, MIN_LND_PATCH_VER, MIN_LND_PRE_RELEASE_VER,
 };
-use crate::lndk_offers::{OfferError, SendPaymentParams};
+use crate::lndk_offers::{get_destination, get_node_id, OfferError, SendPaymentParams};
+use crate::logging::{set_redact_logs, CorrelatedEncoder, Correlation, LogOptions, Sensitive};
 use crate::onion_messenger::{LndkNodeIdLookUp, MessengerUtilities};
+use crate::payer_key::{PayerKey, PayerKeyError, PayerKeyStrategy};
+use crate::proof::{ProofError, ProofOfPayment, ProofStore};
+use crate::reload::{ConfigSource, ReloadableSettings, Reloader};
+use crate::rest::{serve_rest, RestError};
+use crate::server::{serve_grpc, LNDKServer, ServerNode};
+use crate::tls::{watch_tls_creds, CertificateGenFailure, TlsConfig};
+use bitcoin::bech32::{self, FromBase32, ToBase32};
+use bitcoin::hashes::sha256::Hash;
 use bitcoin::network::constants::Network;
 use bitcoin::secp256k1::{PublicKey, Secp256k1};
 use home::home_dir;
-use lightning::blinded_path::{BlindedPath, Direction, IntroductionNode};
+use lightning::blinded_path::{BlindedPath, IntroductionNode};
 use lightning::ln::channelmanager::PaymentId;
 use lightning::ln::inbound_payment::ExpandedKey;
-use lightning::ln::msgs::DecodeError;
 use lightning::ln::peer_handler::IgnoringMessageHandler;
 use lightning::offers::invoice::Bolt12Invoice;
 use lightning::offers::invoice_error::InvoiceError;
+use lightning::offers::invoice_request::InvoiceRequest;
 use lightning::offers::offer::Offer;
+use lightning::offers::parse::Bolt12ParseError;
 use lightning::onion_message::messenger::{
     DefaultMessageRouter, Destination, OnionMessenger, PendingOnionMessage,
 };
 use lightning::onion_message::offers::{OffersMessage, OffersMessageHandler};
 use lightning::routing::gossip::NetworkGraph;
 use lightning::sign::{EntropySource, KeyMaterial};
+use lightning::util::ser::Writeable;
 use lnd::BUILD_TAGS_REQUIRED;
-use log::{debug, error, info, LevelFilter};
+use log::{debug, error, info, LevelFilter, ParseLevelError};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::Append;
 use log4rs::config::{Appender, Config as LogConfig, Logger, Root};
-use log4rs::encode::pattern::PatternEncoder;
+use log4rs::Handle;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::create_dir_all;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, Once};
-use tokio::time::{sleep, timeout, Duration};
-use tonic_lnd::lnrpc::{ChanInfoRequest, GetInfoRequest, Payment};
-use tonic_lnd::verrpc::VersionRequest;
-use tonic_lnd::Client;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tonic_lnd::lnrpc::{GetInfoRequest, Payment};
+use tonic_lnd::tonic::Status;
+use tonic_lnd::{Client, ConnectError};
 use triggered::{Listener, Trigger};
 
 static INIT: Once = Once::new();
 
+// The handle to LNDK's logger, plus the file, level and options it logs with, kept so that the log
+// level can be changed while we're running. The settings are only set if the logger was installed
+// by setup_logger.
+static LOG_HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
+static LOG_SETTINGS: Mutex<Option<(String, LevelFilter, LogOptions)>> = Mutex::new(None);
+
 pub fn init_logger(config: LogConfig) {
     INIT.call_once(|| {
-        log4rs::init_config(config).expect("failed to initialize logger");
+        let handle = log4rs::init_config(config).expect("failed to initialize logger");
+        *LOG_HANDLE.lock().unwrap() = Some(handle);
     });
 }
 
@@ -64,47 +108,119 @@ pub const DEFAULT_DATA_DIR: &str = ".lndk";
 
 pub const TLS_CERT_FILENAME: &str = "tls-cert.pem";
 pub const TLS_KEY_FILENAME: &str = "tls-key.pem";
+pub const BUDGET_FILENAME: &str = "budget.json";
+pub const PROOFS_DIRNAME: &str = "proofs";
+pub const PAYER_SEED_FILENAME: &str = "payer_seed";
+pub const NODES_DIRNAME: &str = "nodes";
+pub const LOG_FILENAME: &str = "lndk.log";
 pub const DEFAULT_RESPONSE_INVOICE_TIMEOUT: u32 = 15;
+// How long we hold onto the result of a completed payment.
+const PAYMENT_RESULT_EXPIRY_SECS: u64 = 24 * 60 * 60;
 
 #[allow(clippy::result_unit_err)]
-pub fn setup_logger(log_level: Option<String>, log_dir: Option<String>) -> Result<(), ()> {
-    let log_level = match log_level {
-        Some(level_str) => match LevelFilter::from_str(&level_str) {
-            Ok(level) => level,
-            Err(_) => {
-                // Since the logger isn't set up yet, we use a println just this once.
-                println!(
-                    "User provided log level '{}' is invalid. Make sure it is set to either 'error',
-                    'warn', 'info', 'debug' or 'trace'",
-                    level_str
-                );
-                return Err(());
-            }
-        },
-        None => LevelFilter::Trace,
+pub fn setup_logger(
+    log_level: Option<String>,
+    log_dir: Option<String>,
+    options: LogOptions,
+) -> Result<(), ()> {
+    let log_level = match parse_log_level(log_level.as_deref()) {
+        Ok(level) => level,
+        Err(_) => {
+            // Since the logger isn't set up yet, we use a println just this once.
+            println!(
+                "User provided log level '{}' is invalid. Make sure it is set to either 'error',
+                'warn', 'info', 'debug' or 'trace'",
+                log_level.unwrap_or_default()
+            );
+            return Err(());
+        }
     };
 
     let log_dir = log_dir.unwrap_or_else(|| {
-        home_dir()
-            .unwrap()
-            .join(".lndk")
-            .join("lndk.log")
+        default_data_dir()
+            .join(LOG_FILENAME)
             .as_path()
             .to_str()
             .unwrap()
             .to_string()
     });
 
-    // Log both to stdout and a log file.
-    let stdout = ConsoleAppender::builder().build();
-    let lndk_logs = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} - {m}{n}")))
-        .build(log_dir)
-        .unwrap();
+    init_logger(log_config(log_level, &log_dir, options));
+    *LOG_SETTINGS.lock().unwrap() = Some((log_dir, log_level, options));
+
+    Ok(())
+}
+
+/// Changes the level that LNDK's logger logs at. Returns false if the logger wasn't installed by
+/// setup_logger, for example because LNDK is running inside a service that handles its own
+/// logging.
+pub fn set_log_level(log_level: LevelFilter) -> bool {
+    let handle = LOG_HANDLE.lock().unwrap();
+    let mut settings = LOG_SETTINGS.lock().unwrap();
+    match (handle.as_ref(), settings.as_mut()) {
+        (Some(handle), Some((log_file, level, options))) => {
+            handle.set_config(log_config(log_level, log_file, *options));
+            *level = log_level;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Returns the level that LNDK's logger logs at, if it was installed by setup_logger.
+pub fn log_level() -> Option<LevelFilter> {
+    LOG_SETTINGS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|(_, level, _)| *level)
+}
+
+/// Parses a log level set by the user. If none is set, we log everything.
+pub fn parse_log_level(log_level: Option<&str>) -> Result<LevelFilter, ParseLevelError> {
+    match log_level {
+        Some(level_str) => LevelFilter::from_str(level_str),
+        None => Ok(LevelFilter::Trace),
+    }
+}
+
+fn log_config(log_level: LevelFilter, log_dir: &str, options: LogOptions) -> LogConfig {
+    // Log both to stdout and a log file. Both tag each line with the payment or request it's
+    // about.
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(CorrelatedEncoder::new(
+            options.format,
+            "{d} {l} {t} - {m}{n}",
+        )))
+        .build();
+    let encoder = Box::new(CorrelatedEncoder::new(options.format, "{d} - {m}{n}"));
+    let lndk_logs: Box<dyn Append> = match options.rotation {
+        Some(rotation) => {
+            let roller = FixedWindowRoller::builder()
+                .build(&format!("{log_dir}.{{}}"), rotation.max_files)
+                .unwrap();
+            let policy = CompoundPolicy::new(
+                Box::new(SizeTrigger::new(rotation.max_size_bytes)),
+                Box::new(roller),
+            );
+            Box::new(
+                RollingFileAppender::builder()
+                    .encoder(encoder)
+                    .build(log_dir, Box::new(policy))
+                    .unwrap(),
+            )
+        }
+        None => Box::new(
+            FileAppender::builder()
+                .encoder(encoder)
+                .build(log_dir)
+                .unwrap(),
+        ),
+    };
 
-    let config = LogConfig::builder()
+    LogConfig::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .appender(Appender::builder().build("lndk_logs", Box::new(lndk_logs)))
+        .appender(Appender::builder().build("lndk_logs", lndk_logs))
         .logger(Logger::builder().build("h2", LevelFilter::Info))
         .logger(Logger::builder().build("hyper", LevelFilter::Info))
         .logger(Logger::builder().build("rustls", LevelFilter::Info))
@@ -117,11 +233,36 @@ pub fn setup_logger(log_level: Option<String>, log_dir: Option<String>) -> Resul
                 .appender("lndk_logs")
                 .build(log_level),
         )
-        .unwrap();
+        .unwrap()
+}
 
-    init_logger(config);
+/// Returns the directory LNDK keeps its files in if the user doesn't set one, ~/.lndk.
+pub fn default_data_dir() -> PathBuf {
+    home_dir().unwrap().join(DEFAULT_DATA_DIR)
+}
 
-    Ok(())
+/// Returns the directory within the data directory that holds LNDK's state for the given network,
+/// such as its TLS credentials, spending record and proofs of payment. Like LND, each network gets
+/// its own directory (e.g. ~/.lndk/data/mainnet), so instances on different networks don't clash.
+pub fn network_data_dir(data_dir: &Path, network: Network) -> PathBuf {
+    data_dir.join("data").join(network_dir_name(network))
+}
+
+/// Returns where LNDK logs to on the given network if the user doesn't set a log file, e.g.
+/// ~/.lndk/logs/mainnet/lndk.log.
+pub fn network_log_file(data_dir: &Path, network: Network) -> PathBuf {
+    data_dir
+        .join("logs")
+        .join(network_dir_name(network))
+        .join(LOG_FILENAME)
+}
+
+// We name network directories the way LND does, which calls the main network 'mainnet'.
+fn network_dir_name(network: Network) -> String {
+    match network {
+        Network::Bitcoin => "mainnet".to_string(),
+        network => network.to_string(),
+    }
 }
 
 #[derive(Clone)]
@@ -139,25 +280,45 @@ pub struct LifecycleSignals {
     pub listener: Listener,
 }
 
-pub struct LndkOnionMessenger {}
+pub struct LndkOnionMessenger {
+    // channel_cache holds the channels we've looked up to resolve compact blinded paths.
+    channel_cache: Arc<ChannelCache>,
+}
 
 impl LndkOnionMessenger {
     pub fn new() -> Self {
-        LndkOnionMessenger {}
+        LndkOnionMessenger {
+            channel_cache: Arc::new(ChannelCache::default()),
+        }
+    }
+
+    /// Sets the cache used to look up the nodes of the channels that compact blinded paths point
+    /// to. It should be shared with the OfferHandler so that both sides resolve paths from it.
+    pub fn with_channel_cache(mut self, channel_cache: Arc<ChannelCache>) -> Self {
+        self.channel_cache = channel_cache;
+        self
     }
 
     pub async fn run(
         &self,
         args: Cfg,
-        offer_handler: Arc<impl OffersMessageHandler>,
+        offer_handler: Arc<impl OffersMessageHandler + Send + Sync + 'static>,
     ) -> Result<(), ()> {
-        let mut client = get_lnd_client(args.lnd).expect("failed to connect");
-        let info = client
-            .lightning()
-            .get_info(GetInfoRequest {})
+        let client = get_lnd_client(args.lnd).expect("failed to connect");
+        self.run_with_backend(client, args.signals, args.skip_version_check, offer_handler)
             .await
-            .expect("failed to get info")
-            .into_inner();
+    }
+
+    /// Runs the onion messenger against any node backend, such as an in-memory
+    /// [`sim::SimNode`] rather than LND.
+    pub async fn run_with_backend(
+        &self,
+        mut node: impl NodeBackend,
+        signals: LifecycleSignals,
+        skip_version_check: bool,
+        offer_handler: Arc<impl OffersMessageHandler + Send + Sync + 'static>,
+    ) -> Result<(), ()> {
+        let info = node.get_info().await.expect("failed to get info");
         let network = get_network(info.clone()).await?;
 
         let pubkey = PublicKey::from_str(&info.identity_pubkey).unwrap();
@@ -168,12 +329,7 @@ impl LndkOnionMessenger {
             return Err(());
         }
 
-        let version = client
-            .versioner()
-            .get_version(VersionRequest {})
-            .await
-            .expect("failed to get version")
-            .into_inner();
+        let version = node.get_version().await.expect("failed to get version");
 
         if !has_build_tags(&version, None) {
             error!(
@@ -184,7 +340,7 @@ impl LndkOnionMessenger {
             return Err(());
         }
 
-        if !args.skip_version_check && !has_version(&version, None) {
+        if !skip_version_check && !has_version(&version, None) {
             error!(
                     "The LND version {} is not compatible with LNDK. Please update to version {}.{}.{}-{} or higher.",
                     &version.version, MIN_LND_MAJOR_VER, MIN_LND_MINOR_VER, MIN_LND_PATCH_VER, MIN_LND_PRE_RELEASE_VER
@@ -195,49 +351,49 @@ impl LndkOnionMessenger {
         // On startup, we want to get a list of our currently online peers to notify the onion
         // messenger that they are connected. This sets up our "start state" for the
         // messenger correctly.
-        let current_peers = client
-            .lightning()
-            .list_peers(tonic_lnd::lnrpc::ListPeersRequest {
-                latest_error: false,
-            })
-            .await
-            .map_err(|e| {
-                error!("Could not lookup current peers: {e}.");
-            })?;
+        let current_peers = node.list_peers().await.map_err(|e| {
+            error!("Could not lookup current peers: {e}.");
+        })?;
 
         let mut peer_support = HashMap::new();
-        for peer in current_peers.into_inner().peers {
+        for peer in current_peers.peers {
             let pubkey = PublicKey::from_str(&peer.pub_key).unwrap();
             let onion_support = features_support_onion_messages(&peer.features);
             peer_support.insert(pubkey, onion_support);
         }
 
-        // Create an onion messenger that depends on LND's signer client and consume related events.
-        let mut node_client = client.signer().clone();
-        let node_signer = LndNodeSigner::new(pubkey, &mut node_client);
-        let messenger_utils = MessengerUtilities::new();
-        let network_graph = &NetworkGraph::new(network, &messenger_utils);
-        let message_router = &DefaultMessageRouter::new(network_graph, &messenger_utils);
-        let node_id_lookup = LndkNodeIdLookUp::new(client.clone(), pubkey);
+        // Create an onion messenger that depends on the node's signer and consume related events.
+        // The signer and node id lookup are served by their own tasks, which exit once the onion
+        // messenger is dropped.
+        let (node_signer, signer_task) = LndNodeSigner::new(pubkey, node.clone());
+        tokio::spawn(signer_task);
+        let (node_id_lookup, lookup_task) =
+            LndkNodeIdLookUp::new(node.clone(), pubkey, Arc::clone(&self.channel_cache));
+        tokio::spawn(lookup_task);
+        tokio::spawn(invalidate_closed_channels(
+            node.clone(),
+            Arc::clone(&self.channel_cache),
+            signals.listener.clone(),
+        ));
+
+        let messenger_utils = Arc::new(MessengerUtilities::new());
+        let network_graph = Arc::new(NetworkGraph::new(network, Arc::clone(&messenger_utils)));
+        let message_router = Arc::new(DefaultMessageRouter::new(
+            network_graph,
+            Arc::clone(&messenger_utils),
+        ));
         let onion_messenger = OnionMessenger::new(
-            &messenger_utils,
-            &node_signer,
-            &messenger_utils,
-            &node_id_lookup,
+            Arc::clone(&messenger_utils),
+            Arc::new(node_signer),
+            Arc::clone(&messenger_utils),
+            Arc::new(node_id_lookup),
             message_router,
             offer_handler,
             IgnoringMessageHandler {},
         );
 
-        let mut peers_client = client.lightning().clone();
-        self.run_onion_messenger(
-            peer_support,
-            &mut peers_client,
-            onion_messenger,
-            network,
-            args.signals,
-        )
-        .await
+        self.run_onion_messenger(peer_support, node, onion_messenger, network, signals)
+            .await
     }
 }
 
@@ -247,8 +403,549 @@ impl Default for LndkOnionMessenger {
     }
 }
 
+/// LndkBuilder starts LNDK inside another tokio service. By default it only runs the onion
+/// messenger, keeps its state in memory and leaves logging to the host application. The gRPC and
+/// REST servers, on-disk state and LNDK's own logger are all opt in.
+pub struct LndkBuilder {
+    lnd: LndCfg,
+    additional_nodes: Vec<LndCfg>,
+    data_dir: Option<PathBuf>,
+    response_invoice_timeout: Option<u32>,
+    limits: SpendingLimits,
+    payer_key_strategy: PayerKeyStrategy,
+    compact_paths: bool,
+    skip_version_check: bool,
+    logger: Option<(Option<String>, Option<String>)>,
+    log_options: LogOptions,
+    redact_logs: Option<bool>,
+    tls: Option<TlsConfig>,
+    grpc_addr: Option<SocketAddr>,
+    rest_addr: Option<SocketAddr>,
+    config_source: Option<Arc<dyn ConfigSource>>,
+}
+
+impl LndkBuilder {
+    pub fn new(lnd: LndCfg) -> Self {
+        LndkBuilder {
+            lnd,
+            additional_nodes: Vec::new(),
+            data_dir: None,
+            response_invoice_timeout: None,
+            limits: SpendingLimits::default(),
+            payer_key_strategy: PayerKeyStrategy::default(),
+            compact_paths: false,
+            skip_version_check: false,
+            logger: None,
+            log_options: LogOptions::default(),
+            redact_logs: None,
+            tls: None,
+            grpc_addr: None,
+            rest_addr: None,
+            config_source: None,
+        }
+    }
+
+    /// Adds another LND node for LNDK to make payments from, on the same network as the first. Each
+    /// node gets its own onion messenger and offer handler, with its own spending record, proofs of
+    /// payment and payer seed (kept in <data_dir>/nodes/<pubkey>), but they're all served from the
+    /// same gRPC and REST endpoints. Clients pick a node by its pubkey or alias (see
+    /// NODE_METADATA_KEY).
+    pub fn with_node(mut self, lnd: LndCfg) -> Self {
+        self.additional_nodes.push(lnd);
+        self
+    }
+
+    /// Sets the directory LNDK keeps its spending record, proofs of payment and generated TLS
+    /// credentials in. Since the directory is used as is, callers running LNDK against several
+    /// networks should pass a different directory for each (see network_data_dir).
+    pub fn with_data_dir(mut self, data_dir: PathBuf) -> Self {
+        self.data_dir = Some(data_dir);
+        self
+    }
+
+    /// Sets how long in seconds we wait for an offer's creator to respond with an invoice.
+    pub fn with_response_invoice_timeout(mut self, timeout: u32) -> Self {
+        self.response_invoice_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the limits that outgoing payments are checked against.
+    pub fn with_spending_limits(mut self, limits: SpendingLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets how we pick the key that signs our invoice requests.
+    pub fn with_payer_key_strategy(mut self, strategy: PayerKeyStrategy) -> Self {
+        self.payer_key_strategy = strategy;
+        self
+    }
+
+    /// Sets whether the blinded paths we create use compact introduction nodes.
+    pub fn with_compact_paths(mut self, compact_paths: bool) -> Self {
+        self.compact_paths = compact_paths;
+        self
+    }
+
+    /// Skips checking that LND's version is one LNDK supports.
+    pub fn with_skip_version_check(mut self, skip_version_check: bool) -> Self {
+        self.skip_version_check = skip_version_check;
+        self
+    }
+
+    /// Installs LNDK's global logger on start. This should be left unset if the host application
+    /// sets up its own logger.
+    pub fn with_logger(mut self, log_level: Option<String>, log_file: Option<String>) -> Self {
+        self.logger = Some((log_level, log_file));
+        self
+    }
+
+    /// Sets the format and rotation of LNDK's logs, if LNDK installs its own logger.
+    pub fn with_log_options(mut self, options: LogOptions) -> Self {
+        self.log_options = options;
+        self
+    }
+
+    /// Sets whether sensitive data such as payer notes, invoices and onion messages is left out of
+    /// the logs. If unset, logs are only redacted on mainnet.
+    pub fn with_redact_logs(mut self, redact: bool) -> Self {
+        self.redact_logs = Some(redact);
+        self
+    }
+
+    /// Sets the TLS credentials the gRPC and REST servers use. If unset, credentials are generated
+    /// in the data directory.
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Serves LNDK's gRPC API at the given address.
+    pub fn with_grpc_server(mut self, addr: SocketAddr) -> Self {
+        self.grpc_addr = Some(addr);
+        self
+    }
+
+    /// Serves LNDK's API as JSON over HTTPS at the given address.
+    pub fn with_rest_server(mut self, addr: SocketAddr) -> Self {
+        self.rest_addr = Some(addr);
+        self
+    }
+
+    /// Lets LNDK's settings be reloaded while it's running, re-reading them from the given source.
+    /// Without a source, LNDK keeps the settings it was started with.
+    pub fn with_config_source(mut self, source: Arc<dyn ConfigSource>) -> Self {
+        self.config_source = Some(source);
+        self
+    }
+
+    /// Connects to LND and starts LNDK's tasks on the current tokio runtime.
+    pub async fn start(self) -> Result<LndkHandle, LndkError> {
+        if let Some((log_level, log_file)) = self.logger {
+            setup_logger(log_level, log_file, self.log_options).map_err(|_| LndkError::Logger)?;
+        }
+
+        let primary = connect_node(&self.lnd).await?;
+        let network = primary.network;
+        set_redact_logs(self.redact_logs.unwrap_or(network == Network::Bitcoin));
+
+        // Each additional node keeps its spending record, proofs of payment and payer seed in its
+        // own directory, so that they don't get mixed up with the first node's.
+        let mut nodes = vec![(primary, self.data_dir.clone())];
+        for lnd in &self.additional_nodes {
+            let node = connect_node(lnd).await?;
+            if node.network != network {
+                return Err(LndkError::NetworkMismatch(node.node_id));
+            }
+            let data_dir = self
+                .data_dir
+                .as_ref()
+                .map(|dir| dir.join(NODES_DIRNAME).join(node.node_id.to_string()));
+            nodes.push((node, data_dir));
+        }
+
+        let (shutdown, listener) = triggered::trigger();
+        let signals = LifecycleSignals {
+            shutdown,
+            listener: listener.clone(),
+        };
+
+        let mut handlers = Vec::new();
+        let mut messengers = Vec::new();
+        for (i, (node, data_dir)) in nodes.iter().enumerate() {
+            if let Some(data_dir) = data_dir {
+                create_dir_all(data_dir).map_err(LndkError::DataDir)?;
+            }
+            // The first node uses the strategy we were given, and the others get their own payer
+            // seed if they need one, so that payments from different nodes can't be linked.
+            let payer_key_strategy = if i == 0 {
+                self.payer_key_strategy.clone()
+            } else {
+                let seed_path = data_dir.as_ref().map(|dir| dir.join(PAYER_SEED_FILENAME));
+                self.payer_key_strategy
+                    .for_node(seed_path.as_deref())
+                    .map_err(LndkError::PayerKey)?
+            };
+            let budget = Budget::new(
+                self.limits.clone(),
+                data_dir.as_ref().map(|dir| dir.join(BUDGET_FILENAME)),
+            )
+            .map_err(LndkError::Budget)?;
+            let proofs = ProofStore::new(data_dir.as_ref().map(|dir| dir.join(PROOFS_DIRNAME)))
+                .map_err(LndkError::Proofs)?;
+
+            // The onion messenger and the offer handler both resolve compact blinded paths, so
+            // they share a cache of the channels they've looked up.
+            let channel_cache = Arc::new(ChannelCache::default());
+            let handler = Arc::new(
+                OfferHandler::new(self.response_invoice_timeout)
+                    .with_budget(budget)
+                    .with_proof_store(proofs)
+                    .with_payer_key_strategy(payer_key_strategy)
+                    .with_channel_cache(Arc::clone(&channel_cache))
+                    .with_compact_paths(self.compact_paths),
+            );
+            messengers.push((
+                node.client.clone(),
+                LndkOnionMessenger::new().with_channel_cache(channel_cache),
+                Arc::clone(&handler),
+            ));
+            handlers.push(handler);
+        }
+
+        // We make sure that everything the servers need is in place before we start any tasks, so
+        // that we don't leave anything running if we fail. All of our nodes are served from the
+        // same endpoint.
+        let servers = if self.grpc_addr.is_some() || self.rest_addr.is_some() {
+            let tls = match (self.tls, &self.data_dir) {
+                (Some(tls), _) => tls,
+                (None, Some(data_dir)) => TlsConfig::generated(data_dir.clone(), None),
+                (None, None) => return Err(LndkError::MissingTls),
+            };
+            tls.prepare().map_err(LndkError::TlsGen)?;
+            let creds = tls.read().map_err(LndkError::TlsRead)?;
+            let tls_watch = watch::channel(tls.clone());
+
+            let mut server_nodes = Vec::new();
+            for ((node, _), handler) in nodes.iter().zip(&handlers) {
+                let lnd_cert = node
+                    .lnd
+                    .creds
+                    .get_certificate_string()
+                    .map_err(|_| LndkError::LndCert)?;
+                server_nodes.push(ServerNode {
+                    offer_handler: Arc::clone(handler),
+                    node_id: node.node_id,
+                    alias: node.alias.clone(),
+                    lnd_cert,
+                    address: node.lnd.address.clone(),
+                });
+            }
+            let server = LNDKServer::for_nodes(server_nodes, network);
+            Some((tls, tls_watch, creds, server))
+        } else {
+            None
+        };
+
+        // If the user can reload LNDK's settings, the reloader needs to know what we started with
+        // and how to pass new TLS credentials on to the servers.
+        let reloader = self.config_source.map(|source| {
+            let current = ReloadableSettings {
+                log_level: None,
+                response_invoice_timeout: self.response_invoice_timeout,
+                limits: self.limits,
+                tls: servers.as_ref().map(|(tls, ..)| tls.clone()),
+            };
+            let tls_sender = servers.as_ref().map(|(_, (sender, _), ..)| sender.clone());
+            Arc::new(Reloader::new(source, handlers.clone(), tls_sender, current))
+        });
+
+        let mut tasks = JoinSet::new();
+        let skip_version_check = self.skip_version_check;
+        for (messenger_client, messenger, messenger_handler) in messengers {
+            let messenger_signals = signals.clone();
+            tasks.spawn(async move {
+                messenger
+                    .run_with_backend(
+                        messenger_client,
+                        messenger_signals,
+                        skip_version_check,
+                        messenger_handler,
+                    )
+                    .await
+                    .map_err(|_| LndkError::Messenger)
+            });
+        }
+
+        if let Some((_, (_, tls_receiver), creds, server)) = servers {
+            let server = Arc::new(match reloader {
+                Some(ref reloader) => server.with_reloader(Arc::clone(reloader)),
+                None => server,
+            });
+            let (creds_sender, creds_receiver) = watch::channel(creds);
+            tokio::spawn(watch_tls_creds(
+                tls_receiver,
+                creds_sender,
+                listener.clone(),
+            ));
+
+            if let Some(addr) = self.grpc_addr {
+                let server_fut = serve_grpc(
+                    Arc::clone(&server),
+                    addr,
+                    creds_receiver.clone(),
+                    listener.clone(),
+                );
+                tasks.spawn(async move { server_fut.await.map_err(LndkError::Grpc) });
+            }
+            if let Some(addr) = self.rest_addr {
+                let server_fut = serve_rest(server, addr, creds_receiver, listener);
+                tasks.spawn(async move { server_fut.await.map_err(LndkError::Rest) });
+            }
+        }
+
+        let node_handlers = nodes
+            .iter()
+            .zip(&handlers)
+            .map(|((node, _), handler)| (node.node_id, Arc::clone(handler)))
+            .collect();
+        let (primary, _) = nodes.swap_remove(0);
+        Ok(LndkHandle {
+            offer_handler: Arc::clone(&handlers[0]),
+            node_handlers,
+            reloader,
+            client: primary.client,
+            node_id: primary.node_id,
+            network,
+            signals,
+            tasks,
+        })
+    }
+}
+
+// ConnectedNode is an LND node that LndkBuilder has connected to.
+struct ConnectedNode {
+    lnd: LndCfg,
+    client: Client,
+    node_id: PublicKey,
+    alias: Option<String>,
+    network: Network,
+}
+
+async fn connect_node(lnd: &LndCfg) -> Result<ConnectedNode, LndkError> {
+    let mut client = get_lnd_client(lnd.clone()).map_err(LndkError::Connect)?;
+    let info = client
+        .lightning()
+        .get_info(GetInfoRequest {})
+        .await
+        .map_err(LndkError::Lnd)?
+        .into_inner();
+    let network = get_network(info.clone())
+        .await
+        .map_err(|_| LndkError::Network)?;
+
+    Ok(ConnectedNode {
+        lnd: lnd.clone(),
+        client,
+        node_id: PublicKey::from_str(&info.identity_pubkey).unwrap(),
+        alias: Some(info.alias).filter(|alias| !alias.is_empty()),
+        network,
+    })
+}
+
+/// LndkHandle is returned by LndkBuilder once LNDK is running. Dropping the handle stops all of
+/// LNDK's tasks, so callers should hold onto it (or await join) for as long as LNDK should run.
+pub struct LndkHandle {
+    offer_handler: Arc<OfferHandler>,
+    // The handlers of all of our nodes, including the first.
+    node_handlers: Vec<(PublicKey, Arc<OfferHandler>)>,
+    reloader: Option<Arc<Reloader>>,
+    client: Client,
+    node_id: PublicKey,
+    network: Network,
+    signals: LifecycleSignals,
+    tasks: JoinSet<Result<(), LndkError>>,
+}
+
+impl LndkHandle {
+    /// Returns the handler that makes payments, for anything not covered by the handle's own
+    /// methods.
+    pub fn offer_handler(&self) -> Arc<OfferHandler> {
+        Arc::clone(&self.offer_handler)
+    }
+
+    /// Returns the handler that makes payments from the given node, if it's one of ours. The
+    /// handle's own methods pay from the first node.
+    pub fn node_offer_handler(&self, node_id: PublicKey) -> Option<Arc<OfferHandler>> {
+        self.node_handlers
+            .iter()
+            .find(|(id, _)| *id == node_id)
+            .map(|(_, handler)| Arc::clone(handler))
+    }
+
+    /// Pays an offer, waiting until the payment completes.
+    pub async fn pay_offer(
+        &self,
+        offer: Offer,
+        amount: Option<u64>,
+        payer_note: Option<String>,
+    ) -> Result<Payment, OfferError> {
+        let cfg = self.offer_params(offer, amount, payer_note).await?;
+        self.offer_handler.pay_offer(cfg).await
+    }
+
+    /// Requests an invoice for an offer without paying it.
+    pub async fn get_invoice(
+        &self,
+        offer: Offer,
+        amount: Option<u64>,
+        payer_note: Option<String>,
+    ) -> Result<Bolt12Invoice, OfferError> {
+        let cfg = self.offer_params(offer, amount, payer_note).await?;
+        let (invoice, _, payment_id) = self.offer_handler.get_invoice(cfg).await?;
+
+        // Since we won't pay the invoice, we stop tracking the payment.
+        self.offer_handler
+            .active_payments
+            .lock()
+            .unwrap()
+            .remove(&payment_id);
+
+        Ok(invoice)
+    }
+
+    /// Returns the reloader that applies changes to LNDK's settings, if a config source was set.
+    pub fn config_reloader(&self) -> Option<Arc<Reloader>> {
+        self.reloader.clone()
+    }
+
+    /// Returns a trigger that shuts LNDK down when fired, for example from a signal handler.
+    pub fn shutdown_trigger(&self) -> Trigger {
+        self.signals.shutdown.clone()
+    }
+
+    /// Shuts LNDK down. Use join to wait until it has stopped.
+    pub fn shutdown(&self) {
+        self.signals.shutdown.trigger();
+    }
+
+    /// Waits until LNDK stops. If any of its tasks stops, whether because of an error or because
+    /// shutdown was triggered, the rest are shut down too. Returns the first error, if any.
+    pub async fn join(mut self) -> Result<(), LndkError> {
+        let mut result = Ok(());
+        while let Some(task_result) = self.tasks.join_next().await {
+            self.signals.shutdown.trigger();
+            if let Err(e) = task_result.map_err(LndkError::Task).and_then(|r| r) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    async fn offer_params(
+        &self,
+        offer: Offer,
+        amount: Option<u64>,
+        payer_note: Option<String>,
+    ) -> Result<PayOfferParams, OfferError> {
+        let destination = get_destination(&offer).await?;
+        let reply_path = self
+            .offer_handler
+            .create_reply_path(self.client.clone(), self.node_id)
+            .await?;
+
+        Ok(PayOfferParams {
+            offer,
+            amount,
+            payer_note,
+            network: self.network,
+            client: self.client.clone(),
+            destination,
+            reply_path: Some(reply_path),
+            response_invoice_timeout: None,
+            payment_id: None,
+        })
+    }
+}
+
+/// An error that occurs when starting or running LNDK with LndkBuilder.
+#[derive(Debug)]
+pub enum LndkError {
+    /// LNDK's logger couldn't be set up.
+    Logger,
+    /// We couldn't connect to LND.
+    Connect(ConnectError),
+    /// A call to LND failed.
+    Lnd(Status),
+    /// LND is running on a network that LNDK doesn't recognize.
+    Network,
+    /// One of the nodes is running on a different network than the first.
+    NetworkMismatch(PublicKey),
+    /// The data directory couldn't be created.
+    DataDir(std::io::Error),
+    /// The spending record couldn't be loaded.
+    Budget(BudgetError),
+    /// The proofs of payment couldn't be loaded.
+    Proofs(ProofError),
+    /// The payer seed for one of the nodes couldn't be loaded.
+    PayerKey(PayerKeyError),
+    /// LND's TLS certificate couldn't be read.
+    LndCert,
+    /// A server was requested, but there are no TLS credentials to serve it with since neither
+    /// TLS credentials nor a data directory were set.
+    MissingTls,
+    /// The servers' TLS credentials couldn't be generated.
+    TlsGen(CertificateGenFailure),
+    /// The servers' TLS credentials couldn't be read.
+    TlsRead(std::io::Error),
+    /// The onion messenger stopped with an error.
+    Messenger,
+    /// The gRPC server stopped with an error.
+    Grpc(tonic::transport::Error),
+    /// The REST server stopped with an error.
+    Rest(RestError),
+    /// One of LNDK's tasks panicked.
+    Task(JoinError),
+}
+
+impl Display for LndkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LndkError::Logger => write!(f, "Error setting up logger"),
+            LndkError::Connect(e) => write!(f, "Error connecting to LND: {e}"),
+            LndkError::Lnd(e) => write!(f, "Error calling LND: {e}"),
+            LndkError::Network => write!(f, "Error getting LND's network"),
+            LndkError::NetworkMismatch(node_id) => write!(
+                f,
+                "Node {node_id} is running on a different network than the first node"
+            ),
+            LndkError::DataDir(e) => write!(f, "Error creating data directory: {e}"),
+            LndkError::Budget(e) => write!(f, "Error loading spending record: {e}"),
+            LndkError::Proofs(e) => write!(f, "Error loading proofs of payment: {e}"),
+            LndkError::PayerKey(e) => write!(f, "Error loading payer seed: {e}"),
+            LndkError::LndCert => write!(f, "Error reading LND's TLS certificate"),
+            LndkError::MissingTls => write!(
+                f,
+                "Servers need either TLS credentials or a data directory to generate them in"
+            ),
+            LndkError::TlsGen(e) => write!(f, "Error generating TLS credentials: {e}"),
+            LndkError::TlsRead(e) => write!(f, "Error reading TLS credentials: {e}"),
+            LndkError::Messenger => write!(f, "Onion messenger stopped with an error"),
+            LndkError::Grpc(e) => write!(f, "Error running gRPC server: {e}"),
+            LndkError::Rest(e) => write!(f, "Error running REST server: {e}"),
+            LndkError::Task(e) => write!(f, "LNDK task failed: {e}"),
+        }
+    }
+}
+
+impl Error for LndkError {}
+
 #[allow(dead_code)]
-enum PaymentState {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaymentState {
     InvoiceRequestCreated,
     InvoiceRequestSent,
     InvoiceReceived,
@@ -264,14 +961,84 @@ pub struct OfferHandler {
     pending_messages: Mutex<Vec<PendingOnionMessage<OffersMessage>>>,
     pub messenger_utils: MessengerUtilities,
     expanded_key: ExpandedKey,
-    /// The amount of time in seconds that we will wait for the offer creator to respond with
-    /// an invoice. If not provided, we will use the default value of 15 seconds.
-    pub response_invoice_timeout: u32,
+    // response_invoice_timeout is the amount of time in seconds that we wait for the offer
+    // creator to respond with an invoice, unless a payment sets its own. It can be changed while
+    // we're running, so it's kept in an atomic.
+    response_invoice_timeout: AtomicU32,
+    // budget tracks our outgoing payments against the user's spending limits.
+    budget: Budget,
+    // completed_payments holds the results of payments we've finished processing, so that a
+    // retried request for the same payment gets the original result rather than paying again.
+    completed_payments: Mutex<HashMap<PaymentId, (PaymentResult, Instant)>>,
+    // idempotency_keys holds a hash of the parameters of the request that each idempotency key
+    // was first used with, and when, so that a key can't be reused for a different payment.
+    idempotency_keys: Mutex<HashMap<PaymentId, (Hash, Instant)>>,
+    // proofs holds a proof of payment for each payment we've completed.
+    proofs: ProofStore,
+    // payer_key_strategy decides which key signs the invoice requests we send.
+    payer_key_strategy: PayerKeyStrategy,
+    // channel_cache holds the channels we've looked up to resolve compact blinded paths.
+    channel_cache: Arc<ChannelCache>,
+    // compact_paths decides whether the blinded paths we create refer to their introduction node
+    // by one of its channels rather than by its node id.
+    compact_paths: bool,
+    // outcomes counts the payments we've finished processing, by how they turned out.
+    outcomes: PaymentOutcomes,
 }
 
 pub struct PaymentInfo {
     state: PaymentState,
     invoice: Option<Bolt12Invoice>,
+    // The offer and invoice request are kept so we can put together a proof of payment once the
+    // payment completes. They aren't set if we're paying an invoice that was provided directly.
+    offer: Option<Offer>,
+    invoice_request: Option<InvoiceRequest>,
+    // The key that signed our invoice request, if we sent one.
+    payer_key: Option<PayerKey>,
+}
+
+/// PaymentResult is the outcome of a payment that we've finished processing.
+#[derive(Clone, Debug)]
+pub enum PaymentResult {
+    Succeeded(Payment),
+    Failed(String),
+    Cancelled,
+}
+
+/// PaymentStatus describes how far along we are with a payment.
+#[derive(Clone, Debug)]
+pub enum PaymentStatus {
+    InProgress(PaymentState),
+    Completed(PaymentResult),
+}
+
+/// PaymentMetrics counts the payments that an OfferHandler has made since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PaymentMetrics {
+    /// Every payment we've started, whether it has completed or not.
+    pub attempted: u64,
+    pub succeeded: u64,
+    /// Payments that failed, including those we turned away before sending an invoice request.
+    pub failed: u64,
+    pub cancelled: u64,
+    pub in_flight: u64,
+}
+
+#[derive(Default)]
+struct PaymentOutcomes {
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    cancelled: AtomicU64,
+}
+
+impl PaymentResult {
+    fn into_result(self, payment_id: PaymentId) -> Result<Payment, OfferError> {
+        match self {
+            PaymentResult::Succeeded(payment) => Ok(payment),
+            PaymentResult::Failed(e) => Err(OfferError::PreviousAttemptFailed(e)),
+            PaymentResult::Cancelled => Err(OfferError::PaymentCancelled(payment_id)),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -289,6 +1056,10 @@ pub struct PayOfferParams {
     /// The amount of time in seconds that we will wait for the offer creator to respond with
     /// an invoice. If not provided, we will use the default value of 15 seconds.
     pub response_invoice_timeout: Option<u32>,
+    /// A stable id for this payment, such as one derived from a client's idempotency key. If a
+    /// payment with this id is already in flight or has completed, we won't pay again. If not
+    /// provided, a random id is generated.
+    pub payment_id: Option<PaymentId>,
 }
 
 impl OfferHandler {
@@ -304,20 +1075,325 @@ impl OfferHandler {
             pending_messages: Mutex::new(Vec::new()),
             messenger_utils,
             expanded_key,
-            response_invoice_timeout,
+            response_invoice_timeout: AtomicU32::new(response_invoice_timeout),
+            budget: Budget::default(),
+            completed_payments: Mutex::new(HashMap::new()),
+            idempotency_keys: Mutex::new(HashMap::new()),
+            proofs: ProofStore::default(),
+            payer_key_strategy: PayerKeyStrategy::default(),
+            channel_cache: Arc::new(ChannelCache::default()),
+            compact_paths: false,
+            outcomes: PaymentOutcomes::default(),
+        }
+    }
+
+    /// Returns how long in seconds we wait for an offer's creator to respond with an invoice, for
+    /// payments that don't set their own timeout.
+    pub fn response_invoice_timeout(&self) -> u32 {
+        self.response_invoice_timeout.load(Ordering::Relaxed)
+    }
+
+    /// Changes how long in seconds we wait for an offer's creator to respond with an invoice.
+    /// Payments that are already waiting keep their original timeout.
+    pub fn set_response_invoice_timeout(&self, timeout: u32) {
+        self.response_invoice_timeout
+            .store(timeout, Ordering::Relaxed);
+    }
+
+    /// Sets the spending limits (and spending record) that payments made through this handler
+    /// are checked against. By default no limits are applied.
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Sets where proofs of payment are kept. By default they're only held in memory.
+    pub fn with_proof_store(mut self, proofs: ProofStore) -> Self {
+        self.proofs = proofs;
+        self
+    }
+
+    /// Sets how we pick the key that signs our invoice requests. By default we ask LND for a new
+    /// key for every invoice request.
+    pub fn with_payer_key_strategy(mut self, strategy: PayerKeyStrategy) -> Self {
+        self.payer_key_strategy = strategy;
+        self
+    }
+
+    /// Sets the cache used to look up the nodes of the channels that compact blinded paths point
+    /// to. By default each handler has its own cache.
+    pub fn with_channel_cache(mut self, channel_cache: Arc<ChannelCache>) -> Self {
+        self.channel_cache = channel_cache;
+        self
+    }
+
+    /// Sets whether the blinded paths we create, such as the reply paths in our invoice requests,
+    /// name their introduction node by one of its public channels rather than by its node id. This
+    /// makes for smaller messages and QR codes, but relies on the sender knowing about the channel.
+    /// Off by default.
+    pub fn with_compact_paths(mut self, compact_paths: bool) -> Self {
+        self.compact_paths = compact_paths;
+        self
+    }
+
+    /// Returns how many payments we've made, by how they turned out. Retries that attach to an
+    /// existing payment aren't counted again.
+    pub fn metrics(&self) -> PaymentMetrics {
+        let in_flight = self.active_payments.lock().unwrap().len() as u64;
+        let succeeded = self.outcomes.succeeded.load(Ordering::Relaxed);
+        let failed = self.outcomes.failed.load(Ordering::Relaxed);
+        let cancelled = self.outcomes.cancelled.load(Ordering::Relaxed);
+
+        PaymentMetrics {
+            attempted: succeeded + failed + cancelled + in_flight,
+            succeeded,
+            failed,
+            cancelled,
+            in_flight,
         }
     }
 
-    /// Adds an offer to be paid with the amount specified. May only be called once for a single
-    /// offer.
-    pub async fn pay_offer(&self, cfg: PayOfferParams) -> Result<Payment, OfferError> {
-        let client_clone = cfg.client.clone();
-        let (invoice, validated_amount, payment_id) = self.get_invoice(cfg).await?;
+    /// Looks up the proof of payment for a payment we've completed.
+    pub fn proof_of_payment(&self, payment_id: PaymentId) -> Option<ProofOfPayment> {
+        self.proofs.get(payment_id)
+    }
 
-        self.pay_invoice(client_clone, validated_amount, &invoice, payment_id)
+    /// Adds an offer to be paid with the amount specified. If a payment with the same payment id
+    /// is already in flight, we wait for it to complete and return its result instead.
+    pub async fn pay_offer(&self, mut cfg: PayOfferParams) -> Result<Payment, OfferError> {
+        let payment_id = *cfg
+            .payment_id
+            .get_or_insert_with(|| PaymentId(self.messenger_utils.get_secure_random_bytes()));
+        if let Some(result) = self.payment_result(payment_id) {
+            return result.into_result(payment_id);
+        }
+
+        Correlation::current()
+            .with_payment_id(payment_id)
+            .scope(async {
+                let client_clone = cfg.client.clone();
+                let result = match self.get_invoice(cfg).await {
+                    Ok((invoice, validated_amount, payment_id)) => {
+                        self.pay_invoice(client_clone, validated_amount, &invoice, payment_id)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+
+                self.complete_payment(payment_id, result).await
+            })
             .await
     }
 
+    /// Starts paying an offer, returning the payment id as soon as the invoice request has been
+    /// sent. The rest of the payment happens in the background and its progress can be checked
+    /// with payment_status.
+    pub async fn pay_offer_in_background(
+        self: &Arc<Self>,
+        mut cfg: PayOfferParams,
+    ) -> Result<PaymentId, OfferError> {
+        let payment_id = *cfg
+            .payment_id
+            .get_or_insert_with(|| PaymentId(self.messenger_utils.get_secure_random_bytes()));
+        if self.payment_result(payment_id).is_some() {
+            return Ok(payment_id);
+        }
+
+        let correlation = Correlation::current().with_payment_id(payment_id);
+        let client = cfg.client.clone();
+        let invoice_timeout = cfg.response_invoice_timeout;
+        let validated_amount = match correlation.clone().scope(self.request_invoice(cfg)).await {
+            Ok((_, validated_amount)) => validated_amount,
+            // Someone's already making this payment, so there's nothing more for us to do.
+            Err(OfferError::AlreadyProcessing(id)) if id == payment_id => return Ok(payment_id),
+            Err(e) => {
+                return self
+                    .complete_payment(payment_id, Err(e))
+                    .await
+                    .map(|_| payment_id)
+            }
+        };
+
+        let handler = Arc::clone(self);
+        tokio::spawn(correlation.scope(async move {
+            let result = match handler.receive_invoice(payment_id, invoice_timeout).await {
+                Ok(invoice) => {
+                    handler
+                        .pay_invoice(client, validated_amount, &invoice, payment_id)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = handler.complete_payment(payment_id, result).await {
+                error!("Error paying offer: {e}.");
+            }
+        }));
+
+        Ok(payment_id)
+    }
+
+    /// Pays an invoice that was provided to us directly, rather than one we requested from an
+    /// offer. As with pay_offer, if a payment with the same payment id is already in flight or
+    /// has completed, we return its result rather than paying again.
+    pub(crate) async fn pay_provided_invoice(
+        &self,
+        client: Client,
+        amount: u64,
+        invoice: &Bolt12Invoice,
+        payment_id: PaymentId,
+    ) -> Result<Payment, OfferError> {
+        if let Some(result) = self.payment_result(payment_id) {
+            return result.into_result(payment_id);
+        }
+
+        let result = {
+            let mut active_payments = self.active_payments.lock().unwrap();
+            match active_payments.entry(payment_id) {
+                Entry::Occupied(_) => Err(OfferError::AlreadyProcessing(payment_id)),
+                Entry::Vacant(v) => {
+                    v.insert(PaymentInfo {
+                        state: PaymentState::InvoiceReceived,
+                        invoice: Some(invoice.clone()),
+                        offer: None,
+                        invoice_request: None,
+                        payer_key: None,
+                    });
+                    Ok(())
+                }
+            }
+        };
+
+        let result = match result {
+            Ok(()) => {
+                Correlation::current()
+                    .with_payment_id(payment_id)
+                    .scope(self.pay_invoice(client, amount, invoice, payment_id))
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+
+        self.complete_payment(payment_id, result).await
+    }
+
+    /// Cancels a payment that we haven't yet dispatched any HTLCs for. Any invoice that arrives for
+    /// the payment afterwards is ignored.
+    pub fn cancel_payment(&self, payment_id: PaymentId) -> Result<(), OfferError> {
+        let mut active_payments = self.active_payments.lock().unwrap();
+        match active_payments.get(&payment_id) {
+            Some(info) => match info.state {
+                PaymentState::PaymentDispatched | PaymentState::Paid => {
+                    return Err(OfferError::AlreadyDispatched(payment_id))
+                }
+                _ => {
+                    active_payments.remove(&payment_id);
+                }
+            },
+            None => return Err(OfferError::PaymentNotFound(payment_id)),
+        };
+
+        // We record the cancellation while still holding the active_payments lock, so that
+        // send_payment can't dispatch the payment in between.
+        let mut completed_payments = self.completed_payments.lock().unwrap();
+        completed_payments.insert(payment_id, (PaymentResult::Cancelled, Instant::now()));
+        self.outcomes.cancelled.fetch_add(1, Ordering::Relaxed);
+        info!("Cancelled payment {payment_id}.");
+
+        Ok(())
+    }
+
+    // is_cancelled returns whether the payment was cancelled by the user.
+    pub(crate) fn is_cancelled(&self, payment_id: PaymentId) -> bool {
+        matches!(
+            self.payment_result(payment_id),
+            Some(PaymentResult::Cancelled)
+        )
+    }
+
+    /// Looks up how far along we are with a payment, whether it's still in progress or has
+    /// completed.
+    pub fn payment_status(&self, payment_id: PaymentId) -> Option<PaymentStatus> {
+        if let Some(result) = self.payment_result(payment_id) {
+            return Some(PaymentStatus::Completed(result));
+        }
+
+        let active_payments = self.active_payments.lock().unwrap();
+        active_payments
+            .get(&payment_id)
+            .map(|info| PaymentStatus::InProgress(info.state))
+    }
+
+    /// Looks up the result of a payment we've finished processing.
+    pub fn payment_result(&self, payment_id: PaymentId) -> Option<PaymentResult> {
+        let completed_payments = self.completed_payments.lock().unwrap();
+        completed_payments
+            .get(&payment_id)
+            .map(|(result, _)| result.clone())
+    }
+
+    // complete_payment stores the result of a payment so that it can be looked up later. If the
+    // payment failed because another attempt with the same id is still in flight, we instead
+    // wait for that attempt to finish and return its result.
+    async fn complete_payment(
+        &self,
+        payment_id: PaymentId,
+        result: Result<Payment, OfferError>,
+    ) -> Result<Payment, OfferError> {
+        if let Err(OfferError::AlreadyProcessing(id)) = result {
+            if id == payment_id {
+                return self.wait_for_payment_result(payment_id).await;
+            }
+        }
+
+        // Cancelled payments were already counted by cancel_payment.
+        let payment_result = match result {
+            Ok(ref payment) => {
+                self.outcomes.succeeded.fetch_add(1, Ordering::Relaxed);
+                PaymentResult::Succeeded(payment.clone())
+            }
+            Err(OfferError::PaymentCancelled(_)) => PaymentResult::Cancelled,
+            Err(ref e) => {
+                self.outcomes.failed.fetch_add(1, Ordering::Relaxed);
+                PaymentResult::Failed(e.to_string())
+            }
+        };
+
+        let mut completed_payments = self.completed_payments.lock().unwrap();
+        completed_payments.retain(|_, (_, completed_at)| {
+            completed_at.elapsed() < Duration::from_secs(PAYMENT_RESULT_EXPIRY_SECS)
+        });
+        completed_payments.insert(payment_id, (payment_result, Instant::now()));
+
+        result
+    }
+
+    // wait_for_payment_result waits for an in-flight payment to complete. If the payment stops
+    // being tracked without a result ever being stored (for example because the caller that was
+    // making it went away), we give up.
+    async fn wait_for_payment_result(&self, payment_id: PaymentId) -> Result<Payment, OfferError> {
+        let mut inactive_polls = 0;
+        loop {
+            if let Some(result) = self.payment_result(payment_id) {
+                return result.into_result(payment_id);
+            }
+
+            let active = {
+                let active_payments = self.active_payments.lock().unwrap();
+                active_payments.contains_key(&payment_id)
+            };
+            // The payment is removed from active_payments just before its result is stored, so we
+            // allow for one extra poll before giving up.
+            if !active {
+                inactive_polls += 1;
+                if inactive_polls > 1 {
+                    return Err(OfferError::AlreadyProcessing(payment_id));
+                }
+            }
+
+            sleep(Duration::from_secs(2)).await;
+        }
+    }
+
     /// Sends an invoice request and waits for an invoice to be sent back to us.
     /// Reminder that if this method returns an error after create_invoice_request is called, we
     /// *must* remove the payment_id from self.active_payments.
@@ -325,6 +1401,17 @@ impl OfferHandler {
         &self,
         cfg: PayOfferParams,
     ) -> Result<(Bolt12Invoice, u64, PaymentId), OfferError> {
+        let cfg_timeout = cfg.response_invoice_timeout;
+        let (payment_id, validated_amount) = self.request_invoice(cfg).await?;
+        let invoice = self.receive_invoice(payment_id, cfg_timeout).await?;
+
+        Ok((invoice, validated_amount, payment_id))
+    }
+
+    // request_invoice creates an invoice request for the offer and sends it to the offer creator.
+    // If this succeeds, the payment is left in self.active_payments for receive_invoice to pick
+    // up.
+    async fn request_invoice(&self, cfg: PayOfferParams) -> Result<(PaymentId, u64), OfferError> {
         let (invoice_request, payment_id, validated_amount) = self
             .create_invoice_request(
                 cfg.client.clone(),
@@ -332,6 +1419,7 @@ impl OfferHandler {
                 cfg.network,
                 cfg.amount,
                 cfg.payer_note,
+                cfg.payment_id,
             )
             .await?;
 
@@ -348,9 +1436,24 @@ impl OfferHandler {
             e
         })?;
 
-        let cfg_timeout = cfg
-            .response_invoice_timeout
-            .unwrap_or(self.response_invoice_timeout);
+        {
+            let mut active_payments = self.active_payments.lock().unwrap();
+            active_payments
+                .entry(payment_id)
+                .and_modify(|entry| entry.state = PaymentState::InvoiceRequestSent);
+        }
+
+        Ok((payment_id, validated_amount))
+    }
+
+    // receive_invoice waits for the offer creator to respond to our invoice request, removing the
+    // payment from self.active_payments if they don't respond in time.
+    async fn receive_invoice(
+        &self,
+        payment_id: PaymentId,
+        response_invoice_timeout: Option<u32>,
+    ) -> Result<Bolt12Invoice, OfferError> {
+        let cfg_timeout = response_invoice_timeout.unwrap_or(self.response_invoice_timeout());
 
         let invoice = match timeout(
             Duration::from_secs(cfg_timeout as u64),
@@ -358,7 +1461,8 @@ impl OfferHandler {
         )
         .await
         {
-            Ok(invoice) => invoice,
+            Ok(Ok(invoice)) => invoice,
+            Ok(Err(e)) => return Err(e),
             Err(_) => {
                 error!("Did not receive invoice in {cfg_timeout} seconds.");
                 let mut active_payments = self.active_payments.lock().unwrap();
@@ -373,7 +1477,7 @@ impl OfferHandler {
                 .and_modify(|entry| entry.state = PaymentState::InvoiceReceived);
         }
 
-        Ok((invoice, validated_amount, payment_id))
+        Ok(invoice)
     }
 
     /// Sends an invoice request and waits for an invoice to be sent back to us.
@@ -389,43 +1493,57 @@ impl OfferHandler {
         let payment_hash = invoice.payment_hash();
         let path_info = invoice.payment_paths()[0].clone();
 
+        let introduction_node = match path_info.1.introduction_node {
+            IntroductionNode::NodeId(node_id) => node_id,
+            IntroductionNode::DirectedShortChannelId(direction, scid) => {
+                match get_node_id(client.clone(), &self.channel_cache, scid, direction).await {
+                    Ok(node_id) => node_id,
+                    Err(e) => {
+                        let mut active_payments = self.active_payments.lock().unwrap();
+                        active_payments.remove(&payment_id);
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        debug!("Attempting to pay invoice with introduction node {introduction_node}");
+
         let params = SendPaymentParams {
             path: path_info.1,
+            introduction_node,
             cltv_expiry_delta: path_info.0.cltv_expiry_delta,
             fee_base_msat: path_info.0.fee_base_msat,
             fee_ppm: path_info.0.fee_proportional_millionths,
             payment_hash: payment_hash.0,
             msats: amount,
             payment_id,
+            issuer: invoice.signing_pubkey(),
         };
 
-        let intro_node_id = match params.path.introduction_node {
-            IntroductionNode::NodeId(node_id) => Some(node_id.to_string()),
-            IntroductionNode::DirectedShortChannelId(direction, scid) => {
-                let get_chan_info_request = ChanInfoRequest { chan_id: scid };
-                let chan_info = client
-                    .clone()
-                    .lightning_read_only()
-                    .get_chan_info(get_chan_info_request)
-                    .await
-                    .map_err(OfferError::GetChannelInfo)?
-                    .into_inner();
-                match direction {
-                    Direction::NodeOne => Some(chan_info.node1_pub),
-                    Direction::NodeTwo => Some(chan_info.node2_pub),
-                }
-            }
-        };
-        debug!(
-            "Attempting to pay invoice with introduction node {:?}",
-            intro_node_id
-        );
-
         self.send_payment(client, params)
             .await
             .map(|payment| {
                 let mut active_payments = self.active_payments.lock().unwrap();
-                active_payments.remove(&payment_id);
+                let info = active_payments.remove(&payment_id);
+                std::mem::drop(active_payments);
+
+                let mut proof = ProofOfPayment::new(
+                    info.as_ref().and_then(|info| info.offer.as_ref()),
+                    info.as_ref().and_then(|info| info.invoice_request.as_ref()),
+                    invoice,
+                    payment.payment_preimage.clone(),
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default(),
+                );
+                proof.payer_key = info.and_then(|info| info.payer_key);
+                // The payment went through either way, so we don't fail it if we can't store
+                // the proof.
+                if let Err(e) = self.proofs.insert(payment_id, proof) {
+                    error!("Could not store proof of payment for {payment_id}: {e}");
+                }
+
                 payment
             })
             .map_err(|e| {
@@ -436,14 +1554,18 @@ impl OfferHandler {
     }
 
     /// wait_for_invoice waits for the offer creator to respond with an invoice.
-    async fn wait_for_invoice(&self, payment_id: PaymentId) -> Bolt12Invoice {
+    /// If the payment is cancelled while we're waiting, we stop waiting.
+    async fn wait_for_invoice(&self, payment_id: PaymentId) -> Result<Bolt12Invoice, OfferError> {
         loop {
             {
                 let active_payments = self.active_payments.lock().unwrap();
-                if let Some(pay_info) = active_payments.get(&payment_id) {
-                    if let Some(invoice) = pay_info.invoice.clone() {
-                        return invoice;
+                match active_payments.get(&payment_id) {
+                    Some(pay_info) => {
+                        if let Some(invoice) = pay_info.invoice.clone() {
+                            return Ok(invoice);
+                        }
                     }
+                    None => return Err(OfferError::PaymentCancelled(payment_id)),
                 };
             }
             sleep(Duration::from_secs(2)).await;
@@ -465,36 +1587,49 @@ impl OffersMessageHandler for OfferHandler {
                 None
             }
             OffersMessage::Invoice(invoice) => {
-                info!("Received an invoice: {invoice:?}");
                 let secp_ctx = &Secp256k1::new();
-                // We verify that this invoice is a response to an invoice request we sent.
-                match invoice.verify(&self.expanded_key, secp_ctx) {
-                    Ok(payment_id) => {
-                        info!("Successfully verified invoice for payment_id {payment_id}");
-                        let mut active_payments = self.active_payments.lock().unwrap();
-                        match active_payments.get_mut(&payment_id) {
-                            Some(pay_info) => match pay_info.invoice {
-                                Some(_) => {
-                                    error!("We already received an invoice with this payment id.")
-                                }
+                // We verify that this invoice is a response to an invoice request we sent. If it
+                // is, we know which payment it's for and tag our logs with it.
+                let verified = invoice.verify(&self.expanded_key, secp_ctx);
+                let correlation = match verified {
+                    Ok(payment_id) => Correlation::current().with_payment_id(payment_id),
+                    Err(()) => Correlation::current(),
+                };
+                correlation.sync_scope(|| {
+                    info!("Received an invoice: {:?}", Sensitive(&invoice));
+                    match verified {
+                        Ok(payment_id) => {
+                            info!("Successfully verified invoice for payment_id {payment_id}");
+                            let mut active_payments = self.active_payments.lock().unwrap();
+                            match active_payments.get_mut(&payment_id) {
+                                Some(pay_info) => match pay_info.invoice {
+                                    Some(_) => {
+                                        error!(
+                                            "We already received an invoice with this payment id."
+                                        )
+                                    }
+                                    None => {
+                                        pay_info.state = PaymentState::InvoiceReceived;
+                                        pay_info.invoice = Some(invoice.clone());
+                                    }
+                                },
                                 None => {
-                                    pay_info.state = PaymentState::InvoiceReceived;
-                                    pay_info.invoice = Some(invoice.clone());
+                                    error!("We received an invoice request for a payment id that we don't recognize or already paid: {payment_id:?}. We will ignore the invoice.");
                                 }
-                            },
-                            None => {
-                                error!("We received an invoice request for a payment id that we don't recognize or already paid: {payment_id:?}. We will ignore the invoice.");
                             }
+                            Some(OffersMessage::Invoice(invoice))
+                        }
+                        Err(()) => {
+                            error!(
+                                "Invoice verification failed for invoice: {:?}",
+                                Sensitive(&invoice)
+                            );
+                            Some(OffersMessage::InvoiceError(InvoiceError::from_string(
+                                String::from("invoice verification failure"),
+                            )))
                         }
-                        Some(OffersMessage::Invoice(invoice))
-                    }
-                    Err(()) => {
-                        error!("Invoice verification failed for invoice: {invoice:?}");
-                        Some(OffersMessage::InvoiceError(InvoiceError::from_string(
-                            String::from("invoice verification failure"),
-                        )))
                     }
-                }
+                })
             }
             OffersMessage::InvoiceError(error) => {
                 log::error!("Invoice error received: {}", error);
@@ -508,14 +1643,106 @@ impl OffersMessageHandler for OfferHandler {
     }
 }
 
+/// The human-readable prefix of a bech32-encoded BOLT 12 invoice.
+pub const INVOICE_BECH32_HRP: &str = "lni";
+
+/// The ways a BOLT 12 invoice can be encoded as a string.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvoiceEncoding {
+    /// The invoice's raw TLV stream, hex-encoded.
+    Hex,
+    /// The invoice's raw TLV stream, bech32-encoded without a checksum and prefixed with 'lni',
+    /// the same way BOLT 12 offers are encoded.
+    Bech32,
+}
+
+#[derive(Debug)]
+/// InvoiceDecodeError is an error that occurs when decoding an invoice string.
+pub enum InvoiceDecodeError {
+    /// The string is neither hex nor bech32-encoded with an 'lni' prefix.
+    UnknownEncoding,
+    /// The string has an 'lni' prefix but isn't valid bech32.
+    InvalidBech32(bech32::Error),
+    /// The string isn't valid hex.
+    InvalidHex(hex::FromHexError),
+    /// The string was decoded but doesn't contain a valid invoice.
+    InvalidInvoice(Bolt12ParseError),
+}
+
+impl Display for InvoiceDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvoiceDecodeError::UnknownEncoding => write!(
+                f,
+                "Invoice is neither hex nor bech32-encoded with an '{INVOICE_BECH32_HRP}' prefix"
+            ),
+            InvoiceDecodeError::InvalidBech32(e) => write!(f, "Invalid bech32 invoice: {e}"),
+            InvoiceDecodeError::InvalidHex(e) => write!(f, "Invalid hex invoice: {e}"),
+            InvoiceDecodeError::InvalidInvoice(e) => write!(f, "Invalid invoice: {e:?}"),
+        }
+    }
+}
+
+impl Error for InvoiceDecodeError {}
+
 pub struct Bolt12InvoiceString(pub String);
 
+impl Bolt12InvoiceString {
+    /// Encodes an invoice as a string with the given encoding.
+    pub fn encode(invoice: &Bolt12Invoice, encoding: InvoiceEncoding) -> Self {
+        let bytes = invoice.encode();
+        let s = match encoding {
+            InvoiceEncoding::Hex => hex::encode(bytes),
+            // Encoding only fails if the prefix is invalid, which ours isn't.
+            InvoiceEncoding::Bech32 => {
+                bech32::encode_without_checksum(INVOICE_BECH32_HRP, bytes.to_base32())
+                    .expect("valid bech32 prefix")
+            }
+        };
+        Bolt12InvoiceString(s)
+    }
+
+    /// Works out how the invoice string is encoded. Bech32 invoices are recognized by their
+    /// prefix, which can never be valid hex.
+    pub fn encoding(&self) -> Result<InvoiceEncoding, InvoiceDecodeError> {
+        let s = self.0.trim();
+        // Compare bytes rather than slicing the string, which would panic if the prefix ends
+        // inside a multi-byte character.
+        let prefix = INVOICE_BECH32_HRP.as_bytes();
+        if s.len() >= prefix.len() && s.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix) {
+            Ok(InvoiceEncoding::Bech32)
+        } else if !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit()) {
+            Ok(InvoiceEncoding::Hex)
+        } else {
+            Err(InvoiceDecodeError::UnknownEncoding)
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, InvoiceDecodeError> {
+        match self.encoding()? {
+            InvoiceEncoding::Hex => {
+                hex::decode(self.0.trim()).map_err(InvoiceDecodeError::InvalidHex)
+            }
+            InvoiceEncoding::Bech32 => {
+                // Like offers, long bech32 invoices may be split into chunks joined with '+'.
+                let s: String = self.0.trim().split('+').map(|chunk| chunk.trim()).collect();
+                let (hrp, data) = bech32::decode_without_checksum(&s)
+                    .map_err(InvoiceDecodeError::InvalidBech32)?;
+                if hrp != INVOICE_BECH32_HRP {
+                    return Err(InvoiceDecodeError::UnknownEncoding);
+                }
+                Vec::<u8>::from_base32(&data).map_err(InvoiceDecodeError::InvalidBech32)
+            }
+        }
+    }
+}
+
 impl TryFrom<Bolt12InvoiceString> for Bolt12Invoice {
-    type Error = DecodeError;
+    type Error = InvoiceDecodeError;
 
     fn try_from(s: Bolt12InvoiceString) -> Result<Self, Self::Error> {
-        let bytes: Vec<u8> = hex::decode(s.0).unwrap();
-        Self::try_from(bytes).map_err(|_| DecodeError::InvalidValue)
+        let bytes = s.to_bytes()?;
+        Self::try_from(bytes).map_err(InvoiceDecodeError::InvalidInvoice)
     }
 }
 
@@ -527,5 +1754,100 @@ impl From<String> for Bolt12InvoiceString {
 
 #[cfg(test)]
 mod tests {
-    pub mod test_utils;
+    use super::*;
+    use crate::sim::fixtures::{build_invoice, build_invoice_request, build_offer};
+    use lightning::ln::PaymentHash;
+
+    fn invoice() -> Bolt12Invoice {
+        let invoice_request = build_invoice_request(&build_offer("coffee"));
+        build_invoice(&invoice_request, PaymentHash([1; 32]))
+    }
+
+    #[test]
+    fn test_invoice_string_round_trip() {
+        let invoice = invoice();
+
+        let hex_string = Bolt12InvoiceString::encode(&invoice, InvoiceEncoding::Hex);
+        assert_eq!(hex_string.encoding().unwrap(), InvoiceEncoding::Hex);
+        let decoded = Bolt12Invoice::try_from(hex_string).unwrap();
+        assert_eq!(decoded.encode(), invoice.encode());
+
+        let bech32_string = Bolt12InvoiceString::encode(&invoice, InvoiceEncoding::Bech32);
+        assert!(bech32_string.0.starts_with(INVOICE_BECH32_HRP));
+        assert_eq!(bech32_string.encoding().unwrap(), InvoiceEncoding::Bech32);
+        let decoded = Bolt12Invoice::try_from(bech32_string).unwrap();
+        assert_eq!(decoded.encode(), invoice.encode());
+    }
+
+    #[test]
+    fn test_invoice_string_bech32_variants() {
+        let invoice = invoice();
+        let bech32_string = Bolt12InvoiceString::encode(&invoice, InvoiceEncoding::Bech32).0;
+
+        // Upper case bech32 strings are valid too.
+        let upper: Bolt12InvoiceString = bech32_string.to_uppercase().into();
+        assert!(Bolt12Invoice::try_from(upper).is_ok());
+
+        // And so are strings split into chunks with '+'.
+        let (first, second) = bech32_string.split_at(40);
+        let split: Bolt12InvoiceString = format!("{first}+\n  {second}").into();
+        assert!(Bolt12Invoice::try_from(split).is_ok());
+    }
+
+    #[test]
+    fn test_invoice_string_invalid() {
+        let offer: Bolt12InvoiceString = build_offer("coffee").to_string().into();
+        assert!(matches!(
+            Bolt12Invoice::try_from(offer),
+            Err(InvoiceDecodeError::UnknownEncoding)
+        ));
+
+        let empty: Bolt12InvoiceString = String::new().into();
+        assert!(matches!(
+            Bolt12Invoice::try_from(empty),
+            Err(InvoiceDecodeError::UnknownEncoding)
+        ));
+
+        // A multi-byte character straddling the prefix length must not panic.
+        let multi_byte: Bolt12InvoiceString = "ln\u{e9}1".to_string().into();
+        assert!(matches!(
+            Bolt12Invoice::try_from(multi_byte),
+            Err(InvoiceDecodeError::UnknownEncoding)
+        ));
+
+        let odd_hex: Bolt12InvoiceString = "abc".to_string().into();
+        assert!(matches!(
+            Bolt12Invoice::try_from(odd_hex),
+            Err(InvoiceDecodeError::InvalidHex(_))
+        ));
+
+        let bad_bech32: Bolt12InvoiceString = "lni1bbbbbbbbbo".to_string().into();
+        assert!(matches!(
+            Bolt12Invoice::try_from(bad_bech32),
+            Err(InvoiceDecodeError::InvalidBech32(_))
+        ));
+
+        let not_an_invoice: Bolt12InvoiceString = "00".to_string().into();
+        assert!(matches!(
+            Bolt12Invoice::try_from(not_an_invoice),
+            Err(InvoiceDecodeError::InvalidInvoice(_))
+        ));
+    }
+
+    #[test]
+    fn test_network_dirs() {
+        let data_dir = PathBuf::from("/lndk");
+        assert_eq!(
+            network_data_dir(&data_dir, Network::Bitcoin),
+            PathBuf::from("/lndk/data/mainnet")
+        );
+        assert_eq!(
+            network_data_dir(&data_dir, Network::Regtest),
+            PathBuf::from("/lndk/data/regtest")
+        );
+        assert_eq!(
+            network_log_file(&data_dir, Network::Signet),
+            PathBuf::from("/lndk/logs/signet/lndk.log")
+        );
+    }
 }
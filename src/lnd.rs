@@ -7,24 +7,32 @@ use bitcoin::secp256k1::ecdh::SharedSecret;
 use bitcoin::secp256k1::ecdsa::{RecoverableSignature, Signature};
 use bitcoin::secp256k1::{self, PublicKey, Scalar, Secp256k1};
 use futures::executor::block_on;
+use futures::Stream;
 use lightning::blinded_path::BlindedPath;
 use lightning::ln::msgs::UnsignedGossipMessage;
 use lightning::offers::invoice::UnsignedBolt12Invoice;
 use lightning::offers::invoice_request::{InvoiceRequest, UnsignedInvoiceRequest};
 use lightning::sign::{KeyMaterial, NodeSigner, Recipient};
 use log::error;
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
+use std::future::Future;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::time::Duration;
 use std::{fmt, fs};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tonic_lnd::lnrpc::{
-    GetInfoResponse, HtlcAttempt, ListPeersResponse, NodeInfo, Payment, QueryRoutesResponse, Route,
+    ChannelEdge, ChannelEventSubscription, ChannelEventUpdate, CustomMessage, GetInfoRequest,
+    GetInfoResponse, GraphTopologySubscription, GraphTopologyUpdate, HtlcAttempt,
+    ListPeersResponse, NodeInfo, Payment, PeerEvent, PeerEventSubscription, QueryRoutesResponse,
+    Route, SendCustomMessageRequest, SendCustomMessageResponse, SubscribeCustomMessagesRequest,
 };
-use tonic_lnd::signrpc::{KeyDescriptor, KeyLocator};
+use tonic_lnd::signrpc::{KeyDescriptor, KeyLocator, SharedKeyRequest};
 use tonic_lnd::tonic::Status;
-use tonic_lnd::verrpc::Version;
+use tonic_lnd::verrpc::{Version, VersionRequest};
 use tonic_lnd::walletrpc::KeyReq;
 use tonic_lnd::{Client, ConnectError};
 
@@ -157,7 +165,7 @@ impl Display for ValidationError {
 
 impl Error for ValidationError {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub enum Creds {
     // Absolute paths to the macaroon and certificate.
     Path { macaroon: PathBuf, cert: PathBuf },
@@ -168,6 +176,24 @@ pub enum Creds {
     String { macaroon: String, cert: String },
 }
 
+// We never print a macaroon that's passed in directly, so that it can't end up in our logs.
+impl fmt::Debug for Creds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Creds::Path { macaroon, cert } => f
+                .debug_struct("Path")
+                .field("macaroon", macaroon)
+                .field("cert", cert)
+                .finish(),
+            Creds::String { cert, .. } => f
+                .debug_struct("String")
+                .field("macaroon", &"<redacted>")
+                .field("cert", cert)
+                .finish(),
+        }
+    }
+}
+
 impl Creds {
     #[allow(clippy::result_unit_err)]
     pub fn get_certificate_string(&self) -> Result<String, ()> {
@@ -232,24 +258,67 @@ pub fn has_build_tags(version: &Version, requirement: Option<BuildTagsRequiremen
     true
 }
 
+/// LND_REQUEST_TIMEOUT is how long our synchronous LDK trait implementations wait for LND to
+/// answer a request that they've handed off to the runtime.
+pub(crate) const LND_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// EcdhRequest asks the signer task for the ECDH shared secret of our node key and `key`.
+struct EcdhRequest {
+    key: PublicKey,
+    respond: SyncSender<Result<SharedSecret, ()>>,
+}
+
 /// LndNodeSigner provides signing operations using LND's signer subserver.
-pub(crate) struct LndNodeSigner<'a> {
+///
+/// LDK's NodeSigner trait is synchronous, so we can't await LND's api directly. Instead, calls to
+/// LND are handed off to a signer task running on the tokio runtime (see LndNodeSigner::new), and
+/// the caller waits for the result. Callers should therefore not be on one of the runtime's own
+/// threads.
+pub(crate) struct LndNodeSigner {
     pubkey: PublicKey,
     secp_ctx: Secp256k1<secp256k1::All>,
-    signer: RefCell<&'a mut tonic_lnd::SignerClient>,
+    requests: UnboundedSender<EcdhRequest>,
 }
 
-impl<'a> LndNodeSigner<'a> {
-    pub(crate) fn new(pubkey: PublicKey, signer: &'a mut tonic_lnd::SignerClient) -> Self {
-        LndNodeSigner {
+impl LndNodeSigner {
+    /// Creates a signer, along with the signer task that serves its requests from LND. The task
+    /// must be spawned on the runtime, and exits once the signer is dropped.
+    pub(crate) fn new(
+        pubkey: PublicKey,
+        signer: impl SharedKeyDeriver + Clone + Send + 'static,
+    ) -> (Self, impl Future<Output = ()>) {
+        let (sender, receiver) = unbounded_channel();
+        let node_signer = LndNodeSigner {
             pubkey,
             secp_ctx: Secp256k1::new(),
-            signer: RefCell::new(signer),
-        }
+            requests: sender,
+        };
+
+        (node_signer, serve_ecdh_requests(signer, receiver))
     }
 }
 
-impl<'a> NodeSigner for LndNodeSigner<'a> {
+// serve_ecdh_requests derives shared secrets with LND for LndNodeSigner. Each request is served in
+// its own task so that a slow call to LND doesn't hold up the requests behind it.
+async fn serve_ecdh_requests(
+    signer: impl SharedKeyDeriver + Clone + Send + 'static,
+    mut requests: UnboundedReceiver<EcdhRequest>,
+) {
+    while let Some(request) = requests.recv().await {
+        let mut signer = signer.clone();
+        tokio::spawn(async move {
+            let shared_secret = signer
+                .derive_shared_key(request.key)
+                .await
+                .map_err(|e| error!("Error deriving shared key: {e}."));
+
+            // If the caller has given up waiting, there's no one left to tell.
+            let _ = request.respond.send(shared_secret);
+        });
+    }
+}
+
+impl NodeSigner for LndNodeSigner {
     /// Get node id based on the provided [`Recipient`].
     ///
     /// This method must return the same value each time it is called with a given [`Recipient`]
@@ -287,21 +356,17 @@ impl<'a> NodeSigner for LndNodeSigner<'a> {
             *other_key
         };
 
-        let shared_secret = match block_on(self.signer.borrow_mut().derive_shared_key(
-            tonic_lnd::signrpc::SharedKeyRequest {
-                ephemeral_pubkey: tweaked_key.serialize().into_iter().collect::<Vec<u8>>(),
-                key_desc: None,
-                ..Default::default()
-            },
-        )) {
-            Ok(shared_key_resp) => shared_key_resp.into_inner().shared_key,
-            Err(_) => return Err(()),
-        };
+        let (respond, response) = sync_channel(1);
+        self.requests
+            .send(EcdhRequest {
+                key: tweaked_key,
+                respond,
+            })
+            .map_err(|_| error!("Signer task has exited."))?;
 
-        match SharedSecret::from_slice(&shared_secret) {
-            Ok(secret) => Ok(secret),
-            Err(_) => Err(()),
-        }
+        response
+            .recv_timeout(LND_REQUEST_TIMEOUT)
+            .map_err(|e| error!("Error waiting for shared key: {e}."))?
     }
 
     fn get_inbound_payment_key_material(&self) -> KeyMaterial {
@@ -385,13 +450,14 @@ pub fn string_to_network(network_str: &str) -> Result<Network, NetworkParseError
 #[async_trait]
 pub trait MessageSigner {
     async fn derive_next_key(&mut self, key_loc: KeyReq) -> Result<KeyDescriptor, Status>;
+    async fn derive_key(&mut self, key_loc: KeyLocator) -> Result<KeyDescriptor, Status>;
     async fn sign_message(
         &mut self,
         key_loc: KeyLocator,
         merkle_hash: Hash,
         tag: String,
     ) -> Result<Vec<u8>, Status>;
-    fn sign_uir(
+    async fn sign_uir(
         &mut self,
         key_loc: KeyLocator,
         unsigned_invoice_req: UnsignedInvoiceRequest,
@@ -410,12 +476,19 @@ pub trait PeerConnector {
     ) -> Result<NodeInfo, Status>;
 }
 
+/// ChannelInfoProvider provides a layer of abstraction over the LND API for looking up a channel.
+#[async_trait]
+pub trait ChannelInfoProvider {
+    async fn get_chan_info(&mut self, short_channel_id: u64) -> Result<ChannelEdge, Status>;
+}
+
 /// InvoicePayer provides a layer of abstraction over the LND API for paying for a BOLT 12 invoice.
 #[async_trait]
 pub trait InvoicePayer {
     async fn query_routes(
         &mut self,
         path: BlindedPath,
+        introduction_node: PublicKey,
         cltv_expiry_delta: u16,
         fee_base_msat: u32,
         fee_ppm: u32,
@@ -429,6 +502,171 @@ pub trait InvoicePayer {
     async fn track_payment(&mut self, payment_hash: [u8; 32]) -> Result<Payment, OfferError>;
 }
 
+/// EventStream is a subscription to a stream of updates from the node, such as one of LND's server
+/// streaming RPCs. The stream ends when the subscription does.
+pub type EventStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// NodeInfoProvider provides a layer of abstraction over the LND API for looking up our own node.
+#[async_trait]
+pub trait NodeInfoProvider {
+    async fn get_info(&mut self) -> Result<GetInfoResponse, Status>;
+    async fn get_version(&mut self) -> Result<Version, Status>;
+}
+
+/// EventSubscriber provides a layer of abstraction over the LND API for subscribing to updates
+/// about our peers, the custom messages they send us and our view of the channel graph.
+#[async_trait]
+pub trait EventSubscriber {
+    async fn subscribe_peer_events(&mut self) -> Result<EventStream<PeerEvent>, Status>;
+    async fn subscribe_custom_messages(&mut self) -> Result<EventStream<CustomMessage>, Status>;
+    async fn subscribe_channel_events(&mut self)
+        -> Result<EventStream<ChannelEventUpdate>, Status>;
+    async fn subscribe_channel_graph(&mut self)
+        -> Result<EventStream<GraphTopologyUpdate>, Status>;
+}
+
+/// SendCustomMessage provides a level of abstraction over LND's send custom message API.
+#[async_trait]
+pub trait SendCustomMessage {
+    async fn send_custom_message(
+        &mut self,
+        request: SendCustomMessageRequest,
+    ) -> Result<SendCustomMessageResponse, Status>;
+}
+
+/// SharedKeyDeriver provides a layer of abstraction over the LND API for ECDH with our node key.
+#[async_trait]
+pub trait SharedKeyDeriver {
+    async fn derive_shared_key(&mut self, ephemeral_key: PublicKey)
+        -> Result<SharedSecret, Status>;
+}
+
+/// NodeBackend is the full set of node APIs that LNDK runs on. It's implemented for LND's
+/// [`Client`], and for [`crate::sim::SimNode`], an in-memory node that lets us run LNDK without
+/// bitcoind or LND.
+pub trait NodeBackend:
+    NodeInfoProvider
+    + PeerConnector
+    + EventSubscriber
+    + SendCustomMessage
+    + SharedKeyDeriver
+    + MessageSigner
+    + ChannelInfoProvider
+    + InvoicePayer
+    + Clone
+    + Send
+    + 'static
+{
+}
+
+impl<T> NodeBackend for T where
+    T: NodeInfoProvider
+        + PeerConnector
+        + EventSubscriber
+        + SendCustomMessage
+        + SharedKeyDeriver
+        + MessageSigner
+        + ChannelInfoProvider
+        + InvoicePayer
+        + Clone
+        + Send
+        + 'static
+{
+}
+
+#[async_trait]
+impl NodeInfoProvider for Client {
+    async fn get_info(&mut self) -> Result<GetInfoResponse, Status> {
+        self.lightning()
+            .get_info(GetInfoRequest {})
+            .await
+            .map(|resp| resp.into_inner())
+    }
+
+    async fn get_version(&mut self) -> Result<Version, Status> {
+        self.versioner()
+            .get_version(VersionRequest {})
+            .await
+            .map(|resp| resp.into_inner())
+    }
+}
+
+#[async_trait]
+impl EventSubscriber for Client {
+    async fn subscribe_peer_events(&mut self) -> Result<EventStream<PeerEvent>, Status> {
+        let stream = self
+            .lightning()
+            .subscribe_peer_events(PeerEventSubscription {})
+            .await?
+            .into_inner();
+        Ok(Box::pin(stream))
+    }
+
+    async fn subscribe_custom_messages(&mut self) -> Result<EventStream<CustomMessage>, Status> {
+        let stream = self
+            .lightning()
+            .subscribe_custom_messages(SubscribeCustomMessagesRequest {})
+            .await?
+            .into_inner();
+        Ok(Box::pin(stream))
+    }
+
+    async fn subscribe_channel_events(
+        &mut self,
+    ) -> Result<EventStream<ChannelEventUpdate>, Status> {
+        let stream = self
+            .lightning()
+            .subscribe_channel_events(ChannelEventSubscription {})
+            .await?
+            .into_inner();
+        Ok(Box::pin(stream))
+    }
+
+    async fn subscribe_channel_graph(
+        &mut self,
+    ) -> Result<EventStream<GraphTopologyUpdate>, Status> {
+        let stream = self
+            .lightning()
+            .subscribe_channel_graph(GraphTopologySubscription {})
+            .await?
+            .into_inner();
+        Ok(Box::pin(stream))
+    }
+}
+
+#[async_trait]
+impl SendCustomMessage for Client {
+    async fn send_custom_message(
+        &mut self,
+        request: SendCustomMessageRequest,
+    ) -> Result<SendCustomMessageResponse, Status> {
+        self.lightning()
+            .send_custom_message(request)
+            .await
+            .map(|resp| resp.into_inner())
+    }
+}
+
+#[async_trait]
+impl SharedKeyDeriver for Client {
+    async fn derive_shared_key(
+        &mut self,
+        ephemeral_key: PublicKey,
+    ) -> Result<SharedSecret, Status> {
+        let resp = self
+            .signer()
+            .derive_shared_key(SharedKeyRequest {
+                ephemeral_pubkey: ephemeral_key.serialize().to_vec(),
+                key_desc: None,
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        SharedSecret::from_slice(&resp.shared_key)
+            .map_err(|e| Status::internal(format!("invalid shared key: {e}")))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -686,4 +924,15 @@ mod tests {
         };
         assert!(has_build_tags(&version, Some(get_build_tags_requirement())))
     }
+
+    #[test]
+    fn test_creds_debug_redacts_macaroon() {
+        let creds = Creds::String {
+            macaroon: "0201036c6e64".to_string(),
+            cert: "cert".to_string(),
+        };
+        let debug = format!("{creds:?}");
+        assert!(!debug.contains("0201036c6e64"));
+        assert!(debug.contains("cert"));
+    }
 }
@@ -1,16 +1,19 @@
+use crate::budget::{BudgetError, SpendingLimit};
+use crate::channel_cache::{ChannelCache, ChannelNodes};
 use crate::lnd::{features_support_onion_messages, InvoicePayer, MessageSigner, PeerConnector};
-use crate::{OfferHandler, PaymentState};
+use crate::payer_key::{PayerKey, PayerKeyStrategy, NODE_KEY_FAMILY};
+use crate::{OfferHandler, PaymentState, PAYMENT_RESULT_EXPIRY_SECS};
 use async_trait::async_trait;
 use bitcoin::hashes::sha256::Hash;
+use bitcoin::hashes::{Hash as _, HashEngine};
 use bitcoin::network::constants::Network;
 use bitcoin::secp256k1::schnorr::Signature;
-use bitcoin::secp256k1::{PublicKey, Secp256k1, SignOnly};
-use futures::executor::block_on;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SignOnly, Signing};
 use lightning::blinded_path::{BlindedPath, Direction, IntroductionNode};
 use lightning::ln::channelmanager::PaymentId;
+use lightning::offers::invoice::Bolt12Invoice;
 use lightning::offers::invoice_request::{
-    ExplicitPayerId, InvoiceRequest, InvoiceRequestBuilder, SignInvoiceRequestFn,
-    UnsignedInvoiceRequest,
+    ExplicitPayerId, InvoiceRequest, InvoiceRequestBuilder, PayerIdStrategy, UnsignedInvoiceRequest,
 };
 use lightning::offers::merkle::SignError;
 use lightning::offers::offer::{Amount, Offer};
@@ -18,14 +21,15 @@ use lightning::offers::parse::{Bolt12ParseError, Bolt12SemanticError};
 use lightning::onion_message::messenger::{Destination, PendingOnionMessage};
 use lightning::onion_message::offers::OffersMessage;
 use lightning::sign::EntropySource;
+use lightning::util::ser::Writeable;
 use log::{debug, error};
 use std::collections::hash_map::Entry;
 use std::error::Error;
 use std::fmt::Display;
 use std::str::FromStr;
-use tokio::task;
+use std::time::{Duration, Instant};
 use tonic_lnd::lnrpc::{
-    ChanInfoRequest, GetInfoRequest, HtlcAttempt, ListPeersRequest, ListPeersResponse, NodeInfo,
+    ChannelEdge, GetInfoRequest, HtlcAttempt, ListPeersRequest, ListPeersResponse, NodeInfo,
     Payment, QueryRoutesResponse, Route,
 };
 use tonic_lnd::routerrpc::TrackPaymentRequest;
@@ -69,6 +73,20 @@ pub enum OfferError {
     IntroductionNodeNotFound,
     /// Cannot fetch channel info.
     GetChannelInfo(Status),
+    /// The payment would exceed one of the user's spending limits.
+    BudgetExceeded(SpendingLimit),
+    /// Failed to record the payment against the user's spending limits.
+    BudgetRecordFailure(BudgetError),
+    /// A previous attempt at this payment (with the same payment id) failed.
+    PreviousAttemptFailed(String),
+    /// The payment was cancelled before it was dispatched.
+    PaymentCancelled(PaymentId),
+    /// The payment can't be cancelled because we've already dispatched it.
+    AlreadyDispatched(PaymentId),
+    /// We don't know of an in-flight payment with this id.
+    PaymentNotFound(PaymentId),
+    /// The idempotency key was already used for a payment with different parameters.
+    IdempotencyKeyReused(PaymentId),
 }
 
 impl Display for OfferError {
@@ -98,6 +116,21 @@ impl Display for OfferError {
             OfferError::InvoiceTimeout(e) => write!(f, "Did not receive invoice in {e:?} seconds."),
             OfferError::IntroductionNodeNotFound => write!(f, "Could not find introduction node."),
             OfferError::GetChannelInfo(e) => write!(f, "Could not fetch channel info: {e:?}"),
+            OfferError::BudgetExceeded(limit) => write!(f, "Payment would exceed the {limit}"),
+            OfferError::BudgetRecordFailure(e) => write!(f, "Could not record payment: {e}"),
+            OfferError::PreviousAttemptFailed(e) => {
+                write!(f, "A previous attempt at this payment failed: {e}")
+            }
+            OfferError::PaymentCancelled(id) => write!(f, "Payment {id} was cancelled"),
+            OfferError::AlreadyDispatched(id) => write!(
+                f,
+                "Payment {id} has already been dispatched and can no longer be cancelled"
+            ),
+            OfferError::PaymentNotFound(id) => write!(f, "No in-flight payment with id {id}"),
+            OfferError::IdempotencyKeyReused(id) => write!(
+                f,
+                "The idempotency key for payment {id} was already used for a different payment"
+            ),
         }
     }
 }
@@ -109,7 +142,123 @@ pub fn decode(offer_str: String) -> Result<Offer, Bolt12ParseError> {
     offer_str.parse::<Offer>()
 }
 
+// Sets the parts of an invoice request that don't depend on how we pick the payer id.
+fn set_invoice_request_fields<'a, 'b, P: PayerIdStrategy, T: Signing>(
+    builder: InvoiceRequestBuilder<'a, 'b, P, T>,
+    network: Network,
+    msats: u64,
+    payer_note: Option<String>,
+) -> Result<InvoiceRequestBuilder<'a, 'b, P, T>, OfferError> {
+    let builder = builder
+        .chain(network)
+        .map_err(OfferError::BuildUIRFailure)?
+        .amount_msats(msats)
+        .map_err(OfferError::BuildUIRFailure)?;
+
+    Ok(match payer_note {
+        Some(payer_note_str) => builder.payer_note(payer_note_str),
+        None => builder,
+    })
+}
+
+// Tag used to separate payment ids derived from idempotency keys from any other use of the hash.
+const IDEMPOTENCY_KEY_TAG: &[u8] = b"lndk/idempotency_key";
+
+/// Derives a payment id from a client-provided idempotency key, so that requests retried with the
+/// same key map to the same payment.
+pub fn payment_id_from_idempotency_key(key: &str) -> PaymentId {
+    let mut engine = Hash::engine();
+    engine.input(IDEMPOTENCY_KEY_TAG);
+    engine.input(key.as_bytes());
+    PaymentId(Hash::from_engine(engine).to_byte_array())
+}
+
+/// PaymentParams are what a payment request asks us to pay, which a request that reuses an
+/// idempotency key has to match.
+pub enum PaymentParams<'a> {
+    Offer {
+        offer: &'a Offer,
+        amount: Option<u64>,
+        payer_note: Option<&'a str>,
+    },
+    Invoice {
+        invoice: &'a Bolt12Invoice,
+        amount: u64,
+    },
+}
+
+impl PaymentParams<'_> {
+    // Hashes the encoded offer or invoice along with the other fields, so that a payment hashes
+    // the same however the client encoded its offer or invoice.
+    fn hash(&self) -> Hash {
+        let mut engine = Hash::engine();
+        match self {
+            PaymentParams::Offer {
+                offer,
+                amount,
+                payer_note,
+            } => {
+                input_field(&mut engine, Some(&b"offer"[..]));
+                input_field(&mut engine, Some(offer.encode().as_slice()));
+                input_field(
+                    &mut engine,
+                    amount.map(u64::to_be_bytes).as_ref().map(|a| &a[..]),
+                );
+                input_field(&mut engine, payer_note.map(str::as_bytes));
+            }
+            PaymentParams::Invoice { invoice, amount } => {
+                input_field(&mut engine, Some(&b"invoice"[..]));
+                input_field(&mut engine, Some(invoice.encode().as_slice()));
+                input_field(&mut engine, Some(amount.to_be_bytes().as_slice()));
+            }
+        }
+        Hash::from_engine(engine)
+    }
+}
+
+// Adds an optional field to the hash, so that the fields (and whether they're set) can't run into
+// each other.
+fn input_field(engine: &mut bitcoin::hashes::sha256::HashEngine, field: Option<&[u8]>) {
+    match field {
+        Some(field) => {
+            engine.input(&[1]);
+            engine.input(&(field.len() as u64).to_be_bytes());
+            engine.input(field);
+        }
+        None => engine.input(&[0]),
+    }
+}
+
 impl OfferHandler {
+    /// Makes sure that an idempotency key is only used for one payment. We remember a hash of the
+    /// params of the first request that used the key (which maps to payment_id), and turn away
+    /// later requests with the same key but different params, rather than giving them the first
+    /// payment's result.
+    pub fn check_idempotency_key(
+        &self,
+        payment_id: PaymentId,
+        params: &PaymentParams,
+    ) -> Result<(), OfferError> {
+        let params_hash = params.hash();
+        let mut idempotency_keys = self.idempotency_keys.lock().unwrap();
+        // We only need to remember a key for as long as we remember its payment.
+        idempotency_keys.retain(|id, (_, first_used)| {
+            first_used.elapsed() < Duration::from_secs(PAYMENT_RESULT_EXPIRY_SECS)
+                || self.payment_status(*id).is_some()
+        });
+
+        match idempotency_keys.entry(payment_id) {
+            Entry::Occupied(entry) if entry.get().0 != params_hash => {
+                Err(OfferError::IdempotencyKeyReused(payment_id))
+            }
+            Entry::Occupied(_) => Ok(()),
+            Entry::Vacant(entry) => {
+                entry.insert((params_hash, Instant::now()));
+                Ok(())
+            }
+        }
+    }
+
     pub async fn send_invoice_request(
         &self,
         destination: Destination,
@@ -125,7 +274,8 @@ impl OfferHandler {
             Destination::BlindedPath(ref path) => match path.introduction_node {
                 IntroductionNode::NodeId(pubkey) => connect_to_peer(client.clone(), pubkey).await?,
                 IntroductionNode::DirectedShortChannelId(direction, scid) => {
-                    let pubkey = get_node_id(client.clone(), scid, direction).await?;
+                    let pubkey =
+                        get_node_id(client.clone(), &self.channel_cache, scid, direction).await?;
                     connect_to_peer(client.clone(), pubkey).await?
                 }
             },
@@ -147,7 +297,7 @@ impl OfferHandler {
             let reply_path_intro_node_id = match reply_path.introduction_node {
                 IntroductionNode::NodeId(pubkey) => pubkey.to_string(),
                 IntroductionNode::DirectedShortChannelId(direction, scid) => {
-                    get_node_id(client.clone(), scid, direction)
+                    get_node_id(client.clone(), &self.channel_cache, scid, direction)
                         .await?
                         .to_string()
                 }
@@ -181,59 +331,120 @@ impl OfferHandler {
         network: Network,
         msats: Option<u64>,
         payer_note: Option<String>,
+        payment_id: Option<PaymentId>,
     ) -> Result<(InvoiceRequest, PaymentId, u64), OfferError> {
         let validated_amount = validate_amount(offer.amount(), msats).await?;
 
-        // We use KeyFamily KeyFamilyNodeKey (3) to derive a key. For better privacy, the key
-        // shouldn't correspond to our node id.
-        // https://github.com/lightningnetwork/lnd/blob/a3f8011ed695f6204ec6a13ad5c2a67ac542b109/keychain/derivation.go#L86
-        let key_loc = KeyReq {
-            key_family: 3,
-            ..Default::default()
-        };
-
-        let key_descriptor = signer
-            .derive_next_key(key_loc.clone())
-            .await
-            .map_err(OfferError::DeriveKeyFailure)?;
-        let pubkey = PublicKey::from_slice(&key_descriptor.raw_key_bytes)
-            .expect("failed to deserialize public key");
-
-        // Generate a new payment id for this payment.
-        let payment_id = PaymentId(self.messenger_utils.get_secure_random_bytes());
+        // Use the payment id we were given, or generate a new one for this payment. If we're
+        // already paying with this id there's no need to go any further.
+        let payment_id =
+            payment_id.unwrap_or_else(|| PaymentId(self.messenger_utils.get_secure_random_bytes()));
+        if self
+            .active_payments
+            .lock()
+            .unwrap()
+            .contains_key(&payment_id)
+        {
+            return Err(OfferError::AlreadyProcessing(payment_id));
+        }
 
         // We need to add some metadata to the invoice request to help with verification of the
         // invoice once returned from the offer maker. Once we get an invoice back, this metadata
         // will help us to determine: 1) That the invoice is truly for the invoice request we sent.
         // 2) We don't pay duplicate invoices.
-        let builder: InvoiceRequestBuilder<'_, '_, ExplicitPayerId, SignOnly> = offer
-            .request_invoice_deriving_metadata(
-                pubkey,
-                &self.expanded_key,
-                &self.messenger_utils,
-                payment_id,
-            )
-            .map_err(OfferError::BuildUIRFailure)?
-            .chain(network)
-            .map_err(OfferError::BuildUIRFailure)?
-            .amount_msats(validated_amount)
-            .map_err(OfferError::BuildUIRFailure)?;
-
-        let builder = match payer_note {
-            Some(payer_note_str) => builder.payer_note(payer_note_str),
-            None => builder,
-        };
-
-        let unsigned_invoice_req = builder.build().map_err(OfferError::BuildUIRFailure)?;
+        let (invoice_request, payer_key) = match self.payer_key_strategy {
+            PayerKeyStrategy::Transient => {
+                // LDK derives the payer id from the invoice request's metadata, so it can sign the
+                // invoice request for us.
+                let secp_ctx = Secp256k1::signing_only();
+                let builder = offer
+                    .request_invoice_deriving_payer_id(
+                        &self.expanded_key,
+                        &self.messenger_utils,
+                        &secp_ctx,
+                        payment_id,
+                    )
+                    .map_err(OfferError::BuildUIRFailure)?;
+                let invoice_request =
+                    set_invoice_request_fields(builder, network, validated_amount, payer_note)?
+                        .build_and_sign()
+                        .map_err(OfferError::BuildUIRFailure)?;
+
+                (invoice_request, PayerKey::Transient)
+            }
+            PayerKeyStrategy::PerOffer(ref seed) => {
+                let secp_ctx = Secp256k1::signing_only();
+                let keys = seed.derive_key(&offer, &secp_ctx);
+                let builder: InvoiceRequestBuilder<'_, '_, ExplicitPayerId, SignOnly> = offer
+                    .request_invoice_deriving_metadata(
+                        keys.public_key(),
+                        &self.expanded_key,
+                        &self.messenger_utils,
+                        payment_id,
+                    )
+                    .map_err(OfferError::BuildUIRFailure)?;
+                let unsigned_invoice_req =
+                    set_invoice_request_fields(builder, network, validated_amount, payer_note)?
+                        .build()
+                        .map_err(OfferError::BuildUIRFailure)?;
+                let invoice_request = unsigned_invoice_req
+                    .sign(|message: &UnsignedInvoiceRequest| {
+                        Ok(secp_ctx.sign_schnorr_no_aux_rand(message.as_ref().as_digest(), &keys))
+                    })
+                    .map_err(OfferError::SignError)?;
+
+                (invoice_request, PayerKey::PerOffer)
+            }
+            PayerKeyStrategy::LndNextKey | PayerKeyStrategy::LndKey { .. } => {
+                let key_descriptor = match self.payer_key_strategy {
+                    PayerKeyStrategy::LndKey {
+                        key_family,
+                        key_index,
+                    } => {
+                        signer
+                            .derive_key(KeyLocator {
+                                key_family,
+                                key_index,
+                            })
+                            .await
+                    }
+                    _ => {
+                        signer
+                            .derive_next_key(KeyReq {
+                                key_family: NODE_KEY_FAMILY,
+                                ..Default::default()
+                            })
+                            .await
+                    }
+                }
+                .map_err(OfferError::DeriveKeyFailure)?;
+                let pubkey = PublicKey::from_slice(&key_descriptor.raw_key_bytes)
+                    .expect("failed to deserialize public key");
+                let key_loc = key_descriptor.key_loc.unwrap();
+
+                let builder: InvoiceRequestBuilder<'_, '_, ExplicitPayerId, SignOnly> = offer
+                    .request_invoice_deriving_metadata(
+                        pubkey,
+                        &self.expanded_key,
+                        &self.messenger_utils,
+                        payment_id,
+                    )
+                    .map_err(OfferError::BuildUIRFailure)?;
+                let unsigned_invoice_req =
+                    set_invoice_request_fields(builder, network, validated_amount, payer_note)?
+                        .build()
+                        .map_err(OfferError::BuildUIRFailure)?;
+
+                // To create a valid invoice request, we also need to sign it.
+                let payer_key = PayerKey::Lnd {
+                    key_family: key_loc.key_family,
+                    key_index: key_loc.key_index,
+                };
+                let invoice_request = signer.sign_uir(key_loc, unsigned_invoice_req).await?;
 
-        // To create a valid invoice request, we also need to sign it. This is spawned in a blocking
-        // task because we need to call block_on on sign_message so that sign_closure can be a
-        // synchronous closure.
-        let invoice_request = task::spawn_blocking(move || {
-            signer.sign_uir(key_descriptor.key_loc.unwrap(), unsigned_invoice_req)
-        })
-        .await
-        .unwrap()?;
+                (invoice_request, payer_key)
+            }
+        };
 
         {
             let mut active_payments = self.active_payments.lock().unwrap();
@@ -243,6 +454,9 @@ impl OfferHandler {
                     v.insert(crate::PaymentInfo {
                         state: PaymentState::InvoiceRequestCreated,
                         invoice: None,
+                        offer: Some(offer),
+                        invoice_request: Some(invoice_request.clone()),
+                        payer_key: Some(payer_key),
                     });
                 }
             };
@@ -277,41 +491,87 @@ impl OfferHandler {
             if onion_support {
                 // We also need to check that the candidate introduction node is actually an
                 // advertised node with at least one public channel.
-                match connector.get_node_info(peer.pub_key, true).await {
+                let channels = match connector.get_node_info(peer.pub_key, true).await {
                     Ok(node) => {
                         if node.channels.is_empty() {
                             continue;
                         }
+                        node.channels
                     }
                     Err(_) => continue,
                 };
-                intro_node = Some(pubkey);
+                intro_node = Some((pubkey, channels));
                 break;
             }
         }
 
         let secp_ctx = Secp256k1::new();
-        if intro_node.is_none() {
-            Ok(
+        match intro_node {
+            None => Ok(
                 BlindedPath::one_hop_for_message(node_id, &self.messenger_utils, &secp_ctx)
                     .map_err(|_| {
                         error!("Could not create blinded path.");
                         OfferError::BuildBlindedPathFailure
                     })?,
-            )
-        } else {
-            Ok(BlindedPath::new_for_message(
-                &[intro_node.unwrap(), node_id],
-                &self.messenger_utils,
-                &secp_ctx,
-            )
-            .map_err(|_| {
-                error!("Could not create blinded path.");
-                OfferError::BuildBlindedPathFailure
-            }))?
+            ),
+            Some((intro_node, channels)) => {
+                let mut path = BlindedPath::new_for_message(
+                    &[intro_node, node_id],
+                    &self.messenger_utils,
+                    &secp_ctx,
+                )
+                .map_err(|_| {
+                    error!("Could not create blinded path.");
+                    OfferError::BuildBlindedPathFailure
+                })?;
+
+                if self.compact_paths {
+                    match self.compact_introduction_node(intro_node, node_id, &channels) {
+                        Some(compact_intro_node) => path.introduction_node = compact_intro_node,
+                        None => debug!("No channel to compact introduction node {intro_node}."),
+                    }
+                }
+
+                Ok(path)
+            }
         }
     }
 
+    // compact_introduction_node picks one of the introduction node's public channels that senders
+    // can use to refer to it, in place of its full node id. We prefer a channel with us, since our
+    // own node id lookup can resolve it too. The channel is added to our cache so that we don't
+    // need to ask LND about it when we resolve the path later on.
+    fn compact_introduction_node(
+        &self,
+        intro_node: PublicKey,
+        our_node_id: PublicKey,
+        channels: &[ChannelEdge],
+    ) -> Option<IntroductionNode> {
+        let channels: Vec<(u64, ChannelNodes)> = channels
+            .iter()
+            .map(|channel| {
+                let nodes = ChannelNodes {
+                    node1_pub: channel.node1_pub.clone(),
+                    node2_pub: channel.node2_pub.clone(),
+                };
+                (channel.channel_id, nodes)
+            })
+            .collect();
+
+        let with_us = channels.iter().find(|(_, nodes)| {
+            nodes.node_id(Direction::NodeOne).ok() == Some(our_node_id)
+                || nodes.node_id(Direction::NodeTwo).ok() == Some(our_node_id)
+        });
+        let (scid, nodes) = with_us.or(channels.first())?;
+
+        let direction = [Direction::NodeOne, Direction::NodeTwo]
+            .into_iter()
+            .find(|direction| nodes.node_id(*direction).ok() == Some(intro_node))?;
+        self.channel_cache.insert(*scid, nodes.clone());
+
+        Some(IntroductionNode::DirectedShortChannelId(direction, *scid))
+    }
+
     /// send_payment tries to pay the provided invoice using LND.
     pub(crate) async fn send_payment(
         &self,
@@ -321,6 +581,7 @@ impl OfferHandler {
         let resp = payer
             .query_routes(
                 params.path,
+                params.introduction_node,
                 params.cltv_expiry_delta,
                 params.fee_base_msat,
                 params.fee_ppm,
@@ -329,34 +590,60 @@ impl OfferHandler {
             .await
             .map_err(OfferError::RouteFailure)?;
 
-        let _ = payer
-            .send_to_route(params.payment_hash, resp.routes[0].clone())
-            .await
-            .map_err(OfferError::RouteFailure)?;
-
+        // Before we dispatch any HTLCs, we need to make sure the payment (including routing fees)
+        // fits within the user's spending limits.
+        let route = resp.routes[0].clone();
+        let total_msats = u64::try_from(route.total_amt_msat)
+            .unwrap_or_default()
+            .max(params.msats);
+        self.budget
+            .reserve(params.payment_id, params.issuer, total_msats)
+            .map_err(|e| match e {
+                BudgetError::LimitExceeded(limit) => OfferError::BudgetExceeded(limit),
+                e => OfferError::BudgetRecordFailure(e),
+            })?;
+
+        // Once we mark the payment as dispatched it can no longer be cancelled, so we check
+        // whether it was cancelled in the meantime while holding the lock.
         {
             let mut active_payments = self.active_payments.lock().unwrap();
+            if self.is_cancelled(params.payment_id) {
+                self.budget.release(params.payment_id);
+                return Err(OfferError::PaymentCancelled(params.payment_id));
+            }
             active_payments
                 .entry(params.payment_id)
                 .and_modify(|entry| entry.state = PaymentState::PaymentDispatched);
         }
 
-        // We'll track the payment until it settles.
-        payer
-            .track_payment(params.payment_hash)
-            .await
-            .map_err(|_| OfferError::PaymentFailure)
+        if let Err(e) = payer.send_to_route(params.payment_hash, route).await {
+            self.budget.release(params.payment_id);
+            return Err(OfferError::RouteFailure(e));
+        }
+
+        // We'll track the payment until it settles. If the payment failed, it no longer counts
+        // towards our spending. If we just lost track of it, we have to assume it went through.
+        payer.track_payment(params.payment_hash).await.map_err(|e| {
+            if let OfferError::PaymentFailure = e {
+                self.budget.release(params.payment_id);
+            }
+            OfferError::PaymentFailure
+        })
     }
 }
 
 pub struct SendPaymentParams {
     pub path: BlindedPath,
+    /// The introduction node of the path, resolved if the path is a compact one.
+    pub introduction_node: PublicKey,
     pub cltv_expiry_delta: u16,
     pub fee_base_msat: u32,
     pub fee_ppm: u32,
     pub payment_hash: [u8; 32],
     pub msats: u64,
     pub payment_id: PaymentId,
+    /// The signing pubkey of the invoice we're paying, used to enforce per-issuer spending limits.
+    pub issuer: PublicKey,
 }
 
 /// Checks that the user-provided amount matches the provided offer or invoice.
@@ -365,7 +652,7 @@ pub struct SendPaymentParams {
 ///
 /// * `offer_amount_msats`: The amount set in the offer or invoice.
 /// * `amount_msats`: The amount we want to pay.
-pub(crate) async fn validate_amount(
+pub async fn validate_amount(
     offer_amount_msats: Option<&Amount>,
     pay_amount_msats: Option<u64>,
 ) -> Result<u64, OfferError> {
@@ -518,6 +805,13 @@ impl MessageSigner for Client {
         }
     }
 
+    async fn derive_key(&mut self, key_loc: KeyLocator) -> Result<KeyDescriptor, Status> {
+        match self.wallet().derive_key(key_loc).await {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(e),
+        }
+    }
+
     async fn sign_message(
         &mut self,
         key_loc: KeyLocator,
@@ -539,47 +833,30 @@ impl MessageSigner for Client {
         Ok(resp_inner.signature)
     }
 
-    fn sign_uir(
+    async fn sign_uir(
         &mut self,
         key_loc: KeyLocator,
         unsigned_invoice_req: UnsignedInvoiceRequest,
     ) -> Result<InvoiceRequest, OfferError> {
-        let signer = LndkSigner {
-            client: self.clone(),
-            key_loc,
-        };
-        match unsigned_invoice_req.sign(signer) {
-            Ok(signed_invoice) => Ok(signed_invoice),
-            Err(_) => Err(OfferError::SignError(SignError::Signing)),
-        }
-    }
-}
-
-struct LndkSigner {
-    client: Client,
-    key_loc: KeyLocator,
-}
-
-impl SignInvoiceRequestFn for LndkSigner {
-    fn sign_invoice_request(&self, msg: &UnsignedInvoiceRequest) -> Result<Signature, ()> {
-        let tagged_hash = msg.as_ref();
+        // We ask LND to sign the invoice request's merkle root up front, so that we can hand the
+        // signature to LDK's synchronous signing function without blocking on LND.
+        let tagged_hash = unsigned_invoice_req.as_ref();
+        let merkle_root = tagged_hash.merkle_root();
         let tag = tagged_hash.tag().to_string();
-
-        let mut signer = self.client.clone();
-        let signature = match block_on(signer.sign_message(
-            self.key_loc.clone(),
-            tagged_hash.merkle_root(),
-            tag,
-        )) {
-            Ok(sig) => sig,
-            Err(_e) => return Err(()),
-        };
-
-        let ret = match Signature::from_slice(&signature) {
-            Ok(s) => s,
-            Err(_) => return Err(()),
-        };
-        Ok(ret)
+        let signature = self
+            .sign_message(key_loc, merkle_root, tag)
+            .await
+            .map_err(|e| {
+                error!("Error signing invoice request: {e}.");
+                OfferError::SignError(SignError::Signing)
+            })?;
+        let signature = Signature::from_slice(&signature)
+            .map_err(|_| OfferError::SignError(SignError::Signing))?;
+
+        // LDK checks that the signature is valid for the invoice request's payer id.
+        unsigned_invoice_req
+            .sign(|_: &UnsignedInvoiceRequest| Ok(signature))
+            .map_err(OfferError::SignError)
     }
 }
 
@@ -588,6 +865,7 @@ impl InvoicePayer for Client {
     async fn query_routes(
         &mut self,
         path: BlindedPath,
+        introduction_node: PublicKey,
         cltv_expiry_delta: u16,
         fee_base_msat: u32,
         fee_ppm: u32,
@@ -602,18 +880,6 @@ impl InvoicePayer for Client {
             blinded_hops.push(new_hop);
         }
 
-        let introduction_node = match path.introduction_node {
-            IntroductionNode::NodeId(pubkey) => pubkey,
-            IntroductionNode::DirectedShortChannelId(direction, scid) => {
-                match get_node_id(self.clone(), scid, direction).await {
-                    Ok(pubkey) => pubkey,
-                    Err(e) => {
-                        error!("{e}");
-                        return Err(Status::unknown("Could not get node id."));
-                    }
-                }
-            }
-        };
         let blinded_path = Some(tonic_lnd::lnrpc::BlindedPath {
             introduction_node: introduction_node.serialize().to_vec(),
             blinding_point: path.blinding_point.serialize().to_vec(),
@@ -681,24 +947,19 @@ impl InvoicePayer for Client {
     }
 }
 
-// get_node_id finds an introduction node from the scid and direction provided by a blinded path.
+// get_node_id finds an introduction node from the scid and direction provided by a blinded path,
+// only asking LND about the channel if it isn't in our cache.
 pub(crate) async fn get_node_id(
     client: Client,
+    channel_cache: &ChannelCache,
     scid: u64,
     direction: Direction,
 ) -> Result<PublicKey, OfferError> {
-    let get_info_request = ChanInfoRequest { chan_id: scid };
-    let channel_info = client
-        .lightning_read_only()
-        .get_chan_info(get_info_request)
+    let channel_nodes = channel_cache
+        .lookup(client, scid)
         .await
-        .map_err(OfferError::GetChannelInfo)?
-        .into_inner();
-    let pubkey = match direction {
-        Direction::NodeOne => channel_info.node1_pub,
-        Direction::NodeTwo => channel_info.node2_pub,
-    };
-    PublicKey::from_slice(pubkey.as_bytes()).map_err(|e| {
+        .map_err(OfferError::GetChannelInfo)?;
+    channel_nodes.node_id(direction).map_err(|e| {
         error!("Could not parse pubkey. {e}");
         OfferError::IntroductionNodeNotFound
     })
@@ -707,8 +968,12 @@ pub(crate) async fn get_node_id(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::MessengerUtilities;
+    use crate::budget::{Budget, SpendingLimits};
+    use crate::payer_key::PayerSeed;
+    use crate::sim::fixtures::{build_invoice, build_invoice_request, build_offer};
+    use crate::{MessengerUtilities, PaymentMetrics, PaymentResult, PaymentStatus};
     use bitcoin::secp256k1::{KeyPair, Secp256k1, SecretKey};
+    use lightning::ln::PaymentHash;
     use lightning::offers::merkle::SignError;
     use lightning::offers::offer::{OfferBuilder, Quantity};
     use mockall::mock;
@@ -716,7 +981,7 @@ mod tests {
     use std::collections::HashMap;
     use std::str::FromStr;
     use std::time::{Duration, SystemTime};
-    use tonic_lnd::lnrpc::{ChannelEdge, LightningNode, NodeAddress, Payment};
+    use tonic_lnd::lnrpc::{LightningNode, NodeAddress, Payment};
 
     fn get_offer() -> String {
         "lno1qgsqvgnwgcg35z6ee2h3yczraddm72xrfua9uve2rlrm9deu7xyfzrcgqgn3qzsyvfkx26qkyypvr5hfx60h9w9k934lt8s2n6zc0wwtgqlulw7dythr83dqx8tzumg".to_string()
@@ -782,8 +1047,9 @@ mod tests {
          #[async_trait]
          impl MessageSigner for TestBolt12Signer {
              async fn derive_next_key(&mut self, key_req: KeyReq) -> Result<KeyDescriptor, Status>;
+             async fn derive_key(&mut self, key_loc: KeyLocator) -> Result<KeyDescriptor, Status>;
              async fn sign_message(&mut self, key_loc: KeyLocator, merkle_hash: Hash, tag: String) -> Result<Vec<u8>, Status>;
-             fn sign_uir(&mut self, key_loc: KeyLocator, unsigned_invoice_req: UnsignedInvoiceRequest) -> Result<InvoiceRequest, OfferError>;
+             async fn sign_uir(&mut self, key_loc: KeyLocator, unsigned_invoice_req: UnsignedInvoiceRequest) -> Result<InvoiceRequest, OfferError>;
          }
     }
 
@@ -803,7 +1069,7 @@ mod tests {
 
         #[async_trait]
         impl InvoicePayer for TestInvoicePayer{
-            async fn query_routes(&mut self, path: BlindedPath, cltv_expiry_delta: u16, fee_base_msat: u32, fee_ppm: u32, msats: u64) -> Result<QueryRoutesResponse, Status>;
+            async fn query_routes(&mut self, path: BlindedPath, introduction_node: PublicKey, cltv_expiry_delta: u16, fee_base_msat: u32, fee_ppm: u32, msats: u64) -> Result<QueryRoutesResponse, Status>;
             async fn send_to_route(&mut self, payment_hash: [u8; 32], route: Route) -> Result<HtlcAttempt, Status>;
             async fn track_payment(&mut self, payment_hash: [u8; 32]) -> Result<Payment, OfferError>;
         }
@@ -846,11 +1112,308 @@ mod tests {
                 Network::Regtest,
                 Some(amount),
                 Some("".to_string()),
+                None,
             )
             .await;
         assert!(resp.is_ok())
     }
 
+    #[tokio::test]
+    async fn test_request_invoice_transient_key() {
+        // We shouldn't need LND to derive a key or sign the invoice request.
+        let mut signer_mock = MockTestBolt12Signer::new();
+        signer_mock.expect_derive_next_key().never();
+        signer_mock.expect_derive_key().never();
+        signer_mock.expect_sign_uir().never();
+
+        let offer = decode(get_offer()).unwrap();
+        let handler = OfferHandler::default().with_payer_key_strategy(PayerKeyStrategy::Transient);
+        let (invoice_request, payment_id, _) = handler
+            .create_invoice_request(signer_mock, offer, Network::Regtest, None, None, None)
+            .await
+            .unwrap();
+        assert!(InvoiceRequest::try_from(invoice_request.encode()).is_ok());
+
+        let active_payments = handler.active_payments.lock().unwrap();
+        assert_eq!(
+            active_payments.get(&payment_id).unwrap().payer_key,
+            Some(PayerKey::Transient)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_invoice_per_offer_key() {
+        let seed = PayerSeed::new([1; 32]);
+        let offer = decode(get_offer()).unwrap();
+        let expected_payer_id = seed.derive_key(&offer, &Secp256k1::new()).public_key();
+        let handler =
+            OfferHandler::default().with_payer_key_strategy(PayerKeyStrategy::PerOffer(seed));
+
+        // Each request for the same offer is signed by the same key.
+        for _ in 0..2 {
+            let mut signer_mock = MockTestBolt12Signer::new();
+            signer_mock.expect_derive_next_key().never();
+            signer_mock.expect_sign_uir().never();
+
+            let (invoice_request, payment_id, _) = handler
+                .create_invoice_request(
+                    signer_mock,
+                    offer.clone(),
+                    Network::Regtest,
+                    None,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(invoice_request.payer_id(), expected_payer_id);
+            assert!(InvoiceRequest::try_from(invoice_request.encode()).is_ok());
+
+            let active_payments = handler.active_payments.lock().unwrap();
+            assert_eq!(
+                active_payments.get(&payment_id).unwrap().payer_key,
+                Some(PayerKey::PerOffer)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_invoice_lnd_key() {
+        let mut signer_mock = MockTestBolt12Signer::new();
+        signer_mock.expect_derive_next_key().never();
+        signer_mock
+            .expect_derive_key()
+            .withf(|key_loc| key_loc.key_family == 6 && key_loc.key_index == 2)
+            .returning(|key_loc| {
+                Ok(KeyDescriptor {
+                    raw_key_bytes: PublicKey::from_str(&get_pubkeys()[0])
+                        .unwrap()
+                        .serialize()
+                        .to_vec(),
+                    key_loc: Some(key_loc),
+                })
+            });
+
+        let offer = decode(get_offer()).unwrap();
+        let amount = match offer.amount().unwrap() {
+            Amount::Bitcoin { amount_msats } => *amount_msats,
+            _ => panic!("unexpected amount type"),
+        };
+        let request_offer = offer.clone();
+        signer_mock
+            .expect_sign_uir()
+            .returning(move |_, _| Ok(get_invoice_request(request_offer.clone(), amount)));
+
+        let handler = OfferHandler::default().with_payer_key_strategy(PayerKeyStrategy::LndKey {
+            key_family: 6,
+            key_index: 2,
+        });
+        let (_, payment_id, _) = handler
+            .create_invoice_request(signer_mock, offer, Network::Regtest, None, None, None)
+            .await
+            .unwrap();
+
+        let active_payments = handler.active_payments.lock().unwrap();
+        assert_eq!(
+            active_payments.get(&payment_id).unwrap().payer_key,
+            Some(PayerKey::Lnd {
+                key_family: 6,
+                key_index: 2
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_invoice_with_payment_id() {
+        let mut signer_mock = MockTestBolt12Signer::new();
+
+        signer_mock.expect_derive_next_key().returning(|_| {
+            Ok(KeyDescriptor {
+                raw_key_bytes: PublicKey::from_str(&get_pubkeys()[0])
+                    .unwrap()
+                    .serialize()
+                    .to_vec(),
+                key_loc: Some(KeyLocator {
+                    key_family: 3,
+                    ..Default::default()
+                }),
+            })
+        });
+
+        signer_mock
+            .expect_sign_uir()
+            .returning(move |_, _| Ok(get_invoice_request(decode(get_offer()).unwrap(), 10000)));
+
+        let offer = decode(get_offer()).unwrap();
+        let handler = OfferHandler::default();
+        let payment_id = payment_id_from_idempotency_key("test-key");
+        let (_, resp_id, _) = handler
+            .create_invoice_request(
+                signer_mock,
+                offer.clone(),
+                Network::Regtest,
+                Some(10000),
+                None,
+                Some(payment_id),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp_id, payment_id);
+
+        // A second request with the same payment id shouldn't get as far as deriving a key.
+        let resp = handler
+            .create_invoice_request(
+                MockTestBolt12Signer::new(),
+                offer,
+                Network::Regtest,
+                Some(10000),
+                None,
+                Some(payment_id),
+            )
+            .await;
+        assert!(matches!(resp, Err(OfferError::AlreadyProcessing(id)) if id == payment_id));
+    }
+
+    #[tokio::test]
+    async fn test_payment_status() {
+        let mut signer_mock = MockTestBolt12Signer::new();
+
+        signer_mock.expect_derive_next_key().returning(|_| {
+            Ok(KeyDescriptor {
+                raw_key_bytes: PublicKey::from_str(&get_pubkeys()[0])
+                    .unwrap()
+                    .serialize()
+                    .to_vec(),
+                key_loc: Some(KeyLocator {
+                    key_family: 3,
+                    ..Default::default()
+                }),
+            })
+        });
+
+        signer_mock
+            .expect_sign_uir()
+            .returning(move |_, _| Ok(get_invoice_request(decode(get_offer()).unwrap(), 10000)));
+
+        let handler = OfferHandler::default();
+        let payment_id = payment_id_from_idempotency_key("status-key");
+        assert!(handler.payment_status(payment_id).is_none());
+
+        handler
+            .create_invoice_request(
+                signer_mock,
+                decode(get_offer()).unwrap(),
+                Network::Regtest,
+                Some(10000),
+                None,
+                Some(payment_id),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler.payment_status(payment_id),
+            Some(PaymentStatus::InProgress(
+                PaymentState::InvoiceRequestCreated
+            ))
+        ));
+        assert_eq!(
+            handler.metrics(),
+            PaymentMetrics {
+                attempted: 1,
+                in_flight: 1,
+                ..Default::default()
+            }
+        );
+
+        handler.active_payments.lock().unwrap().remove(&payment_id);
+        assert!(handler
+            .complete_payment(payment_id, Err(OfferError::PaymentFailure))
+            .await
+            .is_err());
+        assert!(matches!(
+            handler.payment_status(payment_id),
+            Some(PaymentStatus::Completed(PaymentResult::Failed(_)))
+        ));
+        assert_eq!(
+            handler.metrics(),
+            PaymentMetrics {
+                attempted: 1,
+                failed: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_payment_id_from_idempotency_key() {
+        assert_eq!(
+            payment_id_from_idempotency_key("key-1"),
+            payment_id_from_idempotency_key("key-1")
+        );
+        assert_ne!(
+            payment_id_from_idempotency_key("key-1"),
+            payment_id_from_idempotency_key("key-2")
+        );
+    }
+
+    #[test]
+    fn test_check_idempotency_key() {
+        let handler = OfferHandler::default();
+        let offer = build_offer("coffee");
+        let offer_params = |amount, payer_note| PaymentParams::Offer {
+            offer: &offer,
+            amount,
+            payer_note,
+        };
+        let payment_id = payment_id_from_idempotency_key("key-1");
+
+        assert!(handler
+            .check_idempotency_key(payment_id, &offer_params(Some(1000), None))
+            .is_ok());
+        // Retrying the same request is fine, but the key can't be used for another payment.
+        assert!(handler
+            .check_idempotency_key(payment_id, &offer_params(Some(1000), None))
+            .is_ok());
+        for params in [
+            offer_params(Some(2000), None),
+            offer_params(None, None),
+            offer_params(Some(1000), Some("")),
+            PaymentParams::Offer {
+                offer: &build_offer("tea"),
+                amount: Some(1000),
+                payer_note: None,
+            },
+        ] {
+            assert!(matches!(
+                handler.check_idempotency_key(payment_id, &params),
+                Err(OfferError::IdempotencyKeyReused(id)) if id == payment_id
+            ));
+        }
+        assert!(handler
+            .check_idempotency_key(
+                payment_id_from_idempotency_key("key-2"),
+                &offer_params(Some(2000), None)
+            )
+            .is_ok());
+
+        // An invoice is identified by its contents and the amount we pay.
+        let invoice = build_invoice(&build_invoice_request(&offer), PaymentHash([1; 32]));
+        let payment_id = payment_id_from_idempotency_key("key-3");
+        let invoice_params = |amount| PaymentParams::Invoice {
+            invoice: &invoice,
+            amount,
+        };
+        assert!(handler
+            .check_idempotency_key(payment_id, &invoice_params(1000))
+            .is_ok());
+        assert!(handler
+            .check_idempotency_key(payment_id, &invoice_params(1000))
+            .is_ok());
+        assert!(handler
+            .check_idempotency_key(payment_id, &invoice_params(2000))
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_request_invoice_derive_key_error() {
         let mut signer_mock = MockTestBolt12Signer::new();
@@ -871,7 +1434,8 @@ mod tests {
                 offer,
                 Network::Regtest,
                 Some(10000),
-                Some("".to_string())
+                Some("".to_string()),
+                None,
             )
             .await
             .is_err())
@@ -906,7 +1470,8 @@ mod tests {
                 offer,
                 Network::Regtest,
                 Some(10000),
-                Some("".to_string())
+                Some("".to_string()),
+                None,
             )
             .await
             .is_err())
@@ -1201,19 +1766,101 @@ mod tests {
         assert!(resp.unwrap().blinded_hops.len() == 2);
     }
 
+    #[tokio::test]
+    async fn test_create_reply_path_compact() {
+        let keys = get_pubkeys();
+        let our_node_id = PublicKey::from_str(&keys[0]).unwrap();
+        let intro_node = PublicKey::from_str(&keys[1]).unwrap();
+        let secp_ctx = Secp256k1::new();
+        let other_node =
+            PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[7; 32]).unwrap());
+
+        let connector = || {
+            let mut connector_mock = MockTestPeerConnector::new();
+            connector_mock.expect_list_peers().returning(|| {
+                let mut feature_entry = HashMap::new();
+                feature_entry.insert(38, tonic_lnd::lnrpc::Feature::default());
+                let peer = tonic_lnd::lnrpc::Peer {
+                    pub_key: get_pubkeys()[1].clone(),
+                    features: feature_entry,
+                    ..Default::default()
+                };
+                Ok(ListPeersResponse { peers: vec![peer] })
+            });
+
+            // The introduction node has a channel with another node, and one with us, where it's
+            // the second node.
+            connector_mock
+                .expect_get_node_info()
+                .returning(move |_, _| {
+                    Ok(NodeInfo {
+                        node: Some(LightningNode::default()),
+                        channels: vec![
+                            ChannelEdge {
+                                channel_id: 1,
+                                node1_pub: other_node.to_string(),
+                                node2_pub: get_pubkeys()[1].clone(),
+                                ..Default::default()
+                            },
+                            ChannelEdge {
+                                channel_id: 2,
+                                node1_pub: get_pubkeys()[0].clone(),
+                                node2_pub: get_pubkeys()[1].clone(),
+                                ..Default::default()
+                            },
+                        ],
+                        ..Default::default()
+                    })
+                });
+            connector_mock
+        };
+
+        // By default, the introduction node is named by its node id.
+        let handler = OfferHandler::default();
+        let path = handler
+            .create_reply_path(connector(), our_node_id)
+            .await
+            .unwrap();
+        assert_eq!(path.introduction_node, IntroductionNode::NodeId(intro_node));
+
+        // With compact paths, it's named by our channel with it instead, which we can then resolve
+        // without asking LND.
+        let handler = OfferHandler::default().with_compact_paths(true);
+        let path = handler
+            .create_reply_path(connector(), our_node_id)
+            .await
+            .unwrap();
+        assert_eq!(path.blinded_hops.len(), 2);
+        assert_eq!(
+            path.introduction_node,
+            IntroductionNode::DirectedShortChannelId(Direction::NodeTwo, 2)
+        );
+        assert_eq!(
+            handler
+                .channel_cache
+                .get(2)
+                .unwrap()
+                .node_id(Direction::NodeTwo)
+                .unwrap(),
+            intro_node
+        );
+    }
+
     #[tokio::test]
     async fn test_send_payment() {
         let mut payer_mock = MockTestInvoicePayer::new();
 
-        payer_mock.expect_query_routes().returning(|_, _, _, _, _| {
-            let route = Route {
-                ..Default::default()
-            };
-            Ok(QueryRoutesResponse {
-                routes: vec![route],
-                ..Default::default()
-            })
-        });
+        payer_mock
+            .expect_query_routes()
+            .returning(|_, _, _, _, _, _| {
+                let route = Route {
+                    ..Default::default()
+                };
+                Ok(QueryRoutesResponse {
+                    routes: vec![route],
+                    ..Default::default()
+                })
+            });
 
         payer_mock.expect_send_to_route().returning(|_, _| {
             Ok(HtlcAttempt {
@@ -1233,23 +1880,93 @@ mod tests {
         let payment_id = PaymentId(MessengerUtilities::new().get_secure_random_bytes());
         let params = SendPaymentParams {
             path: blinded_path,
+            introduction_node: PublicKey::from_str(&get_pubkeys()[0]).unwrap(),
             cltv_expiry_delta: 200,
             fee_base_msat: 1,
             fee_ppm: 0,
             payment_hash: payment_hash,
             msats: 2000,
             payment_id,
+            issuer: PublicKey::from_str(&get_pubkeys()[1]).unwrap(),
         };
         assert!(handler.send_payment(payer_mock, params).await.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_cancel_payment() {
+        let handler = OfferHandler::default();
+        let payment_id = PaymentId(MessengerUtilities::new().get_secure_random_bytes());
+        handler.active_payments.lock().unwrap().insert(
+            payment_id,
+            crate::PaymentInfo {
+                state: PaymentState::InvoiceReceived,
+                invoice: None,
+                offer: None,
+                invoice_request: None,
+                payer_key: None,
+            },
+        );
+
+        assert!(handler.cancel_payment(payment_id).is_ok());
+        assert!(matches!(
+            handler.cancel_payment(payment_id),
+            Err(OfferError::PaymentNotFound(_))
+        ));
+        assert_eq!(handler.metrics().cancelled, 1);
+
+        // If we try to pay after the payment was cancelled, we shouldn't dispatch any HTLCs.
+        let mut payer_mock = MockTestInvoicePayer::new();
+        payer_mock
+            .expect_query_routes()
+            .returning(|_, _, _, _, _, _| {
+                Ok(QueryRoutesResponse {
+                    routes: vec![Route::default()],
+                    ..Default::default()
+                })
+            });
+        payer_mock.expect_send_to_route().never();
+
+        let params = SendPaymentParams {
+            path: get_blinded_path(),
+            introduction_node: PublicKey::from_str(&get_pubkeys()[0]).unwrap(),
+            cltv_expiry_delta: 200,
+            fee_base_msat: 1,
+            fee_ppm: 0,
+            payment_hash: MessengerUtilities::new().get_secure_random_bytes(),
+            msats: 2000,
+            payment_id,
+            issuer: PublicKey::from_str(&get_pubkeys()[1]).unwrap(),
+        };
+        assert!(matches!(
+            handler.send_payment(payer_mock, params).await,
+            Err(OfferError::PaymentCancelled(_))
+        ));
+
+        // Once a payment is dispatched, it's too late to cancel it.
+        let dispatched_id = PaymentId(MessengerUtilities::new().get_secure_random_bytes());
+        handler.active_payments.lock().unwrap().insert(
+            dispatched_id,
+            crate::PaymentInfo {
+                state: PaymentState::PaymentDispatched,
+                invoice: None,
+                offer: None,
+                invoice_request: None,
+                payer_key: None,
+            },
+        );
+        assert!(matches!(
+            handler.cancel_payment(dispatched_id),
+            Err(OfferError::AlreadyDispatched(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_send_payment_query_error() {
         let mut payer_mock = MockTestInvoicePayer::new();
 
         payer_mock
             .expect_query_routes()
-            .returning(|_, _, _, _, _| Err(Status::unknown("unknown error")));
+            .returning(|_, _, _, _, _, _| Err(Status::unknown("unknown error")));
 
         let blinded_path = get_blinded_path();
         let payment_hash = MessengerUtilities::new().get_secure_random_bytes();
@@ -1257,12 +1974,14 @@ mod tests {
         let handler = OfferHandler::default();
         let params = SendPaymentParams {
             path: blinded_path,
+            introduction_node: PublicKey::from_str(&get_pubkeys()[0]).unwrap(),
             cltv_expiry_delta: 200,
             fee_base_msat: 1,
             fee_ppm: 0,
             payment_hash: payment_hash,
             msats: 2000,
             payment_id,
+            issuer: PublicKey::from_str(&get_pubkeys()[1]).unwrap(),
         };
         assert!(handler.send_payment(payer_mock, params).await.is_err());
     }
@@ -1271,15 +1990,17 @@ mod tests {
     async fn test_send_payment_send_error() {
         let mut payer_mock = MockTestInvoicePayer::new();
 
-        payer_mock.expect_query_routes().returning(|_, _, _, _, _| {
-            let route = Route {
-                ..Default::default()
-            };
-            Ok(QueryRoutesResponse {
-                routes: vec![route],
-                ..Default::default()
-            })
-        });
+        payer_mock
+            .expect_query_routes()
+            .returning(|_, _, _, _, _, _| {
+                let route = Route {
+                    ..Default::default()
+                };
+                Ok(QueryRoutesResponse {
+                    routes: vec![route],
+                    ..Default::default()
+                })
+            });
 
         payer_mock
             .expect_send_to_route()
@@ -1291,13 +2012,60 @@ mod tests {
         let handler = OfferHandler::default();
         let params = SendPaymentParams {
             path: blinded_path,
+            introduction_node: PublicKey::from_str(&get_pubkeys()[0]).unwrap(),
             cltv_expiry_delta: 200,
             fee_base_msat: 1,
             fee_ppm: 0,
             payment_hash: payment_hash,
             msats: 2000,
             payment_id,
+            issuer: PublicKey::from_str(&get_pubkeys()[1]).unwrap(),
         };
         assert!(handler.send_payment(payer_mock, params).await.is_err());
     }
+
+    #[tokio::test]
+    async fn test_send_payment_budget_exceeded() {
+        let mut payer_mock = MockTestInvoicePayer::new();
+
+        payer_mock
+            .expect_query_routes()
+            .returning(|_, _, _, _, _, _| {
+                let route = Route {
+                    total_amt_msat: 2001,
+                    ..Default::default()
+                };
+                Ok(QueryRoutesResponse {
+                    routes: vec![route],
+                    ..Default::default()
+                })
+            });
+
+        // We shouldn't try to dispatch the payment at all if it goes over budget, so we don't set
+        // any expectations for send_to_route.
+        let limits = SpendingLimits {
+            max_payment_msats: Some(2000),
+            ..Default::default()
+        };
+        let handler = OfferHandler::default().with_budget(Budget::new(limits, None).unwrap());
+
+        let blinded_path = get_blinded_path();
+        let payment_hash = MessengerUtilities::new().get_secure_random_bytes();
+        let payment_id = PaymentId(MessengerUtilities::new().get_secure_random_bytes());
+        let params = SendPaymentParams {
+            path: blinded_path,
+            introduction_node: PublicKey::from_str(&get_pubkeys()[0]).unwrap(),
+            cltv_expiry_delta: 200,
+            fee_base_msat: 1,
+            fee_ppm: 0,
+            payment_hash: payment_hash,
+            msats: 2000,
+            payment_id,
+            issuer: PublicKey::from_str(&get_pubkeys()[1]).unwrap(),
+        };
+        assert!(matches!(
+            handler.send_payment(payer_mock, params).await,
+            Err(OfferError::BudgetExceeded(SpendingLimit::Payment(2000)))
+        ));
+    }
 }
@@ -0,0 +1,335 @@
+use crate::onion_messenger::MessengerUtilities;
+use bitcoin::hashes::sha256::Hash;
+use bitcoin::hashes::Hash as _;
+use bitcoin::secp256k1::PublicKey;
+use lightning::ln::channelmanager::PaymentId;
+use lightning::sign::EntropySource;
+use log::Record;
+use log4rs::encode::json::JsonEncoder;
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::{Encode, Write};
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+tokio::task_local! {
+    static CORRELATION: Correlation;
+}
+
+// Whether sensitive values are left out of our logs, see set_redact_logs.
+static REDACT_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether LNDK leaves sensitive data, such as payer notes, offers, invoices (with their
+/// preimages and blinded paths) and onion messages, out of its logs. This is on by default on
+/// mainnet, so that logs can be shipped elsewhere without leaking who we're paying.
+pub fn set_redact_logs(redact: bool) {
+    REDACT_LOGS.store(redact, Ordering::Relaxed);
+}
+
+/// Returns whether sensitive data is left out of LNDK's logs.
+pub fn redact_logs() -> bool {
+    REDACT_LOGS.load(Ordering::Relaxed)
+}
+
+/// Sensitive wraps a value that may hold private data so that it can be logged. If logs are
+/// redacted, only a short hash of the value is written, so that lines about the same value can
+/// still be matched up.
+pub struct Sensitive<'a, T: ?Sized>(pub &'a T);
+
+impl<T: Debug + ?Sized> Sensitive<'_, T> {
+    // Writes the value, or only a short hash of it if redact is set.
+    fn fmt_redacted(&self, f: &mut std::fmt::Formatter<'_>, redact: bool) -> std::fmt::Result {
+        if !redact {
+            return self.0.fmt(f);
+        }
+
+        let hash = Hash::hash(format!("{:?}", self.0).as_bytes());
+        write!(f, "<redacted {}>", hex::encode(&hash.to_byte_array()[..8]))
+    }
+}
+
+impl<T: Debug + ?Sized> Debug for Sensitive<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_redacted(f, redact_logs())
+    }
+}
+
+/// LogFormat is the format LNDK writes its log lines in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines, e.g. "2024-05-01T12:00:00Z - [payment_id=..] Received an invoice".
+    #[default]
+    Plain,
+    /// One JSON object per line, for log collectors. Correlation ids are written to the object's
+    /// "mdc" field.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format '{s}', expected either 'plain' or 'json'"
+            )),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Plain => write!(f, "plain"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// LogRotation limits how much space LNDK's log file takes up. Once the file reaches max_size_bytes
+/// it's moved to lndk.log.0, with older logs shifted along to lndk.log.1 and so on, keeping at
+/// most max_files of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogRotation {
+    pub max_size_bytes: u64,
+    pub max_files: u32,
+}
+
+/// LogOptions are the settings for LNDK's logger beyond its level and file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LogOptions {
+    pub format: LogFormat,
+    /// If unset, the log file grows without limit.
+    pub rotation: Option<LogRotation>,
+}
+
+/// Correlation identifies what a log line is about, so that the lines for a single payment or
+/// request can be pulled out of the log. It's attached to every line logged from within
+/// Correlation::scope.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Correlation {
+    /// Identifies the gRPC or REST request we're handling.
+    pub request_id: Option<String>,
+    /// The payment we're making.
+    pub payment_id: Option<PaymentId>,
+    /// The peer that sent us the onion message we're handling.
+    pub peer: Option<PublicKey>,
+}
+
+impl Correlation {
+    /// Returns the correlation of the code we're running in, which is empty outside of any scope.
+    pub fn current() -> Self {
+        CORRELATION
+            .try_with(|correlation| correlation.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns a correlation for a new API request, with a random request id.
+    pub fn new_request() -> Self {
+        let bytes = MessengerUtilities::new().get_secure_random_bytes();
+        Correlation::current().with_request_id(hex::encode(&bytes[..8]))
+    }
+
+    pub fn with_request_id(mut self, request_id: String) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    pub fn with_payment_id(mut self, payment_id: PaymentId) -> Self {
+        self.payment_id = Some(payment_id);
+        self
+    }
+
+    pub fn with_peer(mut self, peer: PublicKey) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Runs the future with this correlation attached to its log lines. Since tokio tasks don't
+    /// inherit it, futures that are spawned need to be scoped too.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CORRELATION.scope(self, f).await
+    }
+
+    /// Runs the closure with this correlation attached to its log lines.
+    pub fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        CORRELATION.sync_scope(self, f)
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        if let Some(ref request_id) = self.request_id {
+            fields.push(("request_id", request_id.clone()));
+        }
+        if let Some(payment_id) = self.payment_id {
+            fields.push(("payment_id", hex::encode(payment_id.0)));
+        }
+        if let Some(peer) = self.peer {
+            fields.push(("peer", peer.to_string()));
+        }
+        fields
+    }
+}
+
+/// CorrelatedEncoder writes log lines in the configured format, tagged with the correlation of the
+/// code that logged them.
+#[derive(Debug)]
+pub(crate) struct CorrelatedEncoder {
+    format: LogFormat,
+    inner: Box<dyn Encode>,
+}
+
+impl CorrelatedEncoder {
+    /// Creates an encoder that writes plain lines with the given log4rs pattern, or JSON.
+    pub(crate) fn new(format: LogFormat, pattern: &str) -> Self {
+        let inner: Box<dyn Encode> = match format {
+            LogFormat::Plain => Box::new(PatternEncoder::new(pattern)),
+            LogFormat::Json => Box::new(JsonEncoder::new()),
+        };
+        CorrelatedEncoder { format, inner }
+    }
+}
+
+impl Encode for CorrelatedEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+        let fields = Correlation::current().fields();
+        if fields.is_empty() {
+            return self.inner.encode(w, record);
+        }
+
+        match self.format {
+            // The JSON encoder writes out log4rs's mapped diagnostic context, which is thread
+            // local. We only set it while encoding this record, since the tasks that log can move
+            // between threads.
+            LogFormat::Json => {
+                let _guards: Vec<_> = fields
+                    .into_iter()
+                    .map(|(key, value)| log_mdc::insert_scoped(key, value))
+                    .collect();
+                self.inner.encode(w, record)
+            }
+            LogFormat::Plain => {
+                let prefix = fields
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}"))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.inner.encode(
+                    w,
+                    &Record::builder()
+                        .args(format_args!("[{prefix}] {}", record.args()))
+                        .level(record.level())
+                        .target(record.target())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .build(),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use log4rs::encode::writer::simple::SimpleWriter;
+
+    fn encode(encoder: &CorrelatedEncoder, message: &str) -> String {
+        let mut buf = Vec::new();
+        encoder
+            .encode(
+                &mut SimpleWriter(&mut buf),
+                &Record::builder()
+                    .args(format_args!("{message}"))
+                    .level(Level::Info)
+                    .build(),
+            )
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_log_format_from_str() {
+        assert_eq!(LogFormat::from_str("plain").unwrap(), LogFormat::Plain);
+        assert_eq!(LogFormat::from_str("json").unwrap(), LogFormat::Json);
+        assert!(LogFormat::from_str("xml").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_correlated_encoder() {
+        let plain = CorrelatedEncoder::new(LogFormat::Plain, "{m}{n}");
+        let json = CorrelatedEncoder::new(LogFormat::Json, "{m}{n}");
+
+        // Outside of a scope, lines are left as they are.
+        assert_eq!(encode(&plain, "hello"), "hello\n");
+
+        let payment_id = PaymentId([1; 32]);
+        let correlation = Correlation::default()
+            .with_request_id("abcd".to_string())
+            .with_payment_id(payment_id);
+        let (plain_line, json_line) = correlation
+            .scope(async { (encode(&plain, "hello"), encode(&json, "hello")) })
+            .await;
+        assert_eq!(
+            plain_line,
+            format!("[request_id=abcd payment_id={}] hello\n", "01".repeat(32))
+        );
+
+        let json_line: serde_json::Value = serde_json::from_str(&json_line).unwrap();
+        assert_eq!(json_line["message"], "hello");
+        assert_eq!(json_line["mdc"]["request_id"], "abcd");
+        assert_eq!(json_line["mdc"]["payment_id"], "01".repeat(32));
+
+        // The context is only set while the record is encoded.
+        assert_eq!(log_mdc::get("request_id", |id| id.map(String::from)), None);
+    }
+
+    // Formats the value as it would be logged, without touching the global setting that other
+    // tests running alongside this one rely on.
+    fn sensitive(value: &str, redact: bool) -> String {
+        struct Logged<'a>(Sensitive<'a, str>, bool);
+        impl Debug for Logged<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.fmt_redacted(f, self.1)
+            }
+        }
+        format!("{:?}", Logged(Sensitive(value), redact))
+    }
+
+    #[test]
+    fn test_sensitive() {
+        let note = "a gift for alice";
+        assert_eq!(sensitive(note, false), "\"a gift for alice\"");
+
+        let redacted = sensitive(note, true);
+        assert!(redacted.starts_with("<redacted "));
+        assert!(!redacted.contains("alice"));
+        // The same value always gets the same hash, so that it can be followed through the logs.
+        assert_eq!(sensitive(note, true), redacted);
+        assert_ne!(sensitive("another note", true), redacted);
+    }
+
+    #[tokio::test]
+    async fn test_nested_correlation() {
+        let peer = PublicKey::from_str(
+            "0313ba7ccbd754c117962b9afab6c2870eb3ef43f364a9f6c43d0fabb4553776ba",
+        )
+        .unwrap();
+        let correlation = Correlation::default()
+            .with_request_id("abcd".to_string())
+            .scope(async {
+                Correlation::current()
+                    .with_peer(peer)
+                    .sync_scope(Correlation::current)
+            })
+            .await;
+        assert_eq!(correlation.request_id, Some("abcd".to_string()));
+        assert_eq!(correlation.peer, Some(peer));
+        assert_eq!(Correlation::current(), Correlation::default());
+    }
+}
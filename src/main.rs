@@ -9,78 +9,134 @@ mod internal {
     include!(concat!(env!("OUT_DIR"), "/configure_me_config.rs"));
 }
 
-use home::home_dir;
 use internal::*;
-use lndk::lnd::{get_lnd_client, validate_lnd_creds, LndCfg};
-use lndk::server::{generate_tls_creds, read_tls, LNDKServer};
+use lndk::budget::{BudgetError, SpendingLimits};
+use lndk::lnd::{get_lnd_client, get_network, validate_lnd_creds, LndCfg};
+use lndk::logging::{LogFormat, LogOptions, LogRotation};
+use lndk::payer_key::PayerKeyStrategy;
+use lndk::reload::{ConfigSource, LoadedConfig, ReloadError, ReloadableSettings};
+use lndk::tls::TlsConfig;
 use lndk::{
-    lndkrpc, setup_logger, Cfg, LifecycleSignals, LndkOnionMessenger, OfferHandler,
-    DEFAULT_DATA_DIR, DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT,
+    default_data_dir, network_data_dir, network_log_file, setup_logger, LndkBuilder,
+    BUDGET_FILENAME, DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, PAYER_SEED_FILENAME, PROOFS_DIRNAME,
+    TLS_CERT_FILENAME, TLS_KEY_FILENAME,
 };
-use lndkrpc::offers_server::OffersServer;
-use log::{error, info};
-use std::fs::create_dir_all;
-use std::path::PathBuf;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{create_dir_all, rename};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::Arc;
-use tokio::select;
 use tokio::signal::unix::SignalKind;
-use tonic::transport::{Server, ServerTlsConfig};
 use tonic_lnd::lnrpc::GetInfoRequest;
 
 #[macro_use]
 extern crate configure_me;
 
+// The name of the config file we look for in the data and working directories.
+const CONFIG_FILENAME: &str = "lndk.conf";
+
+// The settings that only take effect when LNDK starts, which a reload can't change.
+const RESTART_SETTINGS: [&str; 19] = [
+    "address",
+    "cert_path",
+    "cert_pem",
+    "macaroon_path",
+    "macaroon_hex",
+    "nodes_file",
+    "data_dir",
+    "log_dir",
+    "log_format",
+    "log_max_size_mb",
+    "log_max_files",
+    "redact_logs",
+    "grpc_host",
+    "grpc_port",
+    "rest_host",
+    "rest_port",
+    "skip_version_check",
+    "payer_key_strategy",
+    "compact_paths",
+];
+
+// The settings whose values we never print.
+const SECRET_SETTINGS: [&str; 1] = ["macaroon_hex"];
+
 #[tokio::main]
 async fn main() -> Result<(), ()> {
-    let config = Config::including_optional_config_files(&["./lndk.conf"])
+    let config_files = config_files();
+    let config = Config::including_optional_config_files(&config_files)
         .unwrap_or_exit()
         .0;
+    if config.print_config {
+        print_config(&config, &config_files);
+        return Ok(());
+    }
+    // We hold on to the settings that can't be reloaded, so that we can tell the user when a
+    // reload changes them.
+    let startup_settings = restart_settings(&config);
 
-    let data_dir =
-        create_data_dir().map_err(|e| println!("Error creating LNDK's data dir {e:?}"))?;
-    setup_logger(config.log_level, config.log_dir)?;
+    let address = config.address.clone().ok_or_else(|| {
+        println!("Error validating config: address must be set.");
+    })?;
 
+    // Since the logger isn't set up until we know which network LND is on, we print any errors
+    // before then.
     let creds = validate_lnd_creds(
-        config.cert_path,
-        config.cert_pem,
-        config.macaroon_path,
-        config.macaroon_hex,
+        config.cert_path.clone(),
+        config.cert_pem.clone(),
+        config.macaroon_path.clone(),
+        config.macaroon_hex.clone(),
     )
     .map_err(|e| {
-        error!("Error validating config: {e}.");
+        println!("Error validating config: {e}.");
+    })?;
+    let log_options = log_options(&config).map_err(|e| {
+        println!("Error validating config: {e}.");
+    })?;
+    let lnd_args = LndCfg::new(address, creds);
+    let additional_nodes = additional_nodes(&config).map_err(|e| {
+        println!("Error validating config: {e}.");
     })?;
-    let address = config.address.clone();
-    let lnd_args = LndCfg::new(config.address, creds.clone());
 
-    let (shutdown, listener) = triggered::trigger();
-    let signals = LifecycleSignals {
-        shutdown: shutdown.clone(),
-        listener: listener.clone(),
-    };
-    let args = Cfg {
-        lnd: lnd_args,
-        signals,
-        skip_version_check: config.skip_version_check,
-    };
+    let mut client = get_lnd_client(lnd_args.clone()).expect("failed to connect to lnd");
+    let info = client
+        .lightning()
+        .get_info(GetInfoRequest {})
+        .await
+        .expect("failed to get info")
+        .into_inner();
+    let network = get_network(info).await.map_err(|_| {
+        println!("Error getting LND's network.");
+    })?;
+
+    let data_dir = config
+        .data_dir
+        .clone()
+        .map(PathBuf::from)
+        .unwrap_or_else(default_data_dir);
+    let network_dir = network_data_dir(&data_dir, network);
+    create_dir_all(&network_dir)
+        .map_err(|e| println!("Error creating LNDK's data dir {network_dir:?}: {e:?}"))?;
+
+    let log_file = config.log_dir.clone().unwrap_or_else(|| {
+        network_log_file(&data_dir, network)
+            .to_string_lossy()
+            .to_string()
+    });
+    setup_logger(config.log_level.clone(), Some(log_file), log_options)?;
+    move_legacy_files(&data_dir, &network_dir).map_err(|e| {
+        error!("Error moving LNDK's files to {network_dir:?}: {e:?}.");
+    })?;
 
     let mut sigterm_stream = tokio::signal::unix::signal(SignalKind::terminate())
         .map_err(|e| error!("Error initializing sigterm signal: {e}."))?;
     let mut sigint_stream = tokio::signal::unix::signal(SignalKind::interrupt())
         .map_err(|e| error!("Error initializing sigint signal: {e}."))?;
-
-    tokio::spawn(async move {
-        tokio::select! {
-            _ = sigint_stream.recv() => {
-                info!("Received CTRL-C, shutting down..");
-                shutdown.trigger();
-            }
-            _ = sigterm_stream.recv() => {
-                info!("Received SIGTERM, shutting down..");
-                shutdown.trigger();
-            }
-        }
-    });
+    let mut sighup_stream = tokio::signal::unix::signal(SignalKind::hangup())
+        .map_err(|e| error!("Error initializing sighup signal: {e}."))?;
 
     let response_invoice_timeout = config.response_invoice_timeout;
     if let Some(timeout) = response_invoice_timeout {
@@ -90,16 +146,22 @@ async fn main() -> Result<(), ()> {
         }
     }
 
-    let handler = Arc::new(OfferHandler::new(config.response_invoice_timeout));
-    let messenger = LndkOnionMessenger::new();
+    let limits = spending_limits(&config).map_err(|e| {
+        error!("Error parsing issuer_spend_limits: {e}.");
+    })?;
+    let tls = tls_config(&config, &network_dir).map_err(|e| {
+        error!("Error: {e}.");
+    })?;
 
-    let mut client = get_lnd_client(args.lnd.clone()).expect("failed to connect to lnd");
-    let info = client
-        .lightning()
-        .get_info(GetInfoRequest {})
-        .await
-        .expect("failed to get info")
-        .into_inner();
+    let payer_key_strategy = match config.payer_key_strategy {
+        Some(strategy) => {
+            PayerKeyStrategy::from_config(&strategy, &network_dir.join(PAYER_SEED_FILENAME))
+                .map_err(|e| {
+                    error!("Error setting up payer keys: {e}.");
+                })?
+        }
+        None => PayerKeyStrategy::default(),
+    };
 
     let grpc_host = match config.grpc_host {
         Some(host) => host,
@@ -112,52 +174,326 @@ async fn main() -> Result<(), ()> {
     let addr = format!("{grpc_host}:{grpc_port}").parse().map_err(|e| {
         error!("Error parsing API address: {e}");
     })?;
-    let lnd_tls_str = creds.get_certificate_string()?;
 
-    // The user passed in a TLS cert to help us establish a secure connection to LND. But now we
-    // need to generate a TLS credentials for connecting securely to the LNDK server.
-    generate_tls_creds(data_dir.clone(), config.tls_ip).map_err(|e| {
-        error!("Error generating tls credentials: {e}");
-    })?;
-    let identity = read_tls(data_dir).map_err(|e| {
-        error!("Error reading tls credentials: {e}");
-    })?;
+    let config_source = Arc::new(ConfigFiles {
+        config_files,
+        network_dir: network_dir.clone(),
+        startup_settings,
+    });
 
-    let server = LNDKServer::new(
-        Arc::clone(&handler),
-        &info.identity_pubkey,
-        lnd_tls_str,
-        address,
-    )
-    .await;
+    let mut builder = LndkBuilder::new(lnd_args)
+        .with_data_dir(network_dir)
+        .with_spending_limits(limits)
+        .with_payer_key_strategy(payer_key_strategy)
+        .with_compact_paths(config.compact_paths)
+        .with_skip_version_check(config.skip_version_check)
+        .with_tls(tls)
+        .with_grpc_server(addr)
+        .with_config_source(config_source);
+    if let Some(timeout) = response_invoice_timeout {
+        builder = builder.with_response_invoice_timeout(timeout);
+    }
+    if let Some(redact) = config.redact_logs {
+        builder = builder.with_redact_logs(redact);
+    }
+    for node in additional_nodes {
+        builder = builder.with_node(node);
+    }
 
-    let server_fut = Server::builder()
-        .tls_config(ServerTlsConfig::new().identity(identity))
-        .expect("couldn't configure tls")
-        .add_service(OffersServer::new(server))
-        .serve_with_shutdown(addr, listener);
+    // The REST server is only started if the user asks for it.
+    if let Some(rest_port) = config.rest_port {
+        let rest_host = config.rest_host.unwrap_or_else(|| grpc_host.clone());
+        let rest_addr = format!("{rest_host}:{rest_port}").parse().map_err(|e| {
+            error!("Error parsing REST address: {e}");
+        })?;
+        info!("Starting lndk's REST server at address {rest_addr}");
+        builder = builder.with_rest_server(rest_addr);
+    }
 
     info!("Starting lndk's grpc server at address {grpc_host}:{grpc_port}");
+    let handle = builder.start().await.map_err(|e| {
+        error!("Error starting lndk: {e}.");
+    })?;
+
+    let shutdown = handle.shutdown_trigger();
+    let reloader = handle.config_reloader();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sigint_stream.recv() => {
+                    info!("Received CTRL-C, shutting down..");
+                    shutdown.trigger();
+                    return;
+                }
+                _ = sigterm_stream.recv() => {
+                    info!("Received SIGTERM, shutting down..");
+                    shutdown.trigger();
+                    return;
+                }
+                _ = sighup_stream.recv() => {
+                    info!("Received SIGHUP, reloading config..");
+                    if let Some(ref reloader) = reloader {
+                        if let Err(e) = reloader.reload() {
+                            error!("Error reloading config, keeping current settings: {e}.");
+                        }
+                    }
+                }
+            }
+        }
+    });
 
-    select! {
-       _ = messenger.run(args, Arc::clone(&handler)) => {
-           info!("Onion messenger completed");
-       },
-       result2 = server_fut => {
-            match result2 {
-                Ok(_) => info!("API completed"),
-                Err(e) => error!("Error running API: {}", e),
-            };
-       },
+    match handle.join().await {
+        Ok(()) => info!("LNDK stopped."),
+        Err(e) => error!("Error running lndk: {e}."),
     }
 
     Ok(())
 }
 
-// Creates lndk's data directory at ~/.lndk.
-fn create_data_dir() -> Result<PathBuf, std::io::Error> {
-    let path = home_dir().unwrap().join(DEFAULT_DATA_DIR);
-    create_dir_all(&path)?;
+// Returns the config files we read, if they exist: lndk.conf in the data directory, then in the
+// working directory. Since the data directory can itself be set in the environment or on the
+// command line, we parse those on their own first to find it.
+fn config_files() -> Vec<PathBuf> {
+    let data_dir = Config::including_optional_config_files(std::iter::empty::<PathBuf>())
+        .ok()
+        .and_then(|(config, _)| config.data_dir)
+        .map(PathBuf::from)
+        .unwrap_or_else(default_data_dir);
+
+    vec![
+        data_dir.join(CONFIG_FILENAME),
+        Path::new(".").join(CONFIG_FILENAME),
+    ]
+}
+
+// Prints the settings we'd run with in config file syntax, so that users can check how their
+// config files, environment and command line were merged.
+fn print_config(config: &Config, config_files: &[PathBuf]) {
+    for file in config_files.iter().filter(|file| file.exists()) {
+        println!("# Read from {}", file.display());
+    }
+    for (name, value) in settings(config) {
+        match value {
+            Some(_) if SECRET_SETTINGS.contains(&name) => println!("{name} = \"<redacted>\""),
+            Some(value) => println!("{name} = {value}"),
+            None => println!("# {name} is not set"),
+        }
+    }
+}
+
+// Returns every setting, with its value formatted as it would be in a config file, or None if it
+// isn't set.
+fn settings(config: &Config) -> Vec<(&'static str, Option<String>)> {
+    fn value<T: Debug>(value: &Option<T>) -> Option<String> {
+        value.as_ref().map(|value| format!("{value:?}"))
+    }
+
+    vec![
+        ("address", value(&config.address)),
+        ("cert_path", value(&config.cert_path)),
+        ("cert_pem", value(&config.cert_pem)),
+        ("tls_ip", value(&config.tls_ip)),
+        ("tls_cert_path", value(&config.tls_cert_path)),
+        ("tls_key_path", value(&config.tls_key_path)),
+        ("tls_client_ca_path", value(&config.tls_client_ca_path)),
+        ("macaroon_path", value(&config.macaroon_path)),
+        ("macaroon_hex", value(&config.macaroon_hex)),
+        ("nodes_file", value(&config.nodes_file)),
+        ("data_dir", value(&config.data_dir)),
+        ("log_dir", value(&config.log_dir)),
+        ("log_level", value(&config.log_level)),
+        ("log_format", value(&config.log_format)),
+        ("log_max_size_mb", value(&config.log_max_size_mb)),
+        ("log_max_files", value(&config.log_max_files)),
+        ("redact_logs", value(&config.redact_logs)),
+        ("grpc_host", value(&config.grpc_host)),
+        ("grpc_port", value(&config.grpc_port)),
+        ("rest_host", value(&config.rest_host)),
+        ("rest_port", value(&config.rest_port)),
+        (
+            "skip_version_check",
+            Some(config.skip_version_check.to_string()),
+        ),
+        (
+            "response_invoice_timeout",
+            value(&config.response_invoice_timeout),
+        ),
+        ("max_payment_msats", value(&config.max_payment_msats)),
+        (
+            "max_hourly_spend_msats",
+            value(&config.max_hourly_spend_msats),
+        ),
+        (
+            "max_daily_spend_msats",
+            value(&config.max_daily_spend_msats),
+        ),
+        ("issuer_spend_limits", value(&config.issuer_spend_limits)),
+        ("payer_key_strategy", value(&config.payer_key_strategy)),
+        ("compact_paths", Some(config.compact_paths.to_string())),
+    ]
+}
+
+// ConfigFiles re-reads our config files (and environment and command line) when the user asks LNDK
+// to reload.
+struct ConfigFiles {
+    config_files: Vec<PathBuf>,
+    network_dir: PathBuf,
+    startup_settings: Vec<(&'static str, Option<String>)>,
+}
+
+impl ConfigSource for ConfigFiles {
+    fn load(&self) -> Result<LoadedConfig, ReloadError> {
+        let config = Config::including_optional_config_files(&self.config_files)
+            .map_err(|e| ReloadError::Load(e.to_string()))?
+            .0;
+
+        let limits = spending_limits(&config).map_err(|e| ReloadError::InvalidSetting {
+            name: "issuer_spend_limits".to_string(),
+            reason: e.to_string(),
+        })?;
+        let tls =
+            tls_config(&config, &self.network_dir).map_err(|e| ReloadError::InvalidSetting {
+                name: "tls_cert_path".to_string(),
+                reason: e,
+            })?;
+
+        let changed_restart_settings = restart_settings(&config)
+            .into_iter()
+            .zip(&self.startup_settings)
+            .filter(|((_, new), (_, old))| new != old)
+            .map(|((name, _), _)| name.to_string())
+            .collect();
+
+        Ok(LoadedConfig {
+            settings: ReloadableSettings {
+                log_level: config.log_level,
+                response_invoice_timeout: config.response_invoice_timeout,
+                limits,
+                tls: Some(tls),
+            },
+            changed_restart_settings,
+        })
+    }
+}
+
+// Returns the settings that only take effect when LNDK starts, so that we can tell which of them
+// a reload changed.
+fn restart_settings(config: &Config) -> Vec<(&'static str, Option<String>)> {
+    settings(config)
+        .into_iter()
+        .filter(|(name, _)| RESTART_SETTINGS.contains(name))
+        .collect()
+}
+
+fn spending_limits(config: &Config) -> Result<SpendingLimits, BudgetError> {
+    let issuer_daily_msats = match config.issuer_spend_limits {
+        Some(ref limits) => SpendingLimits::parse_issuer_limits(limits)?,
+        None => HashMap::new(),
+    };
 
-    Ok(path)
+    Ok(SpendingLimits {
+        max_payment_msats: config.max_payment_msats,
+        max_hourly_msats: config.max_hourly_spend_msats,
+        max_daily_msats: config.max_daily_spend_msats,
+        issuer_daily_msats,
+    })
+}
+
+// NodeConfig is how an additional LND node is described in nodes_file. It takes the same
+// credentials as the main node's settings.
+#[derive(Deserialize)]
+struct NodeConfig {
+    address: String,
+    cert_path: Option<PathBuf>,
+    cert_pem: Option<String>,
+    macaroon_path: Option<PathBuf>,
+    macaroon_hex: Option<String>,
+}
+
+// Reads the LND nodes listed in nodes_file, which LNDK serves alongside the node set in address.
+fn additional_nodes(config: &Config) -> Result<Vec<LndCfg>, String> {
+    let Some(ref path) = config.nodes_file else {
+        return Ok(vec![]);
+    };
+    let file = std::fs::read_to_string(path)
+        .map_err(|e| format!("could not read nodes_file {path:?}: {e}"))?;
+    let nodes: Vec<NodeConfig> = serde_json::from_str(&file)
+        .map_err(|e| format!("could not parse nodes_file {path:?}: {e}"))?;
+
+    nodes
+        .into_iter()
+        .map(|node| {
+            let creds = validate_lnd_creds(
+                node.cert_path,
+                node.cert_pem,
+                node.macaroon_path,
+                node.macaroon_hex,
+            )
+            .map_err(|e| format!("invalid credentials for node {}: {e}", node.address))?;
+            Ok(LndCfg::new(node.address, creds))
+        })
+        .collect()
+}
+
+// How many rotated log files we keep if the user doesn't say.
+const DEFAULT_LOG_MAX_FILES: u32 = 5;
+
+fn log_options(config: &Config) -> Result<LogOptions, String> {
+    let format = match config.log_format {
+        Some(ref format) => format.parse()?,
+        None => LogFormat::default(),
+    };
+    let rotation = match config.log_max_size_mb {
+        Some(0) => return Err("log_max_size_mb must be more than 0".to_string()),
+        Some(max_size_mb) => Some(LogRotation {
+            max_size_bytes: max_size_mb * 1024 * 1024,
+            max_files: config.log_max_files.unwrap_or(DEFAULT_LOG_MAX_FILES),
+        }),
+        None => None,
+    };
+
+    Ok(LogOptions { format, rotation })
+}
+
+// The user passed in a TLS cert to help us establish a secure connection to LND. But we also need
+// TLS credentials for connecting securely to the LNDK server. Unless the user brings their own, we
+// generate them.
+fn tls_config(config: &Config, network_dir: &Path) -> Result<TlsConfig, String> {
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            if config.tls_ip.is_some() {
+                warn!("Ignoring tls_ip since tls_cert_path is set.");
+            }
+            TlsConfig::external(cert_path.clone(), key_path.clone())
+        }
+        (None, None) => TlsConfig::generated(network_dir.to_path_buf(), config.tls_ip.clone()),
+        _ => return Err("tls_cert_path and tls_key_path must be set together".to_string()),
+    };
+
+    Ok(match config.tls_client_ca_path {
+        Some(ref client_ca_path) => tls.with_client_ca(client_ca_path.clone()),
+        None => tls,
+    })
+}
+
+// Earlier versions of LNDK kept their files at the top of the data directory. If we find any
+// there, we move them into the network's directory, so that we keep using the same TLS credentials
+// and spending record.
+fn move_legacy_files(data_dir: &Path, network_dir: &Path) -> Result<(), std::io::Error> {
+    let filenames = [
+        TLS_CERT_FILENAME,
+        TLS_KEY_FILENAME,
+        BUDGET_FILENAME,
+        PROOFS_DIRNAME,
+        PAYER_SEED_FILENAME,
+    ];
+    for filename in filenames {
+        let (legacy_path, path) = (data_dir.join(filename), network_dir.join(filename));
+        if legacy_path.exists() && !path.exists() {
+            info!("Moving {legacy_path:?} to {path:?}.");
+            rename(legacy_path, path)?;
+        }
+    }
+
+    Ok(())
 }
@@ -1,5 +1,10 @@
+use crate::channel_cache::ChannelCache;
 use crate::clock::TokioClock;
-use crate::lnd::{features_support_onion_messages, ONION_MESSAGES_OPTIONAL};
+use crate::lnd::{
+    features_support_onion_messages, ChannelInfoProvider, EventStream, NodeBackend, PeerConnector,
+    SendCustomMessage, LND_REQUEST_TIMEOUT, ONION_MESSAGES_OPTIONAL,
+};
+use crate::logging::{Correlation, Sensitive};
 use crate::rate_limit::{RateLimiter, TokenLimiter};
 use crate::{LifecycleSignals, LndkOnionMessenger, LDK_LOGGER_NAME};
 use async_trait::async_trait;
@@ -7,10 +12,10 @@ use bitcoin::blockdata::constants::ChainHash;
 use bitcoin::network::constants::Network;
 use bitcoin::secp256k1::PublicKey;
 use core::ops::Deref;
-use futures::executor::block_on;
+use futures::{FutureExt, StreamExt};
 use lightning::blinded_path::NodeIdLookUp;
 use lightning::ln::features::InitFeatures;
-use lightning::ln::msgs::{Init, OnionMessage, OnionMessageHandler};
+use lightning::ln::msgs::{DecodeError, Init, OnionMessage, OnionMessageHandler};
 use lightning::onion_message::messenger::{
     CustomOnionMessageHandler, MessageRouter, OnionMessenger,
 };
@@ -25,18 +30,21 @@ use rand_core::{RngCore, SeedableRng};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::future::Future;
 use std::io::Cursor;
 use std::marker::Copy;
 use std::str::FromStr;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+use tokio::task::JoinSet;
 use tokio::time::{sleep, timeout, Duration, Interval};
 use tokio::{select, time};
-use tonic_lnd::lnrpc::ChanInfoRequest;
-use tonic_lnd::Client;
 use tonic_lnd::{
     lnrpc::peer_event::EventType::PeerOffline, lnrpc::peer_event::EventType::PeerOnline,
-    lnrpc::CustomMessage, lnrpc::PeerEvent, lnrpc::SendCustomMessageRequest,
-    lnrpc::SendCustomMessageResponse, tonic::Status, LightningClient,
+    lnrpc::CustomMessage, lnrpc::PeerEvent, tonic::Status,
 };
 use triggered::Listener;
 
@@ -52,46 +60,83 @@ const DEFAULT_CALL_COUNT: u8 = 10;
 /// DEFAULT_CALL_FREQUENCY is the default period over which peers are rate limited.
 const DEFAULT_CALL_FREQUENCY: Duration = Duration::from_secs(1);
 
+// NodeIdRequest asks the lookup task for the node on the other side of one of our channels.
+struct NodeIdRequest {
+    short_channel_id: u64,
+    respond: SyncSender<Option<PublicKey>>,
+}
+
 /// Node Id LookUp is a utility struct implementing NodeIdLookUp trait for LDK's OnionMessenger.
+///
+/// As with LndNodeSigner, NodeIdLookUp is synchronous, so lookups are handed off to a task running
+/// on the tokio runtime and the caller waits for the result.
 pub struct LndkNodeIdLookUp {
-    client: Client,
-    our_node_id: PublicKey,
+    requests: UnboundedSender<NodeIdRequest>,
 }
 
 impl LndkNodeIdLookUp {
-    pub fn new(client: Client, our_node_id: PublicKey) -> Self {
-        LndkNodeIdLookUp {
-            client,
-            our_node_id,
-        }
+    /// Creates a lookup, along with the task that serves its lookups from the channel cache or
+    /// LND. The task must be spawned on the runtime, and exits once the lookup is dropped.
+    pub fn new(
+        client: impl ChannelInfoProvider + Clone + Send + 'static,
+        our_node_id: PublicKey,
+        channel_cache: Arc<ChannelCache>,
+    ) -> (Self, impl Future<Output = ()>) {
+        let (sender, receiver) = unbounded_channel();
+        let node_id_lookup = LndkNodeIdLookUp { requests: sender };
+
+        (
+            node_id_lookup,
+            serve_node_id_requests(client, our_node_id, channel_cache, receiver),
+        )
     }
 }
 
 impl NodeIdLookUp for LndkNodeIdLookUp {
     fn next_node_id(&self, short_channel_id: u64) -> Option<PublicKey> {
-        let get_chan_info_request = ChanInfoRequest {
-            chan_id: short_channel_id,
-        };
-        let client = self.client.clone();
-        match block_on(
-            client
-                .lightning_read_only()
-                .get_chan_info(get_chan_info_request),
-        ) {
-            Ok(channel_info) => {
-                let channel_info = channel_info.into_inner();
-                let pubkey = if channel_info.node1_pub == self.our_node_id.to_string() {
-                    channel_info.node2_pub
-                } else {
-                    channel_info.node1_pub
-                };
-                PublicKey::from_slice(pubkey.as_bytes()).ok()
-            }
-            Err(e) => {
-                error!("Error getting channel info: {e}.");
-                None
-            }
-        }
+        let (respond, response) = sync_channel(1);
+        self.requests
+            .send(NodeIdRequest {
+                short_channel_id,
+                respond,
+            })
+            .map_err(|_| error!("Node id lookup task has exited."))
+            .ok()?;
+
+        response
+            .recv_timeout(LND_REQUEST_TIMEOUT)
+            .map_err(|e| error!("Error waiting for channel info: {e}."))
+            .ok()?
+    }
+}
+
+// serve_node_id_requests looks up channels for LndkNodeIdLookUp. Each request is served in its own
+// task so that a slow call to LND doesn't hold up the requests behind it.
+async fn serve_node_id_requests(
+    client: impl ChannelInfoProvider + Clone + Send + 'static,
+    our_node_id: PublicKey,
+    channel_cache: Arc<ChannelCache>,
+    mut requests: UnboundedReceiver<NodeIdRequest>,
+) {
+    while let Some(request) = requests.recv().await {
+        let client = client.clone();
+        let channel_cache = Arc::clone(&channel_cache);
+        tokio::spawn(async move {
+            let scid = request.short_channel_id;
+            let node_id = match channel_cache.lookup(client, scid).await {
+                Ok(channel_nodes) => channel_nodes
+                    .counterparty(&our_node_id)
+                    .map_err(|e| error!("Could not parse node id for channel {scid}: {e}."))
+                    .ok(),
+                Err(e) => {
+                    error!("Error getting channel info: {e}.");
+                    None
+                }
+            };
+
+            // If the caller has given up waiting, there's no one left to tell.
+            let _ = request.respond.send(node_id);
+        });
     }
 }
 
@@ -153,6 +198,7 @@ impl LndkOnionMessenger {
     /// The main consumer processes one MessengerEvent at a time, applying basic rate limiting to
     /// each peer to prevent spam.
     pub(crate) async fn run_onion_messenger<
+        B: NodeBackend,
         ES: Deref,
         NS: Deref,
         L: Deref,
@@ -163,7 +209,7 @@ impl LndkOnionMessenger {
     >(
         &self,
         current_peers: HashMap<PublicKey, bool>,
-        ln_client: &mut tonic_lnd::LightningClient,
+        node: B,
         onion_messenger: OnionMessenger<ES, NS, L, NL, MR, OMH, CMH>,
         network: Network,
         signals: LifecycleSignals,
@@ -176,6 +222,7 @@ impl LndkOnionMessenger {
         MR::Target: MessageRouter,
         OMH::Target: OffersMessageHandler,
         CMH::Target: CustomOnionMessageHandler + Sized,
+        OnionMessenger<ES, NS, L, NL, MR, OMH, CMH>: Send + Sync + 'static,
     {
         // Setup channels that we'll use to communicate onion messenger events. We buffer our
         // channels by the number of peers (+1 because we require a non-zero buffer) that
@@ -200,15 +247,14 @@ impl LndkOnionMessenger {
         // online/offline reports, so it's okay if this ends up creating some duplicate
         // events. The event subscription from LND blocks until it gets its first event (which
         // could take very long), so we get the subscription itself inside of our producer thread.
-        let mut peers_client = ln_client.clone();
+        let mut peers_client = node.clone();
         let peers_sender = sender.clone();
         let (peers_shutdown, peers_listener) = (signals.shutdown.clone(), signals.listener.clone());
         set.spawn(async move {
             let peer_subscription = peers_client
-                .subscribe_peer_events(tonic_lnd::lnrpc::PeerEventSubscription {})
+                .subscribe_peer_events()
                 .await
-                .expect("peer subscription failed")
-                .into_inner();
+                .expect("peer subscription failed");
 
             let peer_stream = PeerStream {
                 peer_subscription,
@@ -225,16 +271,15 @@ impl LndkOnionMessenger {
         });
 
         // Subscribe to custom messaging events from LND so that we can receive incoming messages.
-        let mut messages_client = ln_client.clone();
+        let mut messages_client = node.clone();
         let in_msg_sender = sender.clone();
         let (messages_shutdown, messages_listener) =
             (signals.shutdown.clone(), signals.listener.clone());
         set.spawn(async move {
             let message_subscription = messages_client
-                .subscribe_custom_messages(tonic_lnd::lnrpc::SubscribeCustomMessagesRequest {})
+                .subscribe_custom_messages()
                 .await
-                .expect("message subscription failed")
-                .into_inner();
+                .expect("message subscription failed");
 
             let message_stream = MessageStream {
                 message_subscription,
@@ -266,10 +311,8 @@ impl LndkOnionMessenger {
             }
         });
 
-        // Consume events is our main controlling loop, so we run it inline here. We use a RefCell
-        // in onion_messenger to allow interior mutability (see LndNodeSigner) so this
-        // function can't safely be passed off to another thread. This function is expected
-        // to finish if any producing thread exits (because we're no longer receiving the
+        // Consume events is our main controlling loop, so we run it inline here. This function is
+        // expected to finish if any producing thread exits (because we're no longer receiving the
         // events we need).
         let rate_limiter = &mut TokenLimiter::new(
             current_peers.keys().copied(),
@@ -277,11 +320,9 @@ impl LndkOnionMessenger {
             DEFAULT_CALL_FREQUENCY,
             TokioClock::new(),
         );
-        let mut message_sender = CustomMessenger {
-            client: ln_client.clone(),
-        };
+        let mut message_sender = node;
         let consume_result = consume_messenger_events(
-            onion_messenger,
+            Arc::new(onion_messenger),
             receiver,
             &mut message_sender,
             rate_limiter,
@@ -319,15 +360,13 @@ impl LndkOnionMessenger {
 /// lookup_onion_support performs a best-effort lookup in the node's list of current peers to
 /// determine whether it supports onion messaging. If the node is not found a warning is logged and
 /// we assume that onion messaging is not supported.
-async fn lookup_onion_support(pubkey: &PublicKey, client: &mut tonic_lnd::LightningClient) -> bool {
-    match client
-        .list_peers(tonic_lnd::lnrpc::ListPeersRequest {
-            latest_error: false,
-        })
-        .await
-    {
+async fn lookup_onion_support(
+    pubkey: &PublicKey,
+    client: &mut (impl PeerConnector + Clone + Send),
+) -> bool {
+    match client.list_peers().await {
         Ok(peers) => {
-            for peer in peers.into_inner().peers {
+            for peer in peers.peers {
                 if peer.pub_key != pubkey.to_string() {
                     continue;
                 }
@@ -368,17 +407,12 @@ async fn lookup_onion_support(pubkey: &PublicKey, client: &mut tonic_lnd::Lightn
 // map. Sometimes if a peer is new, LND needs a little time to update the feature set.
 async fn check_empty_features(
     pubkey: &PublicKey,
-    mut client: LightningClient,
+    mut client: impl PeerConnector + Send,
 ) -> HashMap<u32, tonic_lnd::lnrpc::Feature> {
     loop {
-        match client
-            .list_peers(tonic_lnd::lnrpc::ListPeersRequest {
-                latest_error: false,
-            })
-            .await
-        {
+        match client.list_peers().await {
             Ok(peers) => {
-                for peer in peers.into_inner().peers {
+                for peer in peers.peers {
                     if peer.pub_key != pubkey.to_string() {
                         continue;
                     }
@@ -426,15 +460,15 @@ trait PeerEventProducer {
     async fn onion_support(&mut self, pubkey: &PublicKey) -> bool;
 }
 
-struct PeerStream {
-    peer_subscription: tonic_lnd::tonic::Streaming<PeerEvent>,
-    client: tonic_lnd::LightningClient,
+struct PeerStream<C: PeerConnector + Clone + Send> {
+    peer_subscription: EventStream<PeerEvent>,
+    client: C,
 }
 
 #[async_trait]
-impl PeerEventProducer for PeerStream {
+impl<C: PeerConnector + Clone + Send> PeerEventProducer for PeerStream<C> {
     async fn receive(&mut self) -> Result<PeerEvent, Status> {
-        match self.peer_subscription.message().await? {
+        match self.peer_subscription.next().await.transpose()? {
             Some(peer_event) => Ok(peer_event),
             None => Err(Status::unknown("no event provided")),
         }
@@ -516,19 +550,52 @@ trait IncomingMessageProducer {
 }
 
 struct MessageStream {
-    message_subscription: tonic_lnd::tonic::Streaming<CustomMessage>,
+    message_subscription: EventStream<CustomMessage>,
 }
 
 #[async_trait]
 impl IncomingMessageProducer for MessageStream {
     async fn receive(&mut self) -> Result<CustomMessage, Status> {
-        match self.message_subscription.message().await? {
+        match self.message_subscription.next().await.transpose()? {
             Some(msg) => Ok(msg),
             None => Err(Status::unknown("no message provided")),
         }
     }
 }
 
+/// IncomingMessageError describes a custom message from LND that can't be read as an onion message.
+#[derive(Debug)]
+pub enum IncomingMessageError {
+    /// InvalidPeer indicates that the sending peer's public key could not be parsed.
+    InvalidPeer(bitcoin::secp256k1::Error),
+    /// InvalidMessage indicates that the message contents are not a valid onion message.
+    InvalidMessage(DecodeError),
+}
+
+impl Error for IncomingMessageError {}
+
+impl fmt::Display for IncomingMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncomingMessageError::InvalidPeer(e) => write!(f, "invalid peer public key: {e}"),
+            IncomingMessageError::InvalidMessage(e) => write!(f, "invalid onion message: {e}"),
+        }
+    }
+}
+
+/// Reads the sending peer and onion message out of a custom message's fields. Both are supplied by
+/// our peers (via LND), so any input must be rejected with an error rather than a panic.
+pub fn parse_onion_message(
+    peer: &[u8],
+    data: &[u8],
+) -> Result<(PublicKey, OnionMessage), IncomingMessageError> {
+    let pubkey = PublicKey::from_slice(peer).map_err(IncomingMessageError::InvalidPeer)?;
+    let onion_message =
+        OnionMessage::read(&mut Cursor::new(data)).map_err(IncomingMessageError::InvalidMessage)?;
+
+    Ok((pubkey, onion_message))
+}
+
 /// Consumes a stream of incoming message events from the IncomingMessageProducer until the stream
 /// exits (by sending an error) or the producer receives the signal to exit (via close of the exit
 /// channel).
@@ -561,18 +628,16 @@ async fn produce_incoming_message_events(
                         continue;
                     }
 
-                    let pubkey = PublicKey::from_slice(&incoming_message.peer).unwrap();
-                    let res = OnionMessage::read(&mut Cursor::new(incoming_message.data));
-                    match res {
-                        Ok(onion_message) => {
+                    match parse_onion_message(&incoming_message.peer, &incoming_message.data) {
+                        Ok((pubkey, onion_message)) => {
                             let event = MessengerEvents::IncomingMessage(pubkey, onion_message);
-                            let event_str = format!("{event:?}");
+                            let event_str = format!("{:?}", Sensitive(&event));
                             match events.send(event).await {
                                 Ok(_) => debug!("Incoming messages sent: {event_str}."),
                                 Err(err) => return Err(ProducerError::SendError(format!("{err}"))),
                             };
                         },
-                        Err(e) => error!("Invalid onion message from: {pubkey}: {e}."),
+                        Err(e) => error!("Invalid onion message from: {}: {e}.", hex::encode(&incoming_message.peer)),
                     };
                 },
                 Err(s) => {
@@ -654,7 +719,7 @@ impl fmt::Display for MessengerEvents {
 /// consume_messenger_events receives a series of onion messaging related events and delivers them
 /// to the OnionMessenger provided, using the RateLimiter to limit resources consumed by each peer.
 async fn consume_messenger_events(
-    onion_messenger: impl OnionMessageHandler,
+    onion_messenger: Arc<impl OnionMessageHandler + Send + Sync + 'static>,
     mut events: Receiver<MessengerEvents>,
     message_sender: &mut impl SendCustomMessage,
     rate_limiter: &mut impl RateLimiter,
@@ -662,7 +727,20 @@ async fn consume_messenger_events(
 ) -> Result<(), ConsumerError> {
     let network = vec![ChainHash::using_genesis_block(network)];
 
-    while let Some(onion_event) = events.recv().await {
+    // Handling an incoming message can take a round trip to LND (to derive a shared secret or look
+    // up a channel), which LndNodeSigner and LndkNodeIdLookUp wait on synchronously. We handle
+    // incoming messages on the blocking thread pool so that this doesn't block the runtime, or
+    // hold up the events behind them.
+    let mut incoming = JoinSet::new();
+
+    let result = loop {
+        let Some(onion_event) = events.recv().await else {
+            break Ok(());
+        };
+
+        // Clean up after any incoming messages that we've finished handling.
+        while let Some(Some(_)) = incoming.join_next().now_or_never() {}
+
         match onion_event {
             // We don't want to log SendOutgoing events, since we send out this event every 100 ms.
             MessengerEvents::SendOutgoing => {}
@@ -678,17 +756,17 @@ async fn consume_messenger_events(
                     InitFeatures::empty()
                 };
 
-                onion_messenger
-                    .peer_connected(
-                        &pubkey,
-                        &Init {
-                            features: init_features,
-                            remote_network_address: None,
-                            networks: Some(network.clone()),
-                        },
-                        false,
-                    )
-                    .map_err(|_| ConsumerError::OnionMessengerFailure)?;
+                let init = Init {
+                    features: init_features,
+                    remote_network_address: None,
+                    networks: Some(network.clone()),
+                };
+                if onion_messenger
+                    .peer_connected(&pubkey, &init, false)
+                    .is_err()
+                {
+                    break Err(ConsumerError::OnionMessengerFailure);
+                }
 
                 // In addition to keeping the onion messenger up to date with the latest peers, we
                 // need to keep our local version up to date so we send outgoing OMs
@@ -709,7 +787,13 @@ async fn consume_messenger_events(
                     continue;
                 }
 
-                onion_messenger.handle_onion_message(&pubkey, &onion_message)
+                // Anything logged while handling the message is tagged with the peer that sent it.
+                let onion_messenger = Arc::clone(&onion_messenger);
+                incoming.spawn_blocking(move || {
+                    Correlation::current().with_peer(pubkey).sync_scope(|| {
+                        onion_messenger.handle_onion_message(&pubkey, &onion_message)
+                    })
+                });
             }
             MessengerEvents::SendOutgoing => {
                 for peer in rate_limiter.peers() {
@@ -720,38 +804,15 @@ async fn consume_messenger_events(
                 }
             }
             MessengerEvents::ProducerExit(e) => {
-                return Err(e);
+                break Err(e);
             }
         }
-    }
-
-    Ok(())
-}
-
-#[async_trait]
-/// SendCustomMessage provides a level of abstraction over LND's send custom message API.
-trait SendCustomMessage {
-    async fn send_custom_message(
-        &mut self,
-        request: SendCustomMessageRequest,
-    ) -> Result<SendCustomMessageResponse, Status>;
-}
+    };
 
-struct CustomMessenger {
-    client: LightningClient,
-}
+    // Let any incoming messages that we're still handling finish before we exit.
+    while incoming.join_next().await.is_some() {}
 
-#[async_trait]
-impl SendCustomMessage for CustomMessenger {
-    async fn send_custom_message(
-        &mut self,
-        request: SendCustomMessageRequest,
-    ) -> Result<SendCustomMessageResponse, Status> {
-        match self.client.send_custom_message(request).await {
-            Ok(resp) => Ok(resp.into_inner()),
-            Err(status) => Err(status),
-        }
-    }
+    result
 }
 
 /// produce_outgoing_message_events is produce for producing outgoing message events at a regular
@@ -813,7 +874,10 @@ async fn relay_outgoing_msg_event(
 
     // TODO: To improve resilience, retry this call in the event of a temporary connection error.
     match ln_client.send_custom_message(req).await {
-        Ok(_) => debug!("Sent outgoing onion message {msg:?} to {peer}."),
+        Ok(_) => debug!(
+            "Sent outgoing onion message {:?} to {peer}.",
+            Sensitive(&msg)
+        ),
         Err(e) => error!("Error sending custom message {e} to {peer}."),
     }
 }
@@ -821,9 +885,13 @@ async fn relay_outgoing_msg_event(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::test_utils::pubkey;
+    use crate::sim::fixtures::{privkey, pubkey, QueuedHandler};
+    use crate::sim::SimNode;
+    use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+    use bitcoin::hashes::{sha256, Hash, HashEngine};
     use bitcoin::network::constants::Network;
-    use bitcoin::secp256k1::PublicKey;
+    use bitcoin::secp256k1::ecdh::SharedSecret;
+    use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1};
     use bytes::BufMut;
     use lightning::events::{EventHandler, EventsProvider};
     use lightning::ln::features::{InitFeatures, NodeFeatures};
@@ -831,8 +899,10 @@ mod tests {
     use lightning::util::ser::Readable;
     use lightning::util::ser::Writeable;
     use mockall::mock;
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
     use std::io::Cursor;
     use tokio::sync::mpsc::channel;
+    use tonic_lnd::lnrpc::{ChannelEdge, SendCustomMessageRequest, SendCustomMessageResponse};
 
     /// Produces an OnionMessage that can be used for tests. We need to manually write individual
     /// bytes because onion messages in LDK can only be created using read/write impls that deal
@@ -861,6 +931,68 @@ mod tests {
         OnionMessage::read(&mut readable).unwrap()
     }
 
+    /// Produces an OnionMessage that asks our node to forward it over the channel with the short
+    /// channel id given. LDK only creates onion messages that are forwarded by node id, so as with
+    /// onion_message we write the bytes ourselves, this time encrypted to our node (see BOLT 4) so
+    /// that it can peel the onion.
+    fn scid_forward_onion_message(our_node_id: PublicKey, scid: u64) -> OnionMessage {
+        let secp_ctx = Secp256k1::new();
+
+        // The encrypted data for our hop tells us to forward the message by short channel id (tlv
+        // type 2).
+        let blinding_secret = privkey(10);
+        let control_tlvs_ss = SharedSecret::new(&our_node_id, &blinding_secret).secret_bytes();
+        let mut encrypted_data = vec![2, 8];
+        encrypted_data.put_u64(scid);
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &hmac(b"rho", &control_tlvs_ss)).unwrap();
+        LessSafeKey::new(key)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key([0; 12]),
+                Aad::empty(),
+                &mut encrypted_data,
+            )
+            .unwrap();
+
+        // Our hop's payload holds the encrypted data (tlv type 4), and is followed by the hmac for
+        // the next hop and padding.
+        let mut hop_data = vec![];
+        hop_data.put_u8(2 + encrypted_data.len() as u8);
+        hop_data.put_u8(4);
+        hop_data.put_u8(encrypted_data.len() as u8);
+        hop_data.put_slice(&encrypted_data);
+        hop_data.put_bytes(1, 32);
+        hop_data.resize(1300, 0);
+
+        // The onion itself is encrypted to our blinded node id.
+        let blinding_factor =
+            Scalar::from_be_bytes(hmac(b"blinded_node_id", &control_tlvs_ss)).unwrap();
+        let blinded_node_id = our_node_id.mul_tweak(&secp_ctx, &blinding_factor).unwrap();
+        let onion_secret = privkey(11);
+        let onion_ss = SharedSecret::new(&blinded_node_id, &onion_secret).secret_bytes();
+        let mut keystream = vec![0; hop_data.len()];
+        ChaCha20Rng::from_seed(hmac(b"rho", &onion_ss)).fill_bytes(&mut keystream);
+        for (byte, key) in hop_data.iter_mut().zip(keystream) {
+            *byte ^= key;
+        }
+
+        let mut w = vec![];
+        w.put_slice(&PublicKey::from_secret_key(&secp_ctx, &blinding_secret).serialize());
+        w.put_u16(1 + 33 + 1300 + 32);
+        w.put_u8(0);
+        w.put_slice(&PublicKey::from_secret_key(&secp_ctx, &onion_secret).serialize());
+        w.put_slice(&hop_data);
+        w.put_slice(&hmac(&hmac(b"mu", &onion_ss), &hop_data));
+
+        let mut readable = Cursor::new(w);
+        OnionMessage::read(&mut readable).unwrap()
+    }
+
+    fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut engine = HmacEngine::<sha256::Hash>::new(key);
+        engine.input(data);
+        Hmac::from_engine(engine).to_byte_array()
+    }
+
     mock! {
             OnionHandler{}
 
@@ -994,7 +1126,9 @@ mod tests {
             .expect_query_peer()
             .withf(move |actual_pk: &PublicKey| *actual_pk == pk_1.clone())
             .returning(|_| true);
-        mock.expect_handle_onion_message().return_once(|_, _| ());
+        mock.expect_handle_onion_message()
+            .times(1)
+            .return_once(|_, _| ());
 
         // Cover incoming onion messages - rate limiter disallows incoming.
         sender
@@ -1015,7 +1149,7 @@ mod tests {
             .unwrap();
 
         let consume_err = consume_messenger_events(
-            mock,
+            Arc::new(mock),
             receiver,
             &mut sender_mock,
             &mut rate_limiter,
@@ -1044,7 +1178,7 @@ mod tests {
         let mut sender_mock = MockSendCustomMessenger::new();
 
         let consume_err = consume_messenger_events(
-            mock,
+            Arc::new(mock),
             receiver,
             &mut sender_mock,
             &mut rate_limiter,
@@ -1065,7 +1199,7 @@ mod tests {
         let mut rate_limiter = MockRateLimiter::new();
 
         assert!(consume_messenger_events(
-            MockOnionHandler::new(),
+            Arc::new(MockOnionHandler::new()),
             receiver_done,
             &mut sender_mock,
             &mut rate_limiter,
@@ -1075,6 +1209,121 @@ mod tests {
         .is_ok());
     }
 
+    mock! {
+        ChannelInfo{}
+
+        #[async_trait]
+        impl ChannelInfoProvider for ChannelInfo {
+            async fn get_chan_info(&mut self, short_channel_id: u64) -> Result<ChannelEdge, Status>;
+        }
+
+        impl Clone for ChannelInfo {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    // Returns a channel info mock that knows about the given (scid, node1, node2) channels, with
+    // node ids hex encoded as LND returns them.
+    fn channel_info(channels: Vec<(u64, PublicKey, PublicKey)>) -> MockChannelInfo {
+        let mut client = MockChannelInfo::new();
+        client.expect_clone().returning(move || {
+            let channels = channels.clone();
+            let mut client = MockChannelInfo::new();
+            client.expect_get_chan_info().returning(move |scid| {
+                channels
+                    .iter()
+                    .find(|(channel_scid, _, _)| *channel_scid == scid)
+                    .map(|(_, node1, node2)| ChannelEdge {
+                        channel_id: scid,
+                        node1_pub: node1.to_string(),
+                        node2_pub: node2.to_string(),
+                        ..Default::default()
+                    })
+                    .ok_or(Status::not_found("edge not found"))
+            });
+            client
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn test_next_node_id() {
+        let our_node_id = pubkey(0);
+        let (pk_1, pk_2) = (pubkey(1), pubkey(2));
+        let client = channel_info(vec![(1, our_node_id, pk_1), (2, pk_2, our_node_id)]);
+        let (lookup, lookup_task) =
+            LndkNodeIdLookUp::new(client, our_node_id, Arc::new(ChannelCache::default()));
+        tokio::spawn(lookup_task);
+
+        // We should get the node on the other side of the channel from us, whichever side we're
+        // on, and nothing for channels that LND doesn't know about. Lookups wait on the lookup
+        // task, so like LDK we make them from outside of the runtime.
+        let node_ids = tokio::task::spawn_blocking(move || {
+            (
+                lookup.next_node_id(1),
+                lookup.next_node_id(2),
+                lookup.next_node_id(3),
+            )
+        })
+        .await
+        .unwrap();
+        assert_eq!(node_ids, (Some(pk_1), Some(pk_2), None));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_node_id_lookup_in_event_loop() {
+        // Our node has a channel with next_node, and is asked to forward a message over it by
+        // short channel id. LDK's OnionMessenger resolves the next hop with our node id lookup,
+        // which it calls from within our event loop.
+        let node = SimNode::new(privkey(1), Network::Regtest);
+        let (sender_node, next_node) = (pubkey(1), pubkey(2));
+        let scid = 42;
+        node.add_peer(sender_node, true);
+        node.add_peer(next_node, true);
+        node.add_channel(scid, node.pubkey(), next_node);
+
+        let (shutdown, listener) = triggered::trigger();
+        let signals = LifecycleSignals {
+            shutdown: shutdown.clone(),
+            listener,
+        };
+        let messenger_node = node.clone();
+        let messenger = tokio::spawn(async move {
+            LndkOnionMessenger::new()
+                .run_with_backend(
+                    messenger_node,
+                    signals,
+                    false,
+                    Arc::new(QueuedHandler::new(vec![])),
+                )
+                .await
+        });
+
+        // The messenger only receives messages once it has subscribed to them, so we keep
+        // delivering the message until it's relayed to the node on the other side of the channel.
+        let data = scid_forward_onion_message(node.pubkey(), scid).encode();
+        let relayed = timeout(Duration::from_secs(10), async {
+            loop {
+                node.receive_custom_message(sender_node, ONION_MESSAGE_TYPE, data.clone());
+                sleep(Duration::from_millis(100)).await;
+                if let Some(relayed) = node.sent_messages().into_iter().next() {
+                    return relayed;
+                }
+            }
+        })
+        .await
+        .expect("onion message not relayed");
+        assert_eq!(relayed.peer, next_node.serialize().to_vec());
+        assert_eq!(relayed.r#type, ONION_MESSAGE_TYPE);
+
+        shutdown.trigger();
+        let result = timeout(Duration::from_secs(10), messenger)
+            .await
+            .expect("messenger did not shut down")
+            .unwrap();
+        assert!(result.is_ok());
+    }
+
     #[tokio::test]
     async fn test_produce_peer_events() {
         let (sender, mut receiver) = channel(4);
@@ -1209,6 +1458,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_onion_message() {
+        let mut data = vec![];
+        onion_message().write(&mut data).unwrap();
+        let peer = pubkey(0).serialize();
+
+        let (parsed_peer, parsed_message) = parse_onion_message(&peer, &data).unwrap();
+        assert_eq!(parsed_peer, pubkey(0));
+        assert_eq!(parsed_message, onion_message());
+
+        assert!(matches!(
+            parse_onion_message(&peer[1..], &data),
+            Err(IncomingMessageError::InvalidPeer(_))
+        ));
+        assert!(matches!(
+            parse_onion_message(&peer, &data[..data.len() / 2]),
+            Err(IncomingMessageError::InvalidMessage(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_produce_incoming_message_exit() {
         let (sender, _receiver) = channel(2);
@@ -0,0 +1,283 @@
+use crate::onion_messenger::MessengerUtilities;
+use bitcoin::hashes::sha256::Hash;
+use bitcoin::hashes::{Hash as _, HashEngine};
+use bitcoin::secp256k1::{KeyPair, Secp256k1, SecretKey, Signing};
+use lightning::offers::offer::Offer;
+use lightning::sign::EntropySource;
+use lightning::util::ser::Writeable;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// The tag we mix into the hash that derives a per-offer payer key from our seed.
+const PAYER_KEY_TAG: &[u8] = b"lndk/payer_key";
+
+// KeyFamilyNodeKey (3), which we derive a new payer key from for each invoice request if using
+// PayerKeyStrategy::LndNextKey. For better privacy, the key shouldn't correspond to our node id.
+// https://github.com/lightningnetwork/lnd/blob/a3f8011ed695f6204ec6a13ad5c2a67ac542b109/keychain/derivation.go#L86
+pub const NODE_KEY_FAMILY: i32 = 3;
+
+/// PayerKeyStrategy decides which key we use as the payer id when we request an invoice for an
+/// offer. The payer id signs the invoice request, so holding its private key lets us prove later
+/// on that we were the ones who paid.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum PayerKeyStrategy {
+    /// Ask LND for the next key in its node key family for every invoice request. This uses up a
+    /// key index in LND's wallet for each attempt.
+    #[default]
+    LndNextKey,
+    /// Derive a one-off key from the invoice request's metadata with LDK, without a round trip to
+    /// LND. The key can't be recovered once LNDK restarts.
+    Transient,
+    /// Derive the same key every time we pay a given offer, from a seed stored in LNDK's data
+    /// directory.
+    PerOffer(PayerSeed),
+    /// Always sign with the LND key at the given key locator.
+    LndKey { key_family: i32, key_index: i32 },
+}
+
+impl PayerKeyStrategy {
+    /// The user picks a strategy with one of 'lnd-next-key', 'transient', 'per-offer' or
+    /// 'lnd-key:<key_family>:<key_index>'. The per-offer strategy loads (or creates) its seed at
+    /// seed_path.
+    pub fn from_config(strategy_str: &str, seed_path: &Path) -> Result<Self, PayerKeyError> {
+        let invalid = || PayerKeyError::InvalidStrategy(strategy_str.to_string());
+        match strategy_str.trim() {
+            "lnd-next-key" => Ok(PayerKeyStrategy::LndNextKey),
+            "transient" => Ok(PayerKeyStrategy::Transient),
+            "per-offer" => Ok(PayerKeyStrategy::PerOffer(PayerSeed::load_or_create(
+                seed_path,
+            )?)),
+            s => {
+                let locator = s.strip_prefix("lnd-key:").ok_or_else(invalid)?;
+                let (key_family, key_index) = locator.split_once(':').ok_or_else(invalid)?;
+                Ok(PayerKeyStrategy::LndKey {
+                    key_family: key_family.parse().map_err(|_| invalid())?,
+                    key_index: key_index.parse().map_err(|_| invalid())?,
+                })
+            }
+        }
+    }
+
+    /// Returns the strategy that another of our LND nodes should use. With the per-offer strategy,
+    /// each node needs its own seed, or two nodes paying the same offer would sign with the same
+    /// key and could be linked. The seed is loaded from (or created at) seed_path, or if there's
+    /// nowhere to keep it, only kept in memory. The other strategies use keys held by each node's
+    /// LND, or one-off keys, so they can be shared as they are.
+    pub fn for_node(&self, seed_path: Option<&Path>) -> Result<Self, PayerKeyError> {
+        match self {
+            PayerKeyStrategy::PerOffer(_) => {
+                let seed = match seed_path {
+                    Some(seed_path) => PayerSeed::load_or_create(seed_path)?,
+                    None => PayerSeed::new(MessengerUtilities::new().get_secure_random_bytes()),
+                };
+                Ok(PayerKeyStrategy::PerOffer(seed))
+            }
+            strategy => Ok(strategy.clone()),
+        }
+    }
+}
+
+/// PayerKey records which key signed an invoice request, so that we can sign with the same key
+/// again later on, e.g. to prove that we paid.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayerKey {
+    /// A transient key derived by LDK, which can't be recovered.
+    Transient,
+    /// A key derived from our payer seed for the offer that we paid.
+    PerOffer,
+    /// A key held by LND at this key locator.
+    Lnd { key_family: i32, key_index: i32 },
+}
+
+/// PayerSeed is the secret we derive per-offer payer keys from.
+#[derive(Clone, PartialEq)]
+pub struct PayerSeed([u8; 32]);
+
+impl PayerSeed {
+    pub fn new(seed: [u8; 32]) -> Self {
+        PayerSeed(seed)
+    }
+
+    /// Reads the seed from the file at path, or if there isn't one yet, generates a new seed and
+    /// writes it there. The file is only readable by the current user.
+    pub fn load_or_create(path: &Path) -> Result<Self, PayerKeyError> {
+        if path.exists() {
+            let bytes = fs::read(path).map_err(PayerKeyError::Io)?;
+            let seed = bytes.try_into().map_err(|_| PayerKeyError::InvalidSeed)?;
+            return Ok(PayerSeed(seed));
+        }
+
+        let seed = MessengerUtilities::new().get_secure_random_bytes();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(PayerKeyError::Io)?;
+        file.write_all(&seed).map_err(PayerKeyError::Io)?;
+        file.sync_all().map_err(PayerKeyError::Io)?;
+
+        Ok(PayerSeed(seed))
+    }
+
+    /// Derives the payer key that we use for the given offer.
+    pub fn derive_key<C: Signing>(&self, offer: &Offer, secp_ctx: &Secp256k1<C>) -> KeyPair {
+        let mut engine = Hash::engine();
+        engine.input(PAYER_KEY_TAG);
+        engine.input(&self.0);
+        engine.input(&offer.encode());
+        let secret = Hash::from_engine(engine);
+
+        // The odds of a hash not being a valid secret key are negligible.
+        let secret_key =
+            SecretKey::from_slice(secret.as_byte_array()).expect("hash is a valid secret key");
+        KeyPair::from_secret_key(secp_ctx, &secret_key)
+    }
+}
+
+// We don't want the seed to end up in the logs.
+impl std::fmt::Debug for PayerSeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PayerSeed(..)")
+    }
+}
+
+#[derive(Debug)]
+/// PayerKeyError is an error that occurs while setting up our payer keys.
+pub enum PayerKeyError {
+    /// User provided a payer key strategy we couldn't parse.
+    InvalidStrategy(String),
+    /// Failed to read or write the payer seed.
+    Io(std::io::Error),
+    /// The payer seed file doesn't hold a valid seed.
+    InvalidSeed,
+}
+
+impl Display for PayerKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayerKeyError::InvalidStrategy(s) => write!(
+                f,
+                "Invalid payer key strategy '{s}', expected one of 'lnd-next-key', 'transient', \
+                'per-offer' or 'lnd-key:<key_family>:<key_index>'"
+            ),
+            PayerKeyError::Io(e) => write!(f, "IO error: {e:?}"),
+            PayerKeyError::InvalidSeed => write!(f, "Payer seed file should hold 32 bytes"),
+        }
+    }
+}
+
+impl Error for PayerKeyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixtures::build_offer;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_strategy_from_config() {
+        let dir = tempdir().unwrap();
+        let seed_path = dir.path().join("payer_seed");
+
+        assert_eq!(
+            PayerKeyStrategy::from_config("lnd-next-key", &seed_path).unwrap(),
+            PayerKeyStrategy::LndNextKey
+        );
+        assert_eq!(
+            PayerKeyStrategy::from_config("transient", &seed_path).unwrap(),
+            PayerKeyStrategy::Transient
+        );
+        assert_eq!(
+            PayerKeyStrategy::from_config("lnd-key:3:7", &seed_path).unwrap(),
+            PayerKeyStrategy::LndKey {
+                key_family: 3,
+                key_index: 7
+            }
+        );
+        // We only create the seed if we need it.
+        assert!(!seed_path.exists());
+
+        for invalid in ["", "random", "lnd-key:3", "lnd-key:a:1", "lnd-key:1:2:3"] {
+            assert!(matches!(
+                PayerKeyStrategy::from_config(invalid, &seed_path),
+                Err(PayerKeyError::InvalidStrategy(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_per_offer_keys() {
+        let dir = tempdir().unwrap();
+        let seed_path = dir.path().join("payer_seed");
+        let secp_ctx = Secp256k1::new();
+
+        let strategy = PayerKeyStrategy::from_config("per-offer", &seed_path).unwrap();
+        let seed = match strategy {
+            PayerKeyStrategy::PerOffer(seed) => seed,
+            _ => panic!("unexpected strategy"),
+        };
+        assert!(seed_path.exists());
+
+        // The key for an offer stays the same, including after we reload the seed, but each offer
+        // gets its own key.
+        let coffee = build_offer("coffee");
+        let key = seed.derive_key(&coffee, &secp_ctx);
+        assert_eq!(seed.derive_key(&coffee, &secp_ctx), key);
+
+        let reloaded = PayerSeed::load_or_create(&seed_path).unwrap();
+        assert_eq!(reloaded, seed);
+        assert_eq!(reloaded.derive_key(&coffee, &secp_ctx), key);
+
+        let tea = build_offer("tea");
+        assert_ne!(seed.derive_key(&tea, &secp_ctx), key);
+
+        // A different seed gives us different keys.
+        let other_seed = PayerSeed::new([9; 32]);
+        assert_ne!(other_seed.derive_key(&coffee, &secp_ctx), key);
+    }
+
+    #[test]
+    fn test_strategy_for_node() {
+        let dir = tempdir().unwrap();
+        let seed_path = dir.path().join("payer_seed");
+        let node_seed_path = dir.path().join("node_payer_seed");
+
+        let strategy = PayerKeyStrategy::from_config("per-offer", &seed_path).unwrap();
+        let node_strategy = strategy.for_node(Some(&node_seed_path)).unwrap();
+        assert!(matches!(node_strategy, PayerKeyStrategy::PerOffer(_)));
+        assert_ne!(node_strategy, strategy);
+        assert!(node_seed_path.exists());
+
+        // The node keeps using the same seed once it's been created.
+        assert_eq!(
+            strategy.for_node(Some(&node_seed_path)).unwrap(),
+            node_strategy
+        );
+        assert_ne!(strategy.for_node(None).unwrap(), strategy);
+
+        let strategy = PayerKeyStrategy::LndKey {
+            key_family: 3,
+            key_index: 7,
+        };
+        assert_eq!(strategy.for_node(Some(&node_seed_path)).unwrap(), strategy);
+    }
+
+    #[test]
+    fn test_invalid_seed_file() {
+        let dir = tempdir().unwrap();
+        let seed_path = dir.path().join("payer_seed");
+        fs::write(&seed_path, [1; 16]).unwrap();
+
+        assert!(matches!(
+            PayerSeed::load_or_create(&seed_path),
+            Err(PayerKeyError::InvalidSeed)
+        ));
+    }
+}
@@ -0,0 +1,371 @@
+use crate::payer_key::PayerKey;
+use crate::{Bolt12InvoiceString, InvoiceDecodeError};
+use bitcoin::hashes::sha256::Hash;
+use bitcoin::hashes::Hash as _;
+use lightning::ln::channelmanager::PaymentId;
+use lightning::offers::invoice::Bolt12Invoice;
+use lightning::offers::invoice_request::InvoiceRequest;
+use lightning::offers::offer::Offer;
+use lightning::util::ser::Writeable;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+// The TLV types that make up the offer and invoice request portions of a BOLT 12 message. An
+// invoice request copies over its offer's records and an invoice copies over its invoice
+// request's records, so we can compare these byte for byte.
+const OFFER_TYPES: Range<u64> = 1..80;
+const INVOICE_REQUEST_TYPES: Range<u64> = 0..160;
+
+/// ProofOfPayment is a receipt for a BOLT 12 payment. The invoice is signed by the payee and
+/// commits to the payment hash, so holding the preimage proves that we paid it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProofOfPayment {
+    /// The bech32-encoded offer that we paid, if we paid an offer rather than an invoice that was
+    /// handed to us directly.
+    pub offer: Option<String>,
+    /// The hex-encoded invoice request that we sent to the offer creator, if we paid an offer.
+    pub invoice_request: Option<String>,
+    /// The hex-encoded signed invoice that we paid.
+    pub invoice: String,
+    /// The hex-encoded payment preimage.
+    pub payment_preimage: String,
+    /// When the payment completed, in seconds since the unix epoch.
+    pub paid_at: u64,
+    /// The key that signed our invoice request, so that we can sign with it again to show that we
+    /// were the payer. Proofs stored before we kept track of this don't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payer_key: Option<PayerKey>,
+}
+
+impl ProofOfPayment {
+    pub fn new(
+        offer: Option<&Offer>,
+        invoice_request: Option<&InvoiceRequest>,
+        invoice: &Bolt12Invoice,
+        payment_preimage: String,
+        paid_at: u64,
+    ) -> Self {
+        ProofOfPayment {
+            offer: offer.map(|offer| offer.to_string()),
+            invoice_request: invoice_request
+                .map(|invoice_request| hex::encode(invoice_request.encode())),
+            invoice: hex::encode(invoice.encode()),
+            payment_preimage,
+            paid_at,
+            payer_key: None,
+        }
+    }
+
+    /// verify checks that the proof holds together:
+    /// 1) The invoice is validly signed. If we have the offer and it sets a signing key, the
+    /// invoice must be signed with that key.
+    /// 2) The invoice (and invoice request, if we have it) was created for the offer.
+    /// 3) The preimage matches the invoice's payment hash.
+    pub fn verify(&self) -> Result<(), ProofError> {
+        // Parsing the invoice also checks its signature against the invoice's signing key. We
+        // accept bech32 invoices here too, since that's how other implementations share them.
+        let invoice = Bolt12Invoice::try_from(Bolt12InvoiceString(self.invoice.clone())).map_err(
+            |e| match e {
+                InvoiceDecodeError::InvalidInvoice(e) => {
+                    ProofError::InvalidInvoice(format!("{e:?}"))
+                }
+                _ => ProofError::InvalidEncoding("invoice".to_string()),
+            },
+        )?;
+        let invoice_bytes = invoice.encode();
+
+        if let Some(ref offer_str) = self.offer {
+            let offer = Offer::from_str(offer_str)
+                .map_err(|e| ProofError::InvalidOffer(format!("{e:?}")))?;
+            if let Some(signing_pubkey) = offer.signing_pubkey() {
+                if signing_pubkey != invoice.signing_pubkey() {
+                    return Err(ProofError::SigningKeyMismatch);
+                }
+            }
+
+            let offer_bytes = offer.encode();
+            if tlv_records(&offer_bytes, OFFER_TYPES)? != tlv_records(&invoice_bytes, OFFER_TYPES)?
+            {
+                return Err(ProofError::OfferMismatch);
+            }
+        }
+
+        if let Some(ref invoice_request_str) = self.invoice_request {
+            let invoice_request_bytes = decode_hex("invoice request", invoice_request_str)?;
+            InvoiceRequest::try_from(invoice_request_bytes.clone())
+                .map_err(|e| ProofError::InvalidInvoiceRequest(format!("{e:?}")))?;
+            if tlv_records(&invoice_request_bytes, INVOICE_REQUEST_TYPES)?
+                != tlv_records(&invoice_bytes, INVOICE_REQUEST_TYPES)?
+            {
+                return Err(ProofError::InvoiceRequestMismatch);
+            }
+        }
+
+        let preimage = decode_hex("payment preimage", &self.payment_preimage)?;
+        if Hash::hash(&preimage).to_byte_array() != invoice.payment_hash().0 {
+            return Err(ProofError::PreimageMismatch);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+/// ProofError is an error that occurs while verifying or storing a proof of payment.
+pub enum ProofError {
+    /// A field of the proof isn't valid hex.
+    InvalidEncoding(String),
+    /// The invoice couldn't be parsed, or its signature is invalid.
+    InvalidInvoice(String),
+    /// The invoice request couldn't be parsed, or its signature is invalid.
+    InvalidInvoiceRequest(String),
+    /// The offer couldn't be parsed.
+    InvalidOffer(String),
+    /// The invoice wasn't signed by the offer's signing key.
+    SigningKeyMismatch,
+    /// The invoice wasn't created for the offer.
+    OfferMismatch,
+    /// The invoice wasn't created in response to the invoice request.
+    InvoiceRequestMismatch,
+    /// The preimage doesn't match the invoice's payment hash.
+    PreimageMismatch,
+    /// Failed to read or write a stored proof.
+    Io(std::io::Error),
+    /// Failed to (de)serialize a stored proof.
+    Serialization(serde_json::Error),
+}
+
+impl Display for ProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProofError::InvalidEncoding(field) => write!(f, "The {field} is not valid hex"),
+            ProofError::InvalidInvoice(e) => write!(f, "Invalid invoice: {e}"),
+            ProofError::InvalidInvoiceRequest(e) => write!(f, "Invalid invoice request: {e}"),
+            ProofError::InvalidOffer(e) => write!(f, "Invalid offer: {e}"),
+            ProofError::SigningKeyMismatch => {
+                write!(f, "The invoice was not signed by the offer's signing key")
+            }
+            ProofError::OfferMismatch => write!(f, "The invoice was not created for the offer"),
+            ProofError::InvoiceRequestMismatch => write!(
+                f,
+                "The invoice was not created in response to the invoice request"
+            ),
+            ProofError::PreimageMismatch => {
+                write!(f, "The preimage does not match the invoice's payment hash")
+            }
+            ProofError::Io(e) => write!(f, "IO error: {e:?}"),
+            ProofError::Serialization(e) => write!(f, "Error serializing proof of payment: {e}"),
+        }
+    }
+}
+
+impl Error for ProofError {}
+
+/// ProofStore holds the proofs of payment for the payments we've made. If a directory is
+/// provided, each proof is also written to disk so that it's still available after a restart.
+#[derive(Default)]
+pub struct ProofStore {
+    proofs: Mutex<HashMap<PaymentId, ProofOfPayment>>,
+    dir: Option<PathBuf>,
+}
+
+impl ProofStore {
+    pub fn new(dir: Option<PathBuf>) -> Result<Self, ProofError> {
+        let mut proofs = HashMap::new();
+        if let Some(ref dir) = dir {
+            fs::create_dir_all(dir).map_err(ProofError::Io)?;
+            for entry in fs::read_dir(dir).map_err(ProofError::Io)? {
+                let path = entry.map_err(ProofError::Io)?.path();
+                if path.extension().map_or(true, |ext| ext != "json") {
+                    continue;
+                }
+
+                let payment_id = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| hex::decode(stem).ok())
+                    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok());
+                let payment_id = match payment_id {
+                    Some(payment_id) => PaymentId(payment_id),
+                    None => {
+                        error!("Skipping unrecognized file in proofs directory: {path:?}");
+                        continue;
+                    }
+                };
+
+                let contents = fs::read_to_string(&path).map_err(ProofError::Io)?;
+                let proof = serde_json::from_str(&contents).map_err(ProofError::Serialization)?;
+                proofs.insert(payment_id, proof);
+            }
+        }
+
+        Ok(ProofStore {
+            proofs: Mutex::new(proofs),
+            dir,
+        })
+    }
+
+    pub(crate) fn insert(
+        &self,
+        payment_id: PaymentId,
+        proof: ProofOfPayment,
+    ) -> Result<(), ProofError> {
+        if let Some(ref dir) = self.dir {
+            // Write to a temporary file first, so that we never leave a half-written proof behind.
+            let contents = serde_json::to_string(&proof).map_err(ProofError::Serialization)?;
+            let path = dir.join(format!("{}.json", hex::encode(payment_id.0)));
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, contents).map_err(ProofError::Io)?;
+            fs::rename(&tmp_path, path).map_err(ProofError::Io)?;
+        }
+
+        let mut proofs = self.proofs.lock().unwrap();
+        proofs.insert(payment_id, proof);
+
+        Ok(())
+    }
+
+    pub fn get(&self, payment_id: PaymentId) -> Option<ProofOfPayment> {
+        let proofs = self.proofs.lock().unwrap();
+        proofs.get(&payment_id).cloned()
+    }
+}
+
+fn decode_hex(field: &str, s: &str) -> Result<Vec<u8>, ProofError> {
+    hex::decode(s).map_err(|_| ProofError::InvalidEncoding(field.to_string()))
+}
+
+// Collects the raw TLV records in a BOLT 12 message whose types fall within the given range.
+fn tlv_records(bytes: &[u8], types: Range<u64>) -> Result<Vec<&[u8]>, ProofError> {
+    let malformed = || ProofError::InvalidEncoding("TLV stream".to_string());
+
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let start = pos;
+        let record_type = read_bigsize(bytes, &mut pos).ok_or_else(malformed)?;
+        let length = read_bigsize(bytes, &mut pos).ok_or_else(malformed)?;
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|length| pos.checked_add(length))
+            .filter(|end| *end <= bytes.len())
+            .ok_or_else(malformed)?;
+
+        if types.contains(&record_type) {
+            records.push(&bytes[start..end]);
+        }
+        pos = end;
+    }
+
+    Ok(records)
+}
+
+// Reads a BOLT 1 BigSize integer, advancing pos past it.
+fn read_bigsize(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let first = *bytes.get(*pos)?;
+    let width = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        _ => {
+            *pos += 1;
+            return Some(first as u64);
+        }
+    };
+
+    let value = bytes.get(*pos + 1..*pos + 1 + width)?;
+    *pos += 1 + width;
+    Some(value.iter().fold(0, |acc, byte| (acc << 8) | *byte as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixtures::{build_invoice, build_invoice_request, build_offer};
+    use lightning::ln::PaymentHash;
+
+    const PREIMAGE: [u8; 32] = [7; 32];
+
+    fn build_proof() -> ProofOfPayment {
+        let offer = build_offer("coffee");
+        let invoice_request = build_invoice_request(&offer);
+        let payment_hash = PaymentHash(Hash::hash(&PREIMAGE).to_byte_array());
+        let invoice = build_invoice(&invoice_request, payment_hash);
+        ProofOfPayment::new(
+            Some(&offer),
+            Some(&invoice_request),
+            &invoice,
+            hex::encode(PREIMAGE),
+            1_700_000_000,
+        )
+    }
+
+    #[test]
+    fn test_verify_proof() {
+        assert!(build_proof().verify().is_ok());
+
+        // We can still check a proof without the offer or invoice request.
+        let proof = ProofOfPayment {
+            offer: None,
+            invoice_request: None,
+            ..build_proof()
+        };
+        assert!(proof.verify().is_ok());
+    }
+
+    #[test]
+    fn test_verify_proof_wrong_preimage() {
+        let proof = ProofOfPayment {
+            payment_preimage: hex::encode([8; 32]),
+            ..build_proof()
+        };
+        assert!(matches!(proof.verify(), Err(ProofError::PreimageMismatch)));
+    }
+
+    #[test]
+    fn test_verify_proof_wrong_offer() {
+        // This offer has the same signing key, but the invoice wasn't created for it.
+        let proof = ProofOfPayment {
+            offer: Some(build_offer("tea").to_string()),
+            ..build_proof()
+        };
+        assert!(matches!(proof.verify(), Err(ProofError::OfferMismatch)));
+    }
+
+    #[test]
+    fn test_verify_proof_tampered_invoice() {
+        let mut proof = build_proof();
+        let mut invoice_bytes = hex::decode(&proof.invoice).unwrap();
+        // Flip a bit in the signature, which is the last record in the invoice.
+        let last = invoice_bytes.len() - 1;
+        invoice_bytes[last] ^= 1;
+        proof.invoice = hex::encode(invoice_bytes);
+        assert!(matches!(proof.verify(), Err(ProofError::InvalidInvoice(_))));
+    }
+
+    #[test]
+    fn test_proof_store_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let payment_id = PaymentId([3; 32]);
+        let proof = ProofOfPayment {
+            payer_key: Some(PayerKey::PerOffer),
+            ..build_proof()
+        };
+
+        let store = ProofStore::new(Some(dir.path().to_path_buf())).unwrap();
+        store.insert(payment_id, proof.clone()).unwrap();
+        assert_eq!(store.get(payment_id), Some(proof.clone()));
+
+        let store = ProofStore::new(Some(dir.path().to_path_buf())).unwrap();
+        assert_eq!(store.get(payment_id), Some(proof));
+        assert_eq!(store.get(PaymentId([4; 32])), None);
+    }
+}
@@ -160,7 +160,7 @@ impl<C: Clock> RateLimiter for TokenLimiter<C> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::test_utils::pubkey;
+    use crate::sim::fixtures::pubkey;
     use core::ops::SubAssign;
     use mockall::mock;
     use tokio::time::{Duration, Instant};
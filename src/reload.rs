@@ -0,0 +1,280 @@
+use crate::budget::SpendingLimits;
+use crate::tls::{CertificateGenFailure, TlsConfig};
+use crate::{
+    log_level as current_log_level, parse_log_level, set_log_level, OfferHandler,
+    DEFAULT_RESPONSE_INVOICE_TIMEOUT,
+};
+use log::info;
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// ReloadableSettings holds the settings that LNDK can change while it's running, without
+/// dropping the payments it's currently making.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReloadableSettings {
+    /// The level LNDK logs at, if LNDK manages its own logger. Changes are checked against the
+    /// level the logger is really using, so this doesn't need to be set for the settings LNDK
+    /// starts with.
+    pub log_level: Option<String>,
+    /// How long in seconds we wait for an offer's creator to respond with an invoice.
+    pub response_invoice_timeout: Option<u32>,
+    /// The limits that outgoing payments are checked against.
+    pub limits: SpendingLimits,
+    /// The TLS credentials LNDK's servers use, if they're running.
+    pub tls: Option<TlsConfig>,
+}
+
+/// LoadedConfig is LNDK's configuration as it was read when the user asked for a reload.
+#[derive(Clone, Debug, Default)]
+pub struct LoadedConfig {
+    /// The new values of the settings we can apply live.
+    pub settings: ReloadableSettings,
+    /// The names of any other settings whose values have changed since LNDK started.
+    pub changed_restart_settings: Vec<String>,
+}
+
+/// ConfigSource reads LNDK's configuration when the user asks for it to be reloaded.
+pub trait ConfigSource: Send + Sync {
+    fn load(&self) -> Result<LoadedConfig, ReloadError>;
+}
+
+/// ReloadReport lists the settings that changed in a reload.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    /// Settings that now have their new values.
+    pub applied: Vec<String>,
+    /// Settings that changed, but only take effect once LNDK is restarted.
+    pub requires_restart: Vec<String>,
+}
+
+/// ReloadError is an error that occurs when reloading LNDK's configuration. If a reload fails,
+/// none of the new settings are applied.
+#[derive(Debug)]
+pub enum ReloadError {
+    /// The configuration couldn't be read.
+    Load(String),
+    /// A setting has a value we can't use.
+    InvalidSetting { name: String, reason: String },
+    /// The new TLS credentials couldn't be generated.
+    TlsGen(CertificateGenFailure),
+    /// The new TLS credentials couldn't be read.
+    TlsRead(std::io::Error),
+}
+
+impl Display for ReloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReloadError::Load(e) => write!(f, "Error reading config: {e}"),
+            ReloadError::InvalidSetting { name, reason } => {
+                write!(f, "Invalid value for {name}: {reason}")
+            }
+            ReloadError::TlsGen(e) => write!(f, "Error generating TLS credentials: {e}"),
+            ReloadError::TlsRead(e) => write!(f, "Error reading TLS credentials: {e}"),
+        }
+    }
+}
+
+impl Error for ReloadError {}
+
+/// Reloader re-reads LNDK's configuration and applies the settings that can be changed live. It's
+/// triggered by SIGHUP or the ReloadConfig RPC.
+pub struct Reloader {
+    source: Arc<dyn ConfigSource>,
+    // The handlers of each of the nodes LNDK is running with.
+    handlers: Vec<Arc<OfferHandler>>,
+    // tls is used to pass a new TLS config on to the task that watches our credentials. It's only
+    // set if LNDK's servers are running.
+    tls: Option<watch::Sender<TlsConfig>>,
+    // current holds the settings we're running with, so that we can tell what changed.
+    current: Mutex<ReloadableSettings>,
+}
+
+impl Reloader {
+    pub fn new(
+        source: Arc<dyn ConfigSource>,
+        handlers: Vec<Arc<OfferHandler>>,
+        tls: Option<watch::Sender<TlsConfig>>,
+        current: ReloadableSettings,
+    ) -> Self {
+        Reloader {
+            source,
+            handlers,
+            tls,
+            current: Mutex::new(current),
+        }
+    }
+
+    /// Reads the configuration and applies any reloadable settings that changed. We check all of
+    /// the new settings before applying any of them, so that a mistake in the config doesn't leave
+    /// us running with only part of it.
+    pub fn reload(&self) -> Result<ReloadReport, ReloadError> {
+        let loaded = self.source.load()?;
+        let new = loaded.settings;
+
+        let log_level =
+            parse_log_level(new.log_level.as_deref()).map_err(|e| ReloadError::InvalidSetting {
+                name: "log_level".to_string(),
+                reason: e.to_string(),
+            })?;
+        if new.response_invoice_timeout == Some(0) {
+            return Err(ReloadError::InvalidSetting {
+                name: "response_invoice_timeout".to_string(),
+                reason: "must be more than 0 seconds".to_string(),
+            });
+        }
+
+        let mut current = self.current.lock().unwrap();
+        let tls_changed = self.tls.is_some() && new.tls.is_some() && new.tls != current.tls;
+        if tls_changed {
+            // We make sure the new credentials are usable, so that we can report a problem
+            // rather than leaving the servers to fail with it.
+            let tls = new.tls.as_ref().unwrap();
+            tls.prepare().map_err(ReloadError::TlsGen)?;
+            tls.read().map_err(ReloadError::TlsRead)?;
+        }
+
+        let mut report = ReloadReport {
+            applied: Vec::new(),
+            requires_restart: loaded.changed_restart_settings,
+        };
+
+        // We compare against the level we're really logging at, since the logger may have been
+        // set up before LNDK was started. If LNDK isn't managing the logger, there's nothing to do.
+        if current_log_level().is_some_and(|level| level != log_level) {
+            set_log_level(log_level);
+            report.applied.push("log_level".to_string());
+        }
+
+        if new.response_invoice_timeout != current.response_invoice_timeout {
+            let timeout = new
+                .response_invoice_timeout
+                .unwrap_or(DEFAULT_RESPONSE_INVOICE_TIMEOUT);
+            for handler in &self.handlers {
+                handler.set_response_invoice_timeout(timeout);
+            }
+            report.applied.push("response_invoice_timeout".to_string());
+        }
+
+        if new.limits != current.limits {
+            for handler in &self.handlers {
+                handler.budget.set_limits(new.limits.clone());
+            }
+            report.applied.push("spending_limits".to_string());
+        }
+
+        if let (true, Some(sender), Some(tls)) = (tls_changed, &self.tls, &new.tls) {
+            sender.send_replace(tls.clone());
+            report.applied.push("tls".to_string());
+        }
+
+        *current = new;
+
+        info!(
+            "Reloaded config. Applied: {:?}. Requires restart: {:?}.",
+            report.applied, report.requires_restart
+        );
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::Budget;
+
+    struct TestSource(Mutex<LoadedConfig>);
+
+    impl ConfigSource for TestSource {
+        fn load(&self) -> Result<LoadedConfig, ReloadError> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    fn reloader(source: Arc<TestSource>) -> (Reloader, Arc<OfferHandler>) {
+        let handler = Arc::new(OfferHandler::new(None).with_budget(Budget::default()));
+        let reloader = Reloader::new(
+            source,
+            vec![Arc::clone(&handler)],
+            None,
+            ReloadableSettings::default(),
+        );
+        (reloader, handler)
+    }
+
+    #[test]
+    fn test_reload() {
+        let source = Arc::new(TestSource(Mutex::new(LoadedConfig::default())));
+        let (reloader, handler) = reloader(Arc::clone(&source));
+
+        // Nothing has changed yet.
+        assert_eq!(reloader.reload().unwrap(), ReloadReport::default());
+
+        let limits = SpendingLimits {
+            max_payment_msats: Some(1000),
+            ..Default::default()
+        };
+        *source.0.lock().unwrap() = LoadedConfig {
+            settings: ReloadableSettings {
+                response_invoice_timeout: Some(30),
+                limits: limits.clone(),
+                ..Default::default()
+            },
+            changed_restart_settings: vec!["grpc_port".to_string()],
+        };
+        assert_eq!(
+            reloader.reload().unwrap(),
+            ReloadReport {
+                applied: vec![
+                    "response_invoice_timeout".to_string(),
+                    "spending_limits".to_string()
+                ],
+                requires_restart: vec!["grpc_port".to_string()],
+            }
+        );
+        assert_eq!(handler.response_invoice_timeout(), 30);
+        assert_eq!(handler.budget.limits(), limits);
+
+        // Unsetting the timeout takes us back to the default.
+        source.0.lock().unwrap().settings.response_invoice_timeout = None;
+        assert_eq!(
+            reloader.reload().unwrap().applied,
+            vec!["response_invoice_timeout".to_string()]
+        );
+        assert_eq!(
+            handler.response_invoice_timeout(),
+            DEFAULT_RESPONSE_INVOICE_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn test_reload_invalid() {
+        let source = Arc::new(TestSource(Mutex::new(LoadedConfig::default())));
+        let (reloader, handler) = reloader(Arc::clone(&source));
+
+        // An invalid setting means none of the others are applied either.
+        source.0.lock().unwrap().settings = ReloadableSettings {
+            log_level: Some("loud".to_string()),
+            response_invoice_timeout: Some(30),
+            ..Default::default()
+        };
+        assert!(matches!(
+            reloader.reload(),
+            Err(ReloadError::InvalidSetting { name, .. }) if name == "log_level"
+        ));
+        assert_eq!(
+            handler.response_invoice_timeout(),
+            DEFAULT_RESPONSE_INVOICE_TIMEOUT
+        );
+
+        source.0.lock().unwrap().settings = ReloadableSettings {
+            response_invoice_timeout: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            reloader.reload(),
+            Err(ReloadError::InvalidSetting { name, .. }) if name == "response_invoice_timeout"
+        ));
+    }
+}
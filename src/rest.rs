@@ -0,0 +1,409 @@
+use crate::lndkrpc::offers_server::Offers;
+use crate::server::{LNDKServer, NODE_METADATA_KEY};
+use crate::tls::TlsCreds;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, StatusCode};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::sync::watch;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::{Code, Request, Response, Status};
+use triggered::Listener;
+
+/// The header REST clients pass the hex-encoded macaroon in. This is the same header that LND's
+/// REST proxy uses, so clients can reuse the code they use to talk to LND.
+pub const MACAROON_HEADER: &str = "Grpc-Metadata-macaroon";
+
+/// The header REST clients use to pick which of LNDK's nodes a request is for, if LNDK is running
+/// with more than one.
+pub const NODE_HEADER: &str = "Grpc-Metadata-node";
+
+// The most we read of a request body. Our requests are small, so this leaves plenty of room for
+// large invoices while stopping clients from making us buffer as much as they like.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+// The headers we pass on to LNDKServer as gRPC metadata, with the metadata key for each.
+const METADATA_HEADERS: [(&str, &str); 2] = [
+    (MACAROON_HEADER, "macaroon"),
+    (NODE_HEADER, NODE_METADATA_KEY),
+];
+
+/// An error that occurs when starting the REST server.
+#[derive(Debug)]
+pub enum RestError {
+    /// Binding to the address or accepting connections failed.
+    IoError(std::io::Error),
+    /// The PEM-encoded TLS credentials couldn't be parsed.
+    InvalidPem(std::io::Error),
+    /// The TLS credentials don't contain a private key.
+    MissingKey,
+    /// The TLS credentials were rejected.
+    TlsError(tokio_rustls::rustls::Error),
+    /// The CA certificate for verifying clients was rejected.
+    ClientCaError(String),
+}
+
+impl Display for RestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestError::IoError(e) => write!(f, "IO error: {e}"),
+            RestError::InvalidPem(e) => write!(f, "Invalid PEM in TLS credentials: {e}"),
+            RestError::MissingKey => write!(f, "No private key found in TLS key file"),
+            RestError::TlsError(e) => write!(f, "Invalid TLS credentials: {e}"),
+            RestError::ClientCaError(e) => write!(f, "Invalid client CA certificate: {e}"),
+        }
+    }
+}
+
+impl Error for RestError {}
+
+/// Serves the Offers service as JSON over HTTP until shutdown, for clients that can't easily speak
+/// gRPC. Each operation is exposed as a POST endpoint that takes the gRPC request as a JSON body
+/// and responds with the gRPC response as JSON, so both interfaces share LNDKServer's logic. When
+/// the TLS credentials change, new connections use the new credentials.
+pub async fn serve_rest(
+    server: Arc<LNDKServer>,
+    addr: SocketAddr,
+    mut creds: watch::Receiver<TlsCreds>,
+    listener: Listener,
+) -> Result<(), RestError> {
+    let tcp_listener = TcpListener::bind(addr).await.map_err(RestError::IoError)?;
+    let mut acceptor = tls_acceptor(&creds.borrow_and_update())?;
+
+    loop {
+        select! {
+            _ = listener.clone() => return Ok(()),
+            Ok(()) = creds.changed() => {
+                match tls_acceptor(&creds.borrow_and_update()) {
+                    Ok(new_acceptor) => acceptor = new_acceptor,
+                    Err(e) => warn!("Error reloading TLS credentials for the REST server: {e}."),
+                }
+            }
+            accepted = tcp_listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Error accepting REST connection: {e}.");
+                        continue;
+                    }
+                };
+                let (acceptor, server) = (acceptor.clone(), Arc::clone(&server));
+                tokio::spawn(async move {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            debug!("TLS handshake with REST client failed: {e}.");
+                            return;
+                        }
+                    };
+                    let service =
+                        service_fn(move |request| handle_request(Arc::clone(&server), request));
+                    if let Err(e) = Http::new().serve_connection(stream, service).await {
+                        debug!("Error serving REST connection: {e}.");
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn tls_acceptor(creds: &TlsCreds) -> Result<TlsAcceptor, RestError> {
+    let certs = rustls_pemfile::certs(&mut creds.cert.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(RestError::InvalidPem)?;
+    let key = rustls_pemfile::private_key(&mut creds.key.as_bytes())
+        .map_err(RestError::InvalidPem)?
+        .ok_or(RestError::MissingKey)?;
+
+    let builder = ServerConfig::builder();
+    let config = match &creds.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut client_ca.as_bytes()) {
+                roots
+                    .add(cert.map_err(RestError::InvalidPem)?)
+                    .map_err(|e| RestError::ClientCaError(e.to_string()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| RestError::ClientCaError(e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    }
+    .with_single_cert(certs, key)
+    .map_err(RestError::TlsError)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+async fn handle_request(
+    server: Arc<LNDKServer>,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    if request.method() != Method::POST {
+        return Ok(error_response(Status::unimplemented(
+            "Only POST requests are supported",
+        )));
+    }
+
+    let path = request.uri().path().to_string();
+    let metadata: Vec<_> = METADATA_HEADERS
+        .iter()
+        .filter_map(|(header, key)| Some((*key, request.headers().get(*header)?.clone())))
+        .collect();
+    let body = match read_body(request.into_body()).await {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+
+    let server = server.as_ref();
+    let response = match path.as_str() {
+        "/v1/offers/pay" => call(&body, &metadata, |r| server.pay_offer(r)).await,
+        "/v1/offers/invoice" => call(&body, &metadata, |r| server.get_invoice(r)).await,
+        "/v1/offers/decode" => call(&body, &metadata, |r| server.decode_offer(r)).await,
+        "/v1/invoices/pay" => call(&body, &metadata, |r| server.pay_invoice(r)).await,
+        "/v1/invoices/decode" => call(&body, &metadata, |r| server.decode_invoice(r)).await,
+        "/v1/budget" => call(&body, &metadata, |r| server.get_budget(r)).await,
+        "/v1/metrics" => call(&body, &metadata, |r| server.get_metrics(r)).await,
+        "/v1/payments/get" => call(&body, &metadata, |r| server.get_payment(r)).await,
+        "/v1/payments/cancel" => call(&body, &metadata, |r| server.cancel_payment(r)).await,
+        "/v1/proofs/get" => call(&body, &metadata, |r| server.get_proof_of_payment(r)).await,
+        "/v1/proofs/verify" => call(&body, &metadata, |r| server.verify_proof_of_payment(r)).await,
+        "/v1/config/reload" => call(&body, &metadata, |r| server.reload_config(r)).await,
+        _ => error_response(Status::not_found(format!("Unknown path {path}"))),
+    };
+
+    Ok(response)
+}
+
+// Reads the request body, up to MAX_BODY_BYTES. If the body can't be read or is too large, the
+// error response to send is returned instead.
+async fn read_body(mut body: Body) -> Result<Vec<u8>, hyper::Response<Body>> {
+    let too_large = || {
+        let mut response = error_response(Status::invalid_argument(format!(
+            "Request body is larger than {MAX_BODY_BYTES} bytes"
+        )));
+        *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+        response
+    };
+
+    // If the client tells us how large the body is, we don't need to read any of it to turn it
+    // away.
+    if body.size_hint().lower() > MAX_BODY_BYTES as u64 {
+        return Err(too_large());
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            error_response(Status::invalid_argument(format!(
+                "Error reading request body: {e}"
+            )))
+        })?;
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+// Decodes the JSON body into the gRPC request, passing the given headers on as gRPC metadata, and
+// encodes the result of the call as JSON.
+async fn call<Req, Resp, F, Fut>(
+    body: &[u8],
+    metadata: &[(&'static str, HeaderValue)],
+    rpc: F,
+) -> hyper::Response<Body>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    F: FnOnce(Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Resp>, Status>>,
+{
+    // Requests without any fields may be sent without a body.
+    let body = if body.is_empty() { &b"{}"[..] } else { body };
+    let message = match serde_json::from_slice(body) {
+        Ok(message) => message,
+        Err(e) => {
+            return error_response(Status::invalid_argument(format!(
+                "Invalid request body: {e}"
+            )))
+        }
+    };
+
+    let mut request = Request::new(message);
+    for (key, value) in metadata {
+        let value: MetadataValue<Ascii> = match value.to_str().ok().and_then(|v| v.parse().ok()) {
+            Some(value) => value,
+            None => {
+                return error_response(Status::invalid_argument(format!(
+                    "Invalid {key} header provided"
+                )))
+            }
+        };
+        request.metadata_mut().insert(*key, value);
+    }
+
+    match rpc(request).await {
+        Ok(response) => json_response(StatusCode::OK, serde_json::to_value(response.get_ref())),
+        Err(status) => error_response(status),
+    }
+}
+
+// Errors use the same format as LND's REST proxy, with the gRPC code mapped to the closest HTTP
+// status.
+fn error_response(status: Status) -> hyper::Response<Body> {
+    let body = json!({
+        "code": status.code() as i32,
+        "message": status.message(),
+        "details": [],
+    });
+
+    json_response(http_status(status.code()), Ok(body))
+}
+
+fn json_response(
+    status: StatusCode,
+    body: Result<serde_json::Value, serde_json::Error>,
+) -> hyper::Response<Body> {
+    let (status, body) = match body {
+        Ok(body) => (status, body),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({
+                "code": Code::Internal as i32,
+                "message": format!("Error encoding response: {e}"),
+                "details": [],
+            }),
+        ),
+    };
+
+    let mut response = hyper::Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Cancelled => StatusCode::from_u16(499).unwrap(),
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lndkrpc::{GetBudgetRequest, GetBudgetResponse};
+
+    async fn body_json(response: hyper::Response<Body>) -> serde_json::Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_call() {
+        // The macaroon header should be passed on as metadata, and the response encoded as JSON.
+        let response = call(
+            br#"{"issuer": "02ab"}"#,
+            &[("macaroon", HeaderValue::from_static("0201"))],
+            |request: Request<GetBudgetRequest>| async move {
+                assert_eq!(request.metadata().get("macaroon").unwrap(), "0201");
+                assert_eq!(request.get_ref().issuer, Some("02ab".to_string()));
+                Ok::<_, Status>(Response::new(GetBudgetResponse::default()))
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_json(response).await,
+            serde_json::to_value(GetBudgetResponse::default()).unwrap()
+        );
+
+        // Requests without a body should decode as empty requests.
+        let response = call(b"", &[], |request: Request<GetBudgetRequest>| async move {
+            assert!(request.metadata().get("macaroon").is_none());
+            Ok::<_, Status>(Response::new(GetBudgetResponse::default()))
+        })
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let body = read_body(Body::from(r#"{"issuer": "02ab"}"#))
+            .await
+            .unwrap();
+        assert_eq!(body, br#"{"issuer": "02ab"}"#);
+
+        // Bodies that are known to be too large up front are turned away without being read.
+        let response = read_body(Body::from(vec![b'a'; MAX_BODY_BYTES + 1]))
+            .await
+            .unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            body_json(response).await["code"],
+            Code::InvalidArgument as i32
+        );
+
+        // As are streamed bodies, once they go over the limit.
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..=MAX_BODY_BYTES / 1024 {
+                if sender.send_data(vec![b'a'; 1024].into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let response = read_body(body).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_call_errors() {
+        let rpc = |_: Request<GetBudgetRequest>| async move {
+            Err::<Response<GetBudgetResponse>, _>(Status::unauthenticated("no macaroon"))
+        };
+        let response = call(b"", &[], rpc).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = body_json(response).await;
+        assert_eq!(body["code"], Code::Unauthenticated as i32);
+        assert_eq!(body["message"], "no macaroon");
+
+        let response = call(b"not json", &[], rpc).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
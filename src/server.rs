@@ -1,58 +1,112 @@
 use crate::lnd::{get_lnd_client, get_network, Creds, LndCfg};
-use crate::lndk_offers::{get_destination, validate_amount};
+use crate::lndk_offers::{
+    get_destination, payment_id_from_idempotency_key, validate_amount, PaymentParams,
+};
+use crate::logging::{Correlation, Sensitive};
+use crate::proof::ProofOfPayment;
+use crate::reload::{ReloadError, Reloader};
+use crate::tls::TlsCreds;
 use crate::{
-    lndkrpc, Bolt12InvoiceString, OfferError, OfferHandler, PayOfferParams, TLS_CERT_FILENAME,
-    TLS_KEY_FILENAME,
+    lndkrpc, Bolt12InvoiceString, InvoiceEncoding, OfferError, OfferHandler, PayOfferParams,
+    PaymentMetrics, PaymentResult, PaymentState, PaymentStatus,
 };
+use bitcoin::blockdata::constants::ChainHash;
+use bitcoin::network::constants::Network;
 use bitcoin::secp256k1::PublicKey;
 use lightning::blinded_path::{BlindedPath, Direction, IntroductionNode};
 use lightning::ln::channelmanager::PaymentId;
-use lightning::ln::features::{BlindedHopFeatures, Bolt12InvoiceFeatures};
+use lightning::ln::features::{BlindedHopFeatures, Bolt12InvoiceFeatures, OfferFeatures};
 use lightning::offers::invoice::{BlindedPayInfo, Bolt12Invoice};
-use lightning::offers::offer::Offer;
+use lightning::offers::offer::{Amount, Offer, Quantity};
 use lightning::sign::EntropySource;
 use lightning::util::ser::Writeable;
-use lndkrpc::offers_server::Offers;
+use lndkrpc::offers_server::{Offers, OffersServer};
 use lndkrpc::{
-    Bolt12InvoiceContents, DecodeInvoiceRequest, FeatureBit, GetInvoiceRequest, GetInvoiceResponse,
-    PayInvoiceRequest, PayInvoiceResponse, PayOfferRequest, PayOfferResponse, PaymentHash,
-    PaymentPaths,
+    Bolt12InvoiceContents, CancelPaymentRequest, CancelPaymentResponse, CurrencyAmount,
+    DecodeInvoiceRequest, DecodeOfferRequest, FeatureBit, GetBudgetRequest, GetBudgetResponse,
+    GetInvoiceRequest, GetInvoiceResponse, GetMetricsRequest, GetMetricsResponse,
+    GetPaymentRequest, GetPaymentResponse, GetProofOfPaymentRequest, NodeMetrics, OfferContents,
+    PayInvoiceRequest, PayInvoiceResponse, PayOfferRequest, PayOfferResponse, PaymentCounts,
+    PaymentHash, PaymentPaths, ReloadConfigRequest, ReloadConfigResponse,
+    VerifyProofOfPaymentResponse,
 };
-use rcgen::{generate_simple_self_signed, CertifiedKey, Error as RcgenError};
-use std::error::Error;
-use std::fmt::Display;
-use std::fs::{metadata, set_permissions, File};
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
+use tokio::select;
+use tokio::sync::watch;
 use tonic::metadata::MetadataMap;
-use tonic::transport::Identity;
+use tonic::transport::Server;
 use tonic::{Request, Response, Status};
-use tonic_lnd::lnrpc::GetInfoRequest;
+use tonic_lnd::lnrpc::{CheckMacPermRequest, GetInfoRequest, MacaroonPermission};
+use triggered::Listener;
+
+/// The metadata key that clients use to pick which of LNDK's nodes a request is for, set to the
+/// node's pubkey or alias. It only needs to be set if LNDK is running with more than one node.
+pub const NODE_METADATA_KEY: &str = "node";
+
+// The LND permissions a macaroon needs to look up payments, which include their preimages, and
+// how much we've spent.
+const READ_PAYMENTS: [(&str, &str); 1] = [("offchain", "read")];
+// The LND permissions a macaroon needs to cancel payments.
+const WRITE_PAYMENTS: [(&str, &str); 1] = [("offchain", "write")];
+// The LND permissions a macaroon needs to reload LNDK's config. Out of LND's default macaroons,
+// only admin.macaroon has them.
+const ADMIN: [(&str, &str); 2] = [("info", "write"), ("macaroon", "generate")];
+
+/// ServerNode is one of the LND nodes that LNDK's API makes payments from.
+pub struct ServerNode {
+    pub offer_handler: Arc<OfferHandler>,
+    pub node_id: PublicKey,
+    /// The node's alias, which clients can use to pick it instead of its pubkey.
+    pub alias: Option<String>,
+    /// The LND tls cert we need to establish a connection with LND.
+    pub lnd_cert: String,
+    pub address: String,
+}
+
 pub struct LNDKServer {
-    offer_handler: Arc<OfferHandler>,
-    node_id: PublicKey,
-    // The LND tls cert we need to establish a connection with LND.
-    lnd_cert: String,
-    address: String,
+    nodes: Vec<ServerNode>,
+    // The network our LND nodes are running on.
+    network: Network,
+    // reloader re-reads LNDK's config for the ReloadConfig call. If it isn't set, LNDK's config
+    // can't be reloaded.
+    reloader: Option<Arc<Reloader>>,
 }
 
 impl LNDKServer {
     pub async fn new(
         offer_handler: Arc<OfferHandler>,
         node_id: &str,
+        network: Network,
         lnd_cert: String,
         address: String,
     ) -> Self {
-        Self {
+        let node = ServerNode {
             offer_handler,
             node_id: PublicKey::from_str(node_id).unwrap(),
+            alias: None,
             lnd_cert,
             address,
+        };
+        Self::for_nodes(vec![node], network)
+    }
+
+    /// Creates a server that makes payments from any of the given nodes, which must all be on the
+    /// same network. Clients pick a node with the node metadata key (see NODE_METADATA_KEY).
+    pub fn for_nodes(nodes: Vec<ServerNode>, network: Network) -> Self {
+        Self {
+            nodes,
+            network,
+            reloader: None,
         }
     }
+
+    /// Lets clients reload LNDK's config with the ReloadConfig call.
+    pub fn with_reloader(mut self, reloader: Arc<Reloader>) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
 }
 
 #[tonic::async_trait]
@@ -61,89 +115,236 @@ impl Offers for LNDKServer {
         &self,
         request: Request<PayOfferRequest>,
     ) -> Result<Response<PayOfferResponse>, Status> {
-        log::info!("Received a request: {:?}", request.get_ref());
+        // Everything logged while handling the request is tagged with a request id.
+        Correlation::new_request()
+            .scope(async move {
+                log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
 
-        let metadata = request.metadata();
-        let macaroon = check_auth_metadata(metadata)?;
-        let creds = Creds::String {
-            cert: self.lnd_cert.clone(),
-            macaroon,
-        };
-        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
-        let mut client = get_lnd_client(lnd_cfg)
-            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
+                let metadata = request.metadata();
+                let macaroon = check_auth_metadata(metadata)?;
+                let node = self.node(metadata)?;
+                let creds = Creds::String {
+                    cert: node.lnd_cert.clone(),
+                    macaroon,
+                };
+                let lnd_cfg = LndCfg::new(node.address.clone(), creds);
+                let mut client = get_lnd_client(lnd_cfg)
+                    .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
 
-        let inner_request = request.get_ref();
-        let offer = Offer::from_str(&inner_request.offer).map_err(|e| {
-            Status::invalid_argument(format!(
-                "The provided offer was invalid. Please provide a valid offer in bech32 format,
-                i.e. starting with 'lno'. Error: {e:?}"
-            ))
-        })?;
+                let inner_request = request.get_ref();
+                let offer = Offer::from_str(&inner_request.offer).map_err(|e| {
+                    Status::invalid_argument(format!(
+                        "The provided offer was invalid. Please provide a valid offer in bech32 format,
+                        i.e. starting with 'lno'. Error: {e:?}"
+                    ))
+                })?;
 
-        let destination = get_destination(&offer).await.map_err(|e| {
-            Status::internal(format!(
-                "Internal error: Couldn't get destination from offer: {e:?}"
-            ))
-        })?;
-        let reply_path = match self
-            .offer_handler
-            .create_reply_path(client.clone(), self.node_id)
-            .await
-        {
-            Ok(reply_path) => reply_path,
-            Err(e) => return Err(Status::internal(format!("Internal error: {e}"))),
-        };
+                let destination = get_destination(&offer).await.map_err(|e| {
+                    Status::internal(format!(
+                        "Internal error: Couldn't get destination from offer: {e:?}"
+                    ))
+                })?;
+                let payment_id = payment_id_from_request(
+                    &node.offer_handler,
+                    &inner_request.idempotency_key,
+                    &PaymentParams::Offer {
+                        offer: &offer,
+                        amount: inner_request.amount,
+                        payer_note: inner_request.payer_note.as_deref(),
+                    },
+                )?;
 
-        let info = client
-            .lightning()
-            .get_info(GetInfoRequest {})
-            .await
-            .expect("failed to get info")
-            .into_inner();
-        let network = get_network(info)
-            .await
-            .map_err(|e| Status::internal(format!("{e:?}")))?;
+                // A retry of a payment we already know about just attaches to it, so we don't need
+                // a reply path or the network from LND. We still check the caller's macaroon,
+                // since we're not making any LND calls on their behalf that would check it.
+                let (reply_path, network) =
+                    if node.offer_handler.payment_status(payment_id).is_some() {
+                        self.authorize(metadata, &WRITE_PAYMENTS).await?;
+                        (None, self.network)
+                    } else {
+                        let reply_path = match node
+                            .offer_handler
+                            .create_reply_path(client.clone(), node.node_id)
+                            .await
+                        {
+                            Ok(reply_path) => reply_path,
+                            Err(e) => return Err(Status::internal(format!("Internal error: {e}"))),
+                        };
 
-        let cfg = PayOfferParams {
-            offer,
-            amount: inner_request.amount,
-            payer_note: inner_request.payer_note.clone(),
-            network,
-            client,
-            destination,
-            reply_path: Some(reply_path),
-            response_invoice_timeout: inner_request.response_invoice_timeout,
-        };
+                        let info = client
+                            .lightning()
+                            .get_info(GetInfoRequest {})
+                            .await
+                            .expect("failed to get info")
+                            .into_inner();
+                        let network = get_network(info)
+                            .await
+                            .map_err(|e| Status::internal(format!("{e:?}")))?;
+                        (Some(reply_path), network)
+                    };
 
-        let payment = match self.offer_handler.pay_offer(cfg).await {
-            Ok(payment) => {
-                log::info!("Payment succeeded.");
-                payment
-            }
-            Err(e) => match e {
-                OfferError::InvalidAmount(e) => {
-                    return Err(Status::invalid_argument(e.to_string()))
+                let cfg = PayOfferParams {
+                    offer,
+                    amount: inner_request.amount,
+                    payer_note: inner_request.payer_note.clone(),
+                    network,
+                    client,
+                    destination,
+                    reply_path,
+                    response_invoice_timeout: inner_request.response_invoice_timeout,
+                    payment_id: Some(payment_id),
+                };
+
+                if inner_request.no_wait {
+                    node.offer_handler
+                        .pay_offer_in_background(cfg)
+                        .await
+                        .map_err(pay_offer_error_status)?;
+                    log::info!("Invoice request sent, payment continuing in the background.");
+
+                    let reply = PayOfferResponse {
+                        payment_preimage: String::new(),
+                        payment_id: hex::encode(payment_id.0),
+                        proof: None,
+                    };
+                    return Ok(Response::new(reply));
                 }
-                OfferError::InvalidCurrency => {
-                    return Err(Status::invalid_argument(format!("{e}")))
+
+                // We make the payment in its own task so that it isn't cancelled part way through
+                // if the client goes away before it completes.
+                let handler = Arc::clone(&node.offer_handler);
+                let payment = Correlation::current().scope(async move {
+                    handler.pay_offer(cfg).await
+                });
+                let payment = tokio::spawn(payment)
+                    .await
+                    .map_err(|e| Status::internal(format!("Internal error: {e}")))?
+                    .map_err(pay_offer_error_status)?;
+                log::info!("Payment succeeded.");
+
+                let reply = PayOfferResponse {
+                    payment_preimage: payment.payment_preimage,
+                    payment_id: hex::encode(payment_id.0),
+                    proof: node
+                        .offer_handler
+                        .proof_of_payment(payment_id)
+                        .map(proof_to_proto),
+                };
+
+                Ok(Response::new(reply))
+            })
+            .await
+    }
+
+    async fn get_payment(
+        &self,
+        request: Request<GetPaymentRequest>,
+    ) -> Result<Response<GetPaymentResponse>, Status> {
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+        // The response may contain the payment preimage, so we make sure the caller is allowed to
+        // see it.
+        let node = self.authorize(request.metadata(), &READ_PAYMENTS).await?;
+
+        let payment_id = parse_payment_id(&request.get_ref().payment_id)?;
+
+        let reply = match node.offer_handler.payment_status(payment_id) {
+            Some(PaymentStatus::InProgress(state)) => GetPaymentResponse {
+                status: payment_state_to_proto(state).into(),
+                payment_preimage: None,
+                failure_reason: None,
+            },
+            Some(PaymentStatus::Completed(PaymentResult::Succeeded(payment))) => {
+                GetPaymentResponse {
+                    status: lndkrpc::PaymentStatus::Succeeded.into(),
+                    payment_preimage: Some(payment.payment_preimage),
+                    failure_reason: None,
                 }
-                _ => return Err(Status::internal(format!("Internal error: {e}"))),
+            }
+            Some(PaymentStatus::Completed(PaymentResult::Failed(reason))) => GetPaymentResponse {
+                status: lndkrpc::PaymentStatus::Failed.into(),
+                payment_preimage: None,
+                failure_reason: Some(reason),
             },
+            Some(PaymentStatus::Completed(PaymentResult::Cancelled)) => GetPaymentResponse {
+                status: lndkrpc::PaymentStatus::Cancelled.into(),
+                payment_preimage: None,
+                failure_reason: None,
+            },
+            None => {
+                return Err(Status::not_found(format!(
+                    "No payment found with id {payment_id}"
+                )))
+            }
         };
 
-        let reply = PayOfferResponse {
-            payment_preimage: payment.payment_preimage,
+        Ok(Response::new(reply))
+    }
+
+    async fn get_proof_of_payment(
+        &self,
+        request: Request<GetProofOfPaymentRequest>,
+    ) -> Result<Response<lndkrpc::ProofOfPayment>, Status> {
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+        // The proof contains the payment preimage, so we make sure the caller is allowed to see
+        // it.
+        let node = self.authorize(request.metadata(), &READ_PAYMENTS).await?;
+        let payment_id = parse_payment_id(&request.get_ref().payment_id)?;
+
+        match node.offer_handler.proof_of_payment(payment_id) {
+            Some(proof) => Ok(Response::new(proof_to_proto(proof))),
+            None => Err(Status::not_found(format!(
+                "No proof of payment found for payment {payment_id}"
+            ))),
+        }
+    }
+
+    async fn verify_proof_of_payment(
+        &self,
+        request: Request<lndkrpc::ProofOfPayment>,
+    ) -> Result<Response<VerifyProofOfPaymentResponse>, Status> {
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+        let proof = proof_from_proto(request.into_inner());
+        let reply = match proof.verify() {
+            Ok(()) => VerifyProofOfPaymentResponse {
+                valid: true,
+                error: None,
+            },
+            Err(e) => VerifyProofOfPaymentResponse {
+                valid: false,
+                error: Some(e.to_string()),
+            },
         };
 
         Ok(Response::new(reply))
     }
 
+    async fn cancel_payment(
+        &self,
+        request: Request<CancelPaymentRequest>,
+    ) -> Result<Response<CancelPaymentResponse>, Status> {
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+        let node = self.authorize(request.metadata(), &WRITE_PAYMENTS).await?;
+        let payment_id = parse_payment_id(&request.get_ref().payment_id)?;
+
+        match node.offer_handler.cancel_payment(payment_id) {
+            Ok(()) => Ok(Response::new(CancelPaymentResponse {})),
+            Err(e @ OfferError::PaymentNotFound(_)) => Err(Status::not_found(format!("{e}"))),
+            Err(e @ OfferError::AlreadyDispatched(_)) => {
+                Err(Status::failed_precondition(format!("{e}")))
+            }
+            Err(e) => Err(Status::internal(format!("Internal error: {e}"))),
+        }
+    }
+
     async fn decode_invoice(
         &self,
         request: Request<DecodeInvoiceRequest>,
     ) -> Result<Response<Bolt12InvoiceContents>, Status> {
-        log::info!("Received a request: {:?}", request.get_ref());
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
 
         let invoice_string: Bolt12InvoiceString = request.get_ref().invoice.clone().into();
         let invoice = Bolt12Invoice::try_from(invoice_string)
@@ -153,140 +354,477 @@ impl Offers for LNDKServer {
         Ok(Response::new(reply))
     }
 
-    async fn get_invoice(
+    async fn decode_offer(
         &self,
-        request: Request<GetInvoiceRequest>,
-    ) -> Result<Response<GetInvoiceResponse>, Status> {
-        log::info!("Received a request: {:?}", request.get_ref());
+        request: Request<DecodeOfferRequest>,
+    ) -> Result<Response<OfferContents>, Status> {
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
 
-        let metadata = request.metadata();
-        let macaroon = check_auth_metadata(metadata)?;
-        let creds = Creds::String {
-            cert: self.lnd_cert.clone(),
-            macaroon,
-        };
-        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
-        let mut client = get_lnd_client(lnd_cfg)
-            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
-
-        let inner_request = request.get_ref();
-        let offer = Offer::from_str(&inner_request.offer).map_err(|e| {
+        let offer = Offer::from_str(&request.get_ref().offer).map_err(|e| {
             Status::invalid_argument(format!(
                 "The provided offer was invalid. Please provide a valid offer in bech32 format,
                 i.e. starting with 'lno'. Error: {e:?}"
             ))
         })?;
 
-        let destination = get_destination(&offer)
-            .await
-            .map_err(|e| Status::unavailable(format!("Couldn't find destination: {e}")))?;
-        let reply_path = match self
-            .offer_handler
-            .create_reply_path(client.clone(), self.node_id)
-            .await
-        {
-            Ok(reply_path) => reply_path,
-            Err(e) => return Err(Status::internal(format!("Internal error: {e}"))),
-        };
+        let reply = generate_offer_contents(&offer, self.network);
+        Ok(Response::new(reply))
+    }
+
+    async fn get_invoice(
+        &self,
+        request: Request<GetInvoiceRequest>,
+    ) -> Result<Response<GetInvoiceResponse>, Status> {
+        // Everything logged while handling the request is tagged with a request id.
+        Correlation::new_request()
+            .scope(async move {
+                log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+                let metadata = request.metadata();
+                let macaroon = check_auth_metadata(metadata)?;
+                let node = self.node(metadata)?;
+                let creds = Creds::String {
+                    cert: node.lnd_cert.clone(),
+                    macaroon,
+                };
+                let lnd_cfg = LndCfg::new(node.address.clone(), creds);
+                let mut client = get_lnd_client(lnd_cfg)
+                    .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
 
-        let info = client
-            .lightning()
-            .get_info(GetInfoRequest {})
+                let inner_request = request.get_ref();
+                let offer = Offer::from_str(&inner_request.offer).map_err(|e| {
+                    Status::invalid_argument(format!(
+                        "The provided offer was invalid. Please provide a valid offer in bech32 format,
+                        i.e. starting with 'lno'. Error: {e:?}"
+                    ))
+                })?;
+
+                let destination = get_destination(&offer)
+                    .await
+                    .map_err(|e| Status::unavailable(format!("Couldn't find destination: {e}")))?;
+                let reply_path = match node
+                    .offer_handler
+                    .create_reply_path(client.clone(), node.node_id)
+                    .await
+                {
+                    Ok(reply_path) => reply_path,
+                    Err(e) => return Err(Status::internal(format!("Internal error: {e}"))),
+                };
+
+                let info = client
+                    .lightning()
+                    .get_info(GetInfoRequest {})
+                    .await
+                    .expect("failed to get info")
+                    .into_inner();
+                let network = get_network(info)
+                    .await
+                    .map_err(|e| Status::internal(format!("{e:?}")))?;
+
+                let cfg = PayOfferParams {
+                    offer,
+                    amount: inner_request.amount,
+                    payer_note: inner_request.payer_note.clone(),
+                    network,
+                    client,
+                    destination,
+                    reply_path: Some(reply_path),
+                    response_invoice_timeout: inner_request.response_invoice_timeout,
+                    payment_id: None,
+                };
+
+                let (invoice, _, payment_id) = match node.offer_handler.get_invoice(cfg).await {
+                    Ok(invoice) => {
+                        log::info!("Invoice request succeeded.");
+                        invoice
+                    }
+                    Err(e) => match e {
+                        OfferError::InvalidAmount(e) => {
+                            return Err(Status::invalid_argument(e.to_string()))
+                        }
+                        OfferError::InvalidCurrency => {
+                            return Err(Status::invalid_argument(format!("{e}")))
+                        }
+                        _ => return Err(Status::internal(format!("Internal error: {e}"))),
+                    },
+                };
+
+                // We need to remove the payment from our tracking map now.
+                {
+                    let mut active_payments = node.offer_handler.active_payments.lock().unwrap();
+                    active_payments.remove(&payment_id);
+                }
+
+                let reply: GetInvoiceResponse = GetInvoiceResponse {
+                    invoice_hex_str: Bolt12InvoiceString::encode(&invoice, InvoiceEncoding::Hex)
+                        .0,
+                    invoice_bech32_str: Bolt12InvoiceString::encode(
+                        &invoice,
+                        InvoiceEncoding::Bech32,
+                    )
+                    .0,
+                    invoice_contents: Some(generate_bolt12_invoice_contents(&invoice)),
+                };
+
+                Ok(Response::new(reply))
+            })
             .await
-            .expect("failed to get info")
-            .into_inner();
-        let network = get_network(info)
+    }
+
+    async fn pay_invoice(
+        &self,
+        request: Request<PayInvoiceRequest>,
+    ) -> Result<Response<PayInvoiceResponse>, Status> {
+        // Everything logged while handling the request is tagged with a request id.
+        Correlation::new_request()
+            .scope(async move {
+                log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+                let metadata = request.metadata();
+                let macaroon = check_auth_metadata(metadata)?;
+                let node = self.node(metadata)?;
+                let creds = Creds::String {
+                    cert: node.lnd_cert.clone(),
+                    macaroon,
+                };
+                let lnd_cfg = LndCfg::new(node.address.clone(), creds);
+                let client = get_lnd_client(lnd_cfg)
+                    .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
+
+                let inner_request = request.get_ref();
+                let invoice_string: Bolt12InvoiceString = inner_request.invoice.clone().into();
+                let invoice = Bolt12Invoice::try_from(invoice_string).map_err(|e| {
+                    Status::invalid_argument(format!(
+                        "The provided invoice was invalid. Please provide a valid invoice in hex format
+                        or bech32 format, i.e. starting with 'lni'. Error: {e}"
+                    ))
+                })?;
+
+                let amount = match validate_amount(invoice.amount(), inner_request.amount).await {
+                    Ok(amount) => amount,
+                    Err(e) => return Err(Status::invalid_argument(e.to_string())),
+                };
+                let payment_id = payment_id_from_request(
+                    &node.offer_handler,
+                    &inner_request.idempotency_key,
+                    &PaymentParams::Invoice { invoice: &invoice, amount },
+                )?;
+
+                // As with pay_offer, the payment runs in its own task so that it isn't cancelled if
+                // the client goes away.
+                let handler = Arc::clone(&node.offer_handler);
+                let result = tokio::spawn(Correlation::current().scope(async move {
+                    handler
+                        .pay_provided_invoice(client, amount, &invoice, payment_id)
+                        .await
+                }))
+                .await
+                .map_err(|e| Status::internal(format!("Internal error: {e}")))?;
+                let invoice = match result {
+                    Ok(invoice) => {
+                        log::info!("Invoice paid.");
+                        invoice
+                    }
+                    Err(e) => match e {
+                        OfferError::BudgetExceeded(_) => {
+                            return Err(Status::resource_exhausted(format!("{e}")))
+                        }
+                        OfferError::PreviousAttemptFailed(_) | OfferError::PaymentCancelled(_) => {
+                            return Err(Status::aborted(format!("{e}")))
+                        }
+                        _ => return Err(Status::internal(format!("Error paying invoice: {e}"))),
+                    },
+                };
+
+                let reply = PayInvoiceResponse {
+                    payment_preimage: invoice.payment_preimage,
+                    proof: node
+                        .offer_handler
+                        .proof_of_payment(payment_id)
+                        .map(proof_to_proto),
+                };
+
+                Ok(Response::new(reply))
+            })
             .await
-            .map_err(|e| Status::internal(format!("{e:?}")))?;
+    }
 
-        let cfg = PayOfferParams {
-            offer,
-            amount: inner_request.amount,
-            payer_note: inner_request.payer_note.clone(),
-            network,
-            client,
-            destination,
-            reply_path: Some(reply_path),
-            response_invoice_timeout: inner_request.response_invoice_timeout,
+    async fn get_budget(
+        &self,
+        request: Request<GetBudgetRequest>,
+    ) -> Result<Response<GetBudgetResponse>, Status> {
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+        let issuer = match request.get_ref().issuer {
+            Some(ref issuer) => Some(PublicKey::from_str(issuer).map_err(|e| {
+                Status::invalid_argument(format!("The provided issuer pubkey was invalid: {e}"))
+            })?),
+            None => None,
         };
 
-        let (invoice, _, payment_id) = match self.offer_handler.get_invoice(cfg).await {
-            Ok(invoice) => {
-                log::info!("Invoice request succeeded.");
-                invoice
-            }
-            Err(e) => match e {
-                OfferError::InvalidAmount(e) => {
-                    return Err(Status::invalid_argument(e.to_string()))
-                }
-                OfferError::InvalidCurrency => {
-                    return Err(Status::invalid_argument(format!("{e}")))
-                }
-                _ => return Err(Status::internal(format!("Internal error: {e}"))),
-            },
+        let node = self.authorize(request.metadata(), &READ_PAYMENTS).await?;
+        let status = node.offer_handler.budget.status(issuer);
+        let reply = GetBudgetResponse {
+            max_payment_msats: status.max_payment_msats,
+            hourly_spent_msats: status.hourly_spent_msats,
+            hourly_remaining_msats: status.hourly_remaining_msats,
+            daily_spent_msats: status.daily_spent_msats,
+            daily_remaining_msats: status.daily_remaining_msats,
+            issuer_spent_msats: status.issuer_spent_msats,
+            issuer_remaining_msats: status.issuer_remaining_msats,
         };
 
-        // We need to remove the payment from our tracking map now.
-        {
-            let mut active_payments = self.offer_handler.active_payments.lock().unwrap();
-            active_payments.remove(&payment_id);
-        }
+        Ok(Response::new(reply))
+    }
+
+    async fn get_metrics(
+        &self,
+        request: Request<GetMetricsRequest>,
+    ) -> Result<Response<GetMetricsResponse>, Status> {
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+        // The metrics cover all of our nodes, so a macaroon for any one of them will do.
+        self.authorize(request.metadata(), &READ_PAYMENTS).await?;
+        let mut total = PaymentMetrics::default();
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let metrics = node.offer_handler.metrics();
+                total.attempted += metrics.attempted;
+                total.succeeded += metrics.succeeded;
+                total.failed += metrics.failed;
+                total.cancelled += metrics.cancelled;
+                total.in_flight += metrics.in_flight;
+
+                NodeMetrics {
+                    node_id: node.node_id.to_string(),
+                    alias: node.alias.clone(),
+                    payments: Some(metrics_to_proto(metrics)),
+                }
+            })
+            .collect();
 
-        let reply: GetInvoiceResponse = GetInvoiceResponse {
-            invoice_hex_str: encode_invoice_as_hex(&invoice)?,
-            invoice_contents: Some(generate_bolt12_invoice_contents(&invoice)),
+        let reply = GetMetricsResponse {
+            nodes,
+            total: Some(metrics_to_proto(total)),
         };
 
         Ok(Response::new(reply))
     }
 
-    async fn pay_invoice(
+    async fn reload_config(
         &self,
-        request: Request<PayInvoiceRequest>,
-    ) -> Result<Response<PayInvoiceResponse>, Status> {
-        log::info!("Received a request: {:?}", request.get_ref());
+        request: Request<ReloadConfigRequest>,
+    ) -> Result<Response<ReloadConfigResponse>, Status> {
+        log::info!("Received a request: {:?}", Sensitive(request.get_ref()));
+
+        self.authorize(request.metadata(), &ADMIN).await?;
+        let reloader = self.reloader.as_ref().ok_or_else(|| {
+            Status::unimplemented("This LNDK instance doesn't support reloading its config")
+        })?;
 
-        let metadata = request.metadata();
+        match reloader.reload() {
+            Ok(report) => Ok(Response::new(ReloadConfigResponse {
+                applied: report.applied,
+                requires_restart: report.requires_restart,
+            })),
+            Err(e @ ReloadError::InvalidSetting { .. }) => {
+                Err(Status::invalid_argument(format!("{e}")))
+            }
+            Err(e) => Err(Status::failed_precondition(format!("{e}"))),
+        }
+    }
+}
+
+impl LNDKServer {
+    // Returns the node that the request is for, once LND has confirmed that the request's macaroon
+    // is valid for it and has the given permissions. Calls that connect to LND with the macaroon
+    // are checked by LND itself, but calls that only touch LNDK's own state need this.
+    async fn authorize(
+        &self,
+        metadata: &MetadataMap,
+        permissions: &[(&str, &str)],
+    ) -> Result<&ServerNode, Status> {
         let macaroon = check_auth_metadata(metadata)?;
+        let node = self.node(metadata)?;
+        let macaroon_bytes = hex::decode(&macaroon).map_err(|e| {
+            Status::unauthenticated(format!("Invalid macaroon string provided: {e}"))
+        })?;
         let creds = Creds::String {
-            cert: self.lnd_cert.clone(),
+            cert: node.lnd_cert.clone(),
             macaroon,
         };
-        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
-        let client = get_lnd_client(lnd_cfg)
+        let mut client = get_lnd_client(LndCfg::new(node.address.clone(), creds))
             .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
 
-        let inner_request = request.get_ref();
-        let invoice_string: Bolt12InvoiceString = inner_request.invoice.clone().into();
-        let invoice = Bolt12Invoice::try_from(invoice_string).map_err(|e| {
-            Status::invalid_argument(format!(
-                "The provided invoice was invalid. Please provide a valid invoice in hex format.
-                Error: {e:?}"
-            ))
-        })?;
-
-        let amount = match validate_amount(invoice.amount(), inner_request.amount).await {
-            Ok(amount) => amount,
-            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        let request = CheckMacPermRequest {
+            macaroon: macaroon_bytes,
+            permissions: permissions
+                .iter()
+                .map(|(entity, action)| MacaroonPermission {
+                    entity: entity.to_string(),
+                    action: action.to_string(),
+                })
+                .collect(),
+            full_method: String::new(),
         };
-        let payment_id = PaymentId(self.offer_handler.messenger_utils.get_secure_random_bytes());
-        let invoice = match self
-            .offer_handler
-            .pay_invoice(client, amount, &invoice, payment_id)
-            .await
-        {
-            Ok(invoice) => {
-                log::info!("Invoice paid.");
-                invoice
+        match client.lightning().check_macaroon_permissions(request).await {
+            Ok(response) if response.get_ref().valid => Ok(node),
+            Ok(_) => Err(Status::permission_denied(
+                "The provided macaroon doesn't have the permissions needed for this call",
+            )),
+            Err(e) if e.code() == tonic_lnd::tonic::Code::Unavailable => Err(Status::unavailable(
+                format!("Couldn't connect to lnd: {}", e.message()),
+            )),
+            Err(e) => Err(Status::unauthenticated(format!(
+                "The provided macaroon was rejected by LND: {}",
+                e.message()
+            ))),
+        }
+    }
+
+    // Returns the node that the request is for. If we only have one node, requests don't need to
+    // pick it.
+    fn node(&self, metadata: &MetadataMap) -> Result<&ServerNode, Status> {
+        let selector = match metadata.get(NODE_METADATA_KEY) {
+            Some(selector) => selector.to_str().map_err(|e| {
+                Status::invalid_argument(format!("Invalid node string provided: {e}"))
+            })?,
+            None => {
+                return match self.nodes.as_slice() {
+                    [node] => Ok(node),
+                    _ => Err(Status::invalid_argument(format!(
+                        "LNDK is running with several nodes: Make sure to pick one by pubkey or \
+                        alias with the '{NODE_METADATA_KEY}' request metadata"
+                    ))),
+                }
             }
-            Err(e) => return Err(Status::internal(format!("Error paying invoice: {e}"))),
         };
 
-        let reply = PayInvoiceResponse {
-            payment_preimage: invoice.payment_preimage,
-        };
+        self.nodes
+            .iter()
+            .find(|node| {
+                node.node_id.to_string() == selector || node.alias.as_deref() == Some(selector)
+            })
+            .ok_or_else(|| {
+                Status::not_found(format!("No node found with pubkey or alias {selector}"))
+            })
+    }
+}
 
-        Ok(Response::new(reply))
+fn metrics_to_proto(metrics: PaymentMetrics) -> PaymentCounts {
+    PaymentCounts {
+        attempted: metrics.attempted,
+        succeeded: metrics.succeeded,
+        failed: metrics.failed,
+        cancelled: metrics.cancelled,
+        in_flight: metrics.in_flight,
+    }
+}
+
+// Maps an error from paying an offer to the status we return to the client.
+fn pay_offer_error_status(e: OfferError) -> Status {
+    match e {
+        OfferError::InvalidAmount(e) => Status::invalid_argument(e.to_string()),
+        OfferError::InvalidCurrency => Status::invalid_argument(format!("{e}")),
+        OfferError::BudgetExceeded(_) => Status::resource_exhausted(format!("{e}")),
+        OfferError::PreviousAttemptFailed(_) | OfferError::PaymentCancelled(_) => {
+            Status::aborted(format!("{e}"))
+        }
+        _ => Status::internal(format!("Internal error: {e}")),
+    }
+}
+
+fn proof_to_proto(proof: ProofOfPayment) -> lndkrpc::ProofOfPayment {
+    lndkrpc::ProofOfPayment {
+        offer: proof.offer,
+        invoice_request_hex_str: proof.invoice_request,
+        invoice_hex_str: proof.invoice,
+        payment_preimage: proof.payment_preimage,
+        paid_at: proof.paid_at,
+    }
+}
+
+fn proof_from_proto(proof: lndkrpc::ProofOfPayment) -> ProofOfPayment {
+    ProofOfPayment {
+        offer: proof.offer,
+        invoice_request: proof.invoice_request_hex_str,
+        invoice: proof.invoice_hex_str,
+        payment_preimage: proof.payment_preimage,
+        paid_at: proof.paid_at,
+        payer_key: None,
+    }
+}
+
+// Parses a hex-encoded payment id provided by the client.
+fn parse_payment_id(payment_id: &str) -> Result<PaymentId, Status> {
+    hex::decode(payment_id)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .map(PaymentId)
+        .ok_or_else(|| {
+            Status::invalid_argument(
+                "The provided payment id was invalid. Please provide a hex-encoded 32 byte id.",
+            )
+        })
+}
+
+fn payment_state_to_proto(state: PaymentState) -> lndkrpc::PaymentStatus {
+    match state {
+        PaymentState::InvoiceRequestCreated => lndkrpc::PaymentStatus::InvoiceRequestCreated,
+        PaymentState::InvoiceRequestSent => lndkrpc::PaymentStatus::InvoiceRequestSent,
+        PaymentState::InvoiceReceived => lndkrpc::PaymentStatus::InvoiceReceived,
+        PaymentState::PaymentDispatched => lndkrpc::PaymentStatus::PaymentDispatched,
+        PaymentState::Paid => lndkrpc::PaymentStatus::Succeeded,
+    }
+}
+
+// Derives the payment id from the client's idempotency key if they provided one, otherwise we
+// generate a random one. A key can only be reused for the same payment, which params describes.
+fn payment_id_from_request(
+    handler: &OfferHandler,
+    idempotency_key: &Option<String>,
+    params: &PaymentParams,
+) -> Result<PaymentId, Status> {
+    let Some(key) = idempotency_key else {
+        return Ok(PaymentId(handler.messenger_utils.get_secure_random_bytes()));
+    };
+
+    let payment_id = payment_id_from_idempotency_key(key);
+    handler
+        .check_idempotency_key(payment_id, params)
+        .map_err(|e| Status::failed_precondition(format!("{e}")))?;
+    Ok(payment_id)
+}
+
+/// Runs LNDK's gRPC server until shutdown. When the TLS credentials are renewed or replaced on
+/// disk, we gracefully stop the server and start it again with the new credentials, without
+/// restarting the rest of the daemon.
+pub async fn serve_grpc(
+    server: Arc<LNDKServer>,
+    addr: SocketAddr,
+    mut creds: watch::Receiver<TlsCreds>,
+    listener: Listener,
+) -> Result<(), tonic::transport::Error> {
+    loop {
+        let tls_config = creds.borrow_and_update().server_tls_config();
+        let shutdown = listener.clone();
+        Server::builder()
+            .tls_config(tls_config)?
+            .add_service(OffersServer::from_arc(Arc::clone(&server)))
+            .serve_with_shutdown(addr, async {
+                select! {
+                    _ = shutdown => {},
+                    Ok(()) = creds.changed() => {},
+                }
+            })
+            .await?;
+
+        if listener.is_triggered() {
+            return Ok(());
+        }
+        log::info!("Restarting lndk's grpc server with new TLS credentials.");
     }
 }
 
@@ -310,82 +848,8 @@ fn check_auth_metadata(metadata: &MetadataMap) -> Result<String, Status> {
     Ok(macaroon)
 }
 
-/// An error that occurs when generating TLS credentials.
-#[derive(Debug)]
-pub enum CertificateGenFailure {
-    RcgenError(RcgenError),
-    IoError(std::io::Error),
-}
-
-impl Display for CertificateGenFailure {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            CertificateGenFailure::RcgenError(e) => {
-                write!(f, "Error generating TLS certificate: {e:?}")
-            }
-            CertificateGenFailure::IoError(e) => write!(f, "IO error: {e:?}"),
-        }
-    }
-}
-
-impl Error for CertificateGenFailure {}
-
-// If a tls cert/key pair doesn't already exist, generate_tls_creds creates the tls cert/key pair
-// required to secure connections to LNDK's gRPC server. By default they are stored in ~/.lndk.
-pub fn generate_tls_creds(
-    data_dir: PathBuf,
-    tls_ips_string: Option<String>,
-) -> Result<(), CertificateGenFailure> {
-    let cert_path = data_dir.join(TLS_CERT_FILENAME);
-    let key_path = data_dir.join(TLS_KEY_FILENAME);
-    let tls_ips = collect_tls_ips(tls_ips_string);
-
-    // Did we have to generate a new key? In that case we also need to regenerate the certificate.
-    if !key_path.exists() || !cert_path.exists() {
-        log::debug!("Generating fresh TLS credentials in {data_dir:?}");
-        let mut subject_alt_names = vec!["localhost".to_string()];
-        if let Some(ips) = tls_ips {
-            for ip in ips {
-                subject_alt_names.push(ip);
-            }
-        };
-
-        let CertifiedKey { cert, key_pair } = generate_simple_self_signed(subject_alt_names)
-            .map_err(CertificateGenFailure::RcgenError)?;
-
-        // Create the tls files. Make sure the key is user-readable only:
-        let mut file = File::create(&key_path).map_err(CertificateGenFailure::IoError)?;
-        let mut perms = metadata(&key_path)
-            .map_err(CertificateGenFailure::IoError)?
-            .permissions();
-        perms.set_mode(0o600);
-        set_permissions(&key_path, perms).map_err(CertificateGenFailure::IoError)?;
-
-        file.write_all(key_pair.serialize_pem().as_bytes())
-            .map_err(CertificateGenFailure::IoError)?;
-        drop(file);
-
-        std::fs::write(&cert_path, cert.pem()).map_err(CertificateGenFailure::IoError)?;
-    };
-
-    Ok(())
-}
-
-// Read the existing tls credentials from disk.
-pub fn read_tls(data_dir: PathBuf) -> Result<Identity, std::io::Error> {
-    let cert = std::fs::read_to_string(data_dir.join(TLS_CERT_FILENAME))?;
-    let key = std::fs::read_to_string(data_dir.join(TLS_KEY_FILENAME))?;
-
-    Ok(Identity::from_pem(cert, key))
-}
-
-// The user first passes in the tls ips as a comma-deliminated string into LNDK. Here we turn that
-// string into a Vec.
-fn collect_tls_ips(tls_ips_str: Option<String>) -> Option<Vec<String>> {
-    tls_ips_str.map(|tls_ips_str| tls_ips_str.split(',').map(|str| str.to_owned()).collect())
-}
-
-fn generate_bolt12_invoice_contents(invoice: &Bolt12Invoice) -> lndkrpc::Bolt12InvoiceContents {
+/// Converts an invoice into its gRPC representation.
+pub fn generate_bolt12_invoice_contents(invoice: &Bolt12Invoice) -> lndkrpc::Bolt12InvoiceContents {
     Bolt12InvoiceContents {
         chain: invoice.chain().to_string(),
         quantity: invoice.quantity(),
@@ -408,12 +872,64 @@ fn generate_bolt12_invoice_contents(invoice: &Bolt12Invoice) -> lndkrpc::Bolt12I
     }
 }
 
-fn encode_invoice_as_hex(invoice: &Bolt12Invoice) -> Result<String, Status> {
-    let mut buffer = Vec::new();
-    invoice
-        .write(&mut buffer)
-        .map_err(|e| Status::internal(format!("Error serializing invoice: {e}")))?;
-    Ok(hex::encode(buffer))
+/// Converts an offer into its gRPC representation, warning about anything that would stop us from
+/// paying it on the given network.
+pub fn generate_offer_contents(offer: &Offer, network: Network) -> OfferContents {
+    let (amount_msats, currency_amount) = match offer.amount() {
+        Some(Amount::Bitcoin { amount_msats }) => (Some(*amount_msats), None),
+        Some(Amount::Currency {
+            iso4217_code,
+            amount,
+        }) => (
+            None,
+            Some(CurrencyAmount {
+                iso4217_code: String::from_utf8_lossy(iso4217_code).to_string(),
+                amount: *amount,
+            }),
+        ),
+        None => (None, None),
+    };
+    let (quantity_max, quantity_unbounded) = match offer.supported_quantity() {
+        Quantity::Bounded(max) => (max.get(), false),
+        Quantity::Unbounded => (0, true),
+        Quantity::One => (1, false),
+    };
+
+    // We flag anything that would stop us from paying the offer.
+    let mut warnings = Vec::new();
+    if offer.is_expired() {
+        warnings.push("The offer has expired".to_string());
+    }
+    if !offer.supports_chain(ChainHash::using_genesis_block(network)) {
+        warnings.push(format!(
+            "The offer is not valid for this node's network ({network})"
+        ));
+    }
+    if currency_amount.is_some() {
+        warnings.push("LNDK doesn't yet support offer currencies other than bitcoin".to_string());
+    }
+
+    OfferContents {
+        chains: offer
+            .chains()
+            .iter()
+            .map(|chain| chain.to_string())
+            .collect(),
+        amount_msats,
+        currency_amount,
+        description: offer
+            .description()
+            .map(|description| description.to_string()),
+        issuer: offer.issuer().map(|issuer| issuer.to_string()),
+        absolute_expiry: offer.absolute_expiry().map(|expiry| expiry.as_secs()),
+        quantity_max,
+        quantity_unbounded,
+        signing_pubkey: offer.signing_pubkey().map(convert_public_key),
+        paths: offer.paths().iter().map(convert_blinded_path).collect(),
+        features: convert_offer_features(offer.offer_features().clone()),
+        has_metadata: offer.metadata().is_some(),
+        warnings,
+    }
 }
 
 fn extract_payment_paths(invoice: &Bolt12Invoice) -> Vec<PaymentPaths> {
@@ -445,6 +961,12 @@ fn convert_invoice_features(features: Bolt12InvoiceFeatures) -> Vec<i32> {
     feature_bits
 }
 
+// Conversion function for offer features.
+// Based on OfferContext in https://docs.rs/lightning/latest/src/lightning/ln/features.rs.html#213
+fn convert_offer_features(_: OfferFeatures) -> Vec<i32> {
+    Vec::new()
+}
+
 // Conversion function for hop features.
 // Based on BlindedHopContext in https://docs.rs/lightning/latest/src/lightning/ln/features.rs.html#213
 fn convert_hop_features(_: BlindedHopFeatures) -> Vec<i32> {
@@ -501,22 +1023,86 @@ fn convert_blinded_path(native_info: &BlindedPath) -> lndkrpc::BlindedPath {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sim::fixtures::pubkey;
+    use lightning::offers::offer::OfferBuilder;
+    use std::time::Duration;
+
+    #[test]
+    fn test_generate_offer_contents() {
+        let offer = OfferBuilder::new(pubkey(1))
+            .description("coffee".to_string())
+            .amount_msats(20_000)
+            .supported_quantity(Quantity::Unbounded)
+            .issuer("Foo Bar".to_string())
+            .chain(Network::Regtest)
+            .build()
+            .unwrap();
+
+        let contents = generate_offer_contents(&offer, Network::Regtest);
+        assert_eq!(contents.amount_msats, Some(20_000));
+        assert_eq!(contents.description, Some("coffee".to_string()));
+        assert_eq!(contents.issuer, Some("Foo Bar".to_string()));
+        assert!(contents.quantity_unbounded);
+        assert_eq!(contents.signing_pubkey, Some(convert_public_key(pubkey(1))));
+        assert!(!contents.has_metadata);
+        assert!(contents.warnings.is_empty());
+
+        // If the offer is for a different network than our node's, we should warn about it.
+        let contents = generate_offer_contents(&offer, Network::Bitcoin);
+        assert_eq!(contents.warnings.len(), 1);
+    }
 
     #[test]
-    fn test_collect_tls_ips() {
-        // Test that it returns a vector of one element if only one ip is provided.
-        let tls_ips_str = Some("192.168.0.1".to_string());
-        let tls_ips = collect_tls_ips(tls_ips_str);
-        assert!(tls_ips.is_some());
-        assert!(tls_ips.as_ref().unwrap().len() == 1);
-
-        // If no ip is provided, collect_tls_ips should return None.
-        assert!(collect_tls_ips(None).is_none());
-
-        // If two ips are provided, a vector of length two should be returned.
-        let tls_ips_str = Some("192.168.0.1,192.168.0.3".to_string());
-        let tls_ips = collect_tls_ips(tls_ips_str);
-        assert!(tls_ips.is_some());
-        assert!(tls_ips.as_ref().unwrap().len() == 2);
+    fn test_generate_offer_contents_expired() {
+        let offer = OfferBuilder::new(pubkey(1))
+            .description("coffee".to_string())
+            .absolute_expiry(Duration::from_secs(1))
+            .build()
+            .unwrap();
+
+        let contents = generate_offer_contents(&offer, Network::Bitcoin);
+        assert_eq!(contents.absolute_expiry, Some(1));
+        assert_eq!(contents.warnings, vec!["The offer has expired".to_string()]);
+    }
+
+    fn server_node(byte: u8, alias: &str) -> ServerNode {
+        ServerNode {
+            offer_handler: Arc::new(OfferHandler::default()),
+            node_id: pubkey(byte),
+            alias: Some(alias.to_string()),
+            lnd_cert: String::new(),
+            address: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_node_selection() {
+        let server = LNDKServer::for_nodes(
+            vec![server_node(1, "alice"), server_node(2, "bob")],
+            Network::Regtest,
+        );
+
+        // With several nodes, requests have to pick one.
+        let mut metadata = MetadataMap::new();
+        assert_eq!(
+            server.node(&metadata).err().unwrap().code(),
+            tonic::Code::InvalidArgument
+        );
+
+        metadata.insert(NODE_METADATA_KEY, "bob".parse().unwrap());
+        assert_eq!(server.node(&metadata).unwrap().node_id, pubkey(2));
+
+        metadata.insert(NODE_METADATA_KEY, pubkey(1).to_string().parse().unwrap());
+        assert_eq!(server.node(&metadata).unwrap().node_id, pubkey(1));
+
+        metadata.insert(NODE_METADATA_KEY, "carol".parse().unwrap());
+        assert_eq!(
+            server.node(&metadata).err().unwrap().code(),
+            tonic::Code::NotFound
+        );
+
+        // With just one node, it's used by default.
+        let server = LNDKServer::for_nodes(vec![server_node(1, "alice")], Network::Regtest);
+        assert_eq!(server.node(&MetadataMap::new()).unwrap().node_id, pubkey(1));
     }
 }
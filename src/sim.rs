@@ -0,0 +1,696 @@
+use crate::lnd::{
+    ChannelInfoProvider, EventStream, EventSubscriber, InvoicePayer, MessageSigner,
+    NodeInfoProvider, PeerConnector, SendCustomMessage, SharedKeyDeriver, BUILD_TAGS_REQUIRED,
+    MIN_LND_MAJOR_VER, MIN_LND_MINOR_VER, MIN_LND_PATCH_VER, MIN_LND_PRE_RELEASE_VER,
+    ONION_MESSAGES_OPTIONAL,
+};
+use crate::OfferError;
+use async_trait::async_trait;
+use bitcoin::hashes::sha256::Hash;
+use bitcoin::hashes::{Hash as _, HashEngine};
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::{KeyPair, Message, PublicKey, Secp256k1, SecretKey};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use lightning::blinded_path::BlindedPath;
+use lightning::offers::invoice_request::{InvoiceRequest, UnsignedInvoiceRequest};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tonic_lnd::lnrpc::channel_event_update::{Channel, UpdateType};
+use tonic_lnd::lnrpc::htlc_attempt::HtlcStatus;
+use tonic_lnd::lnrpc::payment::PaymentStatus;
+use tonic_lnd::lnrpc::peer_event::EventType;
+use tonic_lnd::lnrpc::{
+    Chain, ChannelCloseSummary, ChannelEdge, ChannelEventUpdate, CustomMessage, Feature,
+    GetInfoResponse, GraphTopologyUpdate, HtlcAttempt, LightningNode, ListPeersResponse,
+    NodeAddress, NodeInfo, Payment, Peer, PeerEvent, QueryRoutesResponse, Route,
+    SendCustomMessageRequest, SendCustomMessageResponse,
+};
+use tonic_lnd::signrpc::{KeyDescriptor, KeyLocator};
+use tonic_lnd::tonic::Status;
+use tonic_lnd::verrpc::Version;
+use tonic_lnd::walletrpc::KeyReq;
+
+/// SIM_NODE_ADDRESS is the address the simulated node reports for every node in its graph.
+pub const SIM_NODE_ADDRESS: &str = "127.0.0.1:9735";
+
+/// SimNode is an in-memory lightning node that implements [`crate::lnd::NodeBackend`], so that
+/// LNDK (including its onion messenger) can be run in tests without bitcoind or LND.
+///
+/// Tests drive the node from the outside: connecting and disconnecting peers, delivering custom
+/// messages from peers, adding and closing channels, and inspecting the custom messages that LNDK
+/// has asked the node to send. Clones share the same state, like clones of LND's client. Payments
+/// always succeed, for the route's amount.
+#[derive(Clone)]
+pub struct SimNode {
+    secret_key: SecretKey,
+    pubkey: PublicKey,
+    network: Network,
+    state: Arc<Mutex<SimState>>,
+}
+
+#[derive(Default)]
+struct SimState {
+    // peers maps our connected peers to whether they support onion messages.
+    peers: HashMap<PublicKey, bool>,
+    channels: HashMap<u64, ChannelEdge>,
+    sent_messages: Vec<SendCustomMessageRequest>,
+    payments: HashMap<[u8; 32], Payment>,
+    next_key_index: HashMap<i32, i32>,
+    peer_subscribers: Vec<UnboundedSender<Result<PeerEvent, Status>>>,
+    message_subscribers: Vec<UnboundedSender<Result<CustomMessage, Status>>>,
+    channel_subscribers: Vec<UnboundedSender<Result<ChannelEventUpdate, Status>>>,
+    graph_subscribers: Vec<UnboundedSender<Result<GraphTopologyUpdate, Status>>>,
+}
+
+impl SimNode {
+    pub fn new(secret_key: SecretKey, network: Network) -> Self {
+        SimNode {
+            secret_key,
+            pubkey: PublicKey::from_secret_key(&Secp256k1::new(), &secret_key),
+            network,
+            state: Arc::new(Mutex::new(SimState::default())),
+        }
+    }
+
+    pub fn pubkey(&self) -> PublicKey {
+        self.pubkey
+    }
+
+    /// Connects a peer, notifying any peer event subscribers that it has come online.
+    pub fn add_peer(&self, pubkey: PublicKey, onion_support: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.insert(pubkey, onion_support);
+        notify(
+            &mut state.peer_subscribers,
+            peer_event(pubkey, EventType::PeerOnline),
+        );
+    }
+
+    /// Disconnects a peer, notifying any peer event subscribers that it has gone offline.
+    pub fn remove_peer(&self, pubkey: PublicKey) {
+        let mut state = self.state.lock().unwrap();
+        if state.peers.remove(&pubkey).is_some() {
+            notify(
+                &mut state.peer_subscribers,
+                peer_event(pubkey, EventType::PeerOffline),
+            );
+        }
+    }
+
+    /// Delivers a custom message from a peer to any custom message subscribers.
+    pub fn receive_custom_message(&self, peer: PublicKey, r#type: u32, data: Vec<u8>) {
+        let message = CustomMessage {
+            peer: peer.serialize().to_vec(),
+            r#type,
+            data,
+        };
+        notify(&mut self.state.lock().unwrap().message_subscribers, message);
+    }
+
+    /// Returns the custom messages that have been sent through the node, oldest first.
+    pub fn sent_messages(&self) -> Vec<SendCustomMessageRequest> {
+        self.state.lock().unwrap().sent_messages.clone()
+    }
+
+    /// Adds a channel between two nodes to the node's graph.
+    pub fn add_channel(&self, scid: u64, node1: PublicKey, node2: PublicKey) {
+        let channel = ChannelEdge {
+            channel_id: scid,
+            node1_pub: node1.to_string(),
+            node2_pub: node2.to_string(),
+            ..Default::default()
+        };
+        self.state.lock().unwrap().channels.insert(scid, channel);
+    }
+
+    /// Closes a channel, notifying channel event subscribers if it's one of ours and graph
+    /// subscribers otherwise.
+    pub fn close_channel(&self, scid: u64) {
+        let mut state = self.state.lock().unwrap();
+        let channel = match state.channels.remove(&scid) {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        let summary = ChannelCloseSummary {
+            chan_id: scid,
+            ..Default::default()
+        };
+        let ours = self.pubkey.to_string();
+        if channel.node1_pub == ours || channel.node2_pub == ours {
+            let update = ChannelEventUpdate {
+                r#type: UpdateType::ClosedChannel.into(),
+                channel: Some(Channel::ClosedChannel(summary)),
+            };
+            notify(&mut state.channel_subscribers, update);
+        } else {
+            let update = GraphTopologyUpdate {
+                closed_chans: vec![tonic_lnd::lnrpc::ClosedChannelUpdate {
+                    chan_id: scid,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            notify(&mut state.graph_subscribers, update);
+        }
+    }
+
+    /// Returns the payments that have been sent through the node.
+    pub fn payments(&self) -> Vec<Payment> {
+        self.state
+            .lock()
+            .unwrap()
+            .payments
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    // Derives a key for the key locator from our node key, in place of LND's wallet.
+    fn key_pair(&self, key_loc: &KeyLocator) -> KeyPair {
+        let mut engine = Hash::engine();
+        engine.input(&self.secret_key.secret_bytes());
+        engine.input(&key_loc.key_family.to_be_bytes());
+        engine.input(&key_loc.key_index.to_be_bytes());
+        let secret_key = SecretKey::from_slice(&Hash::from_engine(engine).to_byte_array())
+            .expect("hash is a valid secret key");
+        KeyPair::from_secret_key(&Secp256k1::new(), &secret_key)
+    }
+
+    fn key_descriptor(&self, key_loc: KeyLocator) -> KeyDescriptor {
+        KeyDescriptor {
+            raw_key_bytes: self.key_pair(&key_loc).public_key().serialize().to_vec(),
+            key_loc: Some(key_loc),
+        }
+    }
+}
+
+// notify sends an update to each of the subscribers, dropping those that have gone away.
+fn notify<T: Clone>(subscribers: &mut Vec<UnboundedSender<Result<T, Status>>>, update: T) {
+    subscribers.retain(|subscriber| subscriber.unbounded_send(Ok(update.clone())).is_ok());
+}
+
+fn peer_event(pubkey: PublicKey, event_type: EventType) -> PeerEvent {
+    PeerEvent {
+        pub_key: pubkey.to_string(),
+        r#type: event_type.into(),
+    }
+}
+
+fn onion_message_features() -> HashMap<u32, Feature> {
+    HashMap::from([(
+        ONION_MESSAGES_OPTIONAL,
+        Feature {
+            name: "onion-messages".to_string(),
+            is_required: false,
+            is_known: true,
+        },
+    )])
+}
+
+fn network_name(network: Network) -> String {
+    match network {
+        Network::Bitcoin => "mainnet".to_string(),
+        network => network.to_string(),
+    }
+}
+
+// subscribe registers a new subscriber, returning the stream that it will receive updates on.
+fn subscribe<T: Send + 'static>(
+    subscribers: &mut Vec<UnboundedSender<Result<T, Status>>>,
+) -> EventStream<T> {
+    let (sender, receiver) = unbounded();
+    subscribers.push(sender);
+    Box::pin(receiver)
+}
+
+#[async_trait]
+impl NodeInfoProvider for SimNode {
+    async fn get_info(&mut self) -> Result<GetInfoResponse, Status> {
+        #[allow(deprecated)]
+        Ok(GetInfoResponse {
+            identity_pubkey: self.pubkey.to_string(),
+            num_peers: self.state.lock().unwrap().peers.len() as u32,
+            chains: vec![Chain {
+                chain: "bitcoin".to_string(),
+                network: network_name(self.network),
+            }],
+            features: onion_message_features(),
+            ..Default::default()
+        })
+    }
+
+    async fn get_version(&mut self) -> Result<Version, Status> {
+        Ok(Version {
+            version: format!(
+                "{MIN_LND_MAJOR_VER}.{MIN_LND_MINOR_VER}.{MIN_LND_PATCH_VER}-{MIN_LND_PRE_RELEASE_VER}"
+            ),
+            app_major: MIN_LND_MAJOR_VER,
+            app_minor: MIN_LND_MINOR_VER,
+            app_patch: MIN_LND_PATCH_VER,
+            app_pre_release: MIN_LND_PRE_RELEASE_VER.to_string(),
+            build_tags: BUILD_TAGS_REQUIRED.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+}
+
+#[async_trait]
+impl PeerConnector for SimNode {
+    async fn list_peers(&mut self) -> Result<ListPeersResponse, Status> {
+        let peers = self
+            .state
+            .lock()
+            .unwrap()
+            .peers
+            .iter()
+            .map(|(pubkey, onion_support)| Peer {
+                pub_key: pubkey.to_string(),
+                address: SIM_NODE_ADDRESS.to_string(),
+                features: match onion_support {
+                    true => onion_message_features(),
+                    false => HashMap::new(),
+                },
+                ..Default::default()
+            })
+            .collect();
+
+        Ok(ListPeersResponse { peers })
+    }
+
+    async fn connect_peer(&mut self, node_id: String, _addr: String) -> Result<(), Status> {
+        let pubkey = node_id
+            .parse()
+            .map_err(|e| Status::invalid_argument(format!("invalid node id: {e}")))?;
+        if !self.state.lock().unwrap().peers.contains_key(&pubkey) {
+            self.add_peer(pubkey, true);
+        }
+        Ok(())
+    }
+
+    async fn get_node_info(
+        &mut self,
+        pub_key: String,
+        _include_channels: bool,
+    ) -> Result<NodeInfo, Status> {
+        let num_channels = self
+            .state
+            .lock()
+            .unwrap()
+            .channels
+            .values()
+            .filter(|channel| channel.node1_pub == pub_key || channel.node2_pub == pub_key)
+            .count() as u32;
+
+        Ok(NodeInfo {
+            node: Some(LightningNode {
+                pub_key,
+                addresses: vec![NodeAddress {
+                    network: "tcp".to_string(),
+                    addr: SIM_NODE_ADDRESS.to_string(),
+                }],
+                ..Default::default()
+            }),
+            num_channels,
+            ..Default::default()
+        })
+    }
+}
+
+#[async_trait]
+impl EventSubscriber for SimNode {
+    async fn subscribe_peer_events(&mut self) -> Result<EventStream<PeerEvent>, Status> {
+        Ok(subscribe(&mut self.state.lock().unwrap().peer_subscribers))
+    }
+
+    async fn subscribe_custom_messages(&mut self) -> Result<EventStream<CustomMessage>, Status> {
+        Ok(subscribe(
+            &mut self.state.lock().unwrap().message_subscribers,
+        ))
+    }
+
+    async fn subscribe_channel_events(
+        &mut self,
+    ) -> Result<EventStream<ChannelEventUpdate>, Status> {
+        Ok(subscribe(
+            &mut self.state.lock().unwrap().channel_subscribers,
+        ))
+    }
+
+    async fn subscribe_channel_graph(
+        &mut self,
+    ) -> Result<EventStream<GraphTopologyUpdate>, Status> {
+        Ok(subscribe(&mut self.state.lock().unwrap().graph_subscribers))
+    }
+}
+
+#[async_trait]
+impl SendCustomMessage for SimNode {
+    async fn send_custom_message(
+        &mut self,
+        request: SendCustomMessageRequest,
+    ) -> Result<SendCustomMessageResponse, Status> {
+        let peer = PublicKey::from_slice(&request.peer)
+            .map_err(|e| Status::invalid_argument(format!("invalid peer: {e}")))?;
+
+        let mut state = self.state.lock().unwrap();
+        if !state.peers.contains_key(&peer) {
+            return Err(Status::not_found(format!("peer {peer} is not connected")));
+        }
+        state.sent_messages.push(request);
+
+        Ok(SendCustomMessageResponse {})
+    }
+}
+
+#[async_trait]
+impl SharedKeyDeriver for SimNode {
+    async fn derive_shared_key(
+        &mut self,
+        ephemeral_key: PublicKey,
+    ) -> Result<SharedSecret, Status> {
+        Ok(SharedSecret::new(&ephemeral_key, &self.secret_key))
+    }
+}
+
+#[async_trait]
+impl MessageSigner for SimNode {
+    async fn derive_next_key(&mut self, key_req: KeyReq) -> Result<KeyDescriptor, Status> {
+        let key_index = {
+            let mut state = self.state.lock().unwrap();
+            let next_index = state.next_key_index.entry(key_req.key_family).or_default();
+            *next_index += 1;
+            *next_index
+        };
+
+        Ok(self.key_descriptor(KeyLocator {
+            key_family: key_req.key_family,
+            key_index,
+        }))
+    }
+
+    async fn derive_key(&mut self, key_loc: KeyLocator) -> Result<KeyDescriptor, Status> {
+        Ok(self.key_descriptor(key_loc))
+    }
+
+    async fn sign_message(
+        &mut self,
+        key_loc: KeyLocator,
+        merkle_root: Hash,
+        tag: String,
+    ) -> Result<Vec<u8>, Status> {
+        // Like LND, we sign the BIP 340 tagged hash of the message.
+        let tag_hash = Hash::hash(tag.as_bytes());
+        let mut engine = Hash::engine();
+        engine.input(&tag_hash.to_byte_array());
+        engine.input(&tag_hash.to_byte_array());
+        engine.input(&merkle_root.to_byte_array());
+        let digest = Message::from_slice(&Hash::from_engine(engine).to_byte_array())
+            .map_err(|e| Status::internal(format!("invalid digest: {e}")))?;
+
+        let signature =
+            Secp256k1::new().sign_schnorr_no_aux_rand(&digest, &self.key_pair(&key_loc));
+        let signature: &[u8; 64] = signature.as_ref();
+        Ok(signature.to_vec())
+    }
+
+    async fn sign_uir(
+        &mut self,
+        key_loc: KeyLocator,
+        unsigned_invoice_req: UnsignedInvoiceRequest,
+    ) -> Result<InvoiceRequest, OfferError> {
+        let keys = self.key_pair(&key_loc);
+        unsigned_invoice_req
+            .sign(|message: &UnsignedInvoiceRequest| {
+                Ok(Secp256k1::new().sign_schnorr_no_aux_rand(message.as_ref().as_digest(), &keys))
+            })
+            .map_err(OfferError::SignError)
+    }
+}
+
+#[async_trait]
+impl ChannelInfoProvider for SimNode {
+    async fn get_chan_info(&mut self, short_channel_id: u64) -> Result<ChannelEdge, Status> {
+        self.state
+            .lock()
+            .unwrap()
+            .channels
+            .get(&short_channel_id)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("channel {short_channel_id} not found")))
+    }
+}
+
+#[async_trait]
+impl InvoicePayer for SimNode {
+    async fn query_routes(
+        &mut self,
+        _path: BlindedPath,
+        _introduction_node: PublicKey,
+        _cltv_expiry_delta: u16,
+        fee_base_msat: u32,
+        fee_ppm: u32,
+        msats: u64,
+    ) -> Result<QueryRoutesResponse, Status> {
+        let fees_msat = u64::from(fee_base_msat) + msats * u64::from(fee_ppm) / 1_000_000;
+        let route = Route {
+            total_amt_msat: (msats + fees_msat) as i64,
+            total_fees_msat: fees_msat as i64,
+            ..Default::default()
+        };
+
+        Ok(QueryRoutesResponse {
+            routes: vec![route],
+            ..Default::default()
+        })
+    }
+
+    async fn send_to_route(
+        &mut self,
+        payment_hash: [u8; 32],
+        route: Route,
+    ) -> Result<HtlcAttempt, Status> {
+        let payment = Payment {
+            payment_hash: hex::encode(payment_hash),
+            value_msat: route.total_amt_msat - route.total_fees_msat,
+            fee_msat: route.total_fees_msat,
+            status: PaymentStatus::Succeeded.into(),
+            ..Default::default()
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .payments
+            .insert(payment_hash, payment);
+
+        Ok(HtlcAttempt {
+            status: HtlcStatus::Succeeded.into(),
+            route: Some(route),
+            ..Default::default()
+        })
+    }
+
+    async fn track_payment(&mut self, payment_hash: [u8; 32]) -> Result<Payment, OfferError> {
+        self.state
+            .lock()
+            .unwrap()
+            .payments
+            .get(&payment_hash)
+            .cloned()
+            .ok_or(OfferError::PaymentFailure)
+    }
+}
+
+/// Fixtures shared by LNDK's unit tests and the integration tests that run against [`SimNode`].
+#[doc(hidden)]
+pub mod fixtures {
+    use crate::onion_messenger::MessengerUtilities;
+    use bitcoin::secp256k1::{KeyPair, PublicKey, Secp256k1, SecretKey};
+    use lightning::blinded_path::BlindedPath;
+    use lightning::ln::features::BlindedHopFeatures;
+    use lightning::ln::PaymentHash;
+    use lightning::offers::invoice::{BlindedPayInfo, Bolt12Invoice, UnsignedBolt12Invoice};
+    use lightning::offers::invoice_request::{InvoiceRequest, UnsignedInvoiceRequest};
+    use lightning::offers::offer::{Offer, OfferBuilder};
+    use lightning::onion_message::messenger::PendingOnionMessage;
+    use lightning::onion_message::offers::{OffersMessage, OffersMessageHandler};
+    use std::sync::Mutex;
+
+    pub fn pubkey(byte: u8) -> PublicKey {
+        let secp_ctx = Secp256k1::new();
+        PublicKey::from_secret_key(&secp_ctx, &privkey(42 + byte))
+    }
+
+    pub fn privkey(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    // The keys that offers built by build_offer are signed with, which a node needs to answer
+    // their invoice requests.
+    pub fn offer_keys() -> KeyPair {
+        KeyPair::from_secret_key(&Secp256k1::new(), &privkey(42))
+    }
+
+    pub fn build_offer(description: &str) -> Offer {
+        OfferBuilder::new(offer_keys().public_key())
+            .description(description.to_string())
+            .amount_msats(20_000)
+            .build()
+            .unwrap()
+    }
+
+    pub fn build_invoice_request(offer: &Offer) -> InvoiceRequest {
+        let secp_ctx = Secp256k1::new();
+        let payer_keys = KeyPair::from_secret_key(&secp_ctx, &privkey(43));
+        offer
+            .request_invoice(vec![1; 32], payer_keys.public_key())
+            .unwrap()
+            .build()
+            .unwrap()
+            .sign(|message: &UnsignedInvoiceRequest| {
+                Ok(secp_ctx.sign_schnorr_no_aux_rand(message.as_ref().as_digest(), &payer_keys))
+            })
+            .unwrap()
+    }
+
+    // Builds an invoice for the invoice request, signed by the key of the offer built by
+    // build_offer.
+    pub fn build_invoice(
+        invoice_request: &InvoiceRequest,
+        payment_hash: PaymentHash,
+    ) -> Bolt12Invoice {
+        let secp_ctx = Secp256k1::new();
+        let keys = offer_keys();
+        let path =
+            BlindedPath::new_for_message(&[pubkey(1)], &MessengerUtilities::new(), &secp_ctx)
+                .unwrap();
+        let pay_info = BlindedPayInfo {
+            fee_base_msat: 1,
+            fee_proportional_millionths: 1_000,
+            cltv_expiry_delta: 42,
+            htlc_minimum_msat: 100,
+            htlc_maximum_msat: 1_000_000_000,
+            features: BlindedHopFeatures::empty(),
+        };
+
+        invoice_request
+            .respond_with(vec![(pay_info, path)], payment_hash)
+            .unwrap()
+            .build()
+            .unwrap()
+            .sign(|message: &UnsignedBolt12Invoice| {
+                Ok(secp_ctx.sign_schnorr_no_aux_rand(message.as_ref().as_digest(), &keys))
+            })
+            .unwrap()
+    }
+
+    // QueuedHandler sends out its messages the first time the onion messenger asks it for any.
+    pub struct QueuedHandler {
+        pending: Mutex<Vec<PendingOnionMessage<OffersMessage>>>,
+    }
+
+    impl QueuedHandler {
+        pub fn new(pending: Vec<PendingOnionMessage<OffersMessage>>) -> Self {
+            QueuedHandler {
+                pending: Mutex::new(pending),
+            }
+        }
+    }
+
+    impl OffersMessageHandler for QueuedHandler {
+        fn handle_message(&self, _message: OffersMessage) -> Option<OffersMessage> {
+            None
+        }
+
+        fn release_pending_messages(&self) -> Vec<PendingOnionMessage<OffersMessage>> {
+            std::mem::take(&mut self.pending.lock().unwrap())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixtures::{
+        build_invoice_request, build_offer, privkey, pubkey, QueuedHandler,
+    };
+    use crate::{LifecycleSignals, LndkOnionMessenger};
+    use lightning::onion_message::messenger::{Destination, PendingOnionMessage};
+    use lightning::onion_message::offers::OffersMessage;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    const ONION_MESSAGE_TYPE: u32 = 513;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_onion_messenger() {
+        let node = SimNode::new(privkey(1), Network::Regtest);
+        let peer = pubkey(2);
+        node.add_peer(peer, true);
+
+        let invoice_request = build_invoice_request(&build_offer("sim"));
+        let handler = Arc::new(QueuedHandler::new(vec![PendingOnionMessage {
+            contents: OffersMessage::InvoiceRequest(invoice_request),
+            destination: Destination::Node(peer),
+            reply_path: None,
+        }]));
+
+        let (shutdown, listener) = triggered::trigger();
+        let signals = LifecycleSignals {
+            shutdown: shutdown.clone(),
+            listener,
+        };
+        let messenger_node = node.clone();
+        let messenger = tokio::spawn(async move {
+            LndkOnionMessenger::new()
+                .run_with_backend(messenger_node, signals, false, handler)
+                .await
+        });
+
+        // The messenger polls for outgoing messages, so we wait for it to hand the invoice request
+        // to the node.
+        let sent = timeout(Duration::from_secs(5), async {
+            loop {
+                let sent = node.sent_messages();
+                if !sent.is_empty() {
+                    return sent;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("onion message not sent");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].peer, peer.serialize().to_vec());
+        assert_eq!(sent[0].r#type, ONION_MESSAGE_TYPE);
+
+        // Peers coming and going shouldn't stop the messenger.
+        node.add_peer(pubkey(3), false);
+        node.remove_peer(peer);
+
+        shutdown.trigger();
+        let result = timeout(Duration::from_secs(5), messenger)
+            .await
+            .expect("messenger did not shut down")
+            .unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sim_signing() {
+        let mut node = SimNode::new(privkey(1), Network::Regtest);
+
+        // Keys are derived deterministically, and each call to derive_next_key moves on to the
+        // next index.
+        let first = node.derive_next_key(KeyReq::default()).await.unwrap();
+        let second = node.derive_next_key(KeyReq::default()).await.unwrap();
+        assert_ne!(first.raw_key_bytes, second.raw_key_bytes);
+        let derived = node.derive_key(first.key_loc.unwrap()).await.unwrap();
+        assert_eq!(derived.raw_key_bytes, first.raw_key_bytes);
+
+        // ECDH with our node key agrees with the other side's view of the shared secret.
+        let shared = node.derive_shared_key(pubkey(2)).await.unwrap();
+        assert_eq!(shared, SharedSecret::new(&node.pubkey(), &privkey(44)));
+    }
+}
@@ -0,0 +1,417 @@
+use crate::{TLS_CERT_FILENAME, TLS_KEY_FILENAME};
+use log::{debug, info, warn};
+use rcgen::{date_time_ymd, CertificateParams, Error as RcgenError, KeyPair, SanType};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{metadata, set_permissions, File};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::select;
+use tokio::sync::watch;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use triggered::Listener;
+
+/// How long the self-signed certificates that LNDK generates are valid for.
+pub const TLS_CERT_VALIDITY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// How long before a generated certificate expires that we replace it.
+pub const TLS_CERT_RENEWAL_WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How often we check whether the server's TLS credentials need renewing or have changed on disk.
+pub const TLS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An error that occurs when generating TLS credentials.
+#[derive(Debug)]
+pub enum CertificateGenFailure {
+    RcgenError(RcgenError),
+    IoError(std::io::Error),
+}
+
+impl Display for CertificateGenFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CertificateGenFailure::RcgenError(e) => {
+                write!(f, "Error generating TLS certificate: {e:?}")
+            }
+            CertificateGenFailure::IoError(e) => write!(f, "IO error: {e:?}"),
+        }
+    }
+}
+
+impl Error for CertificateGenFailure {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TlsCredsSource {
+    /// LNDK generates self-signed credentials in its data directory, and replaces them when
+    /// they're about to expire or no longer cover the configured ips and domains.
+    Generated {
+        data_dir: PathBuf,
+        subject_alt_names: Vec<String>,
+    },
+    /// Credentials issued elsewhere, for example by an internal CA. LNDK never writes to these, it
+    /// only reloads them when they change.
+    External {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+}
+
+/// Describes where the TLS credentials for LNDK's gRPC server come from, and whether clients need
+/// to present a certificate of their own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    source: TlsCredsSource,
+    client_ca_path: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Uses self-signed credentials stored in the data directory. The certificate is valid for
+    /// localhost, plus the comma-separated ips or domains in tls_ips.
+    pub fn generated(data_dir: PathBuf, tls_ips: Option<String>) -> Self {
+        let mut subject_alt_names = vec!["localhost".to_string()];
+        if let Some(ips) = collect_tls_ips(tls_ips) {
+            subject_alt_names.extend(ips);
+        }
+
+        TlsConfig {
+            source: TlsCredsSource::Generated {
+                data_dir,
+                subject_alt_names,
+            },
+            client_ca_path: None,
+        }
+    }
+
+    /// Uses a certificate and key that are managed outside of LNDK.
+    pub fn external(cert_path: PathBuf, key_path: PathBuf) -> Self {
+        TlsConfig {
+            source: TlsCredsSource::External {
+                cert_path,
+                key_path,
+            },
+            client_ca_path: None,
+        }
+    }
+
+    /// Requires clients to present a certificate signed by the CA at the given path.
+    pub fn with_client_ca(mut self, client_ca_path: PathBuf) -> Self {
+        self.client_ca_path = Some(client_ca_path);
+        self
+    }
+
+    /// Returns the path of the certificate the server presents to clients.
+    pub fn cert_path(&self) -> PathBuf {
+        match &self.source {
+            TlsCredsSource::Generated { data_dir, .. } => data_dir.join(TLS_CERT_FILENAME),
+            TlsCredsSource::External { cert_path, .. } => cert_path.clone(),
+        }
+    }
+
+    fn key_path(&self) -> PathBuf {
+        match &self.source {
+            TlsCredsSource::Generated { data_dir, .. } => data_dir.join(TLS_KEY_FILENAME),
+            TlsCredsSource::External { key_path, .. } => key_path.clone(),
+        }
+    }
+
+    /// Makes sure that generated credentials exist and are up to date. External credentials are
+    /// left alone.
+    pub fn prepare(&self) -> Result<(), CertificateGenFailure> {
+        match &self.source {
+            TlsCredsSource::Generated {
+                data_dir,
+                subject_alt_names,
+            } => generate_tls_creds(data_dir, subject_alt_names, SystemTime::now()),
+            TlsCredsSource::External { .. } => Ok(()),
+        }
+    }
+
+    /// Reads the current credentials from disk.
+    pub fn read(&self) -> Result<TlsCreds, std::io::Error> {
+        let client_ca = match &self.client_ca_path {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => None,
+        };
+
+        Ok(TlsCreds {
+            cert: std::fs::read_to_string(self.cert_path())?,
+            key: std::fs::read_to_string(self.key_path())?,
+            client_ca,
+        })
+    }
+}
+
+/// The PEM-encoded credentials that LNDK's servers are started with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsCreds {
+    pub(crate) cert: String,
+    pub(crate) key: String,
+    pub(crate) client_ca: Option<String>,
+}
+
+impl TlsCreds {
+    pub fn server_tls_config(&self) -> ServerTlsConfig {
+        let config = ServerTlsConfig::new().identity(Identity::from_pem(&self.cert, &self.key));
+        match &self.client_ca {
+            Some(client_ca) => config.client_ca_root(Certificate::from_pem(client_ca)),
+            None => config,
+        }
+    }
+}
+
+/// Periodically renews generated credentials and checks whether the credentials on disk still match
+/// the ones we're serving. When they change, the new credentials are sent to LNDK's servers so that
+/// they can reload them.
+///
+/// Since tools that rotate certificates don't always write the certificate and key at the same
+/// time, we only reload once we've seen the same new credentials in two checks in a row. If the
+/// TLS config itself is changed (for example when the user reloads LNDK's config), we switch over
+/// right away.
+pub async fn watch_tls_creds(
+    mut tls: watch::Receiver<TlsConfig>,
+    creds: watch::Sender<TlsCreds>,
+    listener: Listener,
+) {
+    let mut interval = tokio::time::interval(TLS_CHECK_INTERVAL);
+    // The first tick completes right away.
+    interval.tick().await;
+
+    let mut pending: Option<TlsCreds> = None;
+    loop {
+        let config_changed = select! {
+            _ = listener.clone() => return,
+            _ = interval.tick() => false,
+            Ok(()) = tls.changed() => true,
+        };
+
+        let config = tls.borrow_and_update().clone();
+        if let Err(e) = config.prepare() {
+            warn!("Error renewing TLS credentials: {e}.");
+            continue;
+        }
+        let new_creds = match config.read() {
+            Ok(creds) => creds,
+            Err(e) => {
+                warn!("Error reading TLS credentials: {e}.");
+                continue;
+            }
+        };
+
+        if *creds.borrow() == new_creds {
+            pending = None;
+        } else if config_changed || pending.as_ref() == Some(&new_creds) {
+            info!("TLS credentials changed, reloading.");
+            creds.send_replace(new_creds);
+            pending = None;
+        } else {
+            pending = Some(new_creds);
+        }
+    }
+}
+
+// Creates the tls cert/key pair required to secure connections to LNDK's gRPC server, if it
+// doesn't already exist. An existing pair is replaced if the certificate is about to expire, or if
+// it doesn't cover the same ips and domains that the user has configured.
+fn generate_tls_creds(
+    data_dir: &Path,
+    subject_alt_names: &[String],
+    now: SystemTime,
+) -> Result<(), CertificateGenFailure> {
+    let cert_path = data_dir.join(TLS_CERT_FILENAME);
+    let key_path = data_dir.join(TLS_KEY_FILENAME);
+
+    // Did we have to generate a new key? In that case we also need to regenerate the certificate.
+    if key_path.exists() && cert_path.exists() {
+        let cert = std::fs::read_to_string(&cert_path).map_err(CertificateGenFailure::IoError)?;
+        if !needs_renewal(&cert, subject_alt_names, now) {
+            return Ok(());
+        }
+        info!("Replacing TLS credentials in {data_dir:?}.");
+    } else {
+        debug!("Generating fresh TLS credentials in {data_dir:?}");
+    }
+
+    let (cert, key) = generate_self_signed(subject_alt_names.to_vec(), now)
+        .map_err(CertificateGenFailure::RcgenError)?;
+
+    // Create the tls files. Make sure the key is user-readable only:
+    let mut file = File::create(&key_path).map_err(CertificateGenFailure::IoError)?;
+    let mut perms = metadata(&key_path)
+        .map_err(CertificateGenFailure::IoError)?
+        .permissions();
+    perms.set_mode(0o600);
+    set_permissions(&key_path, perms).map_err(CertificateGenFailure::IoError)?;
+
+    file.write_all(key.as_bytes())
+        .map_err(CertificateGenFailure::IoError)?;
+    drop(file);
+
+    std::fs::write(&cert_path, cert).map_err(CertificateGenFailure::IoError)?;
+
+    Ok(())
+}
+
+// Generates a self-signed certificate that's valid for TLS_CERT_VALIDITY from now, returning the
+// PEM-encoded certificate and key.
+fn generate_self_signed(
+    subject_alt_names: Vec<String>,
+    now: SystemTime,
+) -> Result<(String, String), RcgenError> {
+    let mut params = CertificateParams::new(subject_alt_names)?;
+    let now = date_time_ymd(1970, 1, 1) + now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    // Backdate the certificate a little, so that clients whose clocks are behind ours accept it.
+    params.not_before = now - Duration::from_secs(60 * 60);
+    params.not_after = now + TLS_CERT_VALIDITY;
+
+    let key_pair = KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+
+    Ok((cert.pem(), key_pair.serialize_pem()))
+}
+
+// Returns whether a generated certificate expires within TLS_CERT_RENEWAL_WINDOW, or was issued
+// for different ips and domains than the ones we've been configured with.
+fn needs_renewal(cert_pem: &str, subject_alt_names: &[String], now: SystemTime) -> bool {
+    let params = match CertificateParams::from_ca_cert_pem(cert_pem) {
+        Ok(params) => params,
+        Err(e) => {
+            // If we can't make sense of the certificate, we leave it for the server to reject
+            // rather than replacing it on every check.
+            warn!("Error parsing TLS certificate: {e:?}.");
+            return false;
+        }
+    };
+
+    let renew_from = (now + TLS_CERT_RENEWAL_WINDOW)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    if params.not_after.unix_timestamp() <= renew_from.as_secs() as i64 {
+        return true;
+    }
+
+    // rcgen treats any name that parses as an ip address as one, so we do the same before
+    // comparing.
+    let cert_names: HashSet<String> = params
+        .subject_alt_names
+        .iter()
+        .filter_map(|name| match name {
+            SanType::DnsName(name) => Some(name.as_str().to_string()),
+            SanType::IpAddress(ip) => Some(ip.to_string()),
+            _ => None,
+        })
+        .collect();
+    let wanted_names: HashSet<String> = subject_alt_names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => name.clone(),
+        })
+        .collect();
+
+    cert_names != wanted_names
+}
+
+// The user first passes in the tls ips as a comma-deliminated string into LNDK. Here we turn that
+// string into a Vec.
+fn collect_tls_ips(tls_ips_str: Option<String>) -> Option<Vec<String>> {
+    tls_ips_str.map(|tls_ips_str| tls_ips_str.split(',').map(|str| str.to_owned()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_collect_tls_ips() {
+        // Test that it returns a vector of one element if only one ip is provided.
+        let tls_ips_str = Some("192.168.0.1".to_string());
+        let tls_ips = collect_tls_ips(tls_ips_str);
+        assert!(tls_ips.is_some());
+        assert!(tls_ips.as_ref().unwrap().len() == 1);
+
+        // If no ip is provided, collect_tls_ips should return None.
+        assert!(collect_tls_ips(None).is_none());
+
+        // If two ips are provided, a vector of length two should be returned.
+        let tls_ips_str = Some("192.168.0.1,192.168.0.3".to_string());
+        let tls_ips = collect_tls_ips(tls_ips_str);
+        assert!(tls_ips.is_some());
+        assert!(tls_ips.as_ref().unwrap().len() == 2);
+    }
+
+    #[test]
+    fn test_needs_renewal() {
+        let now = SystemTime::now();
+        let subject_alt_names = names(&["localhost", "192.168.0.1"]);
+        let (cert, _) = generate_self_signed(subject_alt_names.clone(), now).unwrap();
+
+        assert!(!needs_renewal(&cert, &subject_alt_names, now));
+        assert!(!needs_renewal(&cert, &subject_alt_names, now + 300 * DAY));
+
+        // Once we're within the renewal window, the certificate should be replaced.
+        assert!(needs_renewal(&cert, &subject_alt_names, now + 340 * DAY));
+
+        // The certificate should also be replaced if the names it covers change.
+        assert!(needs_renewal(&cert, &names(&["localhost"]), now));
+        assert!(needs_renewal(
+            &cert,
+            &names(&["localhost", "192.168.0.1", "lndk.org"]),
+            now
+        ));
+    }
+
+    #[test]
+    fn test_generate_tls_creds() {
+        let dir = tempdir().unwrap();
+        let tls = TlsConfig::generated(dir.path().to_path_buf(), None);
+        tls.prepare().unwrap();
+        let creds = tls.read().unwrap();
+
+        // Credentials that are still valid are kept.
+        tls.prepare().unwrap();
+        assert_eq!(tls.read().unwrap(), creds);
+
+        // If the user adds an ip, the credentials are replaced.
+        let tls = TlsConfig::generated(dir.path().to_path_buf(), Some("10.0.0.1".to_string()));
+        tls.prepare().unwrap();
+        assert_ne!(tls.read().unwrap(), creds);
+
+        let key_perms = metadata(dir.path().join(TLS_KEY_FILENAME))
+            .unwrap()
+            .permissions();
+        assert_eq!(key_perms.mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_read_external_creds() {
+        let dir = tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let tls = TlsConfig::external(cert_path.clone(), key_path.clone());
+
+        // External credentials are never generated for the user.
+        tls.prepare().unwrap();
+        assert!(tls.read().is_err());
+
+        std::fs::write(&cert_path, "cert").unwrap();
+        std::fs::write(&key_path, "key").unwrap();
+        let creds = tls.read().unwrap();
+        assert_eq!(creds.cert, "cert");
+        assert_eq!(creds.client_ca, None);
+
+        let ca_path = dir.path().join("ca.pem");
+        std::fs::write(&ca_path, "ca").unwrap();
+        let creds = tls.with_client_ca(ca_path).read().unwrap();
+        assert_eq!(creds.client_ca, Some("ca".to_string()));
+    }
+}
@@ -9,6 +9,7 @@ use ldk_sample::config::LdkUserInfo;
 use ldk_sample::node_api::Node as LdkNode;
 use lightning::util::logger::Level;
 use lndk::lnd::validate_lnd_creds;
+use lndk::logging::LogOptions;
 use lndk::{setup_logger, LifecycleSignals, LndkOnionMessenger, OfferHandler};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
@@ -198,7 +199,7 @@ pub async fn setup_lndk(
             .unwrap()
             .to_string(),
     );
-    setup_logger(None, log_dir).unwrap();
+    setup_logger(None, log_dir, LogOptions::default()).unwrap();
 
     return (lndk_cfg, handler, messenger, shutdown);
 }
@@ -11,6 +11,7 @@ use lightning::blinded_path::{BlindedPath, IntroductionNode};
 use lightning::offers::offer::Quantity;
 use lightning::onion_message::messenger::Destination;
 use lndk::lnd::validate_lnd_creds;
+use lndk::logging::LogOptions;
 use lndk::onion_messenger::MessengerUtilities;
 use lndk::{setup_logger, LifecycleSignals, OfferHandler, PayOfferParams};
 use std::path::PathBuf;
@@ -58,6 +59,7 @@ async fn create_offers(
             destination: Destination::BlindedPath(blinded_path),
             reply_path: Some(reply_path),
             response_invoice_timeout: None,
+            payment_id: None,
         };
 
         pay_cfgs.push(pay_cfg);
@@ -214,7 +216,7 @@ async fn test_lndk_send_invoice_request() {
             .unwrap()
             .to_string(),
     );
-    setup_logger(None, log_dir).unwrap();
+    setup_logger(None, log_dir, LogOptions::default()).unwrap();
 
     // Make sure lndk successfully sends the invoice_request.
     let handler = Arc::new(lndk::OfferHandler::default());
@@ -226,6 +228,7 @@ async fn test_lndk_send_invoice_request() {
             Network::Regtest,
             Some(20_000),
             Some("".to_string()),
+            None,
         )
         .await
         .unwrap();
@@ -269,7 +272,7 @@ async fn test_lndk_send_invoice_request() {
             .unwrap()
             .to_string(),
     );
-    setup_logger(None, log_dir).unwrap();
+    setup_logger(None, log_dir, LogOptions::default()).unwrap();
 
     let handler = Arc::new(lndk::OfferHandler::default());
     let messenger = lndk::LndkOnionMessenger::new();
@@ -280,6 +283,7 @@ async fn test_lndk_send_invoice_request() {
             Network::Regtest,
             Some(20_000),
             Some("".to_string()),
+            None,
         )
         .await
         .unwrap();
@@ -344,6 +348,7 @@ async fn test_lndk_pay_offer() {
         destination: Destination::BlindedPath(blinded_path.clone()),
         reply_path: Some(reply_path),
         response_invoice_timeout: None,
+        payment_id: None,
     };
     select! {
         val = messenger.run(lndk_cfg.clone(), Arc::clone(&handler)) => {
@@ -401,6 +406,7 @@ async fn test_lndk_pay_offer_concurrently() {
         destination: Destination::BlindedPath(blinded_path.clone()),
         reply_path: Some(reply_path),
         response_invoice_timeout: None,
+        payment_id: None,
     };
     // Let's also try to pay the same offer multiple times concurrently.
     select! {
@@ -492,6 +498,7 @@ async fn test_transient_keys() {
             Network::Regtest,
             None,
             None,
+            None,
         ) => {
             let res2 = handler.create_invoice_request(
                 lnd.client.clone().unwrap(),
@@ -499,6 +506,7 @@ async fn test_transient_keys() {
                 Network::Regtest,
                 None,
                 None,
+                None,
             ).await;
 
             let pubkey1 = res1.unwrap().0.payer_id();
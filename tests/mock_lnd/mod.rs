@@ -0,0 +1,516 @@
+// An in-process stand-in for LND's grpc server, so that LNDK can be tested end to end through
+// get_lnd_client without bitcoind or a real LND node.
+//
+// The server answers the Lightning, Router, Signer, WalletKit and Versioner calls that LNDK makes
+// from a SimNode, which tests use to script peers, custom messages and channels. Routes and
+// payment updates can be scripted on the MockLnd itself.
+#![allow(dead_code)]
+
+use bitcoin::hashes::sha256::Hash;
+use bitcoin::hashes::Hash as _;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use futures::{Stream, StreamExt};
+use lndk::lnd::{
+    ChannelInfoProvider, Creds, EventStream, EventSubscriber, LndCfg, MessageSigner,
+    NodeInfoProvider, PeerConnector, SendCustomMessage, SharedKeyDeriver,
+};
+use lndk::sim::SimNode;
+use lndk::tls::TlsConfig;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, ServerStreamingService, UnaryService};
+use tonic::transport::Server;
+use tonic::{Code, Status};
+use tonic_lnd::lnrpc::htlc_attempt::HtlcStatus;
+use tonic_lnd::lnrpc::payment::PaymentStatus;
+use tonic_lnd::lnrpc::{
+    ChanInfoRequest, ChannelEventSubscription, CheckMacPermRequest, CheckMacPermResponse,
+    ConnectPeerRequest, ConnectPeerResponse, GetInfoRequest, GraphTopologySubscription,
+    HtlcAttempt, ListPeersRequest, NodeInfoRequest, Payment, PeerEventSubscription,
+    QueryRoutesRequest, QueryRoutesResponse, Route, SendCustomMessageRequest,
+    SubscribeCustomMessagesRequest,
+};
+use tonic_lnd::routerrpc::{SendToRouteRequest, TrackPaymentRequest};
+use tonic_lnd::signrpc::{
+    KeyLocator, SharedKeyRequest, SharedKeyResponse, SignMessageReq, SignMessageResp,
+};
+use tonic_lnd::verrpc::VersionRequest;
+use tonic_lnd::walletrpc::KeyReq;
+use triggered::Trigger;
+
+/// The macaroon that MockLnd hands out. It's the only macaroon that CheckMacaroonPermissions
+/// accepts, but otherwise the mock doesn't check macaroons.
+pub const MOCK_MACAROON_HEX: &str = "0201036c6e64";
+
+type MockStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// MockLnd serves LND's grpc api on a local port until it's dropped.
+pub struct MockLnd {
+    pub address: String,
+    pub cert_pem: String,
+    node: SimNode,
+    state: Arc<Mutex<MockState>>,
+    shutdown: Trigger,
+    _tls_dir: TempDir,
+}
+
+#[derive(Default)]
+struct MockState {
+    // routes is the response to QueryRoutes. If it isn't set, we make up a route for the amount.
+    routes: Option<Vec<Route>>,
+    // payment_updates are the updates that TrackPaymentV2 streams for each payment hash. If there
+    // are none, a payment that was sent succeeds.
+    payment_updates: HashMap<Vec<u8>, Vec<Payment>>,
+    sent_to_route: Vec<SendToRouteRequest>,
+}
+
+impl MockLnd {
+    /// Starts serving a node with the given key on the network.
+    pub async fn start(node_key: SecretKey, network: Network) -> MockLnd {
+        let tls_dir = tempfile::tempdir().unwrap();
+        let tls = TlsConfig::generated(tls_dir.path().to_path_buf(), Some("127.0.0.1".into()));
+        tls.prepare().expect("failed to generate tls credentials");
+        let creds = tls.read().unwrap();
+        let cert_pem = std::fs::read_to_string(tls.cert_path()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("https://{}", listener.local_addr().unwrap());
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+
+        let mock = MockService {
+            node: SimNode::new(node_key, network),
+            state: Arc::new(Mutex::new(MockState::default())),
+        };
+        let (shutdown, signal) = triggered::trigger();
+        let server = Server::builder()
+            .tls_config(creds.server_tls_config())
+            .unwrap()
+            .add_service(Lightning(mock.clone()))
+            .add_service(Router(mock.clone()))
+            .add_service(Signer(mock.clone()))
+            .add_service(WalletKit(mock.clone()))
+            .add_service(Versioner(mock.clone()))
+            .serve_with_incoming_shutdown(incoming, signal);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                println!("Mock LND server exited: {e}");
+            }
+        });
+
+        MockLnd {
+            address,
+            cert_pem,
+            node: mock.node,
+            state: mock.state,
+            shutdown,
+            _tls_dir: tls_dir,
+        }
+    }
+
+    /// Returns the config LNDK needs to connect to the mock.
+    pub fn lnd_cfg(&self) -> LndCfg {
+        LndCfg::new(
+            self.address.clone(),
+            Creds::String {
+                cert: self.cert_pem.clone(),
+                macaroon: MOCK_MACAROON_HEX.to_string(),
+            },
+        )
+    }
+
+    /// Returns the simulated node behind the mock, which scripts its peers, custom messages and
+    /// channels.
+    pub fn node(&self) -> &SimNode {
+        &self.node
+    }
+
+    /// Sets the routes that QueryRoutes returns.
+    pub fn set_routes(&self, routes: Vec<Route>) {
+        self.state.lock().unwrap().routes = Some(routes);
+    }
+
+    /// Sets the updates that TrackPaymentV2 streams for a payment.
+    pub fn set_payment_updates(&self, payment_hash: [u8; 32], updates: Vec<Payment>) {
+        self.state
+            .lock()
+            .unwrap()
+            .payment_updates
+            .insert(payment_hash.to_vec(), updates);
+    }
+
+    /// Returns the SendToRouteV2 requests that LNDK has made, oldest first.
+    pub fn sent_to_route(&self) -> Vec<SendToRouteRequest> {
+        self.state.lock().unwrap().sent_to_route.clone()
+    }
+}
+
+impl Drop for MockLnd {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+    }
+}
+
+#[derive(Clone)]
+struct MockService {
+    node: SimNode,
+    state: Arc<Mutex<MockState>>,
+}
+
+// The SimNode reports errors with tonic_lnd's version of tonic, so we convert them to ours.
+fn status(status: tonic_lnd::tonic::Status) -> Status {
+    Status::new(Code::from_i32(status.code() as i32), status.message())
+}
+
+fn stream<T: Send + 'static>(events: EventStream<T>) -> MockStream<T> {
+    Box::pin(events.map(|event| event.map_err(status)))
+}
+
+fn parse_pubkey(bytes: &[u8]) -> Result<PublicKey, Status> {
+    PublicKey::from_slice(bytes).map_err(|e| Status::invalid_argument(format!("{e}")))
+}
+
+impl MockService {
+    async fn handle<B>(self, req: http::Request<B>) -> http::Response<BoxBody>
+    where
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        let mut node = self.node.clone();
+        let path = req.uri().path().to_string();
+        match path.as_str() {
+            "/lnrpc.Lightning/GetInfo" => {
+                unary(req, |_: GetInfoRequest| async move {
+                    node.get_info().await.map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/ListPeers" => {
+                unary(req, |_: ListPeersRequest| async move {
+                    node.list_peers().await.map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/ConnectPeer" => {
+                unary(req, |req: ConnectPeerRequest| async move {
+                    let addr = req.addr.unwrap_or_default();
+                    node.connect_peer(addr.pubkey, addr.host)
+                        .await
+                        .map_err(status)?;
+                    Ok(ConnectPeerResponse::default())
+                })
+                .await
+            }
+            "/lnrpc.Lightning/GetNodeInfo" => {
+                unary(req, |req: NodeInfoRequest| async move {
+                    node.get_node_info(req.pub_key, req.include_channels)
+                        .await
+                        .map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/GetChanInfo" => {
+                unary(req, |req: ChanInfoRequest| async move {
+                    node.get_chan_info(req.chan_id).await.map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/SendCustomMessage" => {
+                unary(req, |req: SendCustomMessageRequest| async move {
+                    node.send_custom_message(req).await.map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/SubscribePeerEvents" => {
+                server_streaming(req, |_: PeerEventSubscription| async move {
+                    node.subscribe_peer_events()
+                        .await
+                        .map(stream)
+                        .map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/SubscribeCustomMessages" => {
+                server_streaming(req, |_: SubscribeCustomMessagesRequest| async move {
+                    node.subscribe_custom_messages()
+                        .await
+                        .map(stream)
+                        .map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/SubscribeChannelEvents" => {
+                server_streaming(req, |_: ChannelEventSubscription| async move {
+                    node.subscribe_channel_events()
+                        .await
+                        .map(stream)
+                        .map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/SubscribeChannelGraph" => {
+                server_streaming(req, |_: GraphTopologySubscription| async move {
+                    node.subscribe_channel_graph()
+                        .await
+                        .map(stream)
+                        .map_err(status)
+                })
+                .await
+            }
+            "/lnrpc.Lightning/CheckMacaroonPermissions" => {
+                unary(req, |req: CheckMacPermRequest| async move {
+                    // Like LND, we turn away macaroons that we didn't hand out.
+                    if hex::encode(&req.macaroon) != MOCK_MACAROON_HEX {
+                        return Err(Status::invalid_argument(
+                            "cannot get macaroon: root key with id 0 doesn't exist",
+                        ));
+                    }
+                    Ok(CheckMacPermResponse { valid: true })
+                })
+                .await
+            }
+            "/lnrpc.Lightning/QueryRoutes" => {
+                let state = self.state;
+                unary(req, |req: QueryRoutesRequest| async move {
+                    Ok(query_routes(&state, req))
+                })
+                .await
+            }
+            "/routerrpc.Router/SendToRouteV2" => {
+                let state = self.state;
+                unary(req, |req: SendToRouteRequest| async move {
+                    let route = req.route.clone();
+                    state.lock().unwrap().sent_to_route.push(req);
+                    Ok(HtlcAttempt {
+                        status: HtlcStatus::Succeeded.into(),
+                        route,
+                        ..Default::default()
+                    })
+                })
+                .await
+            }
+            "/routerrpc.Router/TrackPaymentV2" => {
+                let state = self.state;
+                server_streaming(req, |req: TrackPaymentRequest| async move {
+                    let updates = track_payment(&state, req)?;
+                    let updates: MockStream<Payment> =
+                        Box::pin(futures::stream::iter(updates.into_iter().map(Ok)));
+                    Ok(updates)
+                })
+                .await
+            }
+            "/signrpc.Signer/DeriveSharedKey" => {
+                unary(req, |req: SharedKeyRequest| async move {
+                    let key = parse_pubkey(&req.ephemeral_pubkey)?;
+                    let shared_key = node.derive_shared_key(key).await.map_err(status)?;
+                    Ok(SharedKeyResponse {
+                        shared_key: shared_key.secret_bytes().to_vec(),
+                    })
+                })
+                .await
+            }
+            "/signrpc.Signer/SignMessage" => {
+                unary(req, |req: SignMessageReq| async move {
+                    let msg = Hash::from_slice(&req.msg)
+                        .map_err(|e| Status::invalid_argument(format!("{e}")))?;
+                    let tag = String::from_utf8(req.tag)
+                        .map_err(|e| Status::invalid_argument(format!("{e}")))?;
+                    let signature = node
+                        .sign_message(req.key_loc.unwrap_or_default(), msg, tag)
+                        .await
+                        .map_err(status)?;
+                    Ok(SignMessageResp { signature })
+                })
+                .await
+            }
+            "/walletrpc.WalletKit/DeriveNextKey" => {
+                unary(req, |req: KeyReq| async move {
+                    node.derive_next_key(req).await.map_err(status)
+                })
+                .await
+            }
+            "/walletrpc.WalletKit/DeriveKey" => {
+                unary(req, |req: KeyLocator| async move {
+                    node.derive_key(req).await.map_err(status)
+                })
+                .await
+            }
+            "/verrpc.Versioner/GetVersion" => {
+                unary(req, |_: VersionRequest| async move {
+                    node.get_version().await.map_err(status)
+                })
+                .await
+            }
+            _ => http::Response::builder()
+                .status(200)
+                .header("grpc-status", (Code::Unimplemented as i32).to_string())
+                .header("content-type", "application/grpc")
+                .body(empty_body())
+                .unwrap(),
+        }
+    }
+}
+
+fn query_routes(state: &Mutex<MockState>, req: QueryRoutesRequest) -> QueryRoutesResponse {
+    if let Some(routes) = state.lock().unwrap().routes.clone() {
+        return QueryRoutesResponse {
+            routes,
+            ..Default::default()
+        };
+    }
+
+    let fees_msat = req
+        .blinded_payment_paths
+        .first()
+        .map(|path| {
+            path.base_fee_msat as i64 + req.amt_msat * path.proportional_fee_msat as i64 / 1_000_000
+        })
+        .unwrap_or_default();
+    QueryRoutesResponse {
+        routes: vec![Route {
+            total_amt_msat: req.amt_msat + fees_msat,
+            total_fees_msat: fees_msat,
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+fn track_payment(
+    state: &Mutex<MockState>,
+    req: TrackPaymentRequest,
+) -> Result<Vec<Payment>, Status> {
+    let state = state.lock().unwrap();
+    if let Some(updates) = state.payment_updates.get(&req.payment_hash) {
+        return Ok(updates.clone());
+    }
+
+    let sent = state
+        .sent_to_route
+        .iter()
+        .find(|sent| sent.payment_hash == req.payment_hash)
+        .ok_or_else(|| Status::not_found("payment isn't in the db"))?;
+    let route = sent.route.clone().unwrap_or_default();
+    Ok(vec![Payment {
+        payment_hash: hex::encode(&req.payment_hash),
+        value_msat: route.total_amt_msat - route.total_fees_msat,
+        fee_msat: route.total_fees_msat,
+        status: PaymentStatus::Succeeded.into(),
+        ..Default::default()
+    }])
+}
+
+// UnaryFn serves a unary call with a closure.
+struct UnaryFn<F>(Option<F>);
+
+impl<Req, Resp, F, Fut> UnaryService<Req> for UnaryFn<F>
+where
+    F: FnOnce(Req) -> Fut,
+    Fut: Future<Output = Result<Resp, Status>> + Send + 'static,
+{
+    type Response = Resp;
+    type Future = BoxFuture<tonic::Response<Resp>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+        let handler = self.0.take().expect("unary call handled twice");
+        let response = handler(request.into_inner());
+        Box::pin(async move { response.await.map(tonic::Response::new) })
+    }
+}
+
+// StreamFn serves a server streaming call with a closure.
+struct StreamFn<F>(Option<F>);
+
+impl<Req, Resp, F, Fut> ServerStreamingService<Req> for StreamFn<F>
+where
+    F: FnOnce(Req) -> Fut,
+    Fut: Future<Output = Result<MockStream<Resp>, Status>> + Send + 'static,
+    Resp: Send + 'static,
+{
+    type Response = Resp;
+    type ResponseStream = MockStream<Resp>;
+    type Future = BoxFuture<tonic::Response<MockStream<Resp>>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+        let handler = self.0.take().expect("streaming call handled twice");
+        let response = handler(request.into_inner());
+        Box::pin(async move { response.await.map(tonic::Response::new) })
+    }
+}
+
+async fn unary<B, Req, Resp, F, Fut>(req: http::Request<B>, handler: F) -> http::Response<BoxBody>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnOnce(Req) -> Fut,
+    Fut: Future<Output = Result<Resp, Status>> + Send + 'static,
+{
+    let mut grpc = Grpc::new(ProstCodec::<Resp, Req>::default());
+    grpc.unary(UnaryFn(Some(handler)), req).await
+}
+
+async fn server_streaming<B, Req, Resp, F, Fut>(
+    req: http::Request<B>,
+    handler: F,
+) -> http::Response<BoxBody>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnOnce(Req) -> Fut,
+    Fut: Future<Output = Result<MockStream<Resp>, Status>> + Send + 'static,
+{
+    let mut grpc = Grpc::new(ProstCodec::<Resp, Req>::default());
+    grpc.server_streaming(StreamFn(Some(handler)), req).await
+}
+
+// Each of LND's grpc services is served by the same MockService, under the service's name.
+macro_rules! mock_service {
+    ($service:ident, $name:literal) => {
+        #[derive(Clone)]
+        struct $service(MockService);
+
+        impl<B> Service<http::Request<B>> for $service
+        where
+            B: Body + Send + 'static,
+            B::Error: Into<StdError> + Send + 'static,
+        {
+            type Response = http::Response<BoxBody>;
+            type Error = Infallible;
+            type Future = BoxFuture<Self::Response, Self::Error>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, req: http::Request<B>) -> Self::Future {
+                let mock = self.0.clone();
+                Box::pin(async move { Ok(mock.handle(req).await) })
+            }
+        }
+
+        impl NamedService for $service {
+            const NAME: &'static str = $name;
+        }
+    };
+}
+
+mock_service!(Lightning, "lnrpc.Lightning");
+mock_service!(Router, "routerrpc.Router");
+mock_service!(Signer, "signrpc.Signer");
+mock_service!(WalletKit, "walletrpc.WalletKit");
+mock_service!(Versioner, "verrpc.Versioner");
@@ -0,0 +1,317 @@
+mod mock_lnd;
+
+use bitcoin::hashes::sha256::Hash;
+use bitcoin::hashes::Hash as _;
+use bitcoin::network::constants::Network;
+use lightning::ln::PaymentHash;
+use lightning::onion_message::messenger::{Destination, PendingOnionMessage};
+use lightning::onion_message::offers::{OffersMessage, OffersMessageHandler};
+use lightning::util::ser::Writeable;
+use lndk::lndkrpc::offers_server::Offers;
+use lndk::lndkrpc::{
+    GetMetricsRequest, GetPaymentRequest, PayInvoiceRequest, PayOfferRequest, PaymentCounts,
+};
+use lndk::sim::fixtures::{
+    build_invoice, build_invoice_request, build_offer, offer_keys, privkey, pubkey, QueuedHandler,
+};
+use lndk::sim::SimNode;
+use lndk::{Cfg, LifecycleSignals, LndkOnionMessenger, OfferHandler};
+use mock_lnd::{MockLnd, MOCK_MACAROON_HEX};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::time::{sleep, timeout, Duration};
+use tonic_lnd::lnrpc::payment::PaymentStatus;
+use tonic_lnd::lnrpc::Payment;
+
+const ONION_MESSAGE_TYPE: u32 = 513;
+
+// InvoiceResponder answers every invoice request with an invoice for the given payment hash, as the
+// creator of offers built by build_offer.
+struct InvoiceResponder {
+    payment_hash: PaymentHash,
+    polls: AtomicUsize,
+}
+
+impl OffersMessageHandler for InvoiceResponder {
+    fn handle_message(&self, message: OffersMessage) -> Option<OffersMessage> {
+        match message {
+            OffersMessage::InvoiceRequest(invoice_request) => Some(OffersMessage::Invoice(
+                build_invoice(&invoice_request, self.payment_hash),
+            )),
+            _ => None,
+        }
+    }
+
+    fn release_pending_messages(&self) -> Vec<PendingOnionMessage<OffersMessage>> {
+        self.polls.fetch_add(1, Ordering::SeqCst);
+        vec![]
+    }
+}
+
+// Passes the onion messages that one node sends to another on to it, as if they were connected
+// peers.
+async fn relay_messages(from: SimNode, to: SimNode) {
+    let mut relayed = 0;
+    loop {
+        let sent = from.sent_messages();
+        for message in &sent[relayed..] {
+            if message.peer == to.pubkey().serialize().to_vec() {
+                to.receive_custom_message(from.pubkey(), message.r#type, message.data.clone());
+            }
+        }
+        relayed = sent.len();
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+// Scripts LND's updates for a payment that succeeds with the preimage, returning its hash.
+fn expect_payment(lnd: &MockLnd, preimage: [u8; 32]) -> [u8; 32] {
+    let payment_hash = Hash::hash(&preimage).to_byte_array();
+    lnd.set_payment_updates(
+        payment_hash,
+        vec![
+            Payment {
+                status: PaymentStatus::InFlight.into(),
+                ..Default::default()
+            },
+            Payment {
+                payment_hash: hex::encode(payment_hash),
+                payment_preimage: hex::encode(preimage),
+                value_msat: 20_000,
+                status: PaymentStatus::Succeeded.into(),
+                ..Default::default()
+            },
+        ],
+    );
+    payment_hash
+}
+
+async fn server(lnd: &MockLnd, handler: Arc<OfferHandler>) -> LNDKServer {
+    LNDKServer::new(
+        handler,
+        &lnd.node().pubkey().to_string(),
+        Network::Regtest,
+        lnd.cert_pem.clone(),
+        lnd.address.clone(),
+    )
+    .await
+}
+
+fn with_macaroon<T>(message: T, macaroon: &str) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    request
+        .metadata_mut()
+        .insert("macaroon", macaroon.parse().unwrap());
+    request
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_onion_messenger_with_mock_lnd() {
+    let lnd = MockLnd::start(privkey(1), Network::Regtest).await;
+    let peer = pubkey(2);
+    lnd.node().add_peer(peer, true);
+
+    let handler = Arc::new(QueuedHandler::new(vec![PendingOnionMessage {
+        contents: OffersMessage::InvoiceRequest(build_invoice_request(&build_offer("mock"))),
+        destination: Destination::Node(peer),
+        reply_path: None,
+    }]));
+
+    let (shutdown, listener) = triggered::trigger();
+    let args = Cfg {
+        lnd: lnd.lnd_cfg(),
+        signals: LifecycleSignals {
+            shutdown: shutdown.clone(),
+            listener,
+        },
+        skip_version_check: false,
+    };
+    let messenger = tokio::spawn(async move { LndkOnionMessenger::new().run(args, handler).await });
+
+    // The messenger should deliver the invoice request to our peer through LND.
+    let sent = timeout(Duration::from_secs(10), async {
+        loop {
+            let sent = lnd.node().sent_messages();
+            if !sent.is_empty() {
+                return sent;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("onion message not sent");
+    assert_eq!(sent[0].peer, peer.serialize().to_vec());
+    assert_eq!(sent[0].r#type, ONION_MESSAGE_TYPE);
+
+    shutdown.trigger();
+    let result = timeout(Duration::from_secs(10), messenger)
+        .await
+        .expect("messenger did not shut down")
+        .unwrap();
+    assert!(result.is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pay_offer_with_mock_lnd() {
+    let lnd = MockLnd::start(privkey(1), Network::Regtest).await;
+    let preimage = [7; 32];
+    let payment_hash = expect_payment(&lnd, preimage);
+
+    // The offer's creator is a peer of our node, which runs its own onion messenger on a simulated
+    // node and answers our invoice request.
+    let creator = SimNode::new(offer_keys().secret_key(), Network::Regtest);
+    creator.add_peer(lnd.node().pubkey(), true);
+    lnd.node().add_peer(creator.pubkey(), true);
+    let relays = [
+        tokio::spawn(relay_messages(lnd.node().clone(), creator.clone())),
+        tokio::spawn(relay_messages(creator.clone(), lnd.node().clone())),
+    ];
+
+    let (shutdown, listener) = triggered::trigger();
+    let responder = Arc::new(InvoiceResponder {
+        payment_hash: PaymentHash(payment_hash),
+        polls: AtomicUsize::new(0),
+    });
+    let creator_signals = LifecycleSignals {
+        shutdown: shutdown.clone(),
+        listener: listener.clone(),
+    };
+    let creator_responder = Arc::clone(&responder);
+    let creator_messenger = tokio::spawn(async move {
+        LndkOnionMessenger::new()
+            .run_with_backend(creator, creator_signals, false, creator_responder)
+            .await
+    });
+
+    // The creator's messenger polls for outgoing messages once it's listening for incoming ones,
+    // so we wait for it to get going before we send it anything.
+    timeout(Duration::from_secs(10), async {
+        while responder.polls.load(Ordering::SeqCst) < 2 {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("offer creator's messenger didn't start");
+
+    let handler = Arc::new(OfferHandler::new(None));
+    let args = Cfg {
+        lnd: lnd.lnd_cfg(),
+        signals: LifecycleSignals {
+            shutdown: shutdown.clone(),
+            listener,
+        },
+        skip_version_check: false,
+    };
+    let messenger_handler = Arc::clone(&handler);
+    let messenger =
+        tokio::spawn(async move { LndkOnionMessenger::new().run(args, messenger_handler).await });
+
+    let server = server(&lnd, handler).await;
+    let request = with_macaroon(
+        PayOfferRequest {
+            offer: build_offer("mock").to_string(),
+            amount: None,
+            payer_note: None,
+            response_invoice_timeout: Some(10),
+            idempotency_key: None,
+            no_wait: false,
+        },
+        MOCK_MACAROON_HEX,
+    );
+    let response = timeout(Duration::from_secs(30), server.pay_offer(request))
+        .await
+        .expect("payment didn't complete")
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.payment_preimage, hex::encode(preimage));
+
+    // We should have paid the invoice that the offer's creator sent back.
+    let sent = lnd.sent_to_route();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].payment_hash, payment_hash.to_vec());
+    assert_eq!(sent[0].route.as_ref().unwrap().total_amt_msat, 20_021);
+
+    shutdown.trigger();
+    for messenger in [messenger, creator_messenger] {
+        let result = timeout(Duration::from_secs(10), messenger)
+            .await
+            .expect("messenger did not shut down")
+            .unwrap();
+        assert!(result.is_ok());
+    }
+    relays.iter().for_each(|relay| relay.abort());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pay_invoice_with_mock_lnd() {
+    let lnd = MockLnd::start(privkey(1), Network::Regtest).await;
+
+    let preimage = [7; 32];
+    let payment_hash = expect_payment(&lnd, preimage);
+    let invoice = build_invoice(
+        &build_invoice_request(&build_offer("mock")),
+        PaymentHash(payment_hash),
+    );
+
+    let server = server(&lnd, Arc::new(OfferHandler::new(None))).await;
+    let request = with_macaroon(
+        PayInvoiceRequest {
+            invoice: hex::encode(invoice.encode()),
+            amount: None,
+            idempotency_key: None,
+        },
+        MOCK_MACAROON_HEX,
+    );
+
+    let response = server.pay_invoice(request).await.unwrap().into_inner();
+    assert_eq!(response.payment_preimage, hex::encode(preimage));
+
+    // LNDK should have paid the invoice's amount plus the fees of its blinded path.
+    let sent = lnd.sent_to_route();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].payment_hash, payment_hash.to_vec());
+    assert_eq!(sent[0].route.as_ref().unwrap().total_amt_msat, 20_021);
+
+    let metrics = server
+        .get_metrics(with_macaroon(GetMetricsRequest {}, MOCK_MACAROON_HEX))
+        .await
+        .unwrap()
+        .into_inner();
+    let counts = PaymentCounts {
+        attempted: 1,
+        succeeded: 1,
+        ..Default::default()
+    };
+    assert_eq!(metrics.nodes.len(), 1);
+    assert_eq!(metrics.nodes[0].node_id, lnd.node().pubkey().to_string());
+    assert_eq!(metrics.nodes[0].payments, Some(counts.clone()));
+    assert_eq!(metrics.total, Some(counts));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_get_payment_checks_macaroon() {
+    let lnd = MockLnd::start(privkey(1), Network::Regtest).await;
+    let server = server(&lnd, Arc::new(OfferHandler::new(None))).await;
+
+    let request = |macaroon: &str| {
+        with_macaroon(
+            GetPaymentRequest {
+                payment_id: hex::encode([1; 32]),
+            },
+            macaroon,
+        )
+    };
+
+    // A macaroon that LND didn't issue is turned away before we look up the payment.
+    let err = server
+        .get_payment(request("0201036c6e65"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unauthenticated);
+
+    let err = server
+        .get_payment(request(MOCK_MACAROON_HEX))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound);
+}